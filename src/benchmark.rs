@@ -0,0 +1,273 @@
+/* SPDX-License-Identifier: MIT */
+
+use crate::setup::run_device_reset;
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::Write as _;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Size of the built-in corpus, used when no sample is given
+const CORPUS_SIZE: usize = 16 * 1024 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkResult {
+    pub algorithm: String,
+    /// orig_data_size and compr_data_size from mm_stat
+    pub orig_data_size: u64,
+    pub compr_data_size: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchmarkResult {
+    fn ratio(&self) -> f64 {
+        if self.compr_data_size == 0 {
+            return 0.;
+        }
+        self.orig_data_size as f64 / self.compr_data_size as f64
+    }
+
+    /// MB/s of data written into the device
+    fn throughput(&self) -> f64 {
+        self.orig_data_size as f64 / 1024. / 1024. / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+/// Write the sample (or the built-in corpus) into a fresh zram device with each available algorithm,
+/// and print how well each compressed it, best first.
+pub fn run_benchmark(sample: Option<&Path>) -> Result<()> {
+    let data = match sample {
+        Some(path) => {
+            fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?
+        }
+        None => builtin_corpus(CORPUS_SIZE),
+    };
+    if data.is_empty() {
+        return Err(anyhow!("Sample is empty, nothing to compress"));
+    }
+
+    let device_number = add_device()?;
+    let device_name = format!("zram{}", device_number);
+    info!("Benchmarking on /dev/{}", device_name);
+
+    let results = benchmark_device(&device_name, &data);
+
+    /* Clean up even if the benchmark failed. */
+    if let Err(e) = run_device_reset(Path::new("/"), &device_name) {
+        warn!("Failed to reset {}: {}", device_name, e);
+    }
+    if let Err(e) = fs::write(
+        "/sys/class/zram-control/hot_remove",
+        device_number.to_string(),
+    ) {
+        warn!("Failed to remove {}: {}", device_name, e);
+    }
+
+    let mut results = results?;
+    if results.is_empty() {
+        return Err(anyhow!("No compression algorithm could be benchmarked"));
+    }
+    rank(&mut results);
+    print!("{}", format_table(&results));
+    Ok(())
+}
+
+/// A new device, so that no configured one is disturbed
+fn add_device() -> Result<u64> {
+    if !Path::new("/sys/class/zram-control").exists() {
+        return Err(anyhow!(
+            "/sys/class/zram-control doesn't exist: the zram module isn't loaded (modprobe zram)"
+        ));
+    }
+    fs::read_to_string("/sys/class/zram-control/hot_add")
+        .context("Adding zram device (this needs root)")?
+        .trim_end()
+        .parse()
+        .context("Fresh zram device number")
+}
+
+fn benchmark_device(device_name: &str, data: &[u8]) -> Result<Vec<BenchmarkResult>> {
+    let device_sysfs_path = Path::new("/sys/block").join(device_name);
+    let comp_algorithm_path = device_sysfs_path.join("comp_algorithm");
+    let comp_algorithm = fs::read_to_string(&comp_algorithm_path)
+        .with_context(|| format!("Failed to read {}", comp_algorithm_path.display()))?;
+
+    let disksize = data.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    let mut results = vec![];
+    for algorithm in available_algorithms(&comp_algorithm) {
+        run_device_reset(Path::new("/"), device_name)
+            .with_context(|| format!("Failed to reset {}", device_name))?;
+        if let Err(e) = fs::write(&comp_algorithm_path, algorithm) {
+            warn!("Skipping algorithm {:?}: {}", algorithm, e);
+            continue;
+        }
+        fs::write(device_sysfs_path.join("disksize"), disksize.to_string())
+            .with_context(|| format!("Failed to configure disk size of {}", device_name))?;
+
+        let dev_path = Path::new("/dev").join(device_name);
+        let start = Instant::now();
+        let mut dev = OpenOptions::new()
+            .write(true)
+            .open(&dev_path)
+            .with_context(|| format!("Failed to open {}", dev_path.display()))?;
+        dev.write_all(data)
+            .and_then(|_| dev.sync_all())
+            .with_context(|| format!("Failed to write into {}", dev_path.display()))?;
+        let elapsed = start.elapsed();
+
+        let mm_stat_path = device_sysfs_path.join("mm_stat");
+        let (orig_data_size, compr_data_size) = fs::read_to_string(&mm_stat_path)
+            .ok()
+            .as_deref()
+            .and_then(parse_mm_stat)
+            .ok_or_else(|| anyhow!("Failed to read {}", mm_stat_path.display()))?;
+
+        results.push(BenchmarkResult {
+            algorithm: algorithm.to_string(),
+            orig_data_size,
+            compr_data_size,
+            elapsed,
+        });
+    }
+    Ok(results)
+}
+
+/// All the algorithms in a comp_algorithm readback, i.e. "lzo", "zstd", "lz4" in "lzo [zstd] lz4".
+fn available_algorithms(comp_algorithm: &str) -> Vec<&str> {
+    comp_algorithm
+        .split_whitespace()
+        .map(|a| a.trim_start_matches('[').trim_end_matches(']'))
+        .collect()
+}
+
+/// orig_data_size and compr_data_size, the first two fields of mm_stat
+fn parse_mm_stat(mm_stat: &str) -> Option<(u64, u64)> {
+    let mut fields = mm_stat.split_whitespace().map(|f| f.parse().ok());
+    Some((fields.next()??, fields.next()??))
+}
+
+/// Best compression ratio first, then fastest
+fn rank(results: &mut [BenchmarkResult]) {
+    results.sort_by(|a, b| {
+        b.ratio()
+            .total_cmp(&a.ratio())
+            .then(b.throughput().total_cmp(&a.throughput()))
+    });
+}
+
+fn format_table(results: &[BenchmarkResult]) -> String {
+    let mut table = format!(
+        "{:<12} {:>7} {:>12} {:>12} {:>10}\n",
+        "ALGORITHM", "RATIO", "ORIGINAL", "COMPRESSED", "MB/s"
+    );
+    for r in results {
+        writeln!(
+            table,
+            "{:<12} {:>7.2} {:>12} {:>12} {:>10.1}",
+            r.algorithm,
+            r.ratio(),
+            r.orig_data_size,
+            r.compr_data_size,
+            r.throughput()
+        )
+        .unwrap();
+    }
+    table
+}
+
+/// A mix of text (which compresses well) and pseudo-random pages (which don't),
+/// roughly like the contents of swap. Zero pages are left out,
+/// since zram stores them without compressing.
+fn builtin_corpus(size: usize) -> Vec<u8> {
+    const TEXT: &[u8] = include_bytes!("../man/zram-generator.conf.md");
+
+    let mut corpus = Vec::with_capacity(size);
+    let mut text = TEXT.iter().cycle();
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    for page in 0.. {
+        if corpus.len() >= size {
+            break;
+        }
+        let len = PAGE_SIZE.min(size - corpus.len());
+        if page % 4 == 3 {
+            for _ in 0..len {
+                /* xorshift64 */
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                corpus.push(state as u8);
+            }
+        } else {
+            corpus.extend(text.by_ref().take(len));
+        }
+    }
+    corpus
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_available_algorithms() {
+        assert_eq!(
+            available_algorithms("lzo [lzo-rle] lz4 zstd\n"),
+            ["lzo", "lzo-rle", "lz4", "zstd"]
+        );
+        assert!(available_algorithms("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_mm_stat() {
+        assert_eq!(
+            parse_mm_stat("16777216  4353124  4513792        0  4513792        0     1024        0        0\n"),
+            Some((16777216, 4353124))
+        );
+        assert_eq!(parse_mm_stat("12\n"), None);
+        assert_eq!(parse_mm_stat("a b c\n"), None);
+    }
+
+    fn result(algorithm: &str, compr_data_size: u64, millis: u64) -> BenchmarkResult {
+        BenchmarkResult {
+            algorithm: algorithm.to_string(),
+            orig_data_size: 1024 * 1024,
+            compr_data_size,
+            elapsed: Duration::from_millis(millis),
+        }
+    }
+
+    #[test]
+    fn test_rank() {
+        let mut results = vec![
+            result("lzo", 512 * 1024, 10),
+            result("zstd", 256 * 1024, 40),
+            result("lz4", 512 * 1024, 5),
+        ];
+        rank(&mut results);
+        let names: Vec<_> = results.iter().map(|r| &r.algorithm[..]).collect();
+        assert_eq!(names, ["zstd", "lz4", "lzo"]);
+    }
+
+    #[test]
+    fn test_format_table() {
+        assert_eq!(
+            format_table(&[result("zstd", 256 * 1024, 500)]),
+            "\
+ALGORITHM      RATIO     ORIGINAL   COMPRESSED       MB/s
+zstd            4.00      1048576       262144        2.0
+"
+        );
+    }
+
+    #[test]
+    fn test_builtin_corpus() {
+        let corpus = builtin_corpus(10 * PAGE_SIZE + 100);
+        assert_eq!(corpus.len(), 10 * PAGE_SIZE + 100);
+        assert_eq!(corpus, builtin_corpus(10 * PAGE_SIZE + 100));
+        assert!(corpus.chunks(PAGE_SIZE).all(|p| p.iter().any(|&b| b != 0)));
+        assert_ne!(corpus[..PAGE_SIZE], corpus[3 * PAGE_SIZE..4 * PAGE_SIZE]);
+    }
+}
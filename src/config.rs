@@ -3,42 +3,154 @@
 use anyhow::{anyhow, Context, Result};
 use fasteval::Evaler;
 use ini::Ini;
-use log::{info, warn};
+use log::{info, log, log_enabled, trace, warn, Level};
 use std::borrow::Cow;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ffi::OsString;
 use std::fmt;
+use std::fmt::Write as _;
 use std::fs;
-use std::io::{prelude::*, BufReader};
+use std::io::{self, prelude::*, BufReader};
+use std::mem;
+use std::ops::RangeInclusive;
 use std::os::unix::process::ExitStatusExt;
 use std::path::{Component, Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
 
 const DEFAULT_ZRAM_SIZE: &str = "min(ram / 2, 4096)";
+/// for file systems, which are sized for what is to be stored on them, not as a share of memory
+const DEFAULT_ZRAM_SIZE_FS: &str = "min(ram, 4096)";
 const DEFAULT_RESIDENT_LIMIT: &str = "0";
+/// recompress-now= above this many passes is refused, since each goes over the whole device
+const MAX_RECOMPRESS_PASSES: u32 = 16;
+const DEFAULT_SWAP_PRIORITY: i32 = 100;
+/// zram-size/zram-resident-limit above this many times MemTotal are refused
+const MAX_SIZE_RAM_MULTIPLE: u64 = 64;
+/// swap devices above this many times MemTotal are warned about, unless no-size-warning=
+const SIZE_WARNING_RAM_MULTIPLE: u64 = 2;
+/// How long the setup service waits for /sys/block/zramN, unless device-wait= says otherwise
+pub const DEFAULT_DEVICE_WAIT: Duration = Duration::from_secs(1);
+/// device-wait= above this many seconds is refused; setup-timeout= is the place for longer waits
+const MAX_DEVICE_WAIT_SEC: f64 = 300.;
 
 pub struct Device {
     pub name: String,
 
     pub host_memory_limit_mb: Option<u64>,
+    /// how far above host_memory_limit_mb a device set up already is kept
+    pub host_memory_limit_hysteresis_mb: Option<u64>,
+    /// not created if more swap than this, other than zram, is active
+    pub skip_if_swap_above_mb: Option<u64>,
+    /// condition-path-exists=: not created unless the path exists under the root
+    /// (or, if negated with "!", doesn't)
+    pub condition_path_exists: Option<(bool, PathBuf)>,
 
-    /// Default: `DEFAULT_ZRAM_SIZE`
+    /// Default: `DEFAULT_ZRAM_SIZE`, or `DEFAULT_ZRAM_SIZE_FS` for file systems
     pub zram_size: Option<(String, fasteval::ExpressionI, fasteval::Slab)>,
+    /// the memory the device may take up; instead of zram_size, the size is this times expected_ratio
+    pub ram_budget: Option<(String, fasteval::ExpressionI, fasteval::Slab)>,
+    /// the compression ratio ram_budget is multiplied with; default: 1
+    pub expected_ratio: Option<f64>,
     pub compression_algorithms: Algorithms,
+    /// when false, algorithms after the first are not set up for recompression
+    pub recompression: bool,
+    /// when set, a timer recompresses idle pages this often
+    pub recompress_interval: Option<String>,
+    /// how many times to recompress right after setup
+    pub recompress_now: u32,
     pub writeback_dev: Option<PathBuf>,
+    /// the crypttab(5) volume writeback_dev is unlocked as, to order the setup after
+    pub writeback_crypttab_name: Option<String>,
+    /// whether the setup service is bound to writeback_dev, and stopped when it goes away
+    pub writeback_required: bool,
+    /// /sys/block/zramX/block_size, where supported
+    pub block_size: Option<u32>,
+    /// mkswap --pagesize, for a swap device to be used on another architecture
+    pub swap_page_size: Option<u32>,
+    /// format swap in a separate unit just before swapon, instead of in the setup service
+    pub lazy_makefs: bool,
+    /// who formats the device: the setup service, or a systemd-makefs@.service the swap or mount unit requires
+    pub makefs_mode: MakefsMode,
+    /// don't warn about a swap device larger than SIZE_WARNING_RAM_MULTIPLE times MemTotal
+    pub no_size_warning: bool,
+    /// swapoff before hibernating, so that nothing is left in (volatile) zram, and swapon after resuming
+    pub hibernate_safe: bool,
+    /// a swap which fails to activate doesn't hold up swap.target, like nofail in fstab
+    pub swap_nofail: bool,
+    /// how long setup polls for /sys/block/zramN to appear, before hot-adding it or failing
+    pub device_wait: Duration,
+    /// a device already set up as configured (say, in the initrd) is kept when the setup service starts again,
+    /// instead of being set up anew
+    pub persist_across_daemon_reload: bool,
     pub disksize: u64,
+    /// percentage of disksize set aside for file system overhead, on top of zram_size
+    pub fs_reserve: Option<f64>,
 
     /// /sys/block/zramX/mem_limit; default: `DEFAULT_RESIDENT_LIMIT`
     pub zram_resident_limit: Option<(String, fasteval::ExpressionI, fasteval::Slab)>,
     pub mem_limit: u64,
 
-    pub swap_priority: i32,
+    /// swap-priority=; if unset, from role=, or DEFAULT_SWAP_PRIORITY
+    pub swap_priority: Option<i32>,
+    pub role: Option<SwapRole>,
+    /// added to swap_priority, see effective_swap_priority()
+    pub swap_tier: i32,
+    /// vm.swappiness to set while this swap device is configured;
+    /// this is global, see resolve_swappiness() and generator::write_sysctl()
+    pub swappiness: Option<u8>,
     /// when set, a mount unit will be created
     pub mount_point: Option<PathBuf>,
+    /// when set, the setup service creates mount_point
+    pub create_mount_point: bool,
+    /// mode of the root directory of the file system
+    pub mount_mode: Option<u32>,
+    /// owner and group of the root directory of the file system, as configured
+    pub mount_owner: Option<String>,
+    pub mount_group: Option<String>,
+    /// mount_owner and mount_group resolved to numeric ids, see resolve_mount_ids()
+    pub mount_uid: Option<u32>,
+    pub mount_gid: Option<u32>,
+    /// units whose .wants/ the swap or mount unit is linked into;
+    /// empty means swap.target or local-fs.target
+    pub wanted_by: Vec<String>,
+    /// units whose .requires/ the swap or mount unit is linked into
+    pub required_by: Vec<String>,
+    /// After= of the setup service
+    pub after: Vec<String>,
+    /// Before= of the setup service
+    pub before: Vec<String>,
     /// useful mostly for mounts,
     /// None is the same as "swap" when mount_point is not set
     pub fs_type: Option<String>,
     pub options: Cow<'static, str>,
+    /// TimeoutStartSec= of the setup service; None keeps the oneshot default (infinity)
+    pub setup_timeout: Option<String>,
+    /// DefaultDependencies= of the swap or mount unit;
+    /// None keeps the default, which is no for swap and yes for mounts
+    pub default_dependencies: Option<bool>,
+    /// when set, the setup service conflicts with other zram setup tools
+    pub exclusive: bool,
+    /// CPUAffinity= of the setup service
+    pub cpu_affinity: Option<String>,
+    /// MemoryMax= of the setup service
+    pub setup_memory_max: Option<String>,
+    /// TasksMax= of the setup service
+    pub setup_tasks_max: Option<String>,
+    /// Slice= of the setup service
+    pub slice: Option<String>,
+    /// ExecStartPost= lines of the setup service, passed through verbatim
+    pub exec_start_post: Vec<String>,
+    /// ExecStopPost= lines of the setup service, passed through verbatim
+    pub exec_stop_post: Vec<String>,
+    /// Condition*= and Assert*= lines of the setup service, from condition= and assert=, in order
+    pub conditions: Vec<String>,
+
+    /// how disksize was arrived at, see explain_devices()
+    pub explanation: Vec<String>,
+    /// the file and value each key was last set from, to report overrides
+    pub sources: BTreeMap<String, (PathBuf, String)>,
 
     /// deprecated, overrides zram_size
     pub zram_fraction: Option<f64>,
@@ -51,16 +163,62 @@ impl Device {
         Device {
             name,
             host_memory_limit_mb: None,
+            host_memory_limit_hysteresis_mb: None,
+            skip_if_swap_above_mb: None,
+            condition_path_exists: None,
             zram_size: None,
+            ram_budget: None,
+            expected_ratio: None,
             compression_algorithms: Default::default(),
+            recompression: true,
+            recompress_interval: None,
+            recompress_now: 0,
             writeback_dev: None,
+            writeback_crypttab_name: None,
+            writeback_required: true,
+            block_size: None,
+            swap_page_size: None,
+            lazy_makefs: false,
+            makefs_mode: MakefsMode::Setup,
+            no_size_warning: false,
+            hibernate_safe: false,
+            swap_nofail: false,
+            device_wait: DEFAULT_DEVICE_WAIT,
+            persist_across_daemon_reload: true,
             disksize: 0,
+            fs_reserve: None,
             zram_resident_limit: None,
             mem_limit: 0,
-            swap_priority: 100,
+            swap_priority: None,
+            role: None,
+            swap_tier: 0,
+            swappiness: None,
             mount_point: None,
+            create_mount_point: false,
+            mount_mode: None,
+            mount_owner: None,
+            mount_group: None,
+            mount_uid: None,
+            mount_gid: None,
+            wanted_by: vec![],
+            required_by: vec![],
+            after: vec![],
+            before: vec![],
             fs_type: None,
             options: "discard".into(),
+            setup_timeout: None,
+            default_dependencies: None,
+            exclusive: false,
+            cpu_affinity: None,
+            setup_memory_max: None,
+            setup_tasks_max: None,
+            slice: None,
+            exec_start_post: vec![],
+            exec_stop_post: vec![],
+            conditions: vec![],
+
+            explanation: vec![],
+            sources: BTreeMap::new(),
 
             zram_fraction: None,
             max_zram_size_mb: None,
@@ -72,22 +230,133 @@ impl Device {
             && (self.fs_type.is_none() || self.fs_type.as_ref().unwrap() == "swap")
     }
 
-    fn is_enabled(&self, memtotal_mb: u64) -> bool {
-        match self.host_memory_limit_mb {
-            Some(limit_mb) if limit_mb < memtotal_mb => {
-                info!(
+    /// fs-type=none without mount-point=: configured, but neither formatted nor used
+    pub fn is_raw(&self) -> bool {
+        self.mount_point.is_none() && self.fs_type.as_deref() == Some("none")
+    }
+
+    /// Neither swap nor raw, i.e. formatted with a file system
+    fn is_fs(&self) -> bool {
+        !self.is_swap() && !self.is_raw()
+    }
+
+    fn default_zram_size(&self) -> &'static str {
+        match self.is_fs() {
+            true => DEFAULT_ZRAM_SIZE_FS,
+            false => DEFAULT_ZRAM_SIZE,
+        }
+    }
+
+    /// The zram-size= expression equivalent to the obsolete zram-fraction= and max-zram-size=
+    fn obsolete_zram_size(&self) -> String {
+        let fraction = self.zram_fraction.unwrap_or(0.5);
+        match self.max_zram_size_mb.unwrap_or(None) {
+            Some(max_mb) => format!("min(ram * {}, {})", fraction, max_mb),
+            None => format!("ram * {}", fraction),
+        }
+    }
+
+    /// host-memory-limit=, raised by host-memory-limit-hysteresis= if the device was created before,
+    /// so that it doesn't come and go as MemTotal shifts around the limit
+    fn effective_host_memory_limit_mb(&self, previously_created: bool) -> Option<u64> {
+        let limit_mb = self.host_memory_limit_mb?;
+        Some(
+            match (previously_created, self.host_memory_limit_hysteresis_mb) {
+                (true, Some(band_mb)) => limit_mb.saturating_add(band_mb),
+                _ => limit_mb,
+            },
+        )
+    }
+
+    fn is_enabled(
+        &self,
+        memtotal_mb: u64,
+        other_swap_mb: u64,
+        previously_created: bool,
+        warnings: &mut Vec<Warning>,
+    ) -> bool {
+        if let Some(limit_mb) = self.effective_host_memory_limit_mb(previously_created) {
+            if limit_mb < memtotal_mb {
+                warnings.push(Warning::info(format!(
                     "{}: system has too much memory ({:.1}MB), limit is {}MB, ignoring.",
-                    self.name,
-                    memtotal_mb,
-                    self.host_memory_limit_mb.unwrap()
-                );
+                    self.name, memtotal_mb, limit_mb
+                )));
+                return false;
+            }
+        }
 
+        match self.skip_if_swap_above_mb {
+            Some(limit_mb) if limit_mb < other_swap_mb => {
+                warnings.push(Warning::info(format!(
+                    "{}: system already has {}MB of swap other than zram, limit is {}MB, ignoring.",
+                    self.name, other_swap_mb, limit_mb
+                )));
                 false
             }
             _ => true,
         }
     }
 
+    /// Like systemd's ConditionPathExists=, but evaluated now, under root
+    fn condition_met(
+        &self,
+        root: &Path,
+        warnings: &mut Vec<Warning>,
+        explanation: &mut Vec<String>,
+    ) -> bool {
+        let Some((negated, path)) = &self.condition_path_exists else {
+            return true;
+        };
+        let exists = root
+            .join(
+                path.strip_prefix("/")
+                    .expect("verified in verify_mount_point()"),
+            )
+            .exists();
+        explanation.push(format!(
+            "condition-path-exists: {}{}, {}",
+            if *negated { "!" } else { "" },
+            path.display(),
+            if exists { "present" } else { "absent" }
+        ));
+        if exists == *negated {
+            warnings.push(Warning::info(format!(
+                "{}: {} {}, ignoring.",
+                self.name,
+                path.display(),
+                if exists { "exists" } else { "doesn't exist" }
+            )));
+            explanation.push("not created: condition-path-exists not met".to_string());
+            return false;
+        }
+        true
+    }
+
+    /// Whether any recompression algorithms will be set up
+    pub fn has_recompression(&self) -> bool {
+        self.recompression && self.compression_algorithms.compression_algorithms.len() > 1
+    }
+
+    pub fn effective_swap_priority(&self) -> i32 {
+        let priority = self
+            .swap_priority
+            .or(self.role.map(SwapRole::priority))
+            .unwrap_or(DEFAULT_SWAP_PRIORITY);
+        (priority + self.swap_tier).clamp(-1, 0x7FFF)
+    }
+
+    pub fn effective_wanted_by(&self) -> Vec<&str> {
+        match (self.wanted_by.is_empty(), self.is_swap()) {
+            (false, _) => self.wanted_by.iter().map(|u| &u[..]).collect(),
+            (true, true) => vec!["swap.target"],
+            (true, false) => vec!["local-fs.target"],
+        }
+    }
+
+    pub fn has_mount_permissions(&self) -> bool {
+        self.mount_mode.is_some() || self.mount_uid.is_some() || self.mount_gid.is_some()
+    }
+
     pub fn effective_fs_type(&self) -> &str {
         match (self.fs_type.as_ref(), self.is_swap()) {
             (Some(fs_type), _) => fs_type,
@@ -96,6 +365,8 @@ impl Device {
         }
     }
 
+    /// Errors on a negative (or NaN) result, and on one which no amount of compression could fill,
+    /// since that's a mistake in the expression (e.g. a division by 0), not a size the kernel should get
     fn process_size(
         &self,
         zram_option: &Option<(String, fasteval::ExpressionI, fasteval::Slab)>,
@@ -109,11 +380,26 @@ impl Device {
                     .eval(&zs.2, ctx)
                     .with_context(|| format!("{} {}", self.name, label))
                     .and_then(|f| {
-                        if f >= 0. {
+                        let max_mb = (ctx.memtotal_mb * MAX_SIZE_RAM_MULTIPLE) as f64;
+                        if f > max_mb {
+                            Err(anyhow!(
+                                "{}: {}={} is more than {} times MemTotal ({}MB), refusing",
+                                self.name,
+                                label,
+                                f,
+                                MAX_SIZE_RAM_MULTIPLE,
+                                max_mb
+                            ))
+                        } else if f >= 0. {
                             Ok(f)
                         } else {
                             Err(anyhow!("{}: {}={} < 0", self.name, label, f))
                         }
+                    })
+                    .inspect(|&f| {
+                        if log_enabled!(Level::Trace) {
+                            trace!("{}", self.size_trace(label, zs, ctx, f));
+                        }
                     })?
             }
             None => default_size,
@@ -121,32 +407,223 @@ impl Device {
             * 1024.0) as u64)
     }
 
-    fn set_disksize_if_enabled(&mut self, ctx: &mut EvalContext) -> Result<()> {
-        if !self.is_enabled(ctx.memtotal_mb) {
-            return Ok(());
+    /// "zram0: zram-size = min(ram / 2, 4096), ram=7825 → 3912.5MB", the expression with the
+    /// values of the variables it uses, and the result before conversion to bytes
+    fn size_trace(
+        &self,
+        label: &str,
+        zs: &(String, fasteval::ExpressionI, fasteval::Slab),
+        ctx: &mut EvalContext,
+        result_mb: f64,
+    ) -> String {
+        let mut out = format!("{}: {} = {}", self.name, label, zs.0);
+        for name in zs.1.from(&zs.2.ps).var_names(&zs.2) {
+            match fasteval::EvalNamespace::lookup(ctx, &name, vec![], &mut String::new()) {
+                Some(val) => write!(out, ", {}={}", name, val).unwrap(),
+                None => write!(out, ", {} undefined", name).unwrap(),
+            }
+        }
+        write!(out, " → {}MB", result_mb).unwrap();
+        out
+    }
+
+    /// Both steps, without total-size-limit= in between
+    #[cfg(test)]
+    fn set_disksize_if_enabled(
+        &mut self,
+        ctx: &mut EvalContext,
+        warnings: &mut Vec<Warning>,
+        explanation: &mut Vec<String>,
+    ) -> Result<()> {
+        match self.set_base_disksize_if_enabled(ctx, warnings, explanation)? {
+            Some(default_resident_mb) => {
+                self.finish_disksize(ctx, default_resident_mb, warnings, explanation)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The size from zram-size= or ram-budget=, which total-size-limit= applies to;
+    /// None if the device is disabled, otherwise the default for zram-resident-limit=.
+    /// explanation collects a line for each step of the decision, for --explain
+    fn set_base_disksize_if_enabled(
+        &mut self,
+        ctx: &mut EvalContext,
+        warnings: &mut Vec<Warning>,
+        explanation: &mut Vec<String>,
+    ) -> Result<Option<f64>> {
+        explanation.push(format!("MemTotal: {}MB", ctx.memtotal_mb));
+        if let Some(limit_mb) = ctx.ram_limit_mb {
+            explanation.push(format!(
+                "{}: {}MB, ram = {}MB",
+                ctx.memory_source.cgroup_file().unwrap(),
+                limit_mb,
+                ctx.ram_mb()
+            ));
+        }
+
+        let previously_created = ctx.previous_devices.contains(&self.name);
+        let enabled = self.is_enabled(
+            ctx.memtotal_mb,
+            ctx.other_swap_mb,
+            previously_created,
+            warnings,
+        );
+        let limit_mb = self.effective_host_memory_limit_mb(previously_created);
+        let memory_exceeded = matches!(limit_mb, Some(limit_mb) if limit_mb < ctx.memtotal_mb);
+        explanation.push(format!(
+            "host-memory-limit: {}{}{}",
+            OptMB(self.host_memory_limit_mb),
+            match (limit_mb, self.host_memory_limit_mb) {
+                (Some(effective), Some(limit)) if effective != limit => {
+                    format!(" + {}MB hysteresis (created before)", effective - limit)
+                }
+                _ => String::new(),
+            },
+            match (self.host_memory_limit_mb, memory_exceeded) {
+                (None, _) => "",
+                (Some(_), false) => ", not exceeded",
+                (Some(_), true) => ", exceeded",
+            }
+        ));
+        if let Some(limit_mb) = self.skip_if_swap_above_mb {
+            explanation.push(format!(
+                "skip-if-swap-above: {}MB, other swap: {}MB",
+                limit_mb, ctx.other_swap_mb
+            ));
+        }
+        if !enabled {
+            explanation.push(
+                match memory_exceeded {
+                    true => "not created: host-memory-limit exceeded",
+                    false => "not created: skip-if-swap-above exceeded",
+                }
+                .to_string(),
+            );
+            return Ok(None);
         }
 
+        let mut budget_mb = None;
         if self.zram_fraction.is_some() || self.max_zram_size_mb.is_some() {
             // deprecated path
             let max_mb = self.max_zram_size_mb.unwrap_or(None).unwrap_or(u64::MAX);
-            self.disksize = ((self.zram_fraction.unwrap_or(0.5) * ctx.memtotal_mb as f64) as u64)
+            self.disksize = ((self.zram_fraction.unwrap_or(0.5) * ctx.ram_mb() as f64) as u64)
                 .min(max_mb)
                 * (1024 * 1024);
+            explanation.push(format!(
+                "zram-fraction={} max-zram-size={} (obsolete) = {}MB",
+                self.zram_fraction.unwrap_or(0.5),
+                OptMB(self.max_zram_size_mb.unwrap_or(None)),
+                self.disksize / 1024 / 1024
+            ));
+        } else if self.ram_budget.is_some() {
+            /* zram-size is ignored, see read_devices() */
+            let budget = self.process_size(&self.ram_budget, ctx, 0., "ram-budget")?;
+            let ratio = self.expected_ratio.unwrap_or(1.);
+            self.disksize = (budget as f64 * ratio) as u64;
+            budget_mb = Some(budget as f64 / 1024. / 1024.);
+            explanation.push(format!(
+                "ram-budget: {} = {}MB, expected-ratio: {} = {}MB",
+                self.ram_budget.as_ref().unwrap().0,
+                budget / 1024 / 1024,
+                ratio,
+                self.disksize / 1024 / 1024
+            ));
         } else {
-            self.disksize = self.process_size(
-                &self.zram_size,
-                ctx,
-                (ctx.memtotal_mb as f64 / 2.).min(4096.), // DEFAULT_ZRAM_SIZE
-                "zram-size",
-            )?;
+            let default_size = match self.is_fs() {
+                true => (ctx.ram_mb() as f64).min(4096.), // DEFAULT_ZRAM_SIZE_FS
+                false => (ctx.ram_mb() as f64 / 2.).min(4096.), // DEFAULT_ZRAM_SIZE
+            };
+            self.disksize = self.process_size(&self.zram_size, ctx, default_size, "zram-size")?;
+            explanation.push(format!(
+                "zram-size: {} = {}MB",
+                self.zram_size
+                    .as_ref()
+                    .map(|zs| &zs.0[..])
+                    .unwrap_or(self.default_zram_size()),
+                self.disksize / 1024 / 1024
+            ));
+        }
+
+        let warning_mb = ctx.memtotal_mb * SIZE_WARNING_RAM_MULTIPLE;
+        if self.is_swap() && !self.no_size_warning && self.disksize / 1024 / 1024 > warning_mb {
+            warnings.push(Warning::warn(format!(
+                "{}: {} is {}MB, more than {} times MemTotal ({}MB); \
+                 set no-size-warning = true if that's intended.",
+                self.name,
+                match (&self.zram_size, &self.ram_budget) {
+                    _ if self.zram_fraction.is_some() || self.max_zram_size_mb.is_some() => {
+                        format!("zram-size = {}", self.obsolete_zram_size())
+                    }
+                    (_, Some(budget)) => format!(
+                        "ram-budget = {} with expected-ratio = {}",
+                        budget.0,
+                        self.expected_ratio.unwrap_or(1.)
+                    ),
+                    (Some(zs), None) => format!("zram-size = {}", zs.0),
+                    (None, None) => format!("zram-size = {}", self.default_zram_size()),
+                },
+                self.disksize / 1024 / 1024,
+                SIZE_WARNING_RAM_MULTIPLE,
+                ctx.memtotal_mb
+            )));
+        }
+
+        /* With ram-budget=, the budget is what the device may take up, unless set explicitly */
+        Ok(Some(budget_mb.unwrap_or(0.))) // DEFAULT_RESIDENT_LIMIT
+    }
+
+    /// The file system's overhead on top of the base size, and zram-resident-limit=
+    fn finish_disksize(
+        &mut self,
+        ctx: &mut EvalContext,
+        default_resident_mb: f64,
+        warnings: &mut Vec<Warning>,
+        explanation: &mut Vec<String>,
+    ) -> Result<()> {
+        if let Some(reserve) = self.fs_reserve {
+            if self.is_fs() {
+                /* zram-size is what should fit on the file system,
+                 * so grow the device by the share its metadata will take up. */
+                self.disksize = apply_fs_reserve(self.disksize, reserve);
+                explanation.push(format!(
+                    "fs-reserve: {}% = {}MB",
+                    reserve,
+                    self.disksize / 1024 / 1024
+                ));
+            } else {
+                warnings.push(Warning::warn(format!(
+                    "{}: fs-reserve= set on a device without a file system, ignoring.",
+                    self.name
+                )));
+            }
         }
 
-        self.mem_limit = self.process_size(
+        /* Only while evaluating zram-resident-limit, since it's not known before */
+        ctx.disksize_mb = Some(self.disksize as f64 / 1024. / 1024.);
+        let mem_limit = self.process_size(
             &self.zram_resident_limit,
             ctx,
-            0., // DEFAULT_RESIDENT_LIMIT
+            default_resident_mb,
             "zram-resident-limit",
-        )?;
+        );
+        ctx.disksize_mb = None;
+        self.mem_limit = mem_limit?;
+        explanation.push(format!(
+            "zram-resident-limit: {} = {}MB",
+            match (&self.zram_resident_limit, &self.ram_budget) {
+                (Some(zs), _) => &zs.0[..],
+                (None, Some(_)) => "ram-budget",
+                (None, None) => DEFAULT_RESIDENT_LIMIT,
+            },
+            self.mem_limit / 1024 / 1024
+        ));
+
+        explanation.push(format!("disksize: {} bytes", self.disksize));
+        explanation.push(match self.disksize {
+            0 => "not created: size is 0".to_string(),
+            _ => format!("created, as {}", self.effective_fs_type()),
+        });
 
         Ok(())
     }
@@ -162,7 +639,7 @@ impl fmt::Display for Device {
             self.zram_size
                 .as_ref()
                 .map(|zs| &zs.0[..])
-                .unwrap_or(DEFAULT_ZRAM_SIZE),
+                .unwrap_or(self.default_zram_size()),
             self.zram_resident_limit
                 .as_ref()
                 .map(|zs| &zs.0[..])
@@ -171,6 +648,50 @@ impl fmt::Display for Device {
             self.writeback_dev.as_deref().unwrap_or_else(|| Path::new("<none>")).display(),
             self.options
         )?;
+        if !self.recompression {
+            f.write_str(" recompression=off")?;
+        }
+        if self.recompress_now > 0 {
+            write!(f, " recompress-now={}", self.recompress_now)?;
+        }
+        if let Some(bs) = self.block_size {
+            write!(f, " block-size={}", bs)?;
+        }
+        if let Some(ps) = self.swap_page_size {
+            write!(f, " swap-page-size={}", ps)?;
+        }
+        if self.lazy_makefs {
+            f.write_str(" lazy-makefs")?;
+        }
+        if self.makefs_mode == MakefsMode::Systemd {
+            f.write_str(" makefs-mode=systemd")?;
+        }
+        if self.hibernate_safe {
+            f.write_str(" hibernate-safe")?;
+        }
+        if self.swap_nofail {
+            f.write_str(" swap-nofail")?;
+        }
+        if !self.persist_across_daemon_reload {
+            f.write_str(" persist-across-daemon-reload=no")?;
+        }
+        if self.device_wait != DEFAULT_DEVICE_WAIT {
+            write!(f, " device-wait={}", self.device_wait.as_secs_f64())?;
+        }
+        if let Some(band_mb) = self.host_memory_limit_hysteresis_mb {
+            write!(f, " host-memory-limit-hysteresis={}", band_mb)?;
+        }
+        if let Some(limit_mb) = self.skip_if_swap_above_mb {
+            write!(f, " skip-if-swap-above={}", limit_mb)?;
+        }
+        if let Some((negated, path)) = &self.condition_path_exists {
+            write!(
+                f,
+                " condition-path-exists={}{}",
+                if *negated { "!" } else { "" },
+                path.display()
+            )?;
+        }
         if self.zram_fraction.is_some() || self.max_zram_size_mb.is_some() {
             f.write_str(" (")?;
             if let Some(zf) = self.zram_fraction {
@@ -203,6 +724,66 @@ pub struct Algorithms {
     pub compression_algorithms: Vec<(String, String)>, // algorithm, params; first one is real compression, later ones are recompression
     pub recompression_global: String,                  // params
 }
+/// The kernel has room for three recompression algorithms (ZRAM_MAX_COMPS - 1)
+const MAX_RECOMP_PRIORITY: usize = 3;
+/// compression-algorithm = auto: the first of these which the kernel has and can allocate, picked at setup
+pub const AUTO_COMPRESSION_ALGORITHMS: &[&str] = &["zstd", "lz4", "lzo-rle"];
+
+impl Algorithms {
+    /// The algorithms with their priority and the rest of their parameters:
+    /// 0 for the first one, and for recompression algorithms either priority= from their parameters,
+    /// or their position in the list.
+    pub fn with_priorities(&self) -> Result<Vec<(usize, &str, String)>> {
+        let mut ret = vec![];
+        let mut seen = BTreeSet::new();
+        for (index, (algo, params)) in self.compression_algorithms.iter().enumerate() {
+            let mut prio = None;
+            let mut rest = vec![];
+            for param in params.split_whitespace() {
+                match param.strip_prefix("priority=") {
+                    Some(p) => prio = Some(p),
+                    None => rest.push(param),
+                }
+            }
+
+            let prio = match (index, prio) {
+                (_, None) => index,
+                (0, Some(_)) => {
+                    return Err(anyhow!(
+                        "compression algorithm {}: priority= is only for recompression algorithms",
+                        algo
+                    ))
+                }
+                (_, Some(p)) => match p.parse() {
+                    Ok(p @ 1..=MAX_RECOMP_PRIORITY) => p,
+                    _ => {
+                        return Err(anyhow!(
+                            "compression algorithm {}: priority={} is not between 1 and {}",
+                            algo,
+                            p,
+                            MAX_RECOMP_PRIORITY
+                        ))
+                    }
+                },
+            };
+            if algo == "auto" && (prio != 0 || !rest.is_empty()) {
+                return Err(anyhow!(
+                    "compression algorithm auto: only for the first algorithm, without parameters"
+                ));
+            }
+            if !seen.insert(prio) {
+                return Err(anyhow!(
+                    "compression algorithm {}: priority {} is already taken",
+                    algo,
+                    prio
+                ));
+            }
+            ret.push((prio, &algo[..], rest.join(" ")));
+        }
+        Ok(ret)
+    }
+}
+
 impl fmt::Display for Algorithms {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.compression_algorithms[..] {
@@ -221,43 +802,509 @@ impl fmt::Display for Algorithms {
             }
         }
         if !self.recompression_global.is_empty() {
-            write!(f, "(global recompress: {})", self.recompression_global)?;
+            write!(f, "; global recompress ({})", self.recompression_global)?;
         }
         Ok(())
     }
 }
 
 struct EvalContext {
+    /// memtotal_kb converted as set by memtotal-rounding=
     memtotal_mb: u64,
+    memtotal_kb: u64,
+    /// swap other than zram, for skip-if-swap-above= and the swap variable
+    other_swap_mb: u64,
+    /// the devices in the manifest, i.e. set up since boot, for host-memory-limit-hysteresis=
+    previous_devices: BTreeSet<String>,
+    /// the size of the device being set up, for zram-resident-limit=
+    disksize_mb: Option<f64>,
+    /// reserved-indices= and other settings which aren't about a single device
+    globals: Globals,
+    /// total-size-limit=, evaluated after all devices are sized
+    total_size_limit: Option<String>,
+    /// preset!name=, for @name in compression-algorithm=
+    presets: BTreeMap<String, String>,
+    /// subtracted from memtotal_mb for ram_usable, with reserved_mb
+    cmatotal_mb: u64,
+    /// reserved-memory=
+    reserved_mb: u64,
+    /// number of configured devices, whether enabled or not
+    ndevices: u64,
+    on_battery: bool,
+    /// seconds since boot, from /proc/uptime
+    uptime: f64,
+    /// memory-source=
+    memory_source: MemorySource,
+    /// the cgroup limit selected by memory_source, if any; caps memtotal_mb for ram
+    ram_limit_mb: Option<u64>,
     additional: BTreeMap<String, f64>,
 }
 
+/// What the ram variable is based on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemorySource {
+    Meminfo,
+    CgroupMax,
+    CgroupHigh,
+}
+
+impl MemorySource {
+    /// The file in /sys/fs/cgroup with the limit
+    fn cgroup_file(self) -> Option<&'static str> {
+        match self {
+            MemorySource::Meminfo => None,
+            MemorySource::CgroupMax => Some("memory.max"),
+            MemorySource::CgroupHigh => Some("memory.high"),
+        }
+    }
+}
+
+impl FromStr for MemorySource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "meminfo" => Ok(MemorySource::Meminfo),
+            "cgroup-max" => Ok(MemorySource::CgroupMax),
+            "cgroup-high" => Ok(MemorySource::CgroupHigh),
+            _ => Err(anyhow!("unknown memory source {:?}", s)),
+        }
+    }
+}
+
+/// role=, the priority band of a swap device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapRole {
+    /// used before any other swap
+    Primary,
+    /// used after swap with a priority, like the overflow to disk, but before swap without one
+    Overflow,
+}
+
+impl SwapRole {
+    fn priority(self) -> i32 {
+        match self {
+            SwapRole::Primary => 0x7FFF,
+            SwapRole::Overflow => 0,
+        }
+    }
+}
+
+impl FromStr for SwapRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "primary" => Ok(SwapRole::Primary),
+            "overflow" => Ok(SwapRole::Overflow),
+            _ => Err(anyhow!("Unknown swap role \"{}\"", s)),
+        }
+    }
+}
+
+/// makefs-mode=
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MakefsMode {
+    /// "self": zram-generator --setup-device formats the device
+    Setup,
+    /// "systemd": systemd-makefs@dev-zramN.service does, like for x-systemd.makefs in fstab(5)
+    Systemd,
+}
+
+impl FromStr for MakefsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "self" => Ok(MakefsMode::Setup),
+            "systemd" => Ok(MakefsMode::Systemd),
+            _ => Err(anyhow!("unknown makefs mode {:?}", s)),
+        }
+    }
+}
+
+/// How MemTotal (in kB) is converted to megabytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemtotalRounding {
+    Truncate,
+    Round,
+    Ceil,
+}
+
+impl MemtotalRounding {
+    fn mb(self, kb: u64) -> u64 {
+        match self {
+            MemtotalRounding::Truncate => kb / 1024,
+            MemtotalRounding::Round => (kb + 512) / 1024,
+            MemtotalRounding::Ceil => kb.div_ceil(1024),
+        }
+    }
+}
+
+impl FromStr for MemtotalRounding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "truncate" => Ok(MemtotalRounding::Truncate),
+            "round" => Ok(MemtotalRounding::Round),
+            "ceil" => Ok(MemtotalRounding::Ceil),
+            _ => Err(anyhow!("unknown rounding mode {:?}", s)),
+        }
+    }
+}
+
+impl EvalContext {
+    fn ram_mb(&self) -> u64 {
+        match self.ram_limit_mb {
+            Some(limit_mb) => self.memtotal_mb.min(limit_mb),
+            None => self.memtotal_mb,
+        }
+    }
+
+    fn usable_memory_mb(&self) -> u64 {
+        self.memtotal_mb
+            .saturating_sub(self.cmatotal_mb)
+            .saturating_sub(self.reserved_mb)
+    }
+}
+
+#[cfg(test)]
+impl EvalContext {
+    /* A context for a single device on a machine with memtotal_mb of RAM and nothing else. */
+    fn for_test(memtotal_mb: u64) -> EvalContext {
+        EvalContext {
+            memtotal_mb,
+            memtotal_kb: memtotal_mb * 1024,
+            other_swap_mb: 0,
+            cmatotal_mb: 0,
+            reserved_mb: 0,
+            ndevices: 1,
+            on_battery: false,
+            uptime: 0.,
+            previous_devices: BTreeSet::new(),
+            disksize_mb: None,
+            globals: Globals::default(),
+            total_size_limit: None,
+            presets: BTreeMap::new(),
+            memory_source: MemorySource::Meminfo,
+            ram_limit_mb: None,
+            additional: BTreeMap::new(),
+        }
+    }
+}
+
 impl fasteval::EvalNamespace for EvalContext {
     fn lookup(&mut self, name: &str, args: Vec<f64>, _: &mut String) -> Option<f64> {
         if !args.is_empty() {
             None
         } else if name == "ram" {
-            Some(self.memtotal_mb as f64)
+            Some(self.ram_mb() as f64)
+        } else if name == "ram_usable" {
+            Some(self.usable_memory_mb() as f64)
+        } else if name == "swap" {
+            Some(self.other_swap_mb as f64)
+        } else if name == "ndevices" {
+            Some(self.ndevices as f64)
+        } else if name == "on_battery" {
+            Some(self.on_battery as u8 as f64)
+        } else if name == "uptime" {
+            Some(self.uptime)
+        } else if let (Some(disksize_mb), "disksize") = (self.disksize_mb, name) {
+            Some(disksize_mb)
         } else {
             self.additional.get(name).copied()
         }
     }
 }
 
-pub fn read_device(root: &Path, kernel_override: bool, name: &str) -> Result<Option<Device>> {
-    let memtotal_mb = get_total_memory_kb(root)? as f64 / 1024.;
-    Ok(read_devices(root, kernel_override, memtotal_mb as u64)?
+/// Something noteworthy about the configuration: a problem which didn't prevent parsing it
+/// (`Level::Warn`), or a decision made because of it (`Level::Info`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub level: Level,
+    pub message: String,
+}
+
+impl Warning {
+    fn warn(message: String) -> Self {
+        Warning {
+            level: Level::Warn,
+            message,
+        }
+    }
+
+    fn info(message: String) -> Self {
+        Warning {
+            level: Level::Info,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Why the configuration couldn't be read, from the public read_*() functions;
+/// the message includes the whole chain of causes, as `{:#}` of an anyhow::Error would.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A file couldn't be read, or a set! program couldn't be run
+    Io {
+        message: String,
+        kind: io::ErrorKind,
+    },
+    /// A configuration file isn't valid INI or JSON
+    Syntax(String),
+    /// An arithmetic expression couldn't be parsed or evaluated
+    Eval(String),
+    /// A value is wrong, or the settings contradict each other
+    Invalid(String),
+}
+
+impl ConfigError {
+    fn classify(err: anyhow::Error) -> Self {
+        let message = format!("{:#}", err);
+        for cause in err.chain() {
+            if cause.is::<SyntaxError>() || cause.is::<serde_json::Error>() {
+                return ConfigError::Syntax(message);
+            }
+            if cause.is::<fasteval::Error>() {
+                return ConfigError::Eval(message);
+            }
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                return ConfigError::Io {
+                    message,
+                    kind: e.kind(),
+                };
+            }
+        }
+        ConfigError::Invalid(message)
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ConfigError::Io { message, .. }
+            | ConfigError::Syntax(message)
+            | ConfigError::Eval(message)
+            | ConfigError::Invalid(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// An INI parse error, for ConfigError::Syntax
+#[derive(Debug)]
+struct SyntaxError(String);
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+fn log_warnings(warnings: &[Warning]) {
+    for w in warnings {
+        log!(w.level, "{}", w.message);
+    }
+}
+
+pub fn read_device(
+    root: &Path,
+    kernel_override: bool,
+    name: &str,
+) -> Result<Option<Device>, ConfigError> {
+    let mut warnings = vec![];
+    let devices = read_devices_from(root, kernel_override, &mut warnings);
+    log_warnings(&warnings);
+    Ok(devices
+        .map_err(ConfigError::classify)?
         .remove(name)
         .filter(|dev| dev.disksize > 0))
 }
 
-pub fn read_all_devices(root: &Path, kernel_override: bool) -> Result<Vec<Device>> {
-    let memtotal_mb = get_total_memory_kb(root)? as f64 / 1024.;
-    Ok(read_devices(root, kernel_override, memtotal_mb as u64)?
+pub fn read_all_devices(root: &Path, kernel_override: bool) -> Result<Vec<Device>, ConfigError> {
+    Ok(read_all_devices_with_globals(root, kernel_override)?.0)
+}
+
+/// Like read_all_devices(), with the settings which aren't about a single device, for the generator
+pub fn read_all_devices_with_globals(
+    root: &Path,
+    kernel_override: bool,
+) -> Result<(Vec<Device>, Globals), ConfigError> {
+    let mut warnings = vec![];
+    let config = read_config_from(root, kernel_override, &mut warnings);
+    log_warnings(&warnings);
+    let (devices, globals) = config.map_err(ConfigError::classify)?;
+    Ok((
+        devices
+            .into_values()
+            .filter(|dev| dev.disksize > 0)
+            .collect(),
+        globals,
+    ))
+}
+
+/// All configured devices, including the ones which won't be created, sorted by index,
+/// with the explanation of their size filled in.
+pub fn explain_devices(root: &Path, kernel_override: bool) -> Result<Vec<Device>, ConfigError> {
+    let mut warnings = vec![];
+    let devices = read_devices_from(root, kernel_override, &mut warnings);
+    log_warnings(&warnings);
+
+    let mut devices: Vec<_> = devices
+        .map_err(ConfigError::classify)?
+        .into_values()
+        .collect();
+    devices.sort_unstable_by_key(|dev| {
+        dev.name[4..]
+            .parse::<u64>()
+            .expect("already verified in read_devices()")
+    });
+    Ok(devices)
+}
+
+/// Parses the configuration like read_all_devices(), but returns the warnings
+/// instead of logging them, e.g. to show them in an editor before reloading.
+/// The kernel command line is not taken into account.
+#[allow(dead_code)] // only used through the library
+pub fn validate_config(root: &Path) -> Result<Vec<Warning>, ConfigError> {
+    let mut warnings = vec![];
+    read_devices_from(root, false, &mut warnings).map_err(ConfigError::classify)?;
+    Ok(warnings)
+}
+
+/// Settings from directives which aren't about a single device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Globals {
+    /// reserved-indices=; None if all zram devices are managed
+    pub reserved_indices: Option<RangeInclusive<u64>>,
+    /// load-module=: whether the generator loads the zram module
+    pub load_module: bool,
+    /// module-options=: parameters given to modprobe when loading the zram module
+    pub module_options: Vec<String>,
+    /// $ZRAM_GENERATOR_PROFILE: [zramN:profile] sections are only read for this profile
+    pub profile: Option<String>,
+}
+
+impl Default for Globals {
+    fn default() -> Self {
+        Globals {
+            reserved_indices: None,
+            load_module: true,
+            module_options: vec![],
+            profile: None,
+        }
+    }
+}
+
+/// reserved-indices=, for --reset-all. Only the top-level lines are read (and include=s followed),
+/// so that nothing about the devices, which aren't evaluated, can keep them from being reset.
+pub fn read_reserved_indices(root: &Path) -> Result<Option<RangeInclusive<u64>>, ConfigError> {
+    let mut reserved = None;
+    for (_, path) in locate_fragments(root) {
+        read_toplevel_reserved_indices(root, &mut vec![path], &mut reserved)
+            .map_err(ConfigError::classify)?;
+    }
+    Ok(reserved)
+}
+
+/// Like read_fragment(), for reserved-indices= alone; broken includes are left to the generator to report
+fn read_toplevel_reserved_indices(
+    root: &Path,
+    stack: &mut Vec<PathBuf>,
+    reserved: &mut Option<RangeInclusive<u64>>,
+) -> Result<()> {
+    let path = stack
+        .last()
+        .expect("stack has the current fragment")
+        .clone();
+    let mut includes = vec![];
+
+    for (_, props) in load_fragment(&path)?
         .into_iter()
-        .filter(|(_, dev)| dev.disksize > 0)
-        .map(|(_, dev)| dev)
-        .collect())
+        .filter(|(sname, _)| sname.is_none())
+    {
+        for (k, v) in props {
+            match &k[..] {
+                "reserved-indices" => {
+                    *reserved = match &v[..] {
+                        "" => None,
+                        _ => Some(parse_reserved_indices(&v).with_context(|| {
+                            format!("{}: Failed to parse {} \"{}\"", path.display(), k, v)
+                        })?),
+                    }
+                }
+                "include" => includes.push(v),
+                _ => {}
+            }
+        }
+    }
+
+    for include in includes {
+        let Some(target) = include
+            .strip_prefix('/')
+            .map(|relative| root.join(relative))
+        else {
+            continue;
+        };
+        if stack.contains(&target) || stack.len() > MAX_INCLUDE_DEPTH || !target.exists() {
+            continue;
+        }
+        stack.push(target);
+        read_toplevel_reserved_indices(root, stack, reserved)?;
+        stack.pop();
+    }
+    Ok(())
+}
+
+fn read_devices_from(
+    root: &Path,
+    kernel_override: bool,
+    warnings: &mut Vec<Warning>,
+) -> Result<HashMap<String, Device>> {
+    Ok(read_config_from(root, kernel_override, warnings)?.0)
+}
+
+fn read_config_from(
+    root: &Path,
+    kernel_override: bool,
+    warnings: &mut Vec<Warning>,
+) -> Result<(HashMap<String, Device>, Globals)> {
+    read_profile_config_from(root, kernel_override, active_profile()?, warnings)
+}
+
+fn read_profile_config_from(
+    root: &Path,
+    kernel_override: bool,
+    profile: Option<String>,
+    warnings: &mut Vec<Warning>,
+) -> Result<(HashMap<String, Device>, Globals)> {
+    let memtotal_kb = get_total_memory_kb(root)?;
+    let swaptotal_mb = get_optional_meminfo_kb(root, "SwapTotal")? as f64 / 1024.;
+    let cmatotal_mb = get_optional_meminfo_kb(root, "CmaTotal")? as f64 / 1024.;
+    read_devices(
+        root,
+        kernel_override,
+        memtotal_kb,
+        swaptotal_mb as u64,
+        cmatotal_mb as u64,
+        exec_allowed()?,
+        profile,
+        warnings,
+    )
 }
 
 fn toplevel_line(
@@ -266,19 +1313,83 @@ fn toplevel_line(
     val: &str,
     slab: &mut fasteval::Slab,
     ctx: &mut EvalContext,
+    allow_exec: bool,
+    warnings: &mut Vec<Warning>,
 ) -> Result<()> {
     let (op, arg) = if let Some(colon) = k.find('!') {
         k.split_at(colon + 1)
+    } else if k == "reserved-memory" {
+        ctx.reserved_mb = val
+            .parse()
+            .with_context(|| format!("{}: Failed to parse {} \"{}\"", path.display(), k, val))?;
+        return Ok(());
+    } else if k == "memtotal-rounding" {
+        let rounding: MemtotalRounding = val
+            .parse()
+            .with_context(|| format!("{}: Failed to parse {} \"{}\"", path.display(), k, val))?;
+        /* set! is evaluated as it's read, so it'd have seen ram rounded differently than the devices */
+        if !ctx.additional.is_empty() {
+            warnings.push(Warning::warn(format!(
+                "{}: {}={} after a set! directive, ignoring; it must come before them.",
+                path.display(),
+                k,
+                val
+            )));
+            return Ok(());
+        }
+        ctx.memtotal_mb = rounding.mb(ctx.memtotal_kb);
+        return Ok(());
+    } else if k == "reserved-indices" {
+        ctx.globals.reserved_indices = match val {
+            "" => None,
+            _ => Some(parse_reserved_indices(val).with_context(|| {
+                format!("{}: Failed to parse {} \"{}\"", path.display(), k, val)
+            })?),
+        };
+        return Ok(());
+    } else if k == "load-module" {
+        ctx.globals.load_module = parse_boolean(k, val)
+            .with_context(|| format!("{}: Failed to parse {} \"{}\"", path.display(), k, val))?;
+        return Ok(());
+    } else if k == "module-options" {
+        ctx.globals.module_options = parse_module_options(val)
+            .with_context(|| format!("{}: Failed to parse {} \"{}\"", path.display(), k, val))?;
+        return Ok(());
+    } else if k == "total-size-limit" {
+        ctx.total_size_limit = match val {
+            "" | "none" => None,
+            _ => {
+                fasteval::Parser::new()
+                    .parse(val, &mut fasteval::Slab::new().ps)
+                    .with_context(|| {
+                        format!("{}: Failed to parse {} \"{}\"", path.display(), k, val)
+                    })?;
+                Some(val.to_string())
+            }
+        };
+        return Ok(());
+    } else if k == "memory-source" {
+        ctx.memory_source = val
+            .parse()
+            .with_context(|| format!("{}: Failed to parse {} \"{}\"", path.display(), k, val))?;
+        return Ok(());
     } else {
-        warn!(
+        warnings.push(Warning::warn(format!(
             "{}: invalid outside-of-section key {}, ignoring.",
             path.display(),
             k
-        );
+        )));
         return Ok(());
     };
 
     match op {
+        "set!" if !allow_exec => warnings.push(Warning::warn(format!(
+            "{}: {} would run {:?}, but running programs is disabled, ignoring; set ${}=1 to allow it.",
+            path.display(),
+            k,
+            val,
+            ALLOW_EXEC_ENV_VAR
+        ))),
         "set!" => {
             let out = Command::new("/bin/sh")
                 .args(["-c", "--", val])
@@ -291,85 +1402,804 @@ fn toplevel_line(
                 .code()
                 .unwrap_or_else(|| 128 + out.status.signal().unwrap());
             if exit != 0 {
-                warn!("{}: {} exited {}", k, val, exit);
+                warnings.push(Warning::warn(format!("{}: {} exited {}", k, val, exit)));
             }
 
             let expr = String::from_utf8(out.stdout)
                 .with_context(|| format!("{}: {}: {}", path.display(), k, val))?;
-            let evalled = fasteval::Parser::new()
-                .parse(&expr, &mut slab.ps)
-                .and_then(|p| p.from(&slab.ps).eval(slab, ctx))
-                .with_context(|| format!("{}: {}: {}: {}", path.display(), k, val, expr))?;
-            ctx.additional.insert(arg.to_string(), evalled);
-        }
-        _ => warn!(
+            let assignments = match parse_set_assignments(&expr) {
+                Some(assignments) => assignments
+                    .into_iter()
+                    .map(|(key, expr)| (format!("{}_{}", arg, key), expr))
+                    .collect(),
+                None => vec![(arg.to_string(), &expr[..])],
+            };
+            /* In order, so that later lines can use the earlier ones */
+            for (name, expr) in assignments {
+                let evalled = fasteval::Parser::new()
+                    .parse(expr, &mut slab.ps)
+                    .and_then(|p| p.from(&slab.ps).eval(slab, ctx))
+                    .with_context(|| {
+                        format!("{}: {}: {}: {}: {}", path.display(), k, val, name, expr)
+                    })?;
+                ctx.additional.insert(name, evalled);
+            }
+        }
+        "preset!" => {
+            ctx.presets.insert(
+                arg.to_string(),
+                verify_preset(val).with_context(|| format!("{}: {}", path.display(), k))?,
+            );
+        }
+        _ => warnings.push(Warning::warn(format!(
             "{}: unknown outside-of-section operation {}, ignoring.",
             path.display(),
             op
-        ),
+        ))),
     }
     Ok(())
 }
 
+/// set! output of one or more key=expression lines (and nothing else), instead of a single expression;
+/// the comparisons (==, <=, ...) an expression may contain don't count as an assignment
+fn parse_set_assignments(out: &str) -> Option<Vec<(&str, &str)>> {
+    let assignments = out
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let (key, expr) = l.split_once('=')?;
+            let key = key.trim();
+            let valid_key = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_');
+            (valid_key && !expr.starts_with('=')).then_some((key, expr.trim()))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!assignments.is_empty()).then_some(assignments)
+}
+
+/// preset!name= is a single algorithm, with optional parameters
+fn verify_preset(val: &str) -> Result<String> {
+    match val.split_whitespace().collect::<Vec<_>>()[..] {
+        [algo] if !algo.starts_with(['(', '@']) => Ok(algo.to_string()),
+        _ => Err(anyhow!(
+            "{:?} is not a single compression algorithm, like lz4(level=1)",
+            val
+        )),
+    }
+}
+
+/// Replace @name in compression-algorithm= and compression-algorithm-add= with preset!name=
+fn expand_presets<'a>(
+    key: &str,
+    value: &'a str,
+    presets: &BTreeMap<String, String>,
+) -> Result<Cow<'a, str>> {
+    if !key.starts_with("compression-algorithm") || !value.contains('@') {
+        return Ok(value.into());
+    }
+    value
+        .split_whitespace()
+        .map(|word| match word.strip_prefix('@') {
+            Some(name) => presets
+                .get(name)
+                .map(String::as_str)
+                .ok_or_else(|| anyhow!("{}: undefined preset @{}", key, name)),
+            None => Ok(word),
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|words| words.join(" ").into())
+}
+
+/// module-options=: whitespace-separated name=value parameters of the zram module, like num_devices=4
+fn parse_module_options(val: &str) -> Result<Vec<String>> {
+    val.split_whitespace()
+        .map(|option| {
+            let (name, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{:?} is not name=value", option))?;
+            if name.is_empty()
+                || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+                || value.is_empty()
+            {
+                return Err(anyhow!("{:?} is not a module parameter", option));
+            }
+            Ok(option.to_string())
+        })
+        .collect()
+}
+
+/// "N" or "N-M", the indices of the zram devices the generator may create and reset
+fn parse_reserved_indices(val: &str) -> Result<RangeInclusive<u64>> {
+    let (first, last) = val.split_once('-').unwrap_or((val, val));
+    let first: u64 = first.trim().parse()?;
+    let last: u64 = last.trim().parse()?;
+    if first > last {
+        return Err(anyhow!("{} is after {}", first, last));
+    }
+    Ok(first..=last)
+}
+
+/// Whether `set!` may run programs; off unless enabled in the environment
+pub const ALLOW_EXEC_ENV_VAR: &str = "ZRAM_GENERATOR_ALLOW_EXEC";
+
+pub const PROFILE_ENV_VAR: &str = "ZRAM_GENERATOR_PROFILE";
+
+/// The profile selected with --profile or $ZRAM_GENERATOR_PROFILE, if any
+pub fn active_profile() -> Result<Option<String>> {
+    match std::env::var(PROFILE_ENV_VAR) {
+        Ok(val) if val.is_empty() => Ok(None),
+        Ok(val) => {
+            verify_profile_name(&val).with_context(|| format!("${}", PROFILE_ENV_VAR))?;
+            Ok(Some(val))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+fn verify_profile_name(name: &str) -> Result<()> {
+    match !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+    {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "profile {:?} is not made of letters, digits, '_', and '-'",
+            name
+        )),
+    }
+}
+
+/// "zramN" for [zramN], or for [zramN:profile] when profile is the active one;
+/// Some(None) for the sections of other profiles, None for sections which aren't a device's
+fn section_device<'a>(sname: &'a str, active: Option<&str>) -> Option<Option<&'a str>> {
+    let (device, profile) = match sname.split_once(':') {
+        Some((device, profile)) => (device, Some(profile)),
+        None => (sname, None),
+    };
+    if !device.starts_with("zram") || device[4..].parse::<u64>().is_err() {
+        return None;
+    }
+    match profile {
+        None => Some(Some(device)),
+        Some(profile) if verify_profile_name(profile).is_ok() => {
+            Some((Some(profile) == active).then_some(device))
+        }
+        Some(_) => None,
+    }
+}
+
+pub fn exec_allowed() -> Result<bool> {
+    match std::env::var(ALLOW_EXEC_ENV_VAR) {
+        Ok(val) => parse_boolean(ALLOW_EXEC_ENV_VAR, &val),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Flags which cancel each other out in options=; see normalize_options()
+const OPPOSITE_OPTIONS: &[(&str, &str)] = &[
+    ("ro", "rw"),
+    ("auto", "noauto"),
+    ("atime", "noatime"),
+    ("dev", "nodev"),
+    ("exec", "noexec"),
+    ("suid", "nosuid"),
+    ("sync", "async"),
+];
+
+/// The key two options conflict on: the part before "=" (so "discard" and "discard=pages" conflict),
+/// or the pair in OPPOSITE_OPTIONS. x-* options may legitimately be given more than once
+/// (e.g. x-systemd.requires=), so they only conflict with themselves.
+fn option_key(option: &str) -> &str {
+    if option.starts_with("x-") {
+        return option;
+    }
+    let key = option.split_once('=').map_or(option, |(k, _)| k);
+    OPPOSITE_OPTIONS
+        .iter()
+        .find(|(a, b)| key == *a || key == *b)
+        .map_or(key, |(a, _)| a)
+}
+
+/// Drop empty and repeated entries from options=, in the order they were first given;
+/// of conflicting ones (like two pri=), the last one wins, in the place of the first
+fn normalize_options(device_name: &str, options: &str, warnings: &mut Vec<Warning>) -> String {
+    let mut ret: Vec<&str> = vec![];
+    for option in options.split(',').filter(|o| !o.is_empty()) {
+        match ret.iter_mut().find(|o| option_key(o) == option_key(option)) {
+            Some(prev) if *prev == option => {}
+            Some(prev) => {
+                warnings.push(Warning::warn(format!(
+                    "{}: options= has both {} and {}, using {}.",
+                    device_name, prev, option, option
+                )));
+                *prev = option;
+            }
+            None => ret.push(option),
+        }
+    }
+    ret.join(",")
+}
+
+#[allow(clippy::too_many_arguments)]
 fn read_devices(
     root: &Path,
     kernel_override: bool,
-    memtotal_mb: u64,
-) -> Result<HashMap<String, Device>> {
+    memtotal_kb: u64,
+    swaptotal_mb: u64,
+    cmatotal_mb: u64,
+    allow_exec: bool,
+    profile: Option<String>,
+    warnings: &mut Vec<Warning>,
+) -> Result<(HashMap<String, Device>, Globals)> {
     let fragments = locate_fragments(root);
 
     if fragments.is_empty() && !kernel_override {
-        info!("No configuration found.");
+        warnings.push(Warning::info("No configuration found.".to_string()));
     }
 
     let mut devices: HashMap<String, Device> = HashMap::new();
     let mut slab = fasteval::Slab::new();
     let mut ctx = EvalContext {
-        memtotal_mb,
+        memtotal_mb: MemtotalRounding::Truncate.mb(memtotal_kb),
+        memtotal_kb,
+        other_swap_mb: get_other_swap_mb(root, swaptotal_mb, warnings),
+        previous_devices: get_previous_devices(root, warnings),
+        disksize_mb: None,
+        globals: Globals {
+            profile,
+            ..Globals::default()
+        },
+        total_size_limit: None,
+        presets: BTreeMap::new(),
+        cmatotal_mb,
+        reserved_mb: 0,
+        ndevices: 0,
+        on_battery: on_battery(root),
+        uptime: get_uptime(root, warnings),
+        memory_source: MemorySource::Meminfo,
+        ram_limit_mb: None,
         additional: BTreeMap::new(),
     };
 
     for (_, path) in fragments {
-        let ini = Ini::load_from_file(&path)?;
+        let mut stack = vec![path];
+        read_fragment(
+            root,
+            &mut stack,
+            &mut devices,
+            &mut slab,
+            &mut ctx,
+            allow_exec,
+            warnings,
+        )?;
+    }
 
-        for (sname, props) in ini.iter() {
-            let sname = match sname {
-                None => {
-                    for (k, v) in props.iter() {
-                        toplevel_line(&path, k, v, &mut slab, &mut ctx)?;
+    if let Some(range) = &ctx.globals.reserved_indices {
+        let mut outside: Vec<_> = devices
+            .keys()
+            .filter_map(|name| {
+                let index = name[4..]
+                    .parse::<u64>()
+                    .expect("already verified in read_fragment()");
+                (!range.contains(&index)).then_some((index, name.clone()))
+            })
+            .collect();
+        outside.sort_unstable();
+        for (_, name) in outside {
+            warnings.push(Warning::warn(format!(
+                "{}: outside of reserved-indices={}-{}, ignoring.",
+                name,
+                range.start(),
+                range.end()
+            )));
+            devices.remove(&name);
+        }
+    }
+
+    match &ctx.globals.reserved_indices {
+        Some(range) if kernel_override && !range.contains(&0) => {
+            warnings.push(Warning::info(format!(
+                "zram0: outside of reserved-indices={}-{}, not creating it for systemd.zram on the kernel command line.",
+                range.start(),
+                range.end()
+            )));
+        }
+        _ if kernel_override => {
+            devices.entry("zram0".to_string()).or_insert_with(|| {
+                warnings.push(Warning::info("zram0: not configured, creating with default settings because of systemd.zram on the kernel command line.".to_string()));
+                Device::new("zram0".to_string())
+            });
+        }
+        _ => {}
+    }
+
+    apply_kernel_overrides(root, &mut devices, &ctx.presets, warnings);
+
+    if let Some(default) = read_compression_default(root)? {
+        for dev in devices.values_mut() {
+            if dev.compression_algorithms.compression_algorithms.is_empty() {
+                add_compression_algorithms(&mut dev.compression_algorithms, &default);
+            }
+        }
+    }
+
+    for dev in devices.values() {
+        dev.compression_algorithms
+            .with_priorities()
+            .with_context(|| format!("{}: compression-algorithm", dev.name))?;
+    }
+
+    if let Some(policy) = AlgorithmPolicy::read(root)? {
+        for dev in devices.values() {
+            policy.check(dev)?;
+        }
+    }
+
+    for dev in devices.values_mut() {
+        if dev.mount_point.is_some() && dev.fs_type.as_deref() == Some("none") {
+            return Err(anyhow!(
+                "{}: fs-type=none can't be mounted, remove mount-point=",
+                dev.name
+            ));
+        }
+        resolve_mount_ids(root, dev, warnings)?;
+        let options = normalize_options(&dev.name, &dev.options, warnings);
+        if options != dev.options {
+            dev.options = options.into();
+        }
+        if dev.writeback_crypttab_name.is_some() && dev.writeback_dev.is_none() {
+            warnings.push(Warning::warn(format!(
+                "{}: writeback-crypttab-name= set without writeback-device=, ignoring.",
+                dev.name
+            )));
+            dev.writeback_crypttab_name = None;
+        }
+        if dev.default_dependencies.is_some() && dev.is_raw() {
+            warnings.push(Warning::warn(format!(
+                "{}: default-dependencies= set with fs-type=none, which has no swap or mount unit, ignoring.",
+                dev.name
+            )));
+            dev.default_dependencies = None;
+        }
+        if dev.ram_budget.is_some() && dev.zram_size.is_some() {
+            warnings.push(Warning::warn(format!(
+                "{}: zram-size= set together with ram-budget=, ignoring zram-size=.",
+                dev.name
+            )));
+            dev.zram_size = None;
+        }
+        if dev.expected_ratio.is_some() && dev.ram_budget.is_none() {
+            warnings.push(Warning::warn(format!(
+                "{}: expected-ratio= set without ram-budget=, ignoring.",
+                dev.name
+            )));
+            dev.expected_ratio = None;
+        }
+        if dev.role.is_some() && !dev.is_swap() {
+            warnings.push(Warning::warn(format!(
+                "{}: role= set on a device which isn't swap, ignoring.",
+                dev.name
+            )));
+            dev.role = None;
+        }
+        if dev.swap_page_size.is_some() && !dev.is_swap() {
+            warnings.push(Warning::warn(format!(
+                "{}: swap-page-size= set on a device which isn't swap, ignoring.",
+                dev.name
+            )));
+            dev.swap_page_size = None;
+        }
+        if dev.hibernate_safe && !dev.is_swap() {
+            warnings.push(Warning::warn(format!(
+                "{}: hibernate-safe= set on a device which isn't swap, ignoring.",
+                dev.name
+            )));
+            dev.hibernate_safe = false;
+        }
+        if dev.swap_nofail && !dev.is_swap() {
+            warnings.push(Warning::warn(format!(
+                "{}: swap-nofail= set on a device which isn't swap, ignoring.",
+                dev.name
+            )));
+            dev.swap_nofail = false;
+        }
+        if dev.swap_nofail && !dev.required_by.is_empty() {
+            warnings.push(Warning::info(format!(
+                "{}: swap-nofail= set, so {} only want the swap unit instead of requiring it.",
+                dev.name,
+                dev.required_by.join(", ")
+            )));
+        }
+        if dev.lazy_makefs && !dev.is_swap() {
+            warnings.push(Warning::warn(format!(
+                "{}: lazy-makefs= set on a device which isn't swap, ignoring.",
+                dev.name
+            )));
+            dev.lazy_makefs = false;
+        }
+        if dev.makefs_mode == MakefsMode::Systemd {
+            let ignored = if dev.is_raw() {
+                Some("with fs-type=none, which isn't formatted")
+            } else if dev.swap_page_size.is_some() {
+                Some("with swap-page-size=, which systemd-makefs can't pass on")
+            } else {
+                None
+            };
+            if let Some(why) = ignored {
+                warnings.push(Warning::warn(format!(
+                    "{}: makefs-mode=systemd set {}, ignoring.",
+                    dev.name, why
+                )));
+                dev.makefs_mode = MakefsMode::Setup;
+            } else if dev.lazy_makefs {
+                warnings.push(Warning::warn(format!(
+                    "{}: lazy-makefs= has no effect with makefs-mode=systemd, ignoring.",
+                    dev.name
+                )));
+                dev.lazy_makefs = false;
+            }
+        }
+    }
+
+    ctx.ndevices = devices.len() as u64;
+    if let Some(file) = ctx.memory_source.cgroup_file() {
+        ctx.ram_limit_mb = get_cgroup_memory_limit_mb(root, file, warnings);
+    }
+    /* device name → default for zram-resident-limit=, for the enabled devices */
+    let mut enabled = HashMap::new();
+    for dev in devices.values_mut() {
+        let mut explanation = vec![];
+        if dev.condition_met(root, warnings, &mut explanation) {
+            if let Some(default_resident_mb) =
+                dev.set_base_disksize_if_enabled(&mut ctx, warnings, &mut explanation)?
+            {
+                enabled.insert(dev.name.clone(), default_resident_mb);
+            }
+        }
+        dev.explanation = explanation;
+    }
+
+    /* Before the file system's overhead and zram-resident-limit=, which follow from the size */
+    if let Some(expr) = ctx.total_size_limit.clone() {
+        let limit_mb = fasteval::ez_eval(&expr, &mut ctx)
+            .with_context(|| format!("total-size-limit={}", expr))?;
+        if limit_mb < 0. {
+            return Err(anyhow!("total-size-limit={} < 0", limit_mb));
+        }
+        apply_total_size_limit(&mut devices, (limit_mb * 1024. * 1024.) as u64, warnings);
+    }
+
+    for dev in devices.values_mut() {
+        if let Some(&default_resident_mb) = enabled.get(&dev.name) {
+            let mut explanation = mem::take(&mut dev.explanation);
+            dev.finish_disksize(&mut ctx, default_resident_mb, warnings, &mut explanation)?;
+            dev.explanation = explanation;
+        }
+    }
+
+    resolve_swappiness(&mut devices, warnings);
+
+    Ok((devices, ctx.globals))
+}
+
+/// Scale the devices down proportionally if together they'd be larger than limit (bytes),
+/// so that several devices sized from RAM don't add up to more than intended
+fn apply_total_size_limit(
+    devices: &mut HashMap<String, Device>,
+    limit: u64,
+    warnings: &mut Vec<Warning>,
+) {
+    let total: u64 = devices.values().map(|dev| dev.disksize).sum();
+    if total <= limit {
+        return;
+    }
+
+    let scale = limit as f64 / total as f64;
+    for dev in devices.values_mut().filter(|dev| dev.disksize > 0) {
+        let disksize = (dev.disksize as f64 * scale) as u64 / 4096 * 4096;
+        dev.explanation.push(format!(
+            "total-size-limit: {}MB for {}MB of devices, scaled to {}MB",
+            limit / 1024 / 1024,
+            total / 1024 / 1024,
+            disksize / 1024 / 1024
+        ));
+        dev.disksize = disksize;
+    }
+    warnings.push(Warning::info(format!(
+        "devices add up to {}MB, more than total-size-limit={}MB, scaling them down.",
+        total / 1024 / 1024,
+        limit / 1024 / 1024
+    )));
+}
+
+/// Keys which add to a list instead of replacing the earlier value
+const ACCUMULATING_KEYS: &[&str] = &[
+    "compression-algorithm-add",
+    "wanted-by",
+    "required-by",
+    "after",
+    "before",
+    "exec-start-post",
+    "exec-stop-post",
+    "condition",
+    "assert",
+];
+
+/// Reports a key set for the same device by an earlier fragment to a different value,
+/// so that it's clear which drop-in won
+fn note_override(
+    dev: &mut Device,
+    path: &Path,
+    key: &str,
+    value: &str,
+    warnings: &mut Vec<Warning>,
+) {
+    if ACCUMULATING_KEYS.contains(&key) {
+        return;
+    }
+    if let Some((prev_path, prev_value)) = dev
+        .sources
+        .insert(key.to_string(), (path.to_path_buf(), value.to_string()))
+    {
+        if prev_value != value {
+            warnings.push(Warning::info(format!(
+                "{}: {}={} from {} overridden by {}={} from {}",
+                dev.name,
+                key,
+                prev_value,
+                prev_path.display(),
+                key,
+                value,
+                path.display()
+            )));
+        }
+    }
+}
+
+/// How deep include= may nest
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Reads the fragment at the top of stack, which holds the chain of fragments including it,
+/// then the files it includes, in order, as if they were drop-ins right after it.
+fn read_fragment(
+    root: &Path,
+    stack: &mut Vec<PathBuf>,
+    devices: &mut HashMap<String, Device>,
+    slab: &mut fasteval::Slab,
+    ctx: &mut EvalContext,
+    allow_exec: bool,
+    warnings: &mut Vec<Warning>,
+) -> Result<()> {
+    let path = stack
+        .last()
+        .expect("stack has the current fragment")
+        .clone();
+    let mut includes = vec![];
+
+    for (sname, props) in load_fragment(&path)? {
+        let sname = match sname.as_deref() {
+            None => {
+                for (k, v) in &props {
+                    if k == "include" {
+                        includes.push(v.clone());
+                    } else {
+                        toplevel_line(&path, k, v, slab, ctx, allow_exec, warnings)?;
                     }
-                    continue;
-                }
-                Some(sname) if sname.starts_with("zram") && sname[4..].parse::<u64>().is_ok() => {
-                    sname.to_string()
                 }
-                Some(sname) => {
-                    warn!("{}: Ignoring section \"{}\"", path.display(), sname);
+                continue;
+            }
+            Some(sname) => match section_device(sname, ctx.globals.profile.as_deref()) {
+                Some(Some(device)) => device.to_string(),
+                /* Another profile's */
+                Some(None) => continue,
+                None => {
+                    warnings.push(Warning::warn(format!(
+                        "{}: Ignoring section \"{}\"",
+                        path.display(),
+                        sname
+                    )));
                     continue;
                 }
-            };
+            },
+        };
+
+        let dev = devices
+            .entry(sname.clone())
+            .or_insert_with(|| Device::new(sname));
 
-            let dev = devices
-                .entry(sname.clone())
-                .or_insert_with(|| Device::new(sname));
+        for (k, v) in &props {
+            let v = &expand_presets(k, v, &ctx.presets)
+                .with_context(|| format!("{}: [{}]", path.display(), dev.name))?;
+            note_override(dev, &path, k, v, warnings);
+            parse_line(dev, k, v, warnings)?;
+        }
+    }
 
-            for (k, v) in props.iter() {
-                parse_line(dev, k, v)?;
+    for include in includes {
+        let target = match include.strip_prefix('/') {
+            Some(relative) => root.join(relative),
+            None => {
+                warnings.push(Warning::warn(format!(
+                    "{}: include={} is not an absolute path, ignoring.",
+                    path.display(),
+                    include
+                )));
+                continue;
             }
+        };
+        if stack.contains(&target) {
+            return Err(anyhow!(
+                "{}: include={} is a cycle: {}",
+                path.display(),
+                include,
+                stack
+                    .iter()
+                    .chain([&target])
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            ));
+        }
+        if stack.len() > MAX_INCLUDE_DEPTH {
+            return Err(anyhow!(
+                "{}: include={} nests more than {} levels deep",
+                path.display(),
+                include,
+                MAX_INCLUDE_DEPTH
+            ));
+        }
+        if !target.exists() {
+            warnings.push(Warning::info(format!(
+                "{}: include={} doesn't exist, ignoring.",
+                path.display(),
+                include
+            )));
+            continue;
         }
+
+        stack.push(target);
+        read_fragment(root, stack, devices, slab, ctx, allow_exec, warnings)?;
+        stack.pop();
     }
+    Ok(())
+}
 
-    if kernel_override {
-        devices
-            .entry("zram0".to_string())
-            .or_insert_with(|| Device::new("zram0".to_string()));
+/// vm.swappiness is system-wide, so the highest-numbered swap device
+/// which sets it wins, and all such devices are made to agree on it.
+fn resolve_swappiness(devices: &mut HashMap<String, Device>, warnings: &mut Vec<Warning>) {
+    let mut settings: Vec<(u64, String, u8)> = devices
+        .values()
+        .filter(|dev| dev.is_swap() && dev.disksize > 0)
+        .filter_map(|dev| {
+            Some((
+                dev.name[4..]
+                    .parse()
+                    .expect("already verified in read_devices()"),
+                dev.name.clone(),
+                dev.swappiness?,
+            ))
+        })
+        .collect();
+    settings.sort_unstable();
+
+    let (winner, value) = match settings.last() {
+        Some((_, name, value)) => (name.clone(), *value),
+        None => return,
+    };
+    for (_, name, val) in &settings {
+        if *val != value {
+            warnings.push(Warning::warn(format!(
+                "{}: swappiness={} overridden by swappiness={} from {}, since vm.swappiness is global.",
+                name, val, value, winner
+            )));
+        }
     }
 
     for dev in devices.values_mut() {
-        dev.set_disksize_if_enabled(&mut ctx)?;
+        if dev.swappiness.is_some() && dev.is_swap() {
+            dev.swappiness = Some(value);
+        }
     }
+}
 
-    Ok(devices)
+/// Sections of a configuration file, with the global one (None) first, and their keys in order
+type Sections = Vec<(Option<String>, Vec<(String, String)>)>;
+
+fn load_fragment(path: &Path) -> Result<Sections> {
+    if path.extension().is_some_and(|e| e == "json") {
+        return load_json_fragment(path);
+    }
+
+    let ini = Ini::load_from_file(path).map_err(|e| match e {
+        /* The parser counts lines from 0, and the newline (column 0) as part of the next one */
+        ini::Error::Parse(e) => anyhow::Error::new(SyntaxError(format!(
+            "{}: line {}: {}",
+            path.display(),
+            if e.col == 0 { e.line } else { e.line + 1 },
+            e.msg
+        ))),
+        ini::Error::Io(e) => {
+            anyhow::Error::new(e).context(format!("Failed to read {}", path.display()))
+        }
+    })?;
+    Ok(ini
+        .iter()
+        .map(|(sname, props)| {
+            (
+                sname.map(str::to_string),
+                props
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )
+        })
+        .collect())
+}
+
+/// An object of sections (objects) and global directives, e.g.
+/// {"zram0": {"zram-size": "ram / 2", "swap-priority": 100, "wanted-by": ["swap.target"]}}
+/// Arrays give the key once for each element.
+fn load_json_fragment(path: &Path) -> Result<Sections> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let root: serde_json::Value = serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let root = root
+        .as_object()
+        .ok_or_else(|| anyhow!("{}: not a JSON object", path.display()))?;
+
+    let mut global = vec![];
+    let mut sections = vec![];
+    for (k, v) in root {
+        match v {
+            serde_json::Value::Object(props) => {
+                let mut lines = vec![];
+                for (pk, pv) in props {
+                    json_lines(path, pk, pv, &mut lines)?;
+                }
+                sections.push((Some(k.clone()), lines));
+            }
+            _ => json_lines(path, k, v, &mut global)?,
+        }
+    }
+
+    sections.insert(0, (None, global));
+    Ok(sections)
+}
+
+fn json_lines(
+    path: &Path,
+    key: &str,
+    value: &serde_json::Value,
+    lines: &mut Vec<(String, String)>,
+) -> Result<()> {
+    use serde_json::Value;
+
+    let mut push = |v: &Value| {
+        lines.push((
+            key.to_string(),
+            match v {
+                Value::String(s) => s.clone(),
+                Value::Number(n) => n.to_string(),
+                Value::Bool(b) => b.to_string(),
+                _ => {
+                    return Err(anyhow!(
+                        "{}: {}: unsupported value {}",
+                        path.display(),
+                        key,
+                        v
+                    ))
+                }
+            },
+        ));
+        Ok(())
+    };
+
+    match value {
+        Value::Array(items) => items.iter().try_for_each(push),
+        _ => push(value),
+    }
 }
 
 fn locate_fragments(root: &Path) -> BTreeMap<OsString, PathBuf> {
@@ -382,8 +2212,12 @@ fn locate_fragments(root: &Path) -> BTreeMap<OsString, PathBuf> {
                           // programatic creation of config there.
     ];
 
-    let mut fragments =
-        liboverdrop::scan(&base_dirs, "systemd/zram-generator.conf.d", &["conf"], true);
+    let mut fragments = liboverdrop::scan(
+        &base_dirs,
+        "systemd/zram-generator.conf.d",
+        &["conf", "json"],
+        true,
+    );
 
     if let Some(path) = base_dirs
         .into_iter()
@@ -399,381 +2233,3978 @@ fn locate_fragments(root: &Path) -> BTreeMap<OsString, PathBuf> {
     fragments
 }
 
-fn parse_optional_size(val: &str) -> Result<Option<u64>> {
-    Ok(if val == "none" {
-        None
-    } else {
-        Some(
-            val.parse()
-                .with_context(|| format!("Failed to parse optional size \"{}\"", val))?,
-        )
-    })
+/// A percentage in [0, 100), with an optional % suffix
+fn parse_fs_reserve(key: &str, value: &str) -> Result<f64> {
+    match value.strip_suffix('%').unwrap_or(value).parse::<f64>() {
+        Ok(p) if (0. ..100.).contains(&p) => Ok(p),
+        _ => Err(anyhow!(
+            "{}={} is not a percentage between 0 and 100",
+            key,
+            value
+        )),
+    }
 }
 
-fn parse_swap_priority(val: &str) -> Result<i32> {
-    let val = val
-        .parse()
-        .with_context(|| format!("Failed to parse priority \"{}\"", val))?;
+fn parse_expected_ratio(key: &str, value: &str) -> Result<f64> {
+    match value.parse::<f64>() {
+        /* Below 1, the device would be smaller than the memory it's allowed */
+        Ok(r) if (1. ..=MAX_SIZE_RAM_MULTIPLE as f64).contains(&r) => Ok(r),
+        _ => Err(anyhow!(
+            "{}={} is not a ratio between 1 and {}",
+            key,
+            value,
+            MAX_SIZE_RAM_MULTIPLE
+        )),
+    }
+}
 
-    /* See --priority in swapon(8). */
-    match val {
-        -1..=0x7FFF => Ok(val),
-        _ => Err(anyhow!("Swap priority {} out of range", val)),
+fn parse_device_wait(key: &str, value: &str) -> Result<Duration> {
+    match value.parse::<f64>() {
+        Ok(s) if (0. ..=MAX_DEVICE_WAIT_SEC).contains(&s) => Ok(Duration::from_secs_f64(s)),
+        _ => Err(anyhow!(
+            "{}={} is not a number of seconds between 0 and {}",
+            key,
+            value,
+            MAX_DEVICE_WAIT_SEC
+        )),
     }
 }
 
-fn verify_mount_point(key: &str, val: &str) -> Result<PathBuf> {
-    let path = Path::new(val);
+/// The disksize (in bytes, rounded up to a whole page) of which `reserve` percent is `size`
+fn apply_fs_reserve(size: u64, reserve: f64) -> u64 {
+    let disksize = (size as f64 / (1. - reserve / 100.)).ceil() as u64;
+    disksize.div_ceil(4096) * 4096
+}
 
-    if path.is_relative() {
-        return Err(anyhow!("{} {} is not absolute", key, val));
+/// An octal mode, like for chmod(1)
+fn parse_mode(key: &str, value: &str) -> Result<u32> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 && !value.starts_with('+') => Ok(mode),
+        _ => Err(anyhow!("{}={} is not an octal mode", key, value)),
     }
+}
 
-    if path.components().any(|c| c == Component::ParentDir) {
-        return Err(anyhow!("{} {:#?} is not normalized", key, path));
+/// A numeric id, or a name looked up in root/db (etc/passwd or etc/group).
+/// Users from other sources (e.g. LDAP) aren't available this early, so need to be given by id.
+fn resolve_id(root: &Path, db: &str, key: &str, value: &str) -> Result<u32> {
+    if let Ok(id) = value.parse() {
+        return Ok(id);
     }
 
-    Ok(path.components().collect()) // normalise away /./ components
+    let path = root.join(db);
+    let text = fs::read_to_string(&path)
+        .with_context(|| format!("{}={}: failed to read {}", key, value, path.display()))?;
+    text.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields[0] == value && fields.len() > 2)
+        .and_then(|fields| fields[2].parse().ok())
+        .ok_or_else(|| anyhow!("{}={}: not found in /{}", key, value, db))
 }
 
-fn parse_size_expr(
-    dev: &Device,
-    key: &str,
-    value: &str,
-) -> Result<(String, fasteval::ExpressionI, fasteval::Slab)> {
-    let mut sl = fasteval::Slab::new();
-    Ok((
-        value.to_string(),
-        fasteval::Parser::new()
-            .parse_noclear(value, &mut sl.ps)
-            .with_context(|| format!("{} {}", key, dev.name))?,
-        sl,
-    ))
+fn resolve_mount_ids(root: &Path, dev: &mut Device, warnings: &mut Vec<Warning>) -> Result<()> {
+    let configured =
+        dev.mount_mode.is_some() || dev.mount_owner.is_some() || dev.mount_group.is_some();
+    if configured && dev.mount_point.is_none() {
+        warnings.push(Warning::warn(format!(
+            "{}: mount-mode=, mount-owner=, or mount-group= set without mount-point=, ignoring.",
+            dev.name
+        )));
+        dev.mount_mode = None;
+        return Ok(());
+    }
+
+    dev.mount_uid = dev
+        .mount_owner
+        .as_deref()
+        .map(|owner| resolve_id(root, "etc/passwd", "mount-owner", owner))
+        .transpose()
+        .with_context(|| dev.name.clone())?;
+    dev.mount_gid = dev
+        .mount_group
+        .as_deref()
+        .map(|group| resolve_id(root, "etc/group", "mount-group", group))
+        .transpose()
+        .with_context(|| dev.name.clone())?;
+    Ok(())
 }
 
-fn parse_compression_algorithm_params(whole: &str) -> (String, String) {
-    if let Some(paren) = whole.find('(') {
-        let (algo, mut params) = whole.split_at(paren);
-        params = &params[1..];
-        if params.ends_with(')') {
-            params = &params[..params.len() - 1];
+/// The compression-algorithm= for devices which don't set one, e.g. shipped by the distribution.
+/// Like for the main configuration file, /run overrides /etc, which overrides /usr.
+fn read_compression_default(root: &Path) -> Result<Option<String>> {
+    for base in ["run", "etc", "usr/local/lib", "usr/lib"] {
+        let path = root.join(base).join("zram-generator/compression.default");
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let value = text
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                return Ok(Some(value));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
         }
-        (algo.to_string(), params.replace(',', " "))
-    } else {
-        (whole.to_string(), String::new())
     }
+    Ok(None)
 }
 
-fn parse_line(dev: &mut Device, key: &str, value: &str) -> Result<()> {
-    match key {
-        "host-memory-limit" | "memory-limit" => {
-            /* memory-limit is for backwards compat. host-memory-limit name is preferred. */
-            dev.host_memory_limit_mb = parse_optional_size(value)?;
-        }
+const ALGORITHM_POLICY_PATH: &str = "etc/zram-generator/policy.conf";
 
-        "zram-size" => {
-            dev.zram_size = Some(parse_size_expr(dev, key, value)?);
-        }
+/// Which compression algorithms devices may use, set by the administrator in ALGORITHM_POLICY_PATH:
+/// "allow = ..." lines list the only ones permitted, "deny = ..." lines ones which aren't
+#[derive(Debug, Default, PartialEq, Eq)]
+struct AlgorithmPolicy {
+    allow: Option<BTreeSet<String>>,
+    deny: BTreeSet<String>,
+}
 
-        "zram-resident-limit" => {
-            dev.zram_resident_limit = Some(parse_size_expr(dev, key, value)?);
+impl AlgorithmPolicy {
+    fn read(root: &Path) -> Result<Option<AlgorithmPolicy>> {
+        let path = root.join(ALGORITHM_POLICY_PATH);
+        match fs::read_to_string(&path) {
+            Ok(text) => AlgorithmPolicy::parse(&text)
+                .map(Some)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
         }
+    }
 
-        "compression-algorithm" => {
-            dev.compression_algorithms =
-                value
-                    .split_whitespace()
-                    .fold(Default::default(), |mut algos, s| {
-                        let (algo, params) = parse_compression_algorithm_params(s);
-                        if algo.is_empty() {
-                            algos.recompression_global = params;
-                        } else {
-                            algos.compression_algorithms.push((algo, params));
-                        }
-                        algos
-                    });
+    fn parse(text: &str) -> Result<AlgorithmPolicy> {
+        let mut policy = AlgorithmPolicy::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{:?} is not a key=value line", line))?;
+            let algorithms = value.split_whitespace().map(str::to_string);
+            match key.trim_end() {
+                "allow" => policy
+                    .allow
+                    .get_or_insert_with(BTreeSet::new)
+                    .extend(algorithms),
+                "deny" => policy.deny.extend(algorithms),
+                key => return Err(anyhow!("unknown key {}", key)),
+            }
         }
+        Ok(policy)
+    }
 
-        "writeback-device" => {
-            dev.writeback_dev = Some(verify_mount_point(key, value)?);
-        }
+    fn permits(&self, algorithm: &str) -> bool {
+        !self.deny.contains(algorithm)
+            && self
+                .allow
+                .as_ref()
+                .is_none_or(|allow| allow.contains(algorithm))
+    }
 
-        "swap-priority" => {
-            dev.swap_priority = parse_swap_priority(value)?;
+    /// Recompression algorithms are held to the same standard; devices without an algorithm use the kernel's default,
+    /// which is left alone. auto only picks among the permitted ones, so needs one of them.
+    fn check(&self, dev: &Device) -> Result<()> {
+        for (algorithm, _) in &dev.compression_algorithms.compression_algorithms {
+            if algorithm == "auto" {
+                if !AUTO_COMPRESSION_ALGORITHMS
+                    .iter()
+                    .any(|algo| self.permits(algo))
+                {
+                    return Err(anyhow!(
+                        "{}: compression-algorithm=auto: none of {} is allowed by /{}",
+                        dev.name,
+                        AUTO_COMPRESSION_ALGORITHMS.join(", "),
+                        ALGORITHM_POLICY_PATH
+                    ));
+                }
+            } else if !self.permits(algorithm) {
+                return Err(anyhow!(
+                    "{}: compression algorithm {} is not allowed by /{}",
+                    dev.name,
+                    algorithm,
+                    ALGORITHM_POLICY_PATH
+                ));
+            }
         }
+        Ok(())
+    }
+}
+
+/// The AUTO_COMPRESSION_ALGORITHMS compression-algorithm=auto may pick from, in order
+pub fn auto_compression_algorithms(root: &Path) -> Result<Vec<&'static str>> {
+    let policy = AlgorithmPolicy::read(root)?;
+    Ok(AUTO_COMPRESSION_ALGORITHMS
+        .iter()
+        .copied()
+        .filter(|algo| policy.as_ref().is_none_or(|policy| policy.permits(algo)))
+        .collect())
+}
+
+/// Megabytes, or with a K, M, G, or T suffix (powers of 1024, like in systemd), or "none"
+fn parse_optional_size(val: &str) -> Result<Option<u64>> {
+    if val == "none" {
+        return Ok(None);
+    }
+
+    let (num, factor) = match val.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&val[..i], 1. / 1024.),
+        Some((i, 'M' | 'm')) => (&val[..i], 1.),
+        Some((i, 'G' | 'g')) => (&val[..i], 1024.),
+        Some((i, 'T' | 't')) => (&val[..i], 1024. * 1024.),
+        _ => (val, 1.),
+    };
+    match num.trim_end().parse::<f64>() {
+        Ok(num) if num >= 0. && num.is_finite() => Ok(Some((num * factor) as u64)),
+        _ => Err(anyhow!("Failed to parse optional size \"{}\"", val)),
+    }
+}
+
+fn parse_boolean(key: &str, val: &str) -> Result<bool> {
+    /* See parse_boolean() in systemd's src/basic/parse-util.c. */
+    match &val.to_ascii_lowercase()[..] {
+        "1" | "yes" | "y" | "true" | "t" | "on" => Ok(true),
+        "0" | "no" | "n" | "false" | "f" | "off" => Ok(false),
+        _ => Err(anyhow!("Failed to parse {} \"{}\" as a boolean", key, val)),
+    }
+}
+
+/// A boolean (one pass or none), or a number of passes
+fn parse_recompress_now(key: &str, val: &str) -> Result<u32> {
+    if let Ok(enabled) = parse_boolean(key, val) {
+        return Ok(enabled as u32);
+    }
+    match val.parse() {
+        Ok(passes) if passes <= MAX_RECOMPRESS_PASSES => Ok(passes),
+        Ok(_) => Err(anyhow!(
+            "{}={} is more than {} passes",
+            key,
+            val,
+            MAX_RECOMPRESS_PASSES
+        )),
+        Err(_) => Err(anyhow!(
+            "Failed to parse {} \"{}\" as a boolean or a number of passes",
+            key,
+            val
+        )),
+    }
+}
+
+fn parse_swap_priority(val: &str) -> Result<i32> {
+    let val = val
+        .parse()
+        .with_context(|| format!("Failed to parse priority \"{}\"", val))?;
+
+    /* See --priority in swapon(8). */
+    match val {
+        -1..=0x7FFF => Ok(val),
+        _ => Err(anyhow!("Swap priority {} out of range", val)),
+    }
+}
+
+fn parse_block_size(val: &str) -> Result<u32> {
+    let val: u32 = val
+        .parse()
+        .with_context(|| format!("Failed to parse block size \"{}\"", val))?;
+
+    /* From the smallest sector to the largest page size. */
+    match val {
+        512..=0x10000 if val.is_power_of_two() => Ok(val),
+        _ => Err(anyhow!(
+            "Block size {} is not a power of two between 512 and 65536",
+            val
+        )),
+    }
+}
+
+fn parse_swap_page_size(val: &str) -> Result<u32> {
+    let val: u32 = val
+        .parse()
+        .with_context(|| format!("Failed to parse swap page size \"{}\"", val))?;
+
+    /* The page sizes of the architectures Linux runs on, from 4k (most) to 64k (arm64, ppc64, …). */
+    match val {
+        0x1000..=0x10000 if val.is_power_of_two() => Ok(val),
+        _ => Err(anyhow!(
+            "Swap page size {} is not a power of two between 4096 and 65536",
+            val
+        )),
+    }
+}
+
+fn parse_swap_tier(val: &str) -> Result<i32> {
+    match val {
+        "high" => Ok(50),
+        "normal" => Ok(0),
+        "low" => Ok(-50),
+        _ => Err(anyhow!("Unknown swap tier \"{}\"", val)),
+    }
+}
+
+fn parse_swappiness(val: &str) -> Result<u8> {
+    let val = val
+        .parse()
+        .with_context(|| format!("Failed to parse swappiness \"{}\"", val))?;
+
+    /* See vm.swappiness in Documentation/admin-guide/sysctl/vm.rst. */
+    match val {
+        0..=200 => Ok(val),
+        _ => Err(anyhow!("Swappiness {} out of range", val)),
+    }
+}
+
+fn verify_mount_point(key: &str, val: &str) -> Result<PathBuf> {
+    if val.is_empty() {
+        return Err(anyhow!("{} is empty", key));
+    }
+
+    /* These would end up in unit files and sysfs writes verbatim. */
+    if val.chars().any(char::is_control) {
+        return Err(anyhow!("{} {:?} contains control characters", key, val));
+    }
+
+    let path = Path::new(val);
+
+    if path.is_relative() {
+        return Err(anyhow!("{} {} is not absolute", key, val));
+    }
+
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(anyhow!("{} {:#?} is not normalized", key, path));
+    }
+
+    Ok(path.components().collect()) // normalise away /./ components
+}
+
+/* Writing back into another zram device would just take more memory under memory pressure,
+ * and can deadlock. */
+fn verify_writeback_device(key: &str, val: &str) -> Result<PathBuf> {
+    let path = verify_mount_point(key, val)?;
+    let resolved = resolve_symlinks(&path);
+    if is_zram_device_path(&resolved) {
+        return Err(anyhow!(
+            "{} {} is a zram device{}",
+            key,
+            val,
+            match resolved == path {
+                true => String::new(),
+                false => format!(" ({})", resolved.display()),
+            }
+        ));
+    }
+    Ok(path)
+}
+
+/// Where udev links partitions by their GPT partition UUID
+pub const BY_PARTUUID: &str = "/dev/disk/by-partuuid";
+/// Where udev links GPT partitions as <type GUID>.<partition UUID>
+pub const BY_PARTTYPEUUID: &str = "/dev/disk/by-parttypeuuid";
+
+/// A path, or PARTUUID=<uuid> or PARTTYPE=<type GUID> for discoverable partitions.
+/// PARTTYPE= becomes a path in BY_PARTTYPEUUID without the partition UUID,
+/// which setup::resolve_writeback_device() looks up.
+fn parse_writeback_device(key: &str, val: &str) -> Result<PathBuf> {
+    let (dir, guid) = if let Some(guid) = val.strip_prefix("PARTUUID=") {
+        (BY_PARTUUID, guid)
+    } else if let Some(guid) = val.strip_prefix("PARTTYPE=") {
+        (BY_PARTTYPEUUID, guid)
+    } else {
+        return verify_writeback_device(key, val);
+    };
+
+    if !is_guid(guid) {
+        return Err(anyhow!("{} {}: {:?} is not a GUID", key, val, guid));
+    }
+    Ok(Path::new(dir).join(guid.to_ascii_lowercase()))
+}
+
+/// 8-4-4-4-12 hex digits
+fn is_guid(val: &str) -> bool {
+    val.len() == 36
+        && val.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// writeback-device=PARTTYPE=, to be resolved at setup time
+pub fn is_partition_type_path(path: &Path) -> bool {
+    path.parent() == Some(Path::new(BY_PARTTYPEUUID))
+        && path
+            .file_name()
+            .is_some_and(|n| is_guid(&n.to_string_lossy()))
+}
+
+/// writeback-device=PARTUUID= or PARTTYPE=: only found (if at all) at setup time,
+/// so verify_writeback_device() can't check them, and missing them only costs write-back
+pub fn is_partition_path(path: &Path) -> bool {
+    is_partition_type_path(path)
+        || (path.parent() == Some(Path::new(BY_PARTUUID))
+            && path
+                .file_name()
+                .is_some_and(|n| is_guid(&n.to_string_lossy())))
+}
+
+/// The zram device a partition found at setup time (a path under root) turns out to be,
+/// which verify_writeback_device() refuses for paths given directly
+pub fn zram_device_behind(root: &Path, path: &Path) -> Option<PathBuf> {
+    let resolved = resolve_symlinks(&root.join(path.strip_prefix("/").unwrap_or(path)));
+    let resolved = match resolved.strip_prefix(root) {
+        Ok(relative) => Path::new("/").join(relative),
+        Err(_) => resolved,
+    };
+    is_zram_device_path(&resolved).then_some(resolved)
+}
+
+/// Follows symlinks as far as they go; the final target needn't exist,
+/// since udev may not have created it yet when the generator runs.
+fn resolve_symlinks(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    for _ in 0..40 {
+        match fs::read_link(&path) {
+            Ok(target) => {
+                let mut resolved = path.parent().unwrap_or(&path).to_path_buf();
+                for c in target.components() {
+                    match c {
+                        Component::RootDir => resolved = PathBuf::from("/"),
+                        Component::ParentDir => {
+                            resolved.pop();
+                        }
+                        Component::Normal(c) => resolved.push(c),
+                        Component::CurDir | Component::Prefix(_) => {}
+                    }
+                }
+                path = resolved;
+            }
+            Err(_) => break,
+        }
+    }
+    path
+}
+
+fn is_zram_device_path(path: &Path) -> bool {
+    path.parent() == Some(Path::new("/dev"))
+        && path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("zram"))
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/* See "PARSING TIME SPANS" in systemd.time(7). */
+fn verify_timespan(key: &str, val: &str) -> Result<String> {
+    const UNITS: &[&str] = &[
+        "usec", "us", "µs", "msec", "ms", "seconds", "second", "sec", "s", "minutes", "minute",
+        "min", "m", "hours", "hour", "hr", "h", "days", "day", "d", "weeks", "week", "w", "months",
+        "month", "M", "years", "year", "y",
+    ];
+
+    if val == "infinity" {
+        return Ok(val.to_string());
+    }
+
+    let mut rest = val.trim_start();
+    if rest.is_empty() {
+        return Err(anyhow!("{} is empty", key));
+    }
+    while !rest.is_empty() {
+        let num_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        if num_len == 0 || rest[..num_len].parse::<f64>().is_err() {
+            return Err(anyhow!("{} {:?} is not a valid time span", key, val));
+        }
+        rest = rest[num_len..].trim_start();
+
+        let unit_len = rest
+            .find(|c: char| !c.is_alphabetic())
+            .unwrap_or(rest.len());
+        if unit_len != 0 && !UNITS.contains(&&rest[..unit_len]) {
+            return Err(anyhow!(
+                "{} {:?}: unknown time unit {:?}",
+                key,
+                val,
+                &rest[..unit_len]
+            ));
+        }
+        rest = rest[unit_len..].trim_start();
+    }
+
+    Ok(val.to_string())
+}
+
+/* See "VALID UNIT NAMES" in systemd.unit(5). */
+fn verify_unit_name(key: &str, val: &str) -> Result<String> {
+    const SUFFIXES: &[&str] = &[
+        ".service",
+        ".socket",
+        ".device",
+        ".mount",
+        ".automount",
+        ".swap",
+        ".target",
+        ".path",
+        ".timer",
+        ".slice",
+        ".scope",
+    ];
+
+    let prefix = SUFFIXES
+        .iter()
+        .find_map(|s| val.strip_suffix(s))
+        .ok_or_else(|| anyhow!("{} {:?} is not a unit name", key, val))?;
+    if prefix.is_empty()
+        || val.len() > 255
+        || !val
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b":-_.\\@".contains(&b))
+    {
+        return Err(anyhow!("{} {:?} is not a valid unit name", key, val));
+    }
+    Ok(val.to_string())
+}
+
+/// The first field of a crypttab(5) line; the volume appears as /dev/mapper/<name>
+fn verify_crypttab_name(key: &str, val: &str) -> Result<String> {
+    if val.contains(|c: char| c == '/' || c.is_whitespace() || c.is_control())
+        || val == "."
+        || val == ".."
+    {
+        Err(anyhow!("{} {:?} is not a volume name", key, val))
+    } else {
+        Ok(val.to_string())
+    }
+}
+
+/* Slices can't be templated, see systemd.slice(5). */
+fn verify_slice_name(key: &str, val: &str) -> Result<String> {
+    let name = verify_unit_name(key, val)?;
+    if !name.ends_with(".slice") || name.contains('@') {
+        return Err(anyhow!("{} {:?} is not a slice", key, val));
+    }
+    Ok(name)
+}
+
+/* See MemoryMax= and TasksMax= in systemd.resource-control(5):
+ * a number (of bytes, with an optional K, M, G, T, P, or E suffix, for MemoryMax=), a percentage, or "infinity". */
+fn verify_resource_limit(key: &str, val: &str, suffixes: &[char]) -> Result<String> {
+    if val == "infinity" {
+        return Ok(val.to_string());
+    }
+
+    let plausible = if let Some(percent) = val.strip_suffix('%') {
+        percent
+            .parse::<f64>()
+            .is_ok_and(|p| (0. ..=100.).contains(&p))
+    } else {
+        let num = val.strip_suffix(suffixes).unwrap_or(val);
+        !num.is_empty() && num.bytes().all(|b| b.is_ascii_digit())
+    };
+    match plausible {
+        true => Ok(val.to_string()),
+        false => Err(anyhow!("{} {:?} is not a valid limit", key, val)),
+    }
+}
+
+/* See CPUAffinity= in systemd.exec(5). */
+fn verify_cpu_list(key: &str, val: &str) -> Result<String> {
+    if val == "numa" {
+        return Ok(val.to_string());
+    }
+
+    let mut any = false;
+    for range in val.split([' ', ',']).filter(|r| !r.is_empty()) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        match (start.parse::<u32>(), end.parse::<u32>()) {
+            (Ok(start), Ok(end)) if start <= end => any = true,
+            _ => return Err(anyhow!("{} {:?}: invalid CPU range {:?}", key, val, range)),
+        }
+    }
+
+    match any {
+        true => Ok(val.to_string()),
+        false => Err(anyhow!("{} is empty", key)),
+    }
+}
+
+/* See "COMMAND LINES" in systemd.service(5); the rest is left to systemd. */
+fn verify_exec_command(key: &str, val: &str) -> Result<String> {
+    if val.chars().any(char::is_control) {
+        return Err(anyhow!("{} {:?} contains control characters", key, val));
+    }
+
+    let command = val.trim_start_matches(['@', '-', ':', '+', '!']);
+    if command.is_empty() || command.starts_with(char::is_whitespace) {
+        return Err(anyhow!("{} {:?} doesn't specify a command", key, val));
+    }
+
+    Ok(val.to_string())
+}
+
+/// The Condition*=/Assert*= settings of systemd.unit(5), without the prefix
+const SYSTEMD_CONDITIONS: &[&str] = &[
+    "Architecture",
+    "Firmware",
+    "Virtualization",
+    "Host",
+    "KernelCommandLine",
+    "KernelVersion",
+    "Credential",
+    "Environment",
+    "Security",
+    "Capability",
+    "ACPower",
+    "NeedsUpdate",
+    "FirstBoot",
+    "PathExists",
+    "PathExistsGlob",
+    "PathIsDirectory",
+    "PathIsSymbolicLink",
+    "PathIsMountPoint",
+    "PathIsReadWrite",
+    "PathIsEncrypted",
+    "DirectoryNotEmpty",
+    "FileNotEmpty",
+    "FileIsExecutable",
+    "User",
+    "Group",
+    "ControlGroupController",
+    "Memory",
+    "CPUs",
+    "CPUFeature",
+    "OSRelease",
+    "MemoryPressure",
+    "CPUPressure",
+    "IOPressure",
+];
+
+/// condition= takes e.g. "ConditionPathExists=/dev/nvidia0", assert= "AssertPathExists=…"
+fn verify_condition(key: &str, prefix: &str, val: &str) -> Result<String> {
+    if val.chars().any(char::is_control) {
+        return Err(anyhow!("{} {:?} contains control characters", key, val));
+    }
+
+    let (name, arg) = val
+        .split_once('=')
+        .ok_or_else(|| anyhow!("{} {:?} is not of the form {}…=value", key, val, prefix))?;
+    let name = name.trim_end();
+    match name.strip_prefix(prefix) {
+        Some(cond) if SYSTEMD_CONDITIONS.contains(&cond) => {}
+        _ => {
+            return Err(anyhow!(
+                "{} {:?}: {} is not a known {}…= setting",
+                key,
+                val,
+                name,
+                prefix
+            ))
+        }
+    }
+    if arg.trim().is_empty() {
+        return Err(anyhow!("{} {:?} has no value", key, val));
+    }
+
+    Ok(format!("{}={}", name, arg.trim_start()))
+}
+
+fn parse_size_expr(
+    dev: &Device,
+    key: &str,
+    value: &str,
+) -> Result<(String, fasteval::ExpressionI, fasteval::Slab)> {
+    let mut sl = fasteval::Slab::new();
+    Ok((
+        value.to_string(),
+        fasteval::Parser::new()
+            .parse_noclear(value, &mut sl.ps)
+            .with_context(|| format!("{} {}", key, dev.name))?,
+        sl,
+    ))
+}
+
+fn parse_compression_algorithm_params(whole: &str) -> (String, String) {
+    if let Some(paren) = whole.find('(') {
+        let (algo, mut params) = whole.split_at(paren);
+        params = &params[1..];
+        if params.ends_with(')') {
+            params = &params[..params.len() - 1];
+        }
+        (algo.to_string(), params.replace(',', " "))
+    } else {
+        (whole.to_string(), String::new())
+    }
+}
+
+fn add_compression_algorithms(algos: &mut Algorithms, value: &str) {
+    for s in value.split_whitespace() {
+        let (algo, params) = parse_compression_algorithm_params(s);
+        if algo.is_empty() {
+            algos.recompression_global = params;
+        } else {
+            algos.compression_algorithms.push((algo, params));
+        }
+    }
+}
+
+/// The keys of device sections, as understood by parse_line(), for --build-info
+pub const DEVICE_KEYS: &[&str] = &[
+    "host-memory-limit",
+    "host-memory-limit-hysteresis",
+    "skip-if-swap-above",
+    "condition-path-exists",
+    "zram-size",
+    "zram-resident-limit",
+    "ram-budget",
+    "expected-ratio",
+    "fs-reserve",
+    "compression-algorithm",
+    "compression-algorithm-add",
+    "recompression",
+    "recompress-now",
+    "recompress-interval",
+    "writeback-device",
+    "writeback-crypttab-name",
+    "writeback-required",
+    "block-size",
+    "swap-page-size",
+    "lazy-makefs",
+    "makefs-mode",
+    "no-size-warning",
+    "hibernate-safe",
+    "swap-nofail",
+    "device-wait",
+    "persist-across-daemon-reload",
+    "swap-priority",
+    "role",
+    "swap-tier",
+    "swappiness",
+    "mount-point",
+    "create-mount-point",
+    "mount-mode",
+    "mount-owner",
+    "mount-group",
+    "wanted-by",
+    "required-by",
+    "after",
+    "before",
+    "fs-type",
+    "options",
+    "setup-timeout",
+    "setup-memory-max",
+    "setup-tasks-max",
+    "slice",
+    "default-dependencies",
+    "exclusive",
+    "cpu-affinity",
+    "exec-start-post",
+    "exec-stop-post",
+    "condition",
+    "assert",
+];
+
+/// The keys outside of sections, see toplevel_line() and read_fragment()
+pub const DIRECTIVE_KEYS: &[&str] = &[
+    "set!<variable>",
+    "preset!<name>",
+    "include",
+    "reserved-memory",
+    "memory-source",
+    "memtotal-rounding",
+    "reserved-indices",
+    "load-module",
+    "module-options",
+    "total-size-limit",
+];
+
+fn parse_line(dev: &mut Device, key: &str, value: &str, warnings: &mut Vec<Warning>) -> Result<()> {
+    match key {
+        "host-memory-limit" | "memory-limit" => {
+            /* memory-limit is for backwards compat. host-memory-limit name is preferred. */
+            dev.host_memory_limit_mb = parse_optional_size(value)?;
+            if key == "memory-limit" {
+                warnings.push(Warning::warn(format!(
+                    "{}: memory-limit= is deprecated, use host-memory-limit = {} instead.",
+                    dev.name, value
+                )));
+            }
+        }
+
+        "host-memory-limit-hysteresis" => {
+            dev.host_memory_limit_hysteresis_mb = parse_optional_size(value)?;
+        }
+
+        "skip-if-swap-above" => {
+            dev.skip_if_swap_above_mb = parse_optional_size(value)?;
+        }
+
+        "condition-path-exists" => {
+            dev.condition_path_exists = match value.strip_prefix('!') {
+                Some(path) => Some((true, verify_mount_point(key, path)?)),
+                None if value.is_empty() => None,
+                None => Some((false, verify_mount_point(key, value)?)),
+            };
+        }
+
+        "zram-size" => {
+            dev.zram_size = Some(parse_size_expr(dev, key, value)?);
+        }
+
+        "zram-resident-limit" => {
+            dev.zram_resident_limit = Some(parse_size_expr(dev, key, value)?);
+        }
+
+        "ram-budget" => {
+            dev.ram_budget = match value {
+                "" => None,
+                _ => Some(parse_size_expr(dev, key, value)?),
+            };
+        }
+
+        "expected-ratio" => {
+            dev.expected_ratio = match value {
+                "" => None,
+                _ => Some(parse_expected_ratio(key, value)?),
+            };
+        }
+
+        "fs-reserve" => {
+            dev.fs_reserve = match value {
+                "" => None,
+                _ => Some(parse_fs_reserve(key, value)?),
+            };
+        }
+
+        "compression-algorithm" => {
+            dev.compression_algorithms = Default::default();
+            add_compression_algorithms(&mut dev.compression_algorithms, value);
+        }
+
+        "compression-algorithm-add" => {
+            add_compression_algorithms(&mut dev.compression_algorithms, value);
+        }
+
+        "recompression" => {
+            dev.recompression = parse_boolean(key, value)?;
+        }
+
+        "recompress-now" => {
+            dev.recompress_now = parse_recompress_now(key, value)?;
+        }
+
+        "recompress-interval" => {
+            dev.recompress_interval = match value {
+                "" => None,
+                "infinity" => return Err(anyhow!("{} can't be infinity", key)),
+                _ => Some(verify_timespan(key, value)?),
+            };
+        }
+
+        "writeback-device" => {
+            dev.writeback_dev = Some(parse_writeback_device(key, value)?);
+        }
+
+        "writeback-crypttab-name" => {
+            dev.writeback_crypttab_name = match value {
+                "" => None,
+                _ => Some(verify_crypttab_name(key, value)?),
+            };
+        }
+
+        "writeback-required" => {
+            dev.writeback_required = parse_boolean(key, value)?;
+        }
+
+        "block-size" => {
+            dev.block_size = Some(parse_block_size(value)?);
+        }
+
+        "swap-page-size" => {
+            dev.swap_page_size = match value {
+                "" => None,
+                _ => Some(parse_swap_page_size(value)?),
+            };
+        }
+
+        "lazy-makefs" => {
+            dev.lazy_makefs = parse_boolean(key, value)?;
+        }
+
+        "no-size-warning" => {
+            dev.no_size_warning = parse_boolean(key, value)?;
+        }
+
+        "hibernate-safe" => {
+            dev.hibernate_safe = parse_boolean(key, value)?;
+        }
+
+        "swap-nofail" => {
+            dev.swap_nofail = parse_boolean(key, value)?;
+        }
+
+        "persist-across-daemon-reload" => {
+            dev.persist_across_daemon_reload = parse_boolean(key, value)?;
+        }
+
+        "device-wait" => {
+            dev.device_wait = match value {
+                "" => DEFAULT_DEVICE_WAIT,
+                _ => parse_device_wait(key, value)?,
+            };
+        }
+
+        "makefs-mode" => {
+            dev.makefs_mode = value
+                .parse()
+                .with_context(|| format!("Failed to parse {} \"{}\"", key, value))?;
+        }
+
+        "swap-priority" => {
+            dev.swap_priority = match value {
+                "" => None,
+                _ => Some(parse_swap_priority(value)?),
+            };
+        }
+
+        "role" => {
+            dev.role = match value {
+                "" => None,
+                _ => Some(value.parse()?),
+            };
+        }
+
+        "swap-tier" => {
+            dev.swap_tier = parse_swap_tier(value)?;
+        }
+
+        "swappiness" => {
+            dev.swappiness = Some(parse_swappiness(value)?);
+        }
+
+        "mount-point" => {
+            dev.mount_point = Some(verify_mount_point(key, value)?);
+        }
+
+        "create-mount-point" => {
+            dev.create_mount_point = parse_boolean(key, value)?;
+        }
+
+        "mount-mode" => {
+            dev.mount_mode = match value {
+                "" => None,
+                _ => Some(parse_mode(key, value)?),
+            };
+        }
+
+        "mount-owner" | "mount-group" => {
+            let field = match key {
+                "mount-owner" => &mut dev.mount_owner,
+                _ => &mut dev.mount_group,
+            };
+            *field = match value {
+                "" => None,
+                _ => Some(value.to_string()),
+            };
+        }
+
+        "wanted-by" | "required-by" | "after" | "before" => {
+            let units = match key {
+                "wanted-by" => &mut dev.wanted_by,
+                "required-by" => &mut dev.required_by,
+                "after" => &mut dev.after,
+                _ => &mut dev.before,
+            };
+            /* Like in systemd, an empty value resets the list. */
+            if value.is_empty() {
+                units.clear();
+            }
+            for unit in value.split_whitespace() {
+                let unit = verify_unit_name(key, unit)?;
+                if !units.contains(&unit) {
+                    units.push(unit);
+                }
+            }
+        }
+
+        "fs-type" => {
+            dev.fs_type = Some(value.to_string());
+        }
+
+        "options" => {
+            dev.options = value.to_string().into();
+        }
+
+        "setup-timeout" => {
+            dev.setup_timeout = Some(verify_timespan(key, value)?);
+        }
+
+        "setup-memory-max" => {
+            dev.setup_memory_max = Some(verify_resource_limit(
+                key,
+                value,
+                &['K', 'M', 'G', 'T', 'P', 'E'],
+            )?);
+        }
+
+        "setup-tasks-max" => {
+            dev.setup_tasks_max = Some(verify_resource_limit(key, value, &[])?);
+        }
+
+        "slice" => {
+            dev.slice = match value {
+                "" => None,
+                _ => Some(verify_slice_name(key, value)?),
+            };
+        }
+
+        "default-dependencies" => {
+            dev.default_dependencies = match value {
+                "" => None,
+                _ => Some(parse_boolean(key, value)?),
+            };
+        }
+
+        "exclusive" => {
+            dev.exclusive = parse_boolean(key, value)?;
+        }
+
+        "cpu-affinity" => {
+            dev.cpu_affinity = Some(verify_cpu_list(key, value)?);
+        }
+
+        "exec-start-post" | "exec-stop-post" => {
+            let commands = match key {
+                "exec-start-post" => &mut dev.exec_start_post,
+                _ => &mut dev.exec_stop_post,
+            };
+            /* Like in systemd, an empty value resets the list. */
+            if value.is_empty() {
+                commands.clear();
+            } else {
+                commands.push(verify_exec_command(key, value)?);
+            }
+        }
+
+        "condition" | "assert" => {
+            let prefix = match key {
+                "condition" => "Condition",
+                _ => "Assert",
+            };
+            /* An empty value resets the conditions (or assertions) set so far. */
+            if value.is_empty() {
+                dev.conditions.retain(|c| !c.starts_with(prefix));
+            } else {
+                dev.conditions.push(verify_condition(key, prefix, value)?);
+            }
+        }
+
+        "zram-fraction" => {
+            /* zram-fraction is for backwards compat. zram-size = is preferred. */
+
+            dev.zram_fraction = Some(
+                value
+                    .parse()
+                    .with_context(|| format!("Failed to parse zram-fraction \"{}\"", value))
+                    .and_then(|f| {
+                        if f >= 0. {
+                            Ok(f)
+                        } else {
+                            Err(anyhow!("{}: zram-fraction={} < 0", dev.name, f))
+                        }
+                    })?,
+            );
+            warn_obsolete_size(dev, key, warnings);
+        }
+
+        "max-zram-size" => {
+            /* zram-fraction is for backwards compat. zram-size = is preferred. */
+
+            dev.max_zram_size_mb = Some(parse_optional_size(value)?);
+            warn_obsolete_size(dev, key, warnings);
+        }
+
+        _ => {
+            warnings.push(Warning::warn(format!(
+                "{}: unknown key {}, ignoring.",
+                dev.name, key
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn warn_obsolete_size(dev: &Device, key: &str, warnings: &mut Vec<Warning>) {
+    warnings.push(Warning::warn(format!(
+        "{}: {}= is deprecated, use zram-size = {} instead.",
+        dev.name,
+        key,
+        dev.obsolete_zram_size()
+    )));
+}
+
+fn _get_meminfo_kb(path: &Path, field: &str) -> Result<Option<u64>> {
+    for line in
+        BufReader::new(fs::File::open(path).with_context(|| {
+            format!("Failed to read memory information from {}", path.display())
+        })?)
+        .lines()
+    {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        if let (Some(f), Some(val)) = (fields.next(), fields.next()) {
+            if f.strip_suffix(':') == Some(field) {
+                return Ok(Some(val.parse()?));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn _get_total_memory_kb(path: &Path) -> Result<u64> {
+    _get_meminfo_kb(path, "MemTotal")?
+        .ok_or_else(|| anyhow!("Couldn't find MemTotal in {}", path.display()))
+}
+
+/// Overrides MemTotal, for systems without /proc/meminfo and for testing
+const MEMTOTAL_ENV_VAR: &str = "ZRAM_GENERATOR_MEMTOTAL_KB";
+
+fn _memtotal_override(val: Option<OsString>) -> Result<Option<u64>> {
+    val.map(|val| {
+        val.to_str()
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(|| anyhow!("{}={:?}: not a number", MEMTOTAL_ENV_VAR, val))
+    })
+    .transpose()
+}
+
+fn get_total_memory_kb(root: &Path) -> Result<u64> {
+    if let Some(kb) = _memtotal_override(std::env::var_os(MEMTOTAL_ENV_VAR))? {
+        return Ok(kb);
+    }
+
+    let path = root.join("proc/meminfo");
+    _get_total_memory_kb(&path)
+}
+
+/// Whether there are AC adapters, and none of them is online.
+/// Without power supply information, assume we're on AC.
+fn on_battery(root: &Path) -> bool {
+    let power_supply = root.join("sys/class/power_supply");
+    let entries = match fs::read_dir(&power_supply) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+
+    let mut mains = false;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match fs::read_to_string(path.join("type")) {
+            Ok(t) if t.trim_end() == "Mains" => mains = true,
+            _ => continue,
+        }
+        if fs::read_to_string(path.join("online")).is_ok_and(|o| o.trim_end() == "1") {
+            return false;
+        }
+    }
+    mains
+}
+
+/// The first field of /proc/uptime, in seconds.
+/// Missing (e.g. /proc not mounted in a chroot) means 0, like right after boot.
+fn get_uptime(root: &Path, warnings: &mut Vec<Warning>) -> f64 {
+    let path = root.join("proc/uptime");
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(_) => return 0.,
+    };
+    match text.split_whitespace().next().map(str::parse::<f64>) {
+        Some(Ok(uptime)) if uptime.is_finite() && uptime >= 0. => uptime,
+        _ => {
+            warnings.push(Warning::warn(format!(
+                "{}: failed to parse {:?}, using 0 for uptime.",
+                path.display(),
+                text.trim_end()
+            )));
+            0.
+        }
+    }
+}
+
+/// The limit in root/sys/fs/cgroup/file (memory.max or memory.high), in MB: that of the root of the
+/// cgroup namespace, e.g. a container, and not of the generator's or setup service's own cgroup,
+/// so that both see the same. "max", or no such file (e.g. cgroup v1, or the host's root), means no limit;
+/// so does an unparseable one, with a warning.
+fn get_cgroup_memory_limit_mb(root: &Path, file: &str, warnings: &mut Vec<Warning>) -> Option<u64> {
+    let path = root.join("sys/fs/cgroup").join(file);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(_) => {
+            warnings.push(Warning::info(format!(
+                "{} not readable, using MemTotal for ram.",
+                path.display()
+            )));
+            return None;
+        }
+    };
+    match text.trim() {
+        "max" => None,
+        val => match val.parse::<u64>() {
+            Ok(bytes) => Some(bytes / 1024 / 1024),
+            Err(_) => {
+                warnings.push(Warning::warn(format!(
+                    "{}: failed to parse {:?}, using MemTotal for ram.",
+                    path.display(),
+                    val
+                )));
+                None
+            }
+        },
+    }
+}
+
+/// A missing field is the same as none of it:
+/// no SwapTotal (e.g. CONFIG_SWAP=n) means no swap, no CmaTotal means no CMA.
+/// Likewise no /proc/meminfo, if MemTotal is overridden.
+fn get_optional_meminfo_kb(root: &Path, field: &str) -> Result<u64> {
+    let path = root.join("proc/meminfo");
+    if std::env::var_os(MEMTOTAL_ENV_VAR).is_some() && !path.exists() {
+        return Ok(0);
+    }
+    Ok(_get_meminfo_kb(&path, field)?.unwrap_or(0))
+}
+
+/// The devices set up since boot, from the manifest systemd-zram-setup@.service keeps
+fn get_previous_devices(root: &Path, warnings: &mut Vec<Warning>) -> BTreeSet<String> {
+    match crate::setup::read_manifest(root) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            warnings.push(Warning::warn(format!(
+                "{:#}, assuming no devices were created before.",
+                e
+            )));
+            BTreeSet::new()
+        }
+    }
+}
+
+/// Swap other than zram devices (e.g. a disk partition) active now, from /proc/swaps.
+/// Without it, all of SwapTotal is taken to be such swap.
+fn get_other_swap_mb(root: &Path, swaptotal_mb: u64, warnings: &mut Vec<Warning>) -> u64 {
+    let path = root.join("proc/swaps");
+    match fs::read_to_string(&path) {
+        Ok(swaps) => other_swap_kb(&swaps) / 1024,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => swaptotal_mb,
+        Err(e) => {
+            warnings.push(Warning::warn(format!(
+                "Failed to read {} ({}), assuming no swap is zram.",
+                path.display(),
+                e
+            )));
+            swaptotal_mb
+        }
+    }
+}
+
+/// The sizes (in KiB) of the /proc/swaps entries which aren't zram devices, added up
+fn other_swap_kb(swaps: &str) -> u64 {
+    swaps
+        .lines()
+        .skip(1) /* Filename Type Size Used Priority */
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let filename = fields.next()?;
+            let size = fields.nth(1)?.parse::<u64>().ok()?;
+            Some((filename, size))
+        })
+        .filter(|(filename, _)| !filename.starts_with("/dev/zram"))
+        .map(|(_, size)| size)
+        .sum()
+}
+
+/// Short names for systemd.zramN.<key>= on the kernel command line
+const KERNEL_KEY_ALIASES: &[(&str, &str)] =
+    &[("size", "zram-size"), ("algo", "compression-algorithm")];
+
+/// systemd.zramN.<key>=<value> words of the kernel command line, as (word, device, key, value), in order
+fn kernel_device_overrides(cmdline: &str) -> Vec<(&str, String, String, String)> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|w| {
+            let (name, setting) = w.strip_prefix("systemd.")?.split_once('.')?;
+            name.strip_prefix("zram")?.parse::<u64>().ok()?;
+            let (key, value) = setting.split_once('=')?;
+            let key = KERNEL_KEY_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == key)
+                .map_or(key, |(_, key)| key);
+            Some((w, name.to_string(), key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Apply systemd.zramN.<key>=<value> from the kernel command line to the configured devices,
+/// for one-off changes without editing the configuration. Problems are only warned about,
+/// so that a typo doesn't prevent booting.
+fn apply_kernel_overrides(
+    root: &Path,
+    devices: &mut HashMap<String, Device>,
+    presets: &BTreeMap<String, String>,
+    warnings: &mut Vec<Warning>,
+) {
+    let path = root.join("proc/cmdline");
+    let cmdline = match fs::read_to_string(&path) {
+        Ok(cmdline) => cmdline,
+        Err(_) => return,
+    };
+
+    for (option, name, key, value) in kernel_device_overrides(&cmdline) {
+        if !DEVICE_KEYS.contains(&&key[..]) {
+            warnings.push(Warning::warn(format!(
+                "{}: unknown key {} in kernel command line option {}, ignoring.",
+                name, key, option
+            )));
+            continue;
+        }
+        let Some(dev) = devices.get_mut(&name) else {
+            warnings.push(Warning::warn(format!(
+                "{}: not configured, ignoring kernel command line option {}.",
+                name, option
+            )));
+            continue;
+        };
+        let parsed = expand_presets(&key, &value, presets)
+            .and_then(|value| parse_line(dev, &key, &value, warnings));
+        match parsed {
+            Ok(()) => note_override(dev, Path::new("/proc/cmdline"), &key, &value, warnings),
+            Err(e) => warnings.push(Warning::warn(format!(
+                "{}: kernel command line option {}: {:#}, ignoring.",
+                name, option, e
+            ))),
+        }
+    }
+}
+
+fn _kernel_has_option(path: &Path, word: &str) -> Result<Option<bool>> {
+    let text = fs::read_to_string(path)?;
+
+    // Last argument wins
+    Ok(text
+        .split_whitespace()
+        .rev()
+        .filter(|w| w.starts_with(word))
+        .flat_map(|w| match &w[word.len()..] {
+            "" | "=1" | "=yes" | "=true" | "=on" => Some(true),
+            "=0" | "=no" | "=false" | "=off" => Some(false),
+            _ => None,
+        })
+        .next())
+}
+
+pub fn kernel_has_option(root: &Path, word: &str) -> Result<Option<bool>> {
+    let path = root.join("proc/cmdline");
+    _kernel_has_option(&path, word)
+}
+
+/// Turns the generator off regardless of the configuration, e.g. while building an image
+const DISABLE_ENV_VAR: &str = "ZRAM_GENERATOR_DISABLE";
+
+fn _disabled_by_env(val: Option<OsString>) -> bool {
+    let val = match val {
+        Some(val) => val,
+        None => return false,
+    };
+    match val.to_str().map(|v| parse_boolean(DISABLE_ENV_VAR, v)) {
+        Some(Ok(disabled)) => disabled,
+        _ => {
+            warn!("Failed to parse ${}={:?}, ignoring.", DISABLE_ENV_VAR, val);
+            false
+        }
+    }
+}
+
+pub fn disabled_by_env() -> bool {
+    _disabled_by_env(std::env::var_os(DISABLE_ENV_VAR))
+}
+
+pub fn kernel_zram_option(root: &Path) -> Option<bool> {
+    match kernel_has_option(root, "systemd.zram") {
+        Ok(r @ Some(true)) | Ok(r @ None) => r,
+        Ok(Some(false)) => {
+            info!("Disabled by systemd.zram option in /proc/cmdline.");
+            Some(false)
+        }
+        Err(e) => {
+            warn!("Failed to parse /proc/cmdline ({}), ignoring.", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(clippy::unused_io_amount)]
+    fn file_with(data: &[u8]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write(data).unwrap();
+        file.flush().unwrap();
+        file
+    }
+
+    #[test]
+    fn test_load_json_fragment() {
+        let file = file_with(
+            br#"{"set!x": "echo 1", "zram1": {"swap-priority": 5, "wanted-by": ["a.target", "b.target"]}, "zram0": {}}"#,
+        );
+        assert_eq!(
+            load_json_fragment(file.path()).unwrap(),
+            [
+                (None, vec![("set!x".into(), "echo 1".into())]),
+                (
+                    Some("zram1".into()),
+                    vec![
+                        ("swap-priority".into(), "5".into()),
+                        ("wanted-by".into(), "a.target".into()),
+                        ("wanted-by".into(), "b.target".into()),
+                    ]
+                ),
+                (Some("zram0".into()), vec![]),
+            ]
+        );
+
+        for e in [
+            &b"[]"[..],
+            b"{\"zram0\": {\"zram-size\": null}}",
+            b"{\"zram0\": {\"wanted-by\": [[\"a.target\"]]}}",
+            b"{\"zram0\": ",
+        ] {
+            let file = file_with(e);
+            assert!(load_json_fragment(file.path()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_get_total_memory_kb() {
+        let file = file_with(
+            b"\
+MemTotal:        8013220 kB
+MemFree:          721288 kB
+MemAvailable:    1740336 kB
+Buffers:          292752 kB
+",
+        );
+        let mem = _get_total_memory_kb(file.path()).unwrap();
+        assert_eq!(mem, 8013220);
+    }
+
+    #[test]
+    fn test_usable_memory() {
+        let mut ctx = EvalContext {
+            cmatotal_mb: 512,
+            ..EvalContext::for_test(4096)
+        };
+        assert_eq!(ctx.usable_memory_mb(), 3584);
+
+        let mut slab = fasteval::Slab::new();
+        toplevel_line(
+            Path::new("test.conf"),
+            "reserved-memory",
+            "1024",
+            &mut slab,
+            &mut ctx,
+            false,
+            &mut vec![],
+        )
+        .unwrap();
+        assert_eq!(ctx.usable_memory_mb(), 2560);
+
+        ctx.reserved_mb = 8192;
+        assert_eq!(ctx.usable_memory_mb(), 0);
+
+        assert!(toplevel_line(
+            Path::new("test.conf"),
+            "reserved-memory",
+            "1G",
+            &mut slab,
+            &mut ctx,
+            false,
+            &mut vec![],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_disabled_by_env() {
+        assert!(!_disabled_by_env(None));
+        assert!(_disabled_by_env(Some("1".into())));
+        assert!(_disabled_by_env(Some("yes".into())));
+        assert!(!_disabled_by_env(Some("0".into())));
+        assert!(!_disabled_by_env(Some("maybe".into())));
+    }
+
+    #[test]
+    fn test_memtotal_override() {
+        assert_eq!(_memtotal_override(None).unwrap(), None);
+        assert_eq!(
+            _memtotal_override(Some("8013220".into())).unwrap(),
+            Some(8013220)
+        );
+        assert_eq!(
+            _memtotal_override(Some(" 1024\n".into())).unwrap(),
+            Some(1024)
+        );
+        for e in ["", "8G", "-1", "1.5"] {
+            assert!(_memtotal_override(Some(e.into())).is_err(), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_get_meminfo_kb_swap() {
+        let file = file_with(
+            b"\
+MemTotal:        8013220 kB
+SwapCached:            0 kB
+SwapTotal:       4194300 kB
+SwapFree:        4194300 kB
+",
+        );
+        assert_eq!(
+            _get_meminfo_kb(file.path(), "SwapTotal").unwrap(),
+            Some(4194300)
+        );
+        assert_eq!(_get_meminfo_kb(file.path(), "Swap").unwrap(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Couldn't find MemTotal")]
+    fn test_get_total_memory_not_found() {
+        let file = file_with(
+            b"\
+MemTotala:        8013220 kB
+aMemTotal:        8013220 kB
+MemTotal::        8013220 kB
+",
+        );
+        _get_total_memory_kb(file.path()).unwrap();
+    }
+
+    fn power_supply(root: &Path, name: &str, typ: &str, online: Option<&str>) {
+        let path = root.join("sys/class/power_supply").join(name);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("type"), format!("{}\n", typ)).unwrap();
+        if let Some(online) = online {
+            fs::write(path.join("online"), format!("{}\n", online)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_on_battery() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(!on_battery(root.path()));
+
+        power_supply(root.path(), "BAT0", "Battery", None);
+        power_supply(root.path(), "hidpp_battery_0", "Battery", Some("1"));
+        assert!(!on_battery(root.path()));
+
+        power_supply(root.path(), "AC", "Mains", Some("0"));
+        assert!(on_battery(root.path()));
+
+        power_supply(root.path(), "ADP1", "Mains", Some("1"));
+        assert!(!on_battery(root.path()));
+    }
+
+    #[test]
+    fn test_get_uptime() {
+        let root = tempfile::tempdir().unwrap();
+        let mut warnings = vec![];
+        assert_eq!(get_uptime(root.path(), &mut warnings), 0.);
+        assert!(warnings.is_empty());
+
+        fs::create_dir(root.path().join("proc")).unwrap();
+        fs::write(root.path().join("proc/uptime"), "4.52 14.31\n").unwrap();
+        assert_eq!(get_uptime(root.path(), &mut warnings), 4.52);
+        assert!(warnings.is_empty());
+
+        for bad in ["", "-1 0\n", "inf 0\n", "x y\n"] {
+            fs::write(root.path().join("proc/uptime"), bad).unwrap();
+            assert_eq!(get_uptime(root.path(), &mut warnings), 0., "{:?}", bad);
+            assert_eq!(warnings.pop().unwrap().level, Level::Warn);
+        }
+    }
+
+    #[test]
+    fn test_cgroup_memory_limit() {
+        let root = tempfile::tempdir().unwrap();
+        let mut warnings = vec![];
+        assert_eq!(
+            get_cgroup_memory_limit_mb(root.path(), "memory.high", &mut warnings),
+            None
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].level, Level::Info);
+
+        let cgroup = root.path().join("sys/fs/cgroup");
+        fs::create_dir_all(&cgroup).unwrap();
+        let mut warnings = vec![];
+        for (val, exp) in [("max\n", None), ("536870912\n", Some(512))] {
+            fs::write(cgroup.join("memory.high"), val).unwrap();
+            assert_eq!(
+                get_cgroup_memory_limit_mb(root.path(), "memory.high", &mut warnings),
+                exp
+            );
+        }
+        assert!(warnings.is_empty());
+
+        fs::write(cgroup.join("memory.max"), "lots\n").unwrap();
+        assert_eq!(
+            get_cgroup_memory_limit_mb(root.path(), "memory.max", &mut warnings),
+            None
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].level, Level::Warn);
+    }
+
+    #[test]
+    fn test_memory_source() {
+        assert_eq!(
+            "meminfo".parse::<MemorySource>().unwrap(),
+            MemorySource::Meminfo
+        );
+        assert_eq!(
+            "cgroup-high".parse::<MemorySource>().unwrap().cgroup_file(),
+            Some("memory.high")
+        );
+        assert_eq!(
+            "cgroup-max".parse::<MemorySource>().unwrap().cgroup_file(),
+            Some("memory.max")
+        );
+        assert!("cgroup".parse::<MemorySource>().is_err());
+    }
+
+    #[test]
+    fn test_kernel_has_option() {
+        let file = file_with(b"foo=1 foo=0 foo=on foo=off foo\n");
+        assert_eq!(_kernel_has_option(file.path(), "foo").unwrap(), Some(true));
+    }
+
+    #[test]
+    fn test_kernel_has_no_option() {
+        let file = file_with(
+            b"\
+foo=1
+foo=0
+",
+        );
+        assert_eq!(_kernel_has_option(file.path(), "foo").unwrap(), Some(false));
+    }
+
+    #[test]
+    fn test_presets() {
+        let read = |conf: &str| {
+            let root = include_root(&[("etc/systemd/zram-generator.conf", conf)]);
+            read_devices_from(root.path(), false, &mut vec![])
+        };
+
+        let devices = read(
+            "preset!fast = lz4(level=1)\n\
+             preset!small = zstd(level=19)\n\
+             [zram0]\ncompression-algorithm = @fast @small (type=idle)\n\
+             [zram1]\ncompression-algorithm = @small\ncompression-algorithm-add = lzo-rle\n",
+        )
+        .unwrap();
+        assert_eq!(
+            devices["zram0"].compression_algorithms,
+            Algorithms {
+                compression_algorithms: vec![
+                    ("lz4".into(), "level=1".into()),
+                    ("zstd".into(), "level=19".into()),
+                ],
+                recompression_global: "type=idle".into(),
+            }
+        );
+        assert_eq!(
+            devices["zram1"]
+                .compression_algorithms
+                .compression_algorithms,
+            [
+                ("zstd".into(), "level=19".into()),
+                ("lzo-rle".into(), "".into())
+            ]
+        );
+
+        /* Undefined */
+        let err = read("[zram0]\ncompression-algorithm = @fast\n")
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", err).ends_with("compression-algorithm: undefined preset @fast"),
+            "{:#}",
+            err
+        );
+        /* Not expanded elsewhere */
+        assert!(read("preset!fast = lz4\n[zram0]\nmount-point = /@fast\n").is_ok());
+
+        for e in ["", "lz4 zstd", "(level=1)", "@other"] {
+            assert!(verify_preset(e).is_err(), "{:?}", e);
+        }
+        assert_eq!(verify_preset(" lz4(level=1) ").unwrap(), "lz4(level=1)");
+    }
+
+    #[test]
+    fn test_kernel_device_overrides() {
+        assert_eq!(
+            kernel_device_overrides(
+                "BOOT_IMAGE=/vmlinuz systemd.zram systemd.zram=0 systemd.zram0.size=2048 \
+                 systemd.zram1.algo=zstd(level=3) systemd.zram10.swap-priority=5 \
+                 systemd.zramfoo.size=1 systemd.zram0.size systemd.zram0 systemd.unit=rescue.target quiet"
+            ),
+            [
+                ("systemd.zram0.size=2048", "zram0", "zram-size", "2048"),
+                ("systemd.zram1.algo=zstd(level=3)", "zram1", "compression-algorithm", "zstd(level=3)"),
+                ("systemd.zram10.swap-priority=5", "zram10", "swap-priority", "5"),
+            ]
+            .map(|(w, d, k, v)| (w, d.to_string(), k.to_string(), v.to_string()))
+        );
+        assert!(kernel_device_overrides("").is_empty());
+    }
+
+    #[test]
+    fn test_apply_kernel_overrides() {
+        let root = include_root(&[
+            (
+                "etc/systemd/zram-generator.conf",
+                "[zram0]\nzram-size = 1024\ncompression-algorithm = lzo-rle\n",
+            ),
+            (
+                "proc/cmdline",
+                "systemd.zram0.size=2048 systemd.zram0.algo=zstd systemd.zram1.size=1 systemd.zram0.nope=1\n",
+            ),
+        ]);
+        let mut warnings = vec![];
+        let devices = read_devices_from(root.path(), false, &mut warnings).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices["zram0"].disksize, 2048 << 20);
+        assert_eq!(
+            devices["zram0"]
+                .compression_algorithms
+                .compression_algorithms,
+            [("zstd".to_string(), String::new())]
+        );
+        let warnings: Vec<_> = warnings.iter().map(Warning::to_string).collect();
+        assert!(
+            warnings[0].ends_with("overridden by zram-size=2048 from /proc/cmdline"),
+            "{:?}",
+            warnings
+        );
+        assert!(
+            warnings.contains(
+                &"zram1: not configured, ignoring kernel command line option systemd.zram1.size=1."
+                    .to_string()
+            ),
+            "{:?}",
+            warnings
+        );
+        assert!(warnings.contains(
+            &"zram0: unknown key nope in kernel command line option systemd.zram0.nope=1, ignoring.".to_string()
+        ), "{:?}", warnings);
+    }
+
+    #[test]
+    fn test_verify_writeback_device() {
+        for e in ["/dev/zram1", "/dev/./zram0", "//dev/zram10"] {
+            assert!(verify_writeback_device("test", e).is_err(), "{:?}", e);
+        }
+        for p in [
+            "/dev/sda2",
+            "/dev/zram",
+            "/dev/zramfoo",
+            "/dev/mapper/zram1",
+        ] {
+            assert_eq!(verify_writeback_device("test", p).unwrap(), Path::new(p));
+        }
+    }
+
+    #[test]
+    fn test_parse_writeback_device() {
+        assert_eq!(
+            parse_writeback_device("test", "PARTUUID=9F3E6A2B-1C4D-4E5F-8A9B-0C1D2E3F4A5B")
+                .unwrap(),
+            Path::new("/dev/disk/by-partuuid/9f3e6a2b-1c4d-4e5f-8a9b-0c1d2e3f4a5b")
+        );
+        let path = parse_writeback_device("test", "PARTTYPE=0657fd6d-a4ab-43c4-84e5-0933c84b4f4f")
+            .unwrap();
+        assert_eq!(
+            path,
+            Path::new("/dev/disk/by-parttypeuuid/0657fd6d-a4ab-43c4-84e5-0933c84b4f4f")
+        );
+        assert!(is_partition_type_path(&path));
+        assert_eq!(
+            parse_writeback_device("test", "/dev/sda2").unwrap(),
+            Path::new("/dev/sda2")
+        );
+        assert!(!is_partition_type_path(Path::new("/dev/sda2")));
+        assert!(!is_partition_type_path(Path::new(
+            "/dev/disk/by-parttypeuuid/0657fd6d-a4ab-43c4-84e5-0933c84b4f4f.9f3e6a2b-1c4d-4e5f-8a9b-0c1d2e3f4a5b"
+        )));
+
+        for e in [
+            "PARTUUID=",
+            "PARTUUID=2d54ffa0-01",
+            "PARTUUID=9f3e6a2b-1c4d-4e5f-8a9b-0c1d2e3f4a5",
+            "PARTUUID=9f3e6a2b-1c4d-4e5f-8a9b-0c1d2e3f4a5g",
+            "PARTTYPE=9f3e6a2b_1c4d-4e5f-8a9b-0c1d2e3f4a5b",
+            "PARTTYPE=../../../zram0",
+        ] {
+            assert!(parse_writeback_device("test", e).is_err(), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_verify_writeback_device_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let by_id = dir.path().join("disk/by-id");
+        fs::create_dir_all(&by_id).unwrap();
+
+        /* Dangling, like during early boot */
+        std::os::unix::fs::symlink("/dev/zram1", by_id.join("absolute")).unwrap();
+        std::os::unix::fs::symlink("by-id/absolute", dir.path().join("disk/relative")).unwrap();
+        std::os::unix::fs::symlink("../../disk/relative", by_id.join("chain")).unwrap();
+        std::os::unix::fs::symlink("/dev/sda1", by_id.join("other")).unwrap();
+
+        for name in ["absolute", "chain"] {
+            let err = verify_writeback_device("test", by_id.join(name).to_str().unwrap())
+                .unwrap_err()
+                .to_string();
+            assert!(err.ends_with("is a zram device (/dev/zram1)"), "{}", err);
+        }
+        assert!(verify_writeback_device("test", by_id.join("other").to_str().unwrap()).is_ok());
+        assert_eq!(
+            resolve_symlinks(&by_id.join("other")),
+            Path::new("/dev/sda1")
+        );
+    }
+
+    #[test]
+    fn test_verify_mount_point() {
+        for e in [
+            "foo/bar",
+            "/foo/../bar",
+            "/foo/..",
+            "",
+            "/foo\0bar",
+            "/foo\nbar",
+            "/foo/bar\n",
+            "/foo\tbar",
+        ] {
+            assert!(verify_mount_point("test", e).is_err(), "{}", e);
+        }
+
+        for (p, o) in [
+            ("/foobar", "/foobar"),
+            ("/", "/"),
+            ("//", "/"),
+            ("///", "/"),
+            ("/foo/./bar/", "/foo/bar"),
+        ] {
+            assert_eq!(
+                verify_mount_point("test", p).unwrap(),
+                Path::new(o),
+                "{} vs {}",
+                p,
+                o
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_boolean() {
+        for t in ["1", "yes", "y", "true", "t", "on", "Yes", "ON"] {
+            assert!(parse_boolean("test", t).unwrap(), "{}", t);
+        }
+        for f in ["0", "no", "n", "false", "f", "off"] {
+            assert!(!parse_boolean("test", f).unwrap(), "{}", f);
+        }
+        for e in ["", "2", "maybe", "o"] {
+            assert!(parse_boolean("test", e).is_err(), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_device_keys() {
+        for key in DEVICE_KEYS {
+            let mut dev = Device::new("zram0".to_string());
+            let mut warnings = vec![];
+            /* The value may well be invalid, but the key must be known. */
+            let _ = parse_line(&mut dev, key, "", &mut warnings);
+            assert!(warnings.is_empty(), "{}: {:?}", key, warnings);
+        }
+
+        /* And anything else isn't. */
+        for key in ["zram-sizes", "compression_algorithm", "ram-size", "swap"] {
+            assert!(!DEVICE_KEYS.contains(&key));
+            let mut dev = Device::new("zram0".to_string());
+            let mut warnings = vec![];
+            parse_line(&mut dev, key, "1", &mut warnings).unwrap();
+            assert_eq!(
+                warnings,
+                [Warning::warn(format!(
+                    "zram0: unknown key {}, ignoring.",
+                    key
+                ))]
+            );
+        }
+    }
+
+    #[test]
+    fn test_other_swap_kb() {
+        let swaps = "\
+Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/sda2                               partition\t2097148\t\t0\t\t-2
+/dev/zram0                              partition\t8388604\t\t1024\t\t100
+/var/swap\\040file                       file\t\t1048572\t\t0\t\t-3
+";
+        assert_eq!(other_swap_kb(swaps), 2097148 + 1048572);
+        assert_eq!(other_swap_kb("Filename Type Size Used Priority\n"), 0);
+        assert_eq!(other_swap_kb(""), 0);
+    }
+
+    #[test]
+    fn test_host_memory_limit_hysteresis() {
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "host-memory-limit", "2048", &mut vec![]).unwrap();
+        parse_line(&mut dev, "host-memory-limit-hysteresis", "64", &mut vec![]).unwrap();
+
+        /* Below the band, and above it: as without hysteresis */
+        for previously_created in [false, true] {
+            assert!(dev.is_enabled(2048, 0, previously_created, &mut vec![]));
+            assert!(!dev.is_enabled(2113, 0, previously_created, &mut vec![]));
+        }
+        /* Within the band, the previous decision sticks */
+        for memtotal_mb in [2049, 2080, 2112] {
+            assert!(dev.is_enabled(memtotal_mb, 0, true, &mut vec![]));
+            assert!(!dev.is_enabled(memtotal_mb, 0, false, &mut vec![]));
+        }
+
+        let mut warnings = vec![];
+        assert!(!dev.is_enabled(2113, 0, true, &mut warnings));
+        assert_eq!(
+            warnings,
+            [Warning::info(
+                "zram0: system has too much memory (2113MB), limit is 2112MB, ignoring."
+                    .to_string()
+            )]
+        );
+
+        parse_line(
+            &mut dev,
+            "host-memory-limit-hysteresis",
+            "none",
+            &mut vec![],
+        )
+        .unwrap();
+        assert!(!dev.is_enabled(2049, 0, true, &mut vec![]));
+    }
+
+    #[test]
+    fn test_host_memory_limit_hysteresis_manifest() {
+        let explanation = |entry: Option<&str>| {
+            let mut files = vec![
+                ("proc/meminfo", "MemTotal: 2129920 kB\n"), // 2080MB
+                (
+                    "etc/systemd/zram-generator.conf",
+                    "[zram0]\nhost-memory-limit = 2048\nhost-memory-limit-hysteresis = 64\n",
+                ),
+            ];
+            if let Some(path) = entry {
+                files.push((path, "1048576 swap\n"));
+            }
+            let root = include_root(&files);
+            let devices = explain_devices(root.path(), false).unwrap();
+            devices[0].explanation.clone()
+        };
+
+        /* At boot, there's no earlier decision */
+        let e = explanation(None);
+        assert_eq!(e[1], "host-memory-limit: 2048MB, exceeded");
+        assert_eq!(e.last().unwrap(), "not created: host-memory-limit exceeded");
+
+        let e = explanation(Some("run/zram-generator/devices/zram0"));
+        assert_eq!(
+            e[1],
+            "host-memory-limit: 2048MB + 64MB hysteresis (created before), not exceeded"
+        );
+        assert!(e.last().unwrap().starts_with("created"), "{:?}", e);
+
+        let e = explanation(Some("run/zram-generator/devices/zram1"));
+        assert_eq!(e.last().unwrap(), "not created: host-memory-limit exceeded");
+    }
+
+    #[test]
+    fn test_deprecated_keys() {
+        let mut dev = Device::new("zram0".to_string());
+        let mut warnings = vec![];
+        parse_line(&mut dev, "memory-limit", "2048", &mut warnings).unwrap();
+        assert_eq!(dev.host_memory_limit_mb, Some(2048));
+        parse_line(&mut dev, "zram-fraction", "0.25", &mut warnings).unwrap();
+        parse_line(&mut dev, "max-zram-size", "1024", &mut warnings).unwrap();
+        assert_eq!(
+            warnings,
+            [
+                Warning::warn(
+                    "zram0: memory-limit= is deprecated, use host-memory-limit = 2048 instead."
+                        .to_string()
+                ),
+                Warning::warn(
+                    "zram0: zram-fraction= is deprecated, use zram-size = ram * 0.25 instead."
+                        .to_string()
+                ),
+                Warning::warn(
+                    "zram0: max-zram-size= is deprecated, use zram-size = min(ram * 0.25, 1024) instead."
+                        .to_string()
+                ),
+            ]
+        );
+
+        let mut dev = Device::new("zram1".to_string());
+        let mut warnings = vec![];
+        parse_line(&mut dev, "max-zram-size", "none", &mut warnings).unwrap();
+        assert_eq!(
+            warnings,
+            [Warning::warn(
+                "zram1: max-zram-size= is deprecated, use zram-size = ram * 0.5 instead."
+                    .to_string()
+            )]
+        );
+
+        /* The preferred names are quiet */
+        let mut warnings = vec![];
+        parse_line(&mut dev, "host-memory-limit", "2048", &mut warnings).unwrap();
+        parse_line(&mut dev, "zram-size", "ram / 4", &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_skip_if_swap_above() {
+        let mut dev = Device::new("zram0".to_string());
+        assert!(dev.is_enabled(1024, 100_000, false, &mut vec![]));
+
+        parse_line(&mut dev, "skip-if-swap-above", "1G", &mut vec![]).unwrap();
+        assert_eq!(dev.skip_if_swap_above_mb, Some(1024));
+        assert!(dev.is_enabled(1024, 1024, false, &mut vec![]));
+
+        let mut warnings = vec![];
+        assert!(!dev.is_enabled(1024, 2047, false, &mut warnings));
+        assert_eq!(
+            warnings,
+            [Warning::info(
+                "zram0: system already has 2047MB of swap other than zram, limit is 1024MB, ignoring."
+                    .to_string()
+            )]
+        );
+
+        parse_line(&mut dev, "skip-if-swap-above", "none", &mut vec![]).unwrap();
+        assert!(dev.is_enabled(1024, 2047, false, &mut vec![]));
+    }
+
+    #[test]
+    fn test_condition_path_exists() {
+        let root = include_root(&[
+            ("dev/dri/renderD128", ""),
+            (
+                "etc/systemd/zram-generator.conf",
+                "[zram0]\ncondition-path-exists = /dev/dri/renderD128\n\
+                 [zram1]\ncondition-path-exists = /dev/nvidia0\n\
+                 [zram2]\ncondition-path-exists = !/dev/dri/renderD128\n\
+                 [zram3]\ncondition-path-exists = !/dev/nvidia0\n\
+                 [zram4]\ncondition-path-exists = /dev/nvidia0\ncondition-path-exists =\n",
+            ),
+        ]);
+        let mut warnings = vec![];
+        let devices = read_devices_from(root.path(), false, &mut warnings).unwrap();
+        let enabled = |name: &str| devices[name].disksize > 0;
+        assert!(enabled("zram0"));
+        assert!(!enabled("zram1"));
+        assert!(!enabled("zram2"));
+        assert!(enabled("zram3"));
+        /* Reset by the empty value */
+        assert!(enabled("zram4"));
+        assert!(warnings.contains(&Warning::info(
+            "zram1: /dev/nvidia0 doesn't exist, ignoring.".to_string()
+        )));
+        assert!(warnings.contains(&Warning::info(
+            "zram2: /dev/dri/renderD128 exists, ignoring.".to_string()
+        )));
+        assert_eq!(
+            devices["zram1"].explanation,
+            [
+                "condition-path-exists: /dev/nvidia0, absent",
+                "not created: condition-path-exists not met"
+            ]
+        );
+        assert_eq!(
+            devices["zram3"].explanation[0],
+            "condition-path-exists: !/dev/nvidia0, absent"
+        );
+
+        let mut dev = Device::new("zram0".to_string());
+        assert!(parse_line(&mut dev, "condition-path-exists", "dev/dri", &mut vec![]).is_err());
+        assert!(parse_line(
+            &mut dev,
+            "condition-path-exists",
+            "!/dev/../etc",
+            &mut vec![]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_verify_condition() {
+        assert_eq!(
+            verify_condition("condition", "Condition", "ConditionPathExists=/dev/nvidia0").unwrap(),
+            "ConditionPathExists=/dev/nvidia0"
+        );
+        assert_eq!(
+            verify_condition(
+                "condition",
+                "Condition",
+                "ConditionKernelCommandLine = !nozram"
+            )
+            .unwrap(),
+            "ConditionKernelCommandLine=!nozram"
+        );
+        assert_eq!(
+            verify_condition("assert", "Assert", "AssertArchitecture=x86-64").unwrap(),
+            "AssertArchitecture=x86-64"
+        );
+        for v in [
+            "PathExists=/dev/nvidia0",
+            "AssertPathExists=/dev/nvidia0",
+            "ConditionPathExist=/dev/nvidia0",
+            "ConditionPathExists",
+            "ConditionPathExists=",
+            "ConditionPathExists=/a\nb",
+        ] {
+            assert!(
+                verify_condition("condition", "Condition", v).is_err(),
+                "{}",
+                v
+            );
+        }
+        assert!(verify_condition("assert", "Assert", "ConditionPathExists=/x").is_err());
+
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "condition", "ConditionPathExists=/a", &mut vec![]).unwrap();
+        parse_line(&mut dev, "assert", "AssertPathExists=/b", &mut vec![]).unwrap();
+        parse_line(&mut dev, "condition", "ConditionACPower=true", &mut vec![]).unwrap();
+        assert_eq!(
+            dev.conditions,
+            [
+                "ConditionPathExists=/a",
+                "AssertPathExists=/b",
+                "ConditionACPower=true"
+            ]
+        );
+        /* Only resets its own kind */
+        parse_line(&mut dev, "condition", "", &mut vec![]).unwrap();
+        assert_eq!(dev.conditions, ["AssertPathExists=/b"]);
+    }
+
+    #[test]
+    fn test_verify_crypttab_name() {
+        for v in ["wb", "luks-2d54ffa0", "zram-wb.1"] {
+            assert_eq!(
+                verify_crypttab_name("writeback-crypttab-name", v).unwrap(),
+                v
+            );
+        }
+        for e in ["mapper/wb", "w b", "wb\n", ".", ".."] {
+            assert!(
+                verify_crypttab_name("writeback-crypttab-name", e).is_err(),
+                "{:?}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_recompress_now() {
+        for (v, passes) in [
+            ("yes", 1),
+            ("off", 0),
+            ("1", 1),
+            ("0", 0),
+            ("3", 3),
+            ("16", 16),
+        ] {
+            assert_eq!(
+                parse_recompress_now("recompress-now", v).unwrap(),
+                passes,
+                "{}",
+                v
+            );
+        }
+        for e in ["", "17", "-1", "2.5", "maybe"] {
+            assert!(
+                parse_recompress_now("recompress-now", e).is_err(),
+                "{:?}",
+                e
+            );
+        }
+
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "recompress-now", "2", &mut vec![]).unwrap();
+        assert!(dev.to_string().ends_with(" recompress-now=2"));
+    }
+
+    #[test]
+    fn test_parse_swap_page_size() {
+        for e in ["4k", "-4096", "0", "512", "2048", "4097", "131072"] {
+            assert!(parse_swap_page_size(e).is_err(), "{:?}", e);
+        }
+        for (p, o) in [("4096", 4096), ("16384", 16384), ("65536", 65536)] {
+            assert_eq!(parse_swap_page_size(p).unwrap(), o);
+        }
+
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "swap-page-size", "16384", &mut vec![]).unwrap();
+        assert!(dev.to_string().ends_with(" swap-page-size=16384"));
+        parse_line(&mut dev, "swap-page-size", "", &mut vec![]).unwrap();
+        assert_eq!(dev.swap_page_size, None);
+    }
+
+    #[test]
+    fn test_parse_block_size() {
+        for e in ["", "4k", "-4096", "0", "256", "1000", "4097", "131072"] {
+            assert!(parse_block_size(e).is_err(), "{:?}", e);
+        }
+        for (p, o) in [("512", 512), ("4096", 4096), ("65536", 65536)] {
+            assert_eq!(parse_block_size(p).unwrap(), o);
+        }
+
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "block-size", "4096", &mut vec![]).unwrap();
+        assert!(dev.to_string().ends_with(" block-size=4096"));
+    }
+
+    #[test]
+    fn test_effective_swap_priority() {
+        let mut dev = Device::new("zram0".to_string());
+        for (prio, tier, effective) in [
+            ("100", "normal", 100),
+            ("100", "high", 150),
+            ("100", "low", 50),
+            ("32767", "high", 32767),
+            ("10", "low", -1),
+        ] {
+            parse_line(&mut dev, "swap-priority", prio, &mut vec![]).unwrap();
+            parse_line(&mut dev, "swap-tier", tier, &mut vec![]).unwrap();
+            assert_eq!(
+                dev.effective_swap_priority(),
+                effective,
+                "{} {}",
+                prio,
+                tier
+            );
+        }
+        assert!(parse_line(&mut dev, "swap-tier", "medium", &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_device_wait() {
+        let mut dev = Device::new("zram0".to_string());
+        assert_eq!(dev.device_wait, DEFAULT_DEVICE_WAIT);
+        assert!(!dev.to_string().contains("device-wait"));
+
+        parse_line(&mut dev, "device-wait", "2.5", &mut vec![]).unwrap();
+        assert_eq!(dev.device_wait, Duration::from_millis(2500));
+        assert!(dev.to_string().ends_with(" device-wait=2.5"));
+        parse_line(&mut dev, "device-wait", "0", &mut vec![]).unwrap();
+        assert_eq!(dev.device_wait, Duration::ZERO);
+        parse_line(&mut dev, "device-wait", "", &mut vec![]).unwrap();
+        assert_eq!(dev.device_wait, DEFAULT_DEVICE_WAIT);
+
+        for e in ["-1", "301", "1s", "NaN", "inf"] {
+            assert!(
+                parse_line(&mut dev, "device-wait", e, &mut vec![]).is_err(),
+                "{}",
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn test_persist_across_daemon_reload() {
+        let mut dev = Device::new("zram0".to_string());
+        assert!(dev.persist_across_daemon_reload);
+        assert!(!dev.to_string().contains("persist-across-daemon-reload"));
+
+        parse_line(&mut dev, "persist-across-daemon-reload", "no", &mut vec![]).unwrap();
+        assert!(!dev.persist_across_daemon_reload);
+        assert!(dev
+            .to_string()
+            .ends_with(" persist-across-daemon-reload=no"));
+        parse_line(&mut dev, "persist-across-daemon-reload", "yes", &mut vec![]).unwrap();
+        assert!(dev.persist_across_daemon_reload);
+        assert!(parse_line(
+            &mut dev,
+            "persist-across-daemon-reload",
+            "maybe",
+            &mut vec![]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_swap_role() {
+        let mut dev = Device::new("zram0".to_string());
+        assert_eq!(dev.effective_swap_priority(), 100);
+        for (role, tier, effective) in [
+            ("primary", "normal", 32767),
+            ("primary", "low", 32717),
+            ("overflow", "normal", 0),
+            ("overflow", "high", 50),
+            ("overflow", "low", -1),
+        ] {
+            parse_line(&mut dev, "role", role, &mut vec![]).unwrap();
+            parse_line(&mut dev, "swap-tier", tier, &mut vec![]).unwrap();
+            assert_eq!(
+                dev.effective_swap_priority(),
+                effective,
+                "{} {}",
+                role,
+                tier
+            );
+        }
+        assert!(parse_line(&mut dev, "role", "secondary", &mut vec![]).is_err());
+
+        /* An explicit swap-priority= wins, in either order */
+        parse_line(&mut dev, "swap-tier", "normal", &mut vec![]).unwrap();
+        parse_line(&mut dev, "swap-priority", "20", &mut vec![]).unwrap();
+        assert_eq!(dev.effective_swap_priority(), 20);
+        parse_line(&mut dev, "role", "primary", &mut vec![]).unwrap();
+        assert_eq!(dev.effective_swap_priority(), 20);
+        /* Until a drop-in resets it */
+        parse_line(&mut dev, "swap-priority", "", &mut vec![]).unwrap();
+        assert_eq!(dev.effective_swap_priority(), 32767);
+        parse_line(&mut dev, "role", "", &mut vec![]).unwrap();
+        assert_eq!(dev.effective_swap_priority(), 100);
+
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "[zram0]\nrole = primary\n\
+             [zram1]\nrole = overflow\n\
+             [zram2]\nrole = overflow\nmount-point = /var/tmp\n",
+        )]);
+        let mut warnings = vec![];
+        let devices = read_devices_from(root.path(), false, &mut warnings).unwrap();
+        assert_eq!(devices["zram0"].effective_swap_priority(), 32767);
+        assert_eq!(devices["zram1"].effective_swap_priority(), 0);
+        assert_eq!(devices["zram2"].role, None);
+        assert_eq!(
+            warnings.iter().map(Warning::to_string).collect::<Vec<_>>(),
+            ["zram2: role= set on a device which isn't swap, ignoring."]
+        );
+    }
+
+    #[test]
+    fn test_parse_swappiness() {
+        for e in ["", "-1", "201", "256", "lots"] {
+            assert!(parse_swappiness(e).is_err(), "{:?}", e);
+        }
+        for (p, o) in [("0", 0), ("60", 60), ("200", 200)] {
+            assert_eq!(parse_swappiness(p).unwrap(), o);
+        }
+    }
+
+    #[test]
+    fn test_verify_timespan() {
+        for e in ["", " ", "foo", "5 parsecs", "1.2.3s", "s", "-5s"] {
+            assert!(verify_timespan("test", e).is_err(), "{:?}", e);
+        }
+
+        for p in [
+            "infinity", "90", "90s", "5min", "1h 30min", "2min30s", "1.5h", " 10 s",
+        ] {
+            assert_eq!(verify_timespan("test", p).unwrap(), p);
+        }
+    }
+
+    #[test]
+    fn test_parse_optional_size() {
+        for (val, mb) in [
+            ("8G", Some(8192)),
+            ("8192", Some(8192)),
+            ("8192M", Some(8192)),
+            ("8388608K", Some(8192)),
+            ("1.5g", Some(1536)),
+            ("1T", Some(1024 * 1024)),
+            ("0", Some(0)),
+            ("none", None),
+        ] {
+            assert_eq!(parse_optional_size(val).unwrap(), mb, "{}", val);
+        }
+        for e in ["", "G", "-1G", "8GB", "8 G B", "inf", "NaN", "ram"] {
+            assert!(parse_optional_size(e).is_err(), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_algorithms_with_priorities() {
+        let prios = |value| {
+            let mut algos = Algorithms::default();
+            add_compression_algorithms(&mut algos, value);
+            algos.with_priorities().map(|v| {
+                v.into_iter()
+                    .map(|(p, a, params)| format!("{}:{}:{}", p, a, params))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        /* Positional */
+        assert_eq!(
+            prios("lzo-rle zstd(level=3) lz4hc").unwrap(),
+            ["0:lzo-rle:", "1:zstd:level=3", "2:lz4hc:"]
+        );
+        /* Explicit */
+        assert_eq!(
+            prios("lzo-rle zstd(level=3,priority=2) lz4hc(priority=1)").unwrap(),
+            ["0:lzo-rle:", "2:zstd:level=3", "1:lz4hc:"]
+        );
+        /* Mixed: the others keep their position */
+        assert_eq!(
+            prios("lzo-rle zstd(priority=3) lz4hc").unwrap(),
+            ["0:lzo-rle:", "3:zstd:", "2:lz4hc:"]
+        );
+        assert_eq!(
+            prios("lzo-rle zstd lz4hc(priority=3)").unwrap(),
+            ["0:lzo-rle:", "1:zstd:", "3:lz4hc:"]
+        );
+        assert_eq!(prios("auto zstd").unwrap(), ["0:auto:", "1:zstd:"]);
+
+        for e in [
+            "lzo-rle(priority=1) zstd",
+            "lzo-rle zstd(priority=0)",
+            "lzo-rle zstd(priority=4)",
+            "lzo-rle zstd(priority=x)",
+            "lzo-rle zstd(priority=2) lz4hc",
+            "lzo-rle zstd(priority=1) lz4hc(priority=1)",
+            "auto(level=3)",
+            "lzo-rle auto",
+        ] {
+            assert!(prios(e).is_err(), "{}", e);
+        }
+    }
+
+    #[test]
+    fn test_algorithms_display() {
+        let mut algos = Algorithms::default();
+        assert_eq!(algos.to_string(), "<default>");
+
+        add_compression_algorithms(&mut algos, "zstd(level=3) lz4hc (type=idle,threshold=1024)");
+        assert_eq!(
+            algos.to_string(),
+            "zstd (level=3) then lz4hc; global recompress (type=idle threshold=1024)"
+        );
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("mount-mode", "755").unwrap(), 0o755);
+        assert_eq!(parse_mode("mount-mode", "01777").unwrap(), 0o1777);
+        for e in ["", "rwx", "8", "0x755", "+755", "-755", "17777"] {
+            assert!(parse_mode("mount-mode", e).is_err(), "{:?}", e);
+        }
+    }
+
+    #[test]
+    fn test_resolve_mount_ids() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("etc")).unwrap();
+        fs::write(
+            root.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\nbuilder:x:1001:1001::/home/builder:/bin/sh\n",
+        )
+        .unwrap();
+        fs::write(
+            root.path().join("etc/group"),
+            "root:x:0:\nbuild\nscratch:x:990:\n",
+        )
+        .unwrap();
+
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "mount-point", "/var/tmp", &mut vec![]).unwrap();
+        parse_line(&mut dev, "mount-owner", "builder", &mut vec![]).unwrap();
+        parse_line(&mut dev, "mount-group", "scratch", &mut vec![]).unwrap();
+        resolve_mount_ids(root.path(), &mut dev, &mut vec![]).unwrap();
+        assert_eq!((dev.mount_uid, dev.mount_gid), (Some(1001), Some(990)));
+
+        parse_line(&mut dev, "mount-group", "1234", &mut vec![]).unwrap();
+        resolve_mount_ids(root.path(), &mut dev, &mut vec![]).unwrap();
+        assert_eq!(dev.mount_gid, Some(1234));
+
+        parse_line(&mut dev, "mount-group", "build", &mut vec![]).unwrap();
+        let err = resolve_mount_ids(root.path(), &mut dev, &mut vec![]).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "zram0: mount-group=build: not found in /etc/group"
+        );
+
+        let mut dev = Device::new("zram1".to_string());
+        parse_line(&mut dev, "mount-mode", "700", &mut vec![]).unwrap();
+        let mut warnings = vec![];
+        resolve_mount_ids(root.path(), &mut dev, &mut warnings).unwrap();
+        assert_eq!(dev.mount_mode, None);
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_verify_unit_name() {
+        for e in [
+            "",
+            "foo",
+            ".target",
+            "a/b.target",
+            "foo.conf",
+            "a b.target",
+            "é.target",
+        ] {
+            assert!(verify_unit_name("test", e).is_err(), "{:?}", e);
+        }
+        for p in [
+            "multi-user.target",
+            "getty@tty1.service",
+            "dev-zram0.swap",
+            "foo\\x2dbar:baz_1.target",
+        ] {
+            assert_eq!(verify_unit_name("test", p).unwrap(), p);
+        }
+    }
+
+    #[test]
+    fn test_verify_slice_name() {
+        for e in [
+            "",
+            "system.service",
+            ".slice",
+            "system-zram@foo.slice",
+            "a b.slice",
+        ] {
+            assert!(verify_slice_name("slice", e).is_err(), "{:?}", e);
+        }
+        for p in [
+            "system.slice",
+            "system-zram.slice",
+            "system-zram\\x2dsetup.slice",
+        ] {
+            assert_eq!(verify_slice_name("slice", p).unwrap(), p);
+        }
+    }
+
+    #[test]
+    fn test_wanted_by() {
+        let mut dev = Device::new("zram0".to_string());
+        assert_eq!(dev.effective_wanted_by(), ["swap.target"]);
+
+        parse_line(&mut dev, "wanted-by", "a.target", &mut vec![]).unwrap();
+        parse_line(&mut dev, "wanted-by", "b.target  c.service", &mut vec![]).unwrap();
+        assert_eq!(
+            dev.effective_wanted_by(),
+            ["a.target", "b.target", "c.service"]
+        );
+
+        parse_line(&mut dev, "wanted-by", "", &mut vec![]).unwrap();
+        parse_line(&mut dev, "mount-point", "/tmp", &mut vec![]).unwrap();
+        assert_eq!(dev.effective_wanted_by(), ["local-fs.target"]);
+
+        assert!(parse_line(&mut dev, "required-by", "a.target b", &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_after_before() {
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "after", "a.service b.mount", &mut vec![]).unwrap();
+        parse_line(&mut dev, "after", "c.service a.service", &mut vec![]).unwrap();
+        parse_line(&mut dev, "before", "d.target", &mut vec![]).unwrap();
+        assert_eq!(dev.after, ["a.service", "b.mount", "c.service"]);
+        assert_eq!(dev.before, ["d.target"]);
+
+        parse_line(&mut dev, "after", "", &mut vec![]).unwrap();
+        assert!(dev.after.is_empty());
+
+        assert!(parse_line(&mut dev, "before", "swap", &mut vec![]).is_err());
+        assert!(parse_line(&mut dev, "after", "../a.service", &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_verify_resource_limit() {
+        const SUFFIXES: &[char] = &['K', 'M', 'G', 'T', 'P', 'E'];
+        for p in ["infinity", "512M", "1G", "1048576", "50%", "12.5%"] {
+            assert_eq!(verify_resource_limit("test", p, SUFFIXES).unwrap(), p);
+        }
+        for e in ["", "M", "-1", "1.5G", "512MB", "101%", "%", "1 G", "none"] {
+            assert!(
+                verify_resource_limit("test", e, SUFFIXES).is_err(),
+                "{:?}",
+                e
+            );
+        }
+
+        assert_eq!(verify_resource_limit("test", "16", &[]).unwrap(), "16");
+        assert!(verify_resource_limit("test", "16K", &[]).is_err());
+    }
+
+    #[test]
+    fn test_verify_cpu_list() {
+        for e in ["", " ,", "a", "1-", "-1", "3-1", "0 x", "0-1-2"] {
+            assert!(verify_cpu_list("test", e).is_err(), "{:?}", e);
+        }
+        for p in ["0", "0-3", "0,2 4-7", "1,", "numa"] {
+            assert_eq!(verify_cpu_list("test", p).unwrap(), p);
+        }
+    }
+
+    #[test]
+    fn test_verify_exec_command() {
+        for e in ["-", "@", "+ /bin/true", "/bin/echo\nfoo", "/bin/echo\tfoo"] {
+            assert!(verify_exec_command("test", e).is_err(), "{:?}", e);
+        }
+
+        for p in [
+            "/bin/true",
+            "-/bin/false",
+            "@/bin/sh sh -c 'echo 1'",
+            "+sysctl vm.swappiness=180",
+        ] {
+            assert_eq!(verify_exec_command("test", p).unwrap(), p);
+        }
+    }
+
+    #[test]
+    fn test_compression_algorithm_add() {
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "compression-algorithm", "lzo-rle", &mut vec![]).unwrap();
+        parse_line(
+            &mut dev,
+            "compression-algorithm-add",
+            "zstd(level=3)",
+            &mut vec![],
+        )
+        .unwrap();
+        parse_line(
+            &mut dev,
+            "compression-algorithm-add",
+            "lz4hc (type=idle)",
+            &mut vec![],
+        )
+        .unwrap();
+        assert_eq!(
+            dev.compression_algorithms,
+            Algorithms {
+                compression_algorithms: vec![
+                    ("lzo-rle".into(), "".into()),
+                    ("zstd".into(), "level=3".into()),
+                    ("lz4hc".into(), "".into()),
+                ],
+                recompression_global: "type=idle".into(),
+            }
+        );
 
-        "mount-point" => {
-            dev.mount_point = Some(verify_mount_point(key, value)?);
+        /* A later compression-algorithm= replaces the whole list. */
+        parse_line(&mut dev, "compression-algorithm", "zstd", &mut vec![]).unwrap();
+        assert_eq!(
+            dev.compression_algorithms,
+            Algorithms {
+                compression_algorithms: vec![("zstd".into(), "".into())],
+                ..Default::default()
+            }
+        );
+    }
+
+    fn dev_with_zram_size_size(val: Option<&str>, memtotal_mb: u64) -> u64 {
+        dev_with_zram_size_size_swap(val, memtotal_mb, 0)
+    }
+
+    fn dev_with_zram_size_size_swap(
+        val: Option<&str>,
+        memtotal_mb: u64,
+        other_swap_mb: u64,
+    ) -> u64 {
+        let mut dev = Device::new("zram0".to_string());
+        if let Some(val) = val {
+            parse_line(&mut dev, "zram-size", val, &mut vec![]).unwrap();
         }
+        assert!(dev.is_enabled(memtotal_mb, 0, false, &mut vec![]));
+        dev.set_disksize_if_enabled(
+            &mut EvalContext {
+                other_swap_mb,
+                additional: vec![("two".to_string(), 2.)].into_iter().collect(),
+                ..EvalContext::for_test(memtotal_mb)
+            },
+            &mut vec![],
+            &mut vec![],
+        )
+        .unwrap();
+        dev.disksize
+    }
 
-        "fs-type" => {
-            dev.fs_type = Some(value.to_string());
+    #[test]
+    fn test_eval_size_expression() {
+        assert_eq!(
+            dev_with_zram_size_size(Some("0.5 * ram"), 100),
+            50 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn test_ram_budget() {
+        let read = |conf: &str| {
+            let root = include_root(&[("etc/systemd/zram-generator.conf", conf)]);
+            let mut warnings = vec![];
+            let devices = read_devices_from(root.path(), false, &mut warnings).unwrap();
+            let dev = &devices["zram0"];
+            (
+                dev.disksize / 1024 / 1024,
+                dev.mem_limit / 1024 / 1024,
+                warnings.iter().map(Warning::to_string).collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(
+            read("[zram0]\nram-budget = 2048\nexpected-ratio = 3\n"),
+            (6144, 2048, vec![])
+        );
+        /* An explicit zram-resident-limit= wins */
+        assert_eq!(
+            read("[zram0]\nram-budget = 2048\nexpected-ratio = 2.5\nzram-resident-limit = 1024\n"),
+            (5120, 1024, vec![])
+        );
+        /* MemTotal is 7825MB */
+        assert_eq!(
+            read("[zram0]\nram-budget = ram / 5\n"),
+            (1565, 1565, vec![])
+        );
+        assert_eq!(
+            read("[zram0]\nram-budget = 1024\nexpected-ratio = 2\nzram-size = 100\n"),
+            (
+                2048,
+                1024,
+                vec![
+                    "zram0: zram-size= set together with ram-budget=, ignoring zram-size=."
+                        .to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            read("[zram0]\nzram-size = 100\nexpected-ratio = 2\n"),
+            (
+                100,
+                0,
+                vec!["zram0: expected-ratio= set without ram-budget=, ignoring.".to_string()]
+            )
+        );
+
+        let mut dev = Device::new("zram0".to_string());
+        for e in ["0.5", "0", "-2", "65", "x", "NaN"] {
+            assert!(
+                parse_line(&mut dev, "expected-ratio", e, &mut vec![]).is_err(),
+                "{}",
+                e
+            );
         }
+    }
 
-        "options" => {
-            dev.options = value.to_string().into();
+    #[test]
+    fn test_normalize_options() {
+        let normalize = |options: &str| {
+            let mut warnings = vec![];
+            let ret = normalize_options("zram0", options, &mut warnings);
+            (
+                ret,
+                warnings.iter().map(Warning::to_string).collect::<Vec<_>>(),
+            )
+        };
+
+        assert_eq!(normalize(""), (String::new(), vec![]));
+        assert_eq!(
+            normalize("discard,nofail,,discard,nofail"),
+            ("discard,nofail".to_string(), vec![])
+        );
+        assert_eq!(
+            normalize("pri=10,discard,pri=20"),
+            (
+                "pri=20,discard".to_string(),
+                vec!["zram0: options= has both pri=10 and pri=20, using pri=20.".to_string()]
+            )
+        );
+        assert_eq!(
+            normalize("discard=once,nodev,noexec,discard=pages,exec"),
+            (
+                "discard=pages,nodev,exec".to_string(),
+                vec![
+                    "zram0: options= has both discard=once and discard=pages, using discard=pages."
+                        .to_string(),
+                    "zram0: options= has both noexec and exec, using exec.".to_string()
+                ]
+            )
+        );
+        assert_eq!(normalize("rw,ro").0, "ro");
+        /* x-* may be repeated, only exact copies are dropped */
+        assert_eq!(
+            normalize("x-systemd.requires=a.service,x-systemd.requires=b.service,x-systemd.requires=a.service"),
+            (
+                "x-systemd.requires=a.service,x-systemd.requires=b.service".to_string(),
+                vec![]
+            )
+        );
+
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "[zram0]\noptions = pri=5,discard,,pri=7,discard\n",
+        )]);
+        let mut warnings = vec![];
+        let devices = read_devices_from(root.path(), false, &mut warnings).unwrap();
+        assert_eq!(devices["zram0"].options, "pri=7,discard");
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_section_device() {
+        assert_eq!(section_device("zram0", None), Some(Some("zram0")));
+        assert_eq!(
+            section_device("zram0", Some("battery")),
+            Some(Some("zram0"))
+        );
+        assert_eq!(
+            section_device("zram1:battery", Some("battery")),
+            Some(Some("zram1"))
+        );
+        assert_eq!(section_device("zram1:battery", Some("ac")), Some(None));
+        assert_eq!(section_device("zram1:battery", None), Some(None));
+        assert_eq!(section_device("zram1:", Some("battery")), None);
+        assert_eq!(section_device("zram1:bat tery", None), None);
+        assert_eq!(section_device("swap:battery", Some("battery")), None);
+        assert_eq!(section_device("zramX", None), None);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "\
+[zram0]
+zram-size = 1024
+compression-algorithm = lzo
+
+[zram0:battery]
+zram-size = 2048
+
+[zram0:ac]
+compression-algorithm = zstd
+
+[zram1:battery]
+zram-size = 512
+
+[zram2:bad.name]
+zram-size = 256
+",
+        )]);
+        let read = |profile: Option<&str>| {
+            let mut warnings = vec![];
+            let (devices, globals) = read_profile_config_from(
+                root.path(),
+                false,
+                profile.map(str::to_string),
+                &mut warnings,
+            )
+            .unwrap();
+            assert_eq!(globals.profile.as_deref(), profile);
+            /* Overriding the base section is reported like any other override */
+            let warnings: Vec<_> = warnings.iter().filter(|w| w.level == Level::Warn).collect();
+            assert_eq!(warnings.len(), 1, "{:?}", warnings);
+            assert!(warnings[0]
+                .to_string()
+                .ends_with("Ignoring section \"zram2:bad.name\""));
+            devices
+        };
+
+        let devices = read(None);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices["zram0"].disksize, 1024 * 1024 * 1024);
+        assert_eq!(devices["zram0"].compression_algorithms.to_string(), "lzo");
+
+        let devices = read(Some("battery"));
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices["zram0"].disksize, 2048 * 1024 * 1024);
+        assert_eq!(devices["zram0"].compression_algorithms.to_string(), "lzo");
+        assert_eq!(devices["zram1"].disksize, 512 * 1024 * 1024);
+
+        let devices = read(Some("ac"));
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices["zram0"].disksize, 1024 * 1024 * 1024);
+        assert_eq!(devices["zram0"].compression_algorithms.to_string(), "zstd");
+
+        assert!(verify_profile_name("battery-saver_2").is_ok());
+        assert!(verify_profile_name("").is_err());
+        assert!(verify_profile_name("a/b").is_err());
+    }
+
+    #[test]
+    fn test_size_warning() {
+        let read = |conf: &str| {
+            let root = include_root(&[("etc/systemd/zram-generator.conf", conf)]);
+            let mut warnings = vec![];
+            read_devices_from(root.path(), false, &mut warnings).unwrap();
+            warnings.iter().map(Warning::to_string).collect::<Vec<_>>()
+        };
+
+        /* MemTotal is 7825MB */
+        assert_eq!(
+            read("[zram0]\nzram-size = ram * 3\n"),
+            [
+                "zram0: zram-size = ram * 3 is 23475MB, more than 2 times MemTotal (7825MB); \
+              set no-size-warning = true if that's intended."
+            ]
+        );
+        assert_eq!(
+            read("[zram0]\nzram-size = ram * 3\nno-size-warning = true\n"),
+            Vec::<String>::new()
+        );
+        assert_eq!(read("[zram0]\nzram-size = ram * 2\n"), Vec::<String>::new());
+        /* Only for swap */
+        assert_eq!(
+            read("[zram0]\nzram-size = ram * 3\nmount-point = /var/tmp\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_size_trace() {
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(
+            &mut dev,
+            "zram-size",
+            "min(ram * two, swap + 10)",
+            &mut vec![],
+        )
+        .unwrap();
+        let mut ctx = EvalContext {
+            other_swap_mb: 50,
+            additional: vec![("two".to_string(), 2.)].into_iter().collect(),
+            ..EvalContext::for_test(100)
+        };
+        let zs = dev.zram_size.as_ref().unwrap();
+        assert_eq!(
+            dev.size_trace("zram-size", zs, &mut ctx, 60.),
+            "zram0: zram-size = min(ram * two, swap + 10), ram=100, swap=50, two=2 → 60MB"
+        );
+
+        parse_line(&mut dev, "zram-size", "ram / 3", &mut vec![]).unwrap();
+        let zs = dev.zram_size.as_ref().unwrap();
+        assert_eq!(
+            dev.size_trace("zram-size", zs, &mut ctx, 100. / 3.),
+            "zram0: zram-size = ram / 3, ram=100 → 33.333333333333336MB"
+        );
+
+        parse_line(&mut dev, "zram-size", "4096", &mut vec![]).unwrap();
+        let zs = dev.zram_size.as_ref().unwrap();
+        assert_eq!(
+            dev.size_trace("zram-size", zs, &mut ctx, 4096.),
+            "zram0: zram-size = 4096 → 4096MB"
+        );
+    }
+
+    #[test]
+    fn test_eval_size_expression_with_additional() {
+        assert_eq!(
+            dev_with_zram_size_size(Some("0.5 * ram * two"), 100),
+            50 * 2 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn test_eval_size_expression_500() {
+        assert_eq!(
+            dev_with_zram_size_size(Some("500"), 5000),
+            500 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn test_eval_size_expression_500k() {
+        assert_eq!(
+            dev_with_zram_size_size(Some("500k"), 10000),
+            500 * 1000 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    #[allow(clippy::inconsistent_digit_grouping)]
+    fn test_eval_size_expression_32g() {
+        assert_eq!(
+            dev_with_zram_size_size(Some("32G"), 1_000_000_000),
+            32 * 1000_000_000 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn test_eval_size_expression_default() {
+        assert_eq!(dev_with_zram_size_size(None, 100), 50 * 1024 * 1024);
+        assert_eq!(dev_with_zram_size_size(None, 10000), 4096 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_eval_size_expression_default_equivalent() {
+        assert_eq!(
+            dev_with_zram_size_size(Some(DEFAULT_ZRAM_SIZE), 100),
+            50 * 1024 * 1024
+        );
+        assert_eq!(
+            dev_with_zram_size_size(Some(DEFAULT_ZRAM_SIZE), 10000),
+            4096 * 1024 * 1024
+        );
+    }
+
+    fn fs_dev_disksize(lines: &[(&str, &str)], memtotal_mb: u64) -> (u64, Vec<Warning>) {
+        let mut dev = Device::new("zram0".to_string());
+        let mut warnings = vec![];
+        for (key, value) in lines {
+            parse_line(&mut dev, key, value, &mut warnings).unwrap();
         }
+        dev.set_disksize_if_enabled(
+            &mut EvalContext {
+                ..EvalContext::for_test(memtotal_mb)
+            },
+            &mut warnings,
+            &mut vec![],
+        )
+        .unwrap();
+        (dev.disksize, warnings)
+    }
 
-        "zram-fraction" => {
-            /* zram-fraction is for backwards compat. zram-size = is preferred. */
+    #[test]
+    fn test_eval_size_expression_default_fs() {
+        let mount = ("mount-point", "/tmp");
+        assert_eq!(fs_dev_disksize(&[mount], 100).0, 100 * 1024 * 1024);
+        assert_eq!(fs_dev_disksize(&[mount], 10000).0, 4096 * 1024 * 1024);
+        assert_eq!(
+            fs_dev_disksize(&[mount, ("zram-size", DEFAULT_ZRAM_SIZE_FS)], 100).0,
+            100 * 1024 * 1024
+        );
+        /* raw devices are sized like swap */
+        assert_eq!(
+            fs_dev_disksize(&[("fs-type", "none")], 100).0,
+            50 * 1024 * 1024
+        );
+    }
 
-            dev.zram_fraction = Some(
-                value
-                    .parse()
-                    .with_context(|| format!("Failed to parse zram-fraction \"{}\"", value))
-                    .and_then(|f| {
-                        if f >= 0. {
-                            Ok(f)
-                        } else {
-                            Err(anyhow!("{}: zram-fraction={} < 0", dev.name, f))
-                        }
-                    })?,
-            );
+    #[test]
+    fn test_parse_fs_reserve() {
+        assert_eq!(parse_fs_reserve("fs-reserve", "5%").unwrap(), 5.);
+        assert_eq!(parse_fs_reserve("fs-reserve", "0").unwrap(), 0.);
+        assert_eq!(parse_fs_reserve("fs-reserve", "12.5").unwrap(), 12.5);
+        for e in ["", "%", "100%", "-1%", "5 %", "NaN", "five"] {
+            assert!(parse_fs_reserve("fs-reserve", e).is_err(), "{:?}", e);
         }
+    }
 
-        "max-zram-size" => {
-            /* zram-fraction is for backwards compat. zram-size = is preferred. */
+    #[test]
+    fn test_apply_fs_reserve() {
+        assert_eq!(apply_fs_reserve(0, 10.), 0);
+        assert_eq!(apply_fs_reserve(4096 * 1024, 0.), 4096 * 1024);
+        assert_eq!(apply_fs_reserve(900 * 1024 * 1024, 10.), 1000 * 1024 * 1024);
+        assert_eq!(apply_fs_reserve(3 * 4096, 50.), 6 * 4096);
+        /* rounded up to a page */
+        assert_eq!(apply_fs_reserve(4096, 1.), 2 * 4096);
+    }
+
+    #[test]
+    fn test_fs_reserve() {
+        let (disksize, warnings) = fs_dev_disksize(
+            &[
+                ("mount-point", "/tmp"),
+                ("zram-size", "3840"),
+                ("fs-reserve", "6.25%"),
+            ],
+            1000,
+        );
+        assert_eq!(disksize, 4096 * 1024 * 1024);
+        assert!(warnings.is_empty());
+
+        let (disksize, warnings) =
+            fs_dev_disksize(&[("zram-size", "500"), ("fs-reserve", "10%")], 1000);
+        assert_eq!(disksize, 500 * 1024 * 1024);
+        assert_eq!(
+            warnings,
+            [Warning::warn(
+                "zram0: fs-reserve= set on a device without a file system, ignoring.".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined(\"array\")")]
+    fn test_eval_size_expression_unknown_variable() {
+        dev_with_zram_size_size(Some("array(1,2)"), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "zram-size=NaN")]
+    fn test_eval_size_expression_nan() {
+        dev_with_zram_size_size(Some("(ram-100)/0"), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "zram-size=inf is more than 64 times MemTotal (6400MB)")]
+    fn test_eval_size_expression_inf() {
+        dev_with_zram_size_size(Some("(ram-99)/0"), 100); // +∞
+    }
+
+    #[test]
+    fn test_eval_size_expression_max() {
+        assert_eq!(
+            dev_with_zram_size_size(Some("ram * 64"), 100),
+            6400 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "zram-size=6401 is more than 64 times MemTotal")]
+    fn test_eval_size_expression_too_large() {
+        dev_with_zram_size_size(Some("ram * 64 + 1"), 100);
+    }
+
+    #[test]
+    fn test_eval_size_expression_swap_headroom() {
+        assert_eq!(
+            dev_with_zram_size_size_swap(Some("max(0, ram - swap)"), 3000, 1000),
+            2000 * 1024 * 1024
+        );
+        assert_eq!(
+            dev_with_zram_size_size_swap(Some("max(0, ram - swap)"), 3000, 8000),
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "zram-size=-5000 < 0")]
+    fn test_eval_size_expression_swap_negative() {
+        dev_with_zram_size_size_swap(Some("ram - swap"), 3000, 8000);
+    }
+
+    #[test]
+    fn test_eval_size_expression_min() {
+        assert_eq!(
+            dev_with_zram_size_size(Some("min(0.5 * ram, 4000)"), 3000),
+            1500 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn test_toplevel_set() {
+        let mut slab = fasteval::Slab::new();
+        let mut ctx = EvalContext {
+            ..EvalContext::for_test(1024)
+        };
+        let path = Path::new("test.conf");
+
+        toplevel_line(
+            path,
+            "set!two",
+            "echo 1 + 1",
+            &mut slab,
+            &mut ctx,
+            true,
+            &mut vec![],
+        )
+        .unwrap();
+        assert_eq!(ctx.additional.get("two"), Some(&2.));
+
+        toplevel_line(
+            path,
+            "set!sensor",
+            "printf 'size = 1024 * two\\n\\nratio=sensor_size / 512\\n'",
+            &mut slab,
+            &mut ctx,
+            true,
+            &mut vec![],
+        )
+        .unwrap();
+        assert_eq!(ctx.additional.get("sensor_size"), Some(&2048.));
+        assert_eq!(ctx.additional.get("sensor_ratio"), Some(&4.));
+        assert_eq!(ctx.additional.get("sensor"), None);
+
+        /* A comparison is a single expression */
+        toplevel_line(
+            path,
+            "set!big",
+            "echo 'two >= 2'",
+            &mut slab,
+            &mut ctx,
+            true,
+            &mut vec![],
+        )
+        .unwrap();
+        assert_eq!(ctx.additional.get("big"), Some(&1.));
+
+        let err = toplevel_line(
+            path,
+            "set!bad",
+            "printf 'a=1\\nb=(\\n'",
+            &mut slab,
+            &mut ctx,
+            true,
+            &mut vec![],
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("bad_b"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_parse_set_assignments() {
+        assert_eq!(
+            parse_set_assignments("size=2048\nratio = 2.5\n"),
+            Some(vec![("size", "2048"), ("ratio", "2.5")])
+        );
+        assert_eq!(
+            parse_set_assignments("\n_x1 = ram / 2\n\n"),
+            Some(vec![("_x1", "ram / 2")])
+        );
+        assert_eq!(parse_set_assignments("1 + 1\n"), None);
+        assert_eq!(parse_set_assignments("ram == 1024\n"), None);
+        assert_eq!(parse_set_assignments("ram <= 1024\n"), None);
+        assert_eq!(parse_set_assignments("size=1\n2\n"), None);
+        assert_eq!(parse_set_assignments("1size=1\n"), None);
+        assert_eq!(parse_set_assignments("a-b=1\n"), None);
+        assert_eq!(parse_set_assignments(""), None);
+    }
+
+    #[test]
+    fn test_toplevel_set_disabled() {
+        let mut slab = fasteval::Slab::new();
+        let mut ctx = EvalContext {
+            ..EvalContext::for_test(1024)
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let path = Path::new("test.conf");
 
-            dev.max_zram_size_mb = Some(parse_optional_size(value)?);
-        }
+        toplevel_line(
+            path,
+            "set!two",
+            &format!("touch {}; echo 2", marker.display()),
+            &mut slab,
+            &mut ctx,
+            false,
+            &mut vec![],
+        )
+        .unwrap();
+        assert!(ctx.additional.is_empty());
+        assert!(!marker.exists());
+    }
 
-        _ => {
-            warn!("{}: unknown key {}, ignoring.", dev.name, key);
+    fn explain(lines: &[&str], memtotal_mb: u64) -> Vec<String> {
+        let mut dev = Device::new("zram0".to_string());
+        for line in lines {
+            let (k, v) = line.split_once('=').unwrap();
+            parse_line(&mut dev, k, v, &mut vec![]).unwrap();
         }
+        let mut explanation = vec![];
+        dev.set_disksize_if_enabled(
+            &mut EvalContext {
+                ..EvalContext::for_test(memtotal_mb)
+            },
+            &mut vec![],
+            &mut explanation,
+        )
+        .unwrap();
+        explanation
     }
 
-    Ok(())
-}
-
-fn _get_total_memory_kb(path: &Path) -> Result<u64> {
-    for line in
-        BufReader::new(fs::File::open(path).with_context(|| {
-            format!("Failed to read memory information from {}", path.display())
-        })?)
-        .lines()
-    {
-        let line = line?;
-        let mut fields = line.split_whitespace();
-        if let (Some("MemTotal:"), Some(val)) = (fields.next(), fields.next()) {
-            return Ok(val.parse()?);
-        }
+    #[test]
+    fn test_explanation() {
+        assert_eq!(
+            explain(&[], 4000),
+            [
+                "MemTotal: 4000MB",
+                "host-memory-limit: <none>",
+                "zram-size: min(ram / 2, 4096) = 2000MB",
+                "zram-resident-limit: 0 = 0MB",
+                "disksize: 2097152000 bytes",
+                "created, as swap",
+            ]
+        );
+        assert_eq!(
+            explain(&["host-memory-limit=2048"], 4000),
+            [
+                "MemTotal: 4000MB",
+                "host-memory-limit: 2048MB, exceeded",
+                "not created: host-memory-limit exceeded",
+            ]
+        );
+        assert_eq!(
+            explain(
+                &[
+                    "host-memory-limit=8G",
+                    "zram-size=ram - 4000",
+                    "mount-point=/tmp"
+                ],
+                4000
+            )[1..],
+            [
+                "host-memory-limit: 8192MB, not exceeded",
+                "zram-size: ram - 4000 = 0MB",
+                "zram-resident-limit: 0 = 0MB",
+                "disksize: 0 bytes",
+                "not created: size is 0",
+            ]
+        );
+        assert_eq!(
+            explain(&["zram-fraction=0.25"], 4000)[2],
+            "zram-fraction=0.25 max-zram-size=<none> (obsolete) = 1000MB"
+        );
     }
 
-    Err(anyhow!("Couldn't find MemTotal in {}", path.display()))
-}
+    #[test]
+    fn test_validate_config() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("proc")).unwrap();
+        fs::write(root.path().join("proc/meminfo"), "MemTotal: 8013220 kB\n").unwrap();
+        assert_eq!(
+            validate_config(root.path()).unwrap(),
+            [Warning::info("No configuration found.".to_string())]
+        );
 
-fn get_total_memory_kb(root: &Path) -> Result<u64> {
-    let path = root.join("proc/meminfo");
-    _get_total_memory_kb(&path)
-}
+        fs::create_dir_all(root.path().join("etc/systemd")).unwrap();
+        fs::write(
+            root.path().join("etc/systemd/zram-generator.conf"),
+            "\
+[zram0]
+zram-size = ram / 2
+compresion-algorithm = zstd
 
-fn _kernel_has_option(path: &Path, word: &str) -> Result<Option<bool>> {
-    let text = fs::read_to_string(path)?;
+[zram1]
+host-memory-limit = 2048
 
-    // Last argument wins
-    Ok(text
-        .split_whitespace()
-        .rev()
-        .filter(|w| w.starts_with(word))
-        .flat_map(|w| match &w[word.len()..] {
-            "" | "=1" | "=yes" | "=true" | "=on" => Some(true),
-            "=0" | "=no" | "=false" | "=off" => Some(false),
-            _ => None,
-        })
-        .next())
-}
+[swap]
+",
+        )
+        .unwrap();
+        let warnings = validate_config(root.path()).unwrap();
+        let messages: Vec<_> = warnings.iter().map(|w| (w.level, &w.message[..])).collect();
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert!(messages.contains(&(
+            Level::Warn,
+            "zram0: unknown key compresion-algorithm, ignoring."
+        )));
+        assert!(messages
+            .iter()
+            .any(|(l, m)| *l == Level::Warn && m.ends_with("Ignoring section \"swap\"")));
+        assert!(messages
+            .iter()
+            .any(|(l, m)| *l == Level::Info && m.starts_with("zram1: system has too much memory")));
 
-pub fn kernel_has_option(root: &Path, word: &str) -> Result<Option<bool>> {
-    let path = root.join("proc/cmdline");
-    _kernel_has_option(&path, word)
-}
+        fs::write(
+            root.path().join("etc/systemd/zram-generator.conf"),
+            "[zram0]\nswap-priority = 40000\n",
+        )
+        .unwrap();
+        assert!(validate_config(root.path()).is_err());
+    }
 
-pub fn kernel_zram_option(root: &Path) -> Option<bool> {
-    match kernel_has_option(root, "systemd.zram") {
-        Ok(r @ Some(true)) | Ok(r @ None) => r,
-        Ok(Some(false)) => {
-            info!("Disabled by systemd.zram option in /proc/cmdline.");
-            Some(false)
-        }
-        Err(e) => {
-            warn!("Failed to parse /proc/cmdline ({}), ignoring.", e);
-            None
+    fn include_root(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("proc")).unwrap();
+        fs::write(root.path().join("proc/meminfo"), "MemTotal: 8013220 kB\n").unwrap();
+        for (path, contents) in files {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
         }
+        root
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_algorithm_policy_parse() {
+        let policy = AlgorithmPolicy::parse(
+            "# weak algorithms are forbidden\nallow = zstd lz4\n\nallow=lzo-rle\ndeny = lz4\n",
+        )
+        .unwrap();
+        assert!(policy.permits("zstd"));
+        assert!(policy.permits("lzo-rle"));
+        assert!(!policy.permits("lz4"));
+        assert!(!policy.permits("lzo"));
 
-    fn file_with(data: &[u8]) -> tempfile::NamedTempFile {
-        let mut file = tempfile::NamedTempFile::new().unwrap();
-        file.write(data).unwrap();
-        file.flush().unwrap();
-        file
+        let policy = AlgorithmPolicy::parse("deny = lzo lzo-rle\n").unwrap();
+        assert!(policy.permits("zstd"));
+        assert!(!policy.permits("lzo"));
+
+        assert!(AlgorithmPolicy::parse("").unwrap().permits("lzo"));
+        assert!(AlgorithmPolicy::parse("zstd\n").is_err());
+        assert!(AlgorithmPolicy::parse("permit = zstd\n").is_err());
     }
 
     #[test]
-    fn test_get_total_memory_kb() {
-        let file = file_with(
-            b"\
-MemTotal:        8013220 kB
-MemFree:          721288 kB
-MemAvailable:    1740336 kB
-Buffers:          292752 kB
-",
+    fn test_algorithm_policy() {
+        let conf = "[zram0]\ncompression-algorithm = zstd lz4\n\n[zram1]\n";
+
+        /* allowed, and the kernel's default for zram1 */
+        let root = include_root(&[
+            ("etc/systemd/zram-generator.conf", conf),
+            (ALGORITHM_POLICY_PATH, "allow = zstd lz4 lzo-rle\n"),
+        ]);
+        assert_eq!(read_all_devices(root.path(), false).unwrap().len(), 2);
+
+        /* a denied recompression algorithm */
+        let root = include_root(&[
+            ("etc/systemd/zram-generator.conf", conf),
+            (ALGORITHM_POLICY_PATH, "deny = lz4\n"),
+        ]);
+        let Err(err) = read_all_devices(root.path(), false) else {
+            panic!("lz4 is denied");
+        };
+        assert_eq!(
+            err.to_string(),
+            "zram0: compression algorithm lz4 is not allowed by /etc/zram-generator/policy.conf"
         );
-        let mem = _get_total_memory_kb(file.path()).unwrap();
-        assert_eq!(mem, 8013220);
-    }
 
-    #[test]
-    #[should_panic(expected = "Couldn't find MemTotal")]
-    fn test_get_total_memory_not_found() {
-        let file = file_with(
-            b"\
-MemTotala:        8013220 kB
-aMemTotal:        8013220 kB
-MemTotal::        8013220 kB
-",
+        /* also applies to compression.default */
+        let root = include_root(&[
+            ("etc/systemd/zram-generator.conf", "[zram0]\n"),
+            ("usr/lib/zram-generator/compression.default", "lzo\n"),
+            (ALGORITHM_POLICY_PATH, "allow = zstd\n"),
+        ]);
+        assert!(read_all_devices(root.path(), false).is_err());
+
+        /* auto picks only among the permitted algorithms */
+        let auto = "[zram0]\ncompression-algorithm = auto\n";
+        let root = include_root(&[
+            ("etc/systemd/zram-generator.conf", auto),
+            (ALGORITHM_POLICY_PATH, "deny = zstd\n"),
+        ]);
+        assert_eq!(read_all_devices(root.path(), false).unwrap().len(), 1);
+        assert_eq!(
+            auto_compression_algorithms(root.path()).unwrap(),
+            ["lz4", "lzo-rle"]
         );
-        _get_total_memory_kb(file.path()).unwrap();
-    }
 
-    #[test]
-    fn test_kernel_has_option() {
-        let file = file_with(b"foo=1 foo=0 foo=on foo=off foo\n");
-        assert_eq!(_kernel_has_option(file.path(), "foo").unwrap(), Some(true));
-    }
+        let root = include_root(&[
+            ("etc/systemd/zram-generator.conf", auto),
+            (ALGORITHM_POLICY_PATH, "allow = zstd lz4\n"),
+        ]);
+        assert_eq!(read_all_devices(root.path(), false).unwrap().len(), 1);
+        assert_eq!(
+            auto_compression_algorithms(root.path()).unwrap(),
+            ["zstd", "lz4"]
+        );
 
-    #[test]
-    fn test_kernel_has_no_option() {
-        let file = file_with(
-            b"\
-foo=1
-foo=0
-",
+        let root = include_root(&[
+            ("etc/systemd/zram-generator.conf", auto),
+            (ALGORITHM_POLICY_PATH, "allow = lzo\n"),
+        ]);
+        let Err(err) = read_all_devices(root.path(), false) else {
+            panic!("auto has nothing to pick from");
+        };
+        assert_eq!(
+            err.to_string(),
+            "zram0: compression-algorithm=auto: none of zstd, lz4, lzo-rle is allowed by /etc/zram-generator/policy.conf"
+        );
+
+        let root = include_root(&[("etc/systemd/zram-generator.conf", auto)]);
+        assert_eq!(
+            auto_compression_algorithms(root.path()).unwrap(),
+            AUTO_COMPRESSION_ALGORITHMS
         );
-        assert_eq!(_kernel_has_option(file.path(), "foo").unwrap(), Some(false));
     }
 
     #[test]
-    fn test_verify_mount_point() {
-        for e in ["foo/bar", "/foo/../bar", "/foo/.."] {
-            assert!(verify_mount_point("test", e).is_err(), "{}", e);
-        }
-
-        for (p, o) in [
-            ("/foobar", "/foobar"),
-            ("/", "/"),
-            ("//", "/"),
-            ("///", "/"),
-            ("/foo/./bar/", "/foo/bar"),
+    fn test_memtotal_rounding() {
+        use MemtotalRounding::*;
+        for (kb, truncate, round, ceil) in [
+            (16383 * 1024, 16383, 16383, 16383),
+            (16383 * 1024 + 1, 16383, 16383, 16384),
+            (16383 * 1024 + 511, 16383, 16383, 16384),
+            (16383 * 1024 + 512, 16383, 16384, 16384), /* 16383.5MB */
+            (16776908, 16383, 16384, 16384),           /* 16383.7MB */
+            (16384 * 1024 - 1, 16383, 16384, 16384),
         ] {
-            assert_eq!(
-                verify_mount_point("test", p).unwrap(),
-                Path::new(o),
-                "{} vs {}",
-                p,
-                o
-            );
+            assert_eq!(Truncate.mb(kb), truncate, "{}", kb);
+            assert_eq!(Round.mb(kb), round, "{}", kb);
+            assert_eq!(Ceil.mb(kb), ceil, "{}", kb);
         }
+
+        assert_eq!("ceil".parse::<MemtotalRounding>().unwrap(), Ceil);
+        assert!("floor".parse::<MemtotalRounding>().is_err());
     }
 
-    fn dev_with_zram_size_size(val: Option<&str>, memtotal_mb: u64) -> u64 {
-        let mut dev = Device::new("zram0".to_string());
-        if let Some(val) = val {
-            parse_line(&mut dev, "zram-size", val).unwrap();
-        }
-        assert!(dev.is_enabled(memtotal_mb));
-        dev.set_disksize_if_enabled(&mut EvalContext {
-            memtotal_mb,
-            additional: vec![("two".to_string(), 2.)].into_iter().collect(),
-        })
+    #[test]
+    fn test_resident_limit_disksize() {
+        let read = |conf: &str| {
+            let root = include_root(&[
+                ("proc/meminfo", "MemTotal: 8388608 kB\n"), // 8192MB
+                ("etc/systemd/zram-generator.conf", conf),
+            ]);
+            read_devices_from(root.path(), false, &mut vec![])
+        };
+
+        let devices =
+            read("[zram0]\nzram-size = ram / 2\nzram-resident-limit = disksize / 4\n").unwrap();
+        assert_eq!(devices["zram0"].disksize, 4096 << 20);
+        assert_eq!(devices["zram0"].mem_limit, 1024 << 20);
+
+        /* The default size, and after fs-reserve= */
+        let devices = read(
+            "[zram0]\nzram-resident-limit = min(disksize, 1024)\n\
+             [zram1]\nmount-point = /tmp\nzram-size = 950\nfs-reserve = 5%\nzram-resident-limit = disksize\n",
+        )
         .unwrap();
-        dev.disksize
+        assert_eq!(devices["zram0"].mem_limit, 1024 << 20);
+        assert_eq!(devices["zram1"].mem_limit, devices["zram1"].disksize);
+        assert_eq!(devices["zram1"].disksize, 1000 << 20);
+
+        /* Not known yet for zram-size */
+        assert!(read("[zram0]\nzram-size = disksize\n").is_err());
     }
 
     #[test]
-    fn test_eval_size_expression() {
+    fn test_total_size_limit() {
+        let read = |conf: &str| {
+            let root = include_root(&[
+                ("proc/meminfo", "MemTotal: 8388608 kB\n"), // 8192MB
+                ("etc/systemd/zram-generator.conf", conf),
+            ]);
+            read_devices_from(root.path(), false, &mut vec![])
+        };
+
+        let devices = read(
+            "total-size-limit = ram / 2\n\
+             [zram0]\nzram-size = ram / 2\n\
+             [zram1]\nzram-size = 2048\n\
+             [zram2]\nhost-memory-limit = 1024\n",
+        )
+        .unwrap();
+        let (zram0, zram1) = (devices["zram0"].disksize, devices["zram1"].disksize);
+        assert_eq!(zram0, 2863308800); // 4096MB * 2/3, in pages
+        assert_eq!(zram1, 1431654400); // 2048MB * 2/3, in pages
+        assert!(zram0 + zram1 <= 4096 << 20);
+        assert_eq!(devices["zram2"].disksize, 0);
+        assert!(devices["zram0"].explanation.contains(
+            &"total-size-limit: 4096MB for 6144MB of devices, scaled to 2730MB".to_string()
+        ));
+
+        /* The limit applies to zram-size=, before the file system's overhead,
+         * and zram-resident-limit= follows from the scaled size */
+        let devices = read(
+            "total-size-limit = 2048\n\
+             [zram0]\nzram-size = 2048\nzram-resident-limit = disksize / 2\n\
+             [zram1]\nzram-size = 2048\nmount-point = /var/tmp\nfs-reserve = 20%\n",
+        )
+        .unwrap();
+        assert_eq!(devices["zram0"].disksize, 1024 << 20);
+        assert_eq!(devices["zram0"].mem_limit, 512 << 20);
+        assert_eq!(devices["zram1"].disksize, 1280 << 20);
         assert_eq!(
-            dev_with_zram_size_size(Some("0.5 * ram"), 100),
-            50 * 1024 * 1024
+            devices["zram1"].explanation[devices["zram1"].explanation.len() - 5..],
+            [
+                "total-size-limit: 2048MB for 4096MB of devices, scaled to 1024MB",
+                "fs-reserve: 20% = 1280MB",
+                "zram-resident-limit: 0 = 0MB",
+                "disksize: 1342177280 bytes",
+                "created, as ext2",
+            ]
         );
+
+        /* Under the limit, nothing changes */
+        let devices = read(
+            "total-size-limit = 6144\n[zram0]\nzram-size = ram / 2\n[zram1]\nzram-size = 2048\n",
+        )
+        .unwrap();
+        assert_eq!(devices["zram0"].disksize, 4096 << 20);
+        assert_eq!(devices["zram1"].disksize, 2048 << 20);
+
+        let devices = read(
+            "total-size-limit = 1024\ntotal-size-limit = none\n[zram0]\nzram-size = ram / 2\n",
+        )
+        .unwrap();
+        assert_eq!(devices["zram0"].disksize, 4096 << 20);
+
+        assert!(read("total-size-limit = ram +\n[zram0]\n").is_err());
+        assert!(read("total-size-limit = -1\n[zram0]\n").is_err());
     }
 
     #[test]
-    fn test_eval_size_expression_with_additional() {
+    fn test_memtotal_rounding_directive() {
+        let memtotal_mb = |conf: &str| {
+            let root = include_root(&[
+                ("proc/meminfo", "MemTotal: 16776908 kB\n"),
+                ("etc/systemd/zram-generator.conf", conf),
+            ]);
+            let devices = explain_devices(root.path(), false).unwrap();
+            devices[0].explanation[0].clone()
+        };
+        assert_eq!(memtotal_mb("[zram0]\n"), "MemTotal: 16383MB");
         assert_eq!(
-            dev_with_zram_size_size(Some("0.5 * ram * two"), 100),
-            50 * 2 * 1024 * 1024
+            memtotal_mb("memtotal-rounding = truncate\n[zram0]\n"),
+            "MemTotal: 16383MB"
+        );
+        assert_eq!(
+            memtotal_mb("memtotal-rounding = round\n[zram0]\n"),
+            "MemTotal: 16384MB"
+        );
+        assert_eq!(
+            memtotal_mb("memtotal-rounding = ceil\n[zram0]\n"),
+            "MemTotal: 16384MB"
+        );
+
+        /* Not after set!, which has already used ram */
+        let mut slab = fasteval::Slab::new();
+        let mut ctx = EvalContext {
+            memtotal_kb: 16776908,
+            ..EvalContext::for_test(16383)
+        };
+        let path = Path::new("test.conf");
+        let mut warnings = vec![];
+        toplevel_line(
+            path,
+            "set!half",
+            "echo ram / 2",
+            &mut slab,
+            &mut ctx,
+            true,
+            &mut warnings,
+        )
+        .unwrap();
+        toplevel_line(
+            path,
+            "memtotal-rounding",
+            "ceil",
+            &mut slab,
+            &mut ctx,
+            true,
+            &mut warnings,
+        )
+        .unwrap();
+        assert_eq!(ctx.memtotal_mb, 16383);
+        assert_eq!(
+            warnings,
+            [Warning::warn(
+                "test.conf: memtotal-rounding=ceil after a set! directive, ignoring; it must come before them."
+                    .to_string()
+            )]
         );
     }
 
     #[test]
-    fn test_eval_size_expression_500() {
+    fn test_parse_reserved_indices() {
+        assert_eq!(parse_reserved_indices("0-1").unwrap(), 0..=1);
+        assert_eq!(parse_reserved_indices("3").unwrap(), 3..=3);
+        assert_eq!(parse_reserved_indices(" 2 - 5 ").unwrap(), 2..=5);
+        assert_eq!(parse_reserved_indices("4-4").unwrap(), 4..=4);
+        assert!(parse_reserved_indices("").is_err());
+        assert!(parse_reserved_indices("2-1").is_err());
+        assert!(parse_reserved_indices("-1").is_err());
+        assert!(parse_reserved_indices("1-").is_err());
+        assert!(parse_reserved_indices("zram0-zram1").is_err());
+    }
+
+    #[test]
+    fn test_kernel_override_unconfigured_zram0() {
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "[zram1]\nzram-size = ram / 4\n",
+        )]);
+        let mut warnings = vec![];
+        let devices = read_devices_from(root.path(), true, &mut warnings).unwrap();
+        assert!(devices.contains_key("zram0"));
         assert_eq!(
-            dev_with_zram_size_size(Some("500"), 5000),
-            500 * 1024 * 1024
+            warnings,
+            [Warning::info(
+                "zram0: not configured, creating with default settings \
+                 because of systemd.zram on the kernel command line."
+                    .to_string()
+            )]
         );
+
+        /* Nothing to say if it is configured */
+        let root = include_root(&[("etc/systemd/zram-generator.conf", "[zram0]\n")]);
+        let mut warnings = vec![];
+        read_devices_from(root.path(), true, &mut warnings).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
     }
 
     #[test]
-    fn test_eval_size_expression_500k() {
+    fn test_reserved_indices_directive() {
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "reserved-indices = 1-2
+[zram0]
+[zram1]
+[zram3]
+",
+        )]);
+        let mut warnings = vec![];
+        let devices = read_devices_from(root.path(), true, &mut warnings).unwrap();
+        let mut names: Vec<_> = devices.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["zram1"]);
+        let warnings: Vec<_> = warnings.iter().map(Warning::to_string).collect();
         assert_eq!(
-            dev_with_zram_size_size(Some("500k"), 5000),
-            500 * 1000 * 1024 * 1024
+            warnings,
+            [
+                "zram0: outside of reserved-indices=1-2, ignoring.",
+                "zram3: outside of reserved-indices=1-2, ignoring.",
+                "zram0: outside of reserved-indices=1-2, not creating it for systemd.zram on the kernel command line.",
+            ]
         );
+        assert_eq!(read_reserved_indices(root.path()).unwrap(), Some(1..=2));
+
+        /* Without it, the kernel command line still gets zram0 */
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "[zram1]
+",
+        )]);
+        let devices = read_devices_from(root.path(), true, &mut vec![]).unwrap();
+        assert!(devices.contains_key("zram0"));
+        assert_eq!(read_reserved_indices(root.path()).unwrap(), None);
+
+        /* An empty value resets it */
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "reserved-indices = 1\nreserved-indices =\n[zram0]\n",
+        )]);
+        assert_eq!(read_reserved_indices(root.path()).unwrap(), None);
+
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "reserved-indices = 2-1\n",
+        )]);
+        assert!(read_reserved_indices(root.path()).is_err());
+
+        /* Broken devices don't matter, nor does meminfo; includes are followed */
+        let root = include_root(&[
+            (
+                "etc/systemd/zram-generator.conf",
+                "set!x = false\ninclude = /etc/r.conf\n[zram0]\nzram-size = ram +\nswap-priority = high\n",
+            ),
+            ("etc/r.conf", "reserved-indices = 3-4\ninclude = /etc/r.conf\n[zram1]\nfs-type = @x\n"),
+        ]);
+        fs::remove_file(root.path().join("proc/meminfo")).unwrap();
+        assert!(read_devices_from(root.path(), false, &mut vec![]).is_err());
+        assert_eq!(read_reserved_indices(root.path()).unwrap(), Some(3..=4));
     }
 
     #[test]
-    fn test_eval_size_expression_32g() {
+    fn test_module_directives() {
+        let read = |conf: &str| {
+            let root = include_root(&[("etc/systemd/zram-generator.conf", conf)]);
+            read_config_from(root.path(), false, &mut vec![]).map(|(_, globals)| globals)
+        };
+
+        assert_eq!(read("[zram0]\n").unwrap(), Globals::default());
+        let globals =
+            read("load-module = no\nmodule-options = num_devices=4  backend=lz4\n").unwrap();
+        assert!(!globals.load_module);
+        assert_eq!(globals.module_options, ["num_devices=4", "backend=lz4"]);
         assert_eq!(
-            dev_with_zram_size_size(Some("32G"), 5000),
-            32 * 1000_000_000 * 1024 * 1024
+            read("module-options = num_devices=4\nmodule-options =\n")
+                .unwrap()
+                .module_options,
+            Vec::<String>::new()
         );
+
+        for bad in [
+            "load-module = maybe\n",
+            "module-options = num_devices\n",
+            "module-options = num_devices=\n",
+            "module-options = =4\n",
+            "module-options = num-devices=4\n",
+        ] {
+            assert!(read(bad).is_err(), "{:?}", bad);
+        }
     }
 
     #[test]
-    fn test_eval_size_expression_default() {
-        assert_eq!(dev_with_zram_size_size(None, 100), 50 * 1024 * 1024);
-        assert_eq!(dev_with_zram_size_size(None, 10000), 4096 * 1024 * 1024);
+    fn test_load_fragment_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("90-foo.conf");
+        for (text, line) in [
+            ("[zram0]\nzram-size = ram\n[zram1\n", 3),
+            ("[zram0]\n\n\n\n\n\nzram-size = \"ram\n", 7),
+        ] {
+            fs::write(&path, text).unwrap();
+            let err = format!("{:#}", load_fragment(&path).unwrap_err());
+            assert!(
+                err.starts_with(&format!("{}: line {}: ", path.display(), line)),
+                "{}",
+                err
+            );
+        }
+
+        let err = format!(
+            "{:#}",
+            load_fragment(&dir.path().join("nonexistent.conf")).unwrap_err()
+        );
+        assert!(err.starts_with("Failed to read "), "{}", err);
+        assert!(err.contains("nonexistent.conf"), "{}", err);
     }
 
     #[test]
-    fn test_eval_size_expression_default_equivalent() {
+    fn test_config_error_variants() {
+        let read = |conf: &str| {
+            let root = include_root(&[("etc/systemd/zram-generator.conf", conf)]);
+            read_device(root.path(), false, "zram0").err().unwrap()
+        };
+
+        let err = read("[zram0]\nzram-size = ram\n[zram1\n");
+        assert!(matches!(err, ConfigError::Syntax(_)), "{:?}", err);
+        assert!(
+            err.message().contains("zram-generator.conf: line 3: "),
+            "{}",
+            err
+        );
+
+        let root = include_root(&[("etc/systemd/zram-generator.conf.d/90-x.json", "{")]);
+        let err = read_all_devices(root.path(), false).err().unwrap();
+        assert!(matches!(err, ConfigError::Syntax(_)), "{:?}", err);
+
+        let err = read("[zram0]\nzram-size = ram +\n");
+        assert!(matches!(err, ConfigError::Eval(_)), "{:?}", err);
+
+        let err = read("[zram0]\nswap-priority = high\n");
+        assert!(matches!(err, ConfigError::Invalid(_)), "{:?}", err);
         assert_eq!(
-            dev_with_zram_size_size(Some(DEFAULT_ZRAM_SIZE), 100),
-            50 * 1024 * 1024
+            err.to_string(),
+            "Failed to parse priority \"high\": invalid digit found in string"
+        );
+
+        let root = tempfile::tempdir().unwrap();
+        let err = explain_devices(root.path(), false).err().unwrap();
+        assert!(
+            matches!(
+                err,
+                ConfigError::Io {
+                    kind: io::ErrorKind::NotFound,
+                    ..
+                }
+            ),
+            "{:?}",
+            err
         );
+
+        /* Still usable with ? in anyhow */
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "reserved-indices = zram0\n",
+        )]);
+        let f = || -> Result<()> {
+            read_reserved_indices(root.path())?;
+            Ok(())
+        };
+        assert!(f().unwrap_err().is::<ConfigError>());
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let root = include_root(&[
+            ("etc/systemd/zram-generator.conf", "include = /etc/a.conf\n"),
+            ("etc/a.conf", "include = /etc/b.conf\n"),
+            ("etc/b.conf", "include = /etc/a.conf\n"),
+        ]);
+        let err = format!("{:#}", validate_config(root.path()).unwrap_err());
+        let r = root.path().display();
         assert_eq!(
-            dev_with_zram_size_size(Some(DEFAULT_ZRAM_SIZE), 10000),
-            4096 * 1024 * 1024
+            err,
+            format!(
+                "{r}/etc/b.conf: include=/etc/a.conf is a cycle: \
+                 {r}/etc/systemd/zram-generator.conf -> {r}/etc/a.conf -> {r}/etc/b.conf -> {r}/etc/a.conf"
+            )
         );
+
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "include = /etc/systemd/zram-generator.conf\n",
+        )]);
+        assert!(validate_config(root.path()).is_err());
     }
 
     #[test]
-    #[should_panic(expected = "Undefined(\"array\")")]
-    fn test_eval_size_expression_unknown_variable() {
-        dev_with_zram_size_size(Some("array(1,2)"), 100);
+    fn test_include_depth() {
+        let mut files = vec![];
+        let names: Vec<_> = (0..=MAX_INCLUDE_DEPTH + 1)
+            .map(|i| {
+                (
+                    format!("etc/{}.conf", i),
+                    format!("include = /etc/{}.conf\n", i + 1),
+                )
+            })
+            .collect();
+        files.push(("etc/systemd/zram-generator.conf", "include = /etc/0.conf\n"));
+        for (path, contents) in &names {
+            files.push((path, contents));
+        }
+        let root = include_root(&files);
+        let err = format!("{:#}", validate_config(root.path()).unwrap_err());
+        assert!(err.contains("nests more than 8 levels deep"), "{}", err);
     }
 
     #[test]
-    #[should_panic(expected = "zram-size=NaN")]
-    fn test_eval_size_expression_nan() {
-        dev_with_zram_size_size(Some("(ram-100)/0"), 100);
+    fn test_include_missing_relative() {
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "include = /etc/nonexistent.conf\ninclude = relative.conf\n[zram0]\n",
+        )]);
+        let warnings = validate_config(root.path()).unwrap();
+        let messages: Vec<_> = warnings.iter().map(|w| w.level).collect();
+        assert_eq!(messages, [Level::Info, Level::Warn], "{:?}", warnings);
     }
 
     #[test]
-    fn test_eval_size_expression_inf() {
-        assert_eq!(dev_with_zram_size_size(Some("(ram-99)/0"), 100), u64::MAX); // +∞
+    fn test_raw_device() {
+        let root = include_root(&[(
+            "etc/systemd/zram-generator.conf",
+            "[zram0]\nfs-type = none\n[zram1]\nfs-type = none\nmount-point = /mnt\n",
+        )]);
+        let err = format!("{:#}", validate_config(root.path()).unwrap_err());
+        assert_eq!(
+            err,
+            "zram1: fs-type=none can't be mounted, remove mount-point="
+        );
+
+        let mut dev = Device::new("zram0".to_string());
+        parse_line(&mut dev, "fs-type", "none", &mut vec![]).unwrap();
+        assert!(dev.is_raw());
+        assert!(!dev.is_swap());
+        assert_eq!(dev.effective_wanted_by(), ["local-fs.target"]);
     }
 
     #[test]
-    fn test_eval_size_expression_min() {
+    fn test_note_override() {
+        let root = include_root(&[
+            (
+                "usr/lib/systemd/zram-generator.conf",
+                "[zram0]\nzram-size = ram / 2\nwanted-by = a.target\nswap-priority = 10\n",
+            ),
+            (
+                "etc/systemd/zram-generator.conf.d/10-size.conf",
+                "[zram0]\nzram-size = 4096\nwanted-by = b.target\n",
+            ),
+            (
+                "etc/systemd/zram-generator.conf.d/20-same.conf",
+                "[zram0]\nswap-priority = 10\n",
+            ),
+        ]);
+        let warnings = validate_config(root.path()).unwrap();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert_eq!(warnings[0].level, Level::Info);
         assert_eq!(
-            dev_with_zram_size_size(Some("min(0.5 * ram, 4000)"), 3000),
-            1500 * 1024 * 1024
+            warnings[0].message,
+            format!(
+                "zram0: zram-size=ram / 2 from {0}/usr/lib/systemd/zram-generator.conf \
+                 overridden by zram-size=4096 from {0}/etc/systemd/zram-generator.conf.d/10-size.conf",
+                root.path().display()
+            )
         );
     }
 }
@@ -1,10 +1,13 @@
 /* SPDX-License-Identifier: MIT */
 
-use crate::config::Device;
+use crate::config::{self, Device, Globals};
+use crate::setup;
+use crate::units;
 use anyhow::{anyhow, Context, Result};
-use log::{debug, log, warn, Level};
+use log::{debug, info, log, warn, Level};
 use std::cmp;
 use std::collections::BTreeSet;
+use std::fmt::Write as _;
 use std::fs;
 use std::io::{self, Write};
 use std::os::unix::fs::symlink;
@@ -19,7 +22,12 @@ fn make_parent(of: &Path) -> Result<()> {
     Ok(())
 }
 
-fn make_symlink(dst: &str, src: &Path) -> Result<()> {
+fn make_symlink(dst: &str, src: &Path, dry_run: bool) -> Result<()> {
+    if dry_run {
+        info!("Would create symlink {}→{}", src.display(), dst);
+        return Ok(());
+    }
+
     make_parent(src)?;
     symlink(dst, src)
         .with_context(|| format!("Failed to create symlink {}→{}", src.display(), dst))?;
@@ -48,8 +56,22 @@ fn virtualization_container() -> Result<bool> {
     }
 }
 
+/// The arguments of modprobe for loading zram, or None with load-module=no
+fn zram_modprobe_args(globals: &Globals) -> Option<Vec<&str>> {
+    globals.load_module.then(|| {
+        std::iter::once("zram")
+            .chain(globals.module_options.iter().map(String::as_str))
+            .collect()
+    })
+}
+
 fn modprobe(modname: &str, required: bool) {
-    match Command::new("modprobe").arg(modname).status() {
+    modprobe_with(&[modname], required)
+}
+
+fn modprobe_with(args: &[&str], required: bool) {
+    let modname = args[0];
+    match Command::new("modprobe").args(args).status() {
         Err(e) => {
             let level = match !required && e.kind() == io::ErrorKind::NotFound {
                 true => Level::Debug,
@@ -71,8 +93,37 @@ fn modprobe(modname: &str, required: bool) {
     };
 }
 
-pub fn run_generator(devices: &[Device], output_directory: &Path, fake_mode: bool) -> Result<()> {
-    if devices.is_empty() {
+/// File systems which take the owner and mode of their files as mount options (uid=, gid=, umask=),
+/// and don't support chown/chmod
+const ID_MOUNT_OPTIONS_FS: &[&str] = &["vfat", "msdos", "exfat", "ntfs3"];
+
+/// Units of other tools which set up zram devices, for exclusive=
+const OTHER_ZRAM_SETUP_UNITS: &str = "zramswap.service zram-config.service";
+
+/// mount-owner=, mount-group=, and mount-mode= for systemd-tmpfiles(8), relative to the root
+const TMPFILES_PATH: &str = "run/tmpfiles.d/zram-generator.conf";
+
+/// swappiness= for systemd-sysctl(8), relative to the root
+const SYSCTL_PATH: &str = "run/sysctl.d/zram-generator.conf";
+
+/// Where systemctl and systemd-tmpfiles are, for the units which run them;
+/// a distribution installing them elsewhere sets SYSTEMD_BIN_DIR at build time
+pub const SYSTEMD_BIN_DIR: &str = match option_env!("SYSTEMD_BIN_DIR") {
+    Some(dir) => dir,
+    None => "/usr/bin",
+};
+
+pub fn run_generator(
+    devices: &[Device],
+    globals: &Globals,
+    output_directory: &Path,
+    root: &Path,
+    fake_mode: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let previous = setup::read_manifest(root)?;
+
+    if devices.is_empty() && previous.is_empty() {
         debug!("No devices configured, exiting.");
         return Ok(());
     }
@@ -83,23 +134,45 @@ pub fn run_generator(devices: &[Device], output_directory: &Path, fake_mode: boo
     }
 
     for device in devices {
-        handle_device(output_directory, device)?;
+        handle_device(output_directory, device, dry_run)?;
+        handle_recompress_timer(output_directory, device, dry_run)?;
+    }
+    if let (Some(profile), false) = (&globals.profile, devices.is_empty()) {
+        handle_profile(output_directory, profile, dry_run)?;
+    }
+
+    let stale = stale_devices(devices, &previous, &setup::list_zram_devices(root)?);
+    if !stale.is_empty() {
+        handle_stale_devices(output_directory, &stale, dry_run)?;
     }
+    write_tmpfiles(root, devices, dry_run)?;
+    write_sysctl(root, devices, dry_run)?;
 
-    if !devices.is_empty() && !fake_mode {
+    let modprobe_args = zram_modprobe_args(globals);
+    if !devices.is_empty() && dry_run {
+        match &modprobe_args {
+            Some(args) => info!(
+                "Would load the zram module (modprobe {}) and create devices up to /dev/zram{}",
+                args.join(" "),
+                max_device(devices)
+            ),
+            None => info!(
+                "Would create devices up to /dev/zram{} (load-module=no)",
+                max_device(devices)
+            ),
+        }
+    } else if !devices.is_empty() && !fake_mode {
         /* We created some units, let's make sure the module is loaded and the devices exist */
         if !Path::new("/sys/class/zram-control").exists() {
-            modprobe("zram", true);
+            match &modprobe_args {
+                Some(args) => modprobe_with(args, true),
+                None => warn!(
+                    "/sys/class/zram-control doesn't exist, but not loading the zram module (load-module=no)."
+                ),
+            }
         }
 
-        let max_device = devices
-            .iter()
-            .map(|device| {
-                device.name[4..]
-                    .parse()
-                    .expect("already verified in read_devices()")
-            })
-            .fold(0, cmp::max);
+        let max_device = max_device(devices);
 
         if !Path::new("/dev")
             .join(format!("zram{}", max_device))
@@ -122,7 +195,11 @@ pub fn run_generator(devices: &[Device], output_directory: &Path, fake_mode: boo
                 .compression_algorithms
                 .compression_algorithms
                 .iter()
-                .map(|(a, _)| a.as_ref())
+                .flat_map(|(a, _)| match a.as_ref() {
+                    /* Any of them might be picked at setup */
+                    "auto" => config::AUTO_COMPRESSION_ALGORITHMS.to_vec(),
+                    a => vec![a],
+                })
         })
         .collect();
 
@@ -134,13 +211,202 @@ pub fn run_generator(devices: &[Device], output_directory: &Path, fake_mode: boo
         let known = parse_known_compressors(&proc_crypto);
 
         for comp in compressors.difference(&known) {
-            modprobe(&format!("crypto-{}", comp), false);
+            match dry_run {
+                true => info!("Would load module crypto-{}", comp),
+                false => modprobe(&format!("crypto-{}", comp), false),
+            }
         }
     }
 
     Ok(())
 }
 
+/// For --all, on systems without systemd: write the units like the generator,
+/// and set up each device right away, instead of leaving that to systemd-zram-setup@.service,
+/// with the configuration read once for both. In fake mode, the steps are printed instead, like with --print-plan.
+pub fn run_generator_and_setup(
+    devices: Vec<Device>,
+    globals: &Globals,
+    output_directory: &Path,
+    root: &Path,
+    fake_mode: bool,
+) -> Result<()> {
+    run_generator(&devices, globals, output_directory, root, fake_mode, false)?;
+
+    for device in devices {
+        let name = device.name.clone();
+        match fake_mode {
+            true => setup::print_device_plan(Some(device), &name)?,
+            false => setup::run_device_setup(Some(device), &name)
+                .with_context(|| format!("Failed to set up {}", name))?,
+        }
+    }
+    Ok(())
+}
+
+fn max_device(devices: &[Device]) -> u64 {
+    devices
+        .iter()
+        .map(|device| {
+            device.name[4..]
+                .parse()
+                .expect("already verified in read_devices()")
+        })
+        .fold(0, cmp::max)
+}
+
+/// Devices we created earlier which are no longer configured, but still exist.
+/// Devices we didn't create are never touched.
+fn stale_devices(
+    devices: &[Device],
+    previous: &BTreeSet<String>,
+    existing: &[String],
+) -> Vec<String> {
+    existing
+        .iter()
+        .filter(|name| previous.contains(*name))
+        .filter(|name| !devices.iter().any(|dev| &dev.name == *name))
+        .cloned()
+        .collect()
+}
+
+/// The tmpfiles.d(5) line setting the owner and mode of the root of the file system on the device,
+/// for mount-owner=, mount-group=, and mount-mode= on file systems which don't take them as mount options
+fn tmpfiles_line(device: &Device) -> Option<String> {
+    if !device.has_mount_permissions() || ID_MOUNT_OPTIONS_FS.contains(&device.effective_fs_type())
+    {
+        return None;
+    }
+    let field = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    Some(format!(
+        "z {} {} {} {} -",
+        quote_path_arg(device.mount_point.as_ref()?.to_str()?),
+        field(device.mount_mode.map(|mode| format!("{:04o}", mode))),
+        field(device.mount_uid.map(|uid| uid.to_string())),
+        field(device.mount_gid.map(|gid| gid.to_string())),
+    ))
+}
+
+/// Quoting for a path in tmpfiles.d(5) and in path lists like RequiresMountsFor=:
+/// C-style escapes, and specifiers
+fn quote_path_arg(arg: &str) -> String {
+    let mut ret = String::with_capacity(arg.len() + 2);
+    ret.push('"');
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' => {
+                ret.push('\\');
+                ret.push(c);
+            }
+            '%' => ret.push_str("%%"),
+            _ => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// Picked up by systemd-tmpfiles-setup.service, which runs after local-fs.target, and by the
+/// zram-mount-permissions@.service of each device, whenever its file system is mounted
+fn write_tmpfiles(root: &Path, devices: &[Device], dry_run: bool) -> Result<()> {
+    let mut lines: Vec<_> = devices.iter().filter_map(tmpfiles_line).collect();
+    lines.sort_unstable();
+    write_run_config(&root.join(TMPFILES_PATH), &lines, dry_run)
+}
+
+/// vm.swappiness is global, and resolve_swappiness() made all swap devices agree on it,
+/// so it's set once, by systemd-sysctl.service at boot, rather than by each setup service
+fn write_sysctl(root: &Path, devices: &[Device], dry_run: bool) -> Result<()> {
+    let lines: Vec<_> = devices
+        .iter()
+        .filter(|dev| dev.is_swap())
+        .find_map(|dev| dev.swappiness)
+        .map(|swappiness| format!("vm.swappiness = {}", swappiness))
+        .into_iter()
+        .collect();
+    write_run_config(&root.join(SYSCTL_PATH), &lines, dry_run)
+}
+
+/// Write lines to a drop-in under /run, or remove the one from a previous run if there are none
+fn write_run_config(path: &Path, lines: &[String], dry_run: bool) -> Result<()> {
+    if lines.is_empty() {
+        if dry_run {
+            return Ok(());
+        }
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+            }
+            _ => Ok(()),
+        };
+    }
+
+    let mut contents = String::new();
+    for line in lines {
+        writeln!(contents, "{}", line).unwrap();
+    }
+    write_contents(
+        path.parent().unwrap(),
+        path.file_name().unwrap().to_str().unwrap(),
+        &contents,
+        dry_run,
+    )
+}
+
+fn handle_stale_devices(output_directory: &Path, stale: &[String], dry_run: bool) -> Result<()> {
+    let exe = std::env::current_exe().unwrap();
+
+    debug!(
+        "Creating unit file {} (resetting {})",
+        units::RESET_STALE_SERVICE,
+        stale.join(" ")
+    );
+
+    let mut setup_units = String::new();
+    let mut resets = String::new();
+    for name in stale {
+        write!(setup_units, " {}", units::setup_service(name)).unwrap();
+        writeln!(
+            resets,
+            "ExecStart=-{} --reset-device {}",
+            exe.display(),
+            name
+        )
+        .unwrap();
+    }
+
+    /* Stopping systemd-zram-setup@zramN.service also stops the swap or mount unit,
+     * and resets the device. The explicit resets cover the case where it wasn't running. */
+    write_contents(
+        output_directory,
+        units::RESET_STALE_SERVICE,
+        &format!(
+            "\
+[Unit]
+Description=Reset zram devices removed from the configuration
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+DefaultDependencies=no
+Conflicts={setup_units}
+After={setup_units}
+
+[Service]
+Type=oneshot
+{resets}",
+            setup_units = &setup_units[1..],
+            resets = resets,
+        ),
+        dry_run,
+    )?;
+
+    /* Devices set up in the initrd and not configured for the real root are reset at boot;
+     * after daemon-reload, the service is to be started by hand. */
+    let symlink_path = output_directory
+        .join("multi-user.target.wants")
+        .join(units::RESET_STALE_SERVICE);
+    let target_path = format!("../{}", units::RESET_STALE_SERVICE);
+    make_symlink(&target_path, &symlink_path, dry_run)
+}
+
 // Returns a list of names of loaded compressors
 fn parse_known_compressors(proc_crypto: &str) -> BTreeSet<&str> {
     // Extract algorithm names (this includes non-compression algorithms too)
@@ -151,8 +417,18 @@ fn parse_known_compressors(proc_crypto: &str) -> BTreeSet<&str> {
         .collect()
 }
 
-fn write_contents(output_directory: &Path, filename: &str, contents: &str) -> Result<()> {
+fn write_contents(
+    output_directory: &Path,
+    filename: &str,
+    contents: &str,
+    dry_run: bool,
+) -> Result<()> {
     let path = output_directory.join(filename);
+    if dry_run {
+        info!("Would write {}:\n{}", path.display(), contents.trim_end());
+        return Ok(());
+    }
+
     make_parent(&path)?;
 
     let contents = format!(
@@ -167,45 +443,248 @@ fn write_contents(output_directory: &Path, filename: &str, contents: &str) -> Re
     fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))
 }
 
-fn handle_device(output_directory: &Path, device: &Device) -> Result<()> {
+/// So that systemd-zram-setup@.service reads the same [zramN:profile] sections as the generator did,
+/// whichever way the profile was selected
+fn handle_profile(output_directory: &Path, profile: &str, dry_run: bool) -> Result<()> {
+    write_contents(
+        output_directory,
+        &format!("{}.d/profile.conf", units::setup_template()),
+        &format!(
+            "\
+[Service]
+Environment={}={}
+",
+            config::PROFILE_ENV_VAR,
+            profile
+        ),
+        dry_run,
+    )
+}
+
+fn handle_device(output_directory: &Path, device: &Device, dry_run: bool) -> Result<()> {
     if device.is_swap() {
-        handle_zram_swap(output_directory, device)
+        handle_zram_swap(output_directory, device, dry_run)
+    } else if device.is_raw() {
+        handle_zram_raw(output_directory, device, dry_run)
     } else {
-        handle_zram_mount_point(output_directory, device)
+        handle_zram_mount_point(output_directory, device, dry_run)
     }
 }
 
-fn handle_zram_bindings(output_directory: &Path, device: &Device, specific: &str) -> Result<()> {
-    let wb_unit = device
+fn handle_zram_bindings(
+    output_directory: &Path,
+    device: &Device,
+    specific: &str,
+    dry_run: bool,
+) -> Result<()> {
+    /* A partition given by its type is only found at setup time, so there's no device unit to wait for;
+     * one given by its UUID may not exist, which only costs write-back, so the setup mustn't depend on it. */
+    let wb_dev = device
         .writeback_dev
-        .as_ref()
+        .as_deref()
+        .filter(|wd| !config::is_partition_path(wd));
+    let wb_unit = wb_dev
         .map(|wd| unit_name_from_path(wd, ".device"))
         .unwrap_or_default();
 
+    /* With writeback-required=no, the device stays when the backing device goes away,
+     * and writing back fails instead. */
+    let (bound_wb_unit, wanted_wb_unit) = match device.writeback_required {
+        true => (&wb_unit[..], ""),
+        false => ("", &wb_unit[..]),
+    };
+    let binds: Vec<_> = [specific, bound_wb_unit]
+        .into_iter()
+        .filter(|u| !u.is_empty())
+        .collect();
+    if binds.is_empty() && wanted_wb_unit.is_empty() {
+        return Ok(());
+    }
+
+    let mut contents = String::from("[Unit]\n");
+    if !binds.is_empty() {
+        writeln!(contents, "BindsTo={}", binds.join(" ")).unwrap();
+    }
+    if !wanted_wb_unit.is_empty() {
+        writeln!(contents, "Wants={}", wanted_wb_unit).unwrap();
+    }
+    if wb_dev.is_some() {
+        writeln!(contents, "After={}", wb_unit).unwrap();
+    }
+
     /* systemd-zram-setup@.service.
      * We use the packaged unit, and only need to provide a small drop-in. */
     write_contents(
         output_directory,
-        &format!("systemd-zram-setup@{}.service.d/bindings.conf", device.name),
+        &format!("{}.d/bindings.conf", units::setup_service(&device.name)),
+        &contents,
+        dry_run,
+    )
+}
+
+fn handle_zram_service(output_directory: &Path, device: &Device, dry_run: bool) -> Result<()> {
+    let mut unit_settings = String::new();
+    let mut settings = String::new();
+    if device.exclusive {
+        /* Ordering makes systemd stop them before we set up the device. */
+        writeln!(unit_settings, "Conflicts={}", OTHER_ZRAM_SETUP_UNITS).unwrap();
+        writeln!(unit_settings, "After={}", OTHER_ZRAM_SETUP_UNITS).unwrap();
+    }
+    if let (Some(name), Some(_)) = (&device.writeback_crypttab_name, &device.writeback_dev) {
+        /* The device node only appears once unlocked, but BindsTo= on it doesn't wait for that to finish. */
+        let cryptsetup = format!("systemd-cryptsetup@{}.service", unit_name_escape(name));
+        writeln!(unit_settings, "Requires={}", cryptsetup).unwrap();
+        writeln!(unit_settings, "After={}", cryptsetup).unwrap();
+    }
+    if !device.after.is_empty() {
+        writeln!(unit_settings, "After={}", device.after.join(" ")).unwrap();
+    }
+    if !device.before.is_empty() {
+        writeln!(unit_settings, "Before={}", device.before.join(" ")).unwrap();
+    }
+    for condition in &device.conditions {
+        writeln!(unit_settings, "{}", condition).unwrap();
+    }
+    if let Some(ref timeout) = device.setup_timeout {
+        writeln!(settings, "TimeoutStartSec={}", timeout).unwrap();
+    }
+    if let Some(ref cpus) = device.cpu_affinity {
+        writeln!(settings, "CPUAffinity={}", cpus).unwrap();
+    }
+    if let Some(ref max) = device.setup_memory_max {
+        writeln!(settings, "MemoryMax={}", max).unwrap();
+    }
+    if let Some(ref max) = device.setup_tasks_max {
+        writeln!(settings, "TasksMax={}", max).unwrap();
+    }
+    if let Some(ref slice) = device.slice {
+        writeln!(settings, "Slice={}", slice).unwrap();
+    }
+    if let (Some(mount_point), true) = (&device.mount_point, device.create_mount_point) {
+        /* Don't create the directory underneath a file system that's yet to be mounted.
+         * (Not the mount point itself, since that's our own mount unit.) */
+        if let Some(parent) = mount_point.parent() {
+            writeln!(
+                unit_settings,
+                "RequiresMountsFor={}",
+                quote_path_arg(parent.to_str().unwrap())
+            )
+            .unwrap();
+        }
+        writeln!(
+            settings,
+            "ExecStartPre=/bin/mkdir -p {}",
+            quote_exec_arg(mount_point.to_str().unwrap())
+        )
+        .unwrap();
+    }
+    for command in &device.exec_start_post {
+        writeln!(settings, "ExecStartPost={}", command).unwrap();
+    }
+    /* Swap and mount units have no [Service], so these go here too; they run once the device is reset. */
+    for command in &device.exec_stop_post {
+        writeln!(settings, "ExecStopPost={}", command).unwrap();
+    }
+
+    if unit_settings.is_empty() && settings.is_empty() {
+        return Ok(());
+    }
+
+    /* Settings for the packaged systemd-zram-setup@.service itself. */
+    let mut contents = String::new();
+    if !unit_settings.is_empty() {
+        write!(contents, "[Unit]\n{}", unit_settings).unwrap();
+    }
+    if !settings.is_empty() {
+        if !contents.is_empty() {
+            contents.push('\n');
+        }
+        write!(contents, "[Service]\n{}", settings).unwrap();
+    }
+    write_contents(
+        output_directory,
+        &format!("{}.d/service.conf", units::setup_service(&device.name)),
+        &contents,
+        dry_run,
+    )
+}
+
+fn handle_recompress_timer(output_directory: &Path, device: &Device, dry_run: bool) -> Result<()> {
+    let interval = match (&device.recompress_interval, device.has_recompression()) {
+        (None, _) => return Ok(()),
+        (Some(_), false) => {
+            warn!(
+                "{}: recompress-interval= set, but no recompression algorithms configured, ignoring",
+                device.name
+            );
+            return Ok(());
+        }
+        (Some(interval), true) => interval,
+    };
+
+    let service_name = units::instance(units::RECOMPRESS_BASE, &device.name, "service");
+    let timer_name = units::instance(units::RECOMPRESS_BASE, &device.name, "timer");
+
+    debug!(
+        "Creating unit files {} and {} (every {})",
+        service_name, timer_name, interval
+    );
+
+    /* Pages marked idle by the previous run, and not accessed since, are recompressed,
+     * then all pages are marked idle for the next run. */
+    write_contents(
+        output_directory,
+        &service_name,
         &format!(
             "\
 [Unit]
-BindsTo={}{}{}{}{}
+Description=Recompress idle pages on /dev/{zram_device}
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+Requisite={setup_service}
+After={setup_service}
+
+[Service]
+Type=oneshot
+ExecStart=-/bin/sh -c 'echo type=idle >/sys/block/{zram_device}/recompress'
+ExecStart=/bin/sh -c 'echo all >/sys/block/{zram_device}/idle'
 ",
-            specific,
-            &" "[device.writeback_dev.is_none() as usize..],
-            wb_unit,
-            device
-                .writeback_dev
-                .as_ref()
-                .map(|_| "\nAfter=")
-                .unwrap_or_default(),
-            wb_unit,
+            zram_device = device.name,
+            setup_service = units::setup_service(&device.name),
         ),
-    )
+        dry_run,
+    )?;
+
+    write_contents(
+        output_directory,
+        &timer_name,
+        &format!(
+            "\
+[Unit]
+Description=Periodically recompress idle pages on /dev/{zram_device}
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+PartOf={setup_service}
+After={setup_service}
+
+[Timer]
+OnActiveSec={interval}
+OnUnitActiveSec={interval}
+",
+            zram_device = device.name,
+            setup_service = units::setup_service(&device.name),
+            interval = interval,
+        ),
+        dry_run,
+    )?;
+
+    /* started and stopped together with the device */
+    let symlink_path = output_directory
+        .join(format!("{}.wants", units::setup_service(&device.name)))
+        .join(&timer_name);
+    let target_path = format!("../{}", timer_name);
+    make_symlink(&target_path, &symlink_path, dry_run)
 }
 
-fn handle_zram_swap(output_directory: &Path, device: &Device) -> Result<()> {
+fn handle_zram_swap(output_directory: &Path, device: &Device, dry_run: bool) -> Result<()> {
     let swap_name = format!("dev-{}.swap", device.name);
 
     debug!(
@@ -215,7 +694,21 @@ fn handle_zram_swap(output_directory: &Path, device: &Device) -> Result<()> {
         device.disksize / 1024 / 1024
     );
 
-    handle_zram_bindings(output_directory, device, "dev-%i.swap")?;
+    handle_zram_bindings(output_directory, device, "dev-%i.swap", dry_run)?;
+    handle_zram_service(output_directory, device, dry_run)?;
+    warn_misplaced_options(device, true);
+    if device.hibernate_safe {
+        handle_hibernate_safe(output_directory, device, &swap_name, dry_run)?;
+    }
+
+    let makefs = makefs_dependency(output_directory, device, dry_run)?;
+
+    /* Like nofail in fstab: swap.target doesn't wait for the swap, nor fail with it */
+    let before_swap_target = if device.swap_nofail {
+        ""
+    } else {
+        "Before=swap.target\n"
+    };
 
     let shutdown_conflicts = if device.writeback_dev.is_some() {
         // We need to shut down the zram device to disconnect the writeback device.
@@ -237,32 +730,245 @@ fn handle_zram_swap(output_directory: &Path, device: &Device) -> Result<()> {
 Description=Compressed Swap on /dev/{zram_device}
 Documentation=man:zram-generator(8) man:zram-generator.conf(5)
 
-DefaultDependencies=no
+DefaultDependencies={default_dependencies}
 
-Requires=systemd-zram-setup@{zram_device}.service
-After=systemd-zram-setup@{zram_device}.service
-Before=swap.target
-{shutdown_conflicts}
+Requires={setup_service}
+After={setup_service}
+{makefs}{before_swap_target}{shutdown_conflicts}
 [Swap]
 What=/dev/{zram_device}
 Priority={swap_priority}
 Options={options}
 ",
             zram_device = device.name,
-            swap_priority = device.swap_priority,
-            options = device.options.replace('%', "%%"),
+            setup_service = units::setup_service(&device.name),
+            swap_priority = device.effective_swap_priority(),
+            options = expand_options(&device.options, &device.name),
             shutdown_conflicts = shutdown_conflicts,
+            makefs = makefs,
+            before_swap_target = before_swap_target,
+            default_dependencies = match device.default_dependencies {
+                Some(true) => "yes",
+                _ => "no",
+            },
         ),
+        dry_run,
     )?;
 
-    /* enablement symlink */
-    let symlink_path = output_directory.join("swap.target.wants").join(&swap_name);
-    let target_path = format!("../{}", swap_name);
-    make_symlink(&target_path, &symlink_path)?;
+    handle_enablement(
+        output_directory,
+        device,
+        &swap_name,
+        &format!("../{}", swap_name),
+        dry_run,
+    )
+}
+
+/// Requires= and After= on the unit formatting the device, for the swap or mount unit,
+/// if the setup service doesn't do it (lazy-makefs= or makefs-mode=systemd)
+fn makefs_dependency(output_directory: &Path, device: &Device, dry_run: bool) -> Result<String> {
+    if !setup::formats_separately(device) {
+        return Ok(String::new());
+    }
+    let makefs_name = handle_zram_makefs(output_directory, device, dry_run)?;
+    Ok(format!("Requires={}\nAfter={}\n", makefs_name, makefs_name))
+}
+
+/// The command formatting the device, quoted for ExecStart=
+fn makefs_exec_start(device: &Device) -> String {
+    let command = setup::makefs_step(device, &device.name)
+        .and_then(|step| step.command())
+        .expect("only for devices which are formatted");
+    let exec_start: Vec<_> = std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| quote_exec_arg(&arg.to_string_lossy()))
+        .collect();
+    exec_start.join(" ")
+}
 
+/// For lazy-makefs= and makefs-mode=systemd: format the device in its own unit, ordered between the setup service
+/// and the swap or mount unit, so that the setup service doesn't wait for it; recompress-now= follows the formatting.
+/// Returns the unit name.
+fn handle_zram_makefs(output_directory: &Path, device: &Device, dry_run: bool) -> Result<String> {
+    let makefs_name = units::instance(units::ZRAM_MAKEFS_BASE, &device.name, "service");
+
+    let mut recompress = String::new();
+    for step in setup::recompress_now_steps(device, &device.name) {
+        if let setup::Step::Trigger { path, data, .. } = step {
+            writeln!(
+                recompress,
+                "ExecStart=-/bin/sh -c 'echo {} >{}'",
+                data,
+                path.display()
+            )
+            .unwrap();
+        }
+    }
+
+    write_contents(
+        output_directory,
+        &makefs_name,
+        &format!(
+            "\
+[Unit]
+Description={what} on /dev/{zram_device}
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+DefaultDependencies=no
+BindsTo={setup_service}
+After={setup_service}
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={exec_start}
+{recompress}",
+            what = match device.is_swap() {
+                true => "Create swap",
+                false => "Make file system",
+            },
+            zram_device = device.name,
+            setup_service = units::setup_service(&device.name),
+            exec_start = makefs_exec_start(device),
+            recompress = recompress,
+        ),
+        dry_run,
+    )?;
+    Ok(makefs_name)
+}
+
+/// Enablement symlinks for the swap or mount unit (or the setup service, for raw devices),
+/// pointing at target_path
+fn handle_enablement(
+    output_directory: &Path,
+    device: &Device,
+    unit_name: &str,
+    target_path: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let wants = device
+        .effective_wanted_by()
+        .into_iter()
+        .map(|u| (u, "wants"));
+    /* swap-nofail=: nothing may require the swap */
+    let required = if device.swap_nofail {
+        "wants"
+    } else {
+        "requires"
+    };
+    let requires = device.required_by.iter().map(|u| (&u[..], required));
+    for (unit, kind) in wants.chain(requires) {
+        let symlink_path = output_directory
+            .join(format!("{}.{}", unit, kind))
+            .join(unit_name);
+        make_symlink(target_path, &symlink_path, dry_run)?;
+    }
     Ok(())
 }
 
+/// Quoting for a single argument of a command line as described in systemd.service(5)
+fn quote_exec_arg(arg: &str) -> String {
+    let mut ret = String::with_capacity(arg.len() + 2);
+    ret.push('"');
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' => {
+                ret.push('\\');
+                ret.push(c);
+            }
+            '%' => ret.push_str("%%"),
+            '$' => ret.push_str("$$"),
+            _ => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+/// Expands `%i` and `%N` to the device name and `%%` to `%` in options=,
+/// then escapes the result for use in a unit file
+fn expand_options(options: &str, device_name: &str) -> String {
+    let mut ret = String::with_capacity(options.len());
+    let mut chars = options.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some('i' | 'N')) => {
+                chars.next();
+                ret.push_str(device_name);
+            }
+            ('%', Some('%')) => {
+                chars.next();
+                ret.push('%');
+            }
+            ('%', next) => {
+                warn!(
+                    "{}: unknown specifier %{} in options={:?}, leaving as-is",
+                    device_name,
+                    next.map(String::from).unwrap_or_default(),
+                    options
+                );
+                ret.push('%');
+            }
+            _ => ret.push(c),
+        }
+    }
+    ret.replace('%', "%%")
+}
+
+/// Options swapon(8) and systemd.swap(5) understand, besides the x-* ones; see misplaced_options()
+const SWAP_OPTIONS: &[&str] = &[
+    "defaults",
+    "auto",
+    "noauto",
+    "nofail",
+    "discard",
+    "discard=once",
+    "discard=pages",
+];
+
+/// Options in options= which don't belong on a swap device (mount options, or unknown),
+/// or on a mount (pri= and the discard= policies of swapon(8), which file systems don't understand).
+/// discard itself is fine on both, even if it means online discard for a file system.
+fn misplaced_options(options: &str, swap: bool) -> Vec<&str> {
+    options
+        .split(',')
+        .filter(|o| !o.is_empty())
+        .filter(|o| match swap {
+            true => !o.starts_with("x-") && !o.starts_with("pri=") && !SWAP_OPTIONS.contains(o),
+            false => o.starts_with("pri=") || *o == "discard=once" || *o == "discard=pages",
+        })
+        .collect()
+}
+
+fn warn_misplaced_options(device: &Device, swap: bool) {
+    let misplaced = misplaced_options(&device.options, swap);
+    if !misplaced.is_empty() {
+        warn!(
+            "{}: options={} {} for a {}, passing on anyway (copied from a {}?)",
+            device.name,
+            misplaced.join(","),
+            match misplaced.len() {
+                1 => "is not an option",
+                _ => "are not options",
+            },
+            if swap { "swap device" } else { "mount" },
+            if swap { "mount" } else { "swap device" },
+        );
+    }
+}
+
+/// String escaping as described in systemd.unit(5), like systemd-escape(1) without --path
+fn unit_name_escape(s: &str) -> String {
+    let mut obuf = Vec::with_capacity(s.len());
+    for (i, &b) in s.as_bytes().iter().enumerate() {
+        match b {
+            b'.' if i == 0 => write!(obuf, "\\x{:02x}", b'.').unwrap(),
+            b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b':' | b'_' | b'.' => obuf.push(b),
+            _ => write!(obuf, "\\x{:02x}", b).unwrap(),
+        }
+    }
+    String::from_utf8(obuf).unwrap()
+}
+
 /// Path escaping as described in systemd.unit(5)
 ///
 /// `/./` components stripped away when parsing `mount-point =`
@@ -292,7 +998,7 @@ fn unit_name_from_path(path: &Path, suffix: &str) -> String {
     }
 }
 
-fn handle_zram_mount_point(output_directory: &Path, device: &Device) -> Result<()> {
+fn handle_zram_mount_point(output_directory: &Path, device: &Device, dry_run: bool) -> Result<()> {
     if device.mount_point.is_none() {
         /* In this case we don't need to generate any units. */
         return Ok(());
@@ -307,7 +1013,23 @@ fn handle_zram_mount_point(output_directory: &Path, device: &Device) -> Result<(
         device.disksize / 1024 / 1024
     );
 
-    handle_zram_bindings(output_directory, device, mount_name)?;
+    handle_zram_bindings(output_directory, device, mount_name, dry_run)?;
+    handle_zram_service(output_directory, device, dry_run)?;
+    warn_misplaced_options(device, false);
+
+    let mut options = expand_options(&device.options, &device.name);
+    if ID_MOUNT_OPTIONS_FS.contains(&device.effective_fs_type()) {
+        for option in id_mount_options(device) {
+            if !options.is_empty() {
+                options.push(',');
+            }
+            options.push_str(&option);
+        }
+    } else if device.has_mount_permissions() {
+        handle_mount_permissions(output_directory, device, mount_name, dry_run)?;
+    }
+
+    let makefs = makefs_dependency(output_directory, device, dry_run)?;
 
     write_contents(
         output_directory,
@@ -317,27 +1039,171 @@ fn handle_zram_mount_point(output_directory: &Path, device: &Device) -> Result<(
 [Unit]
 Description=Compressed Storage on /dev/{zram_device}
 Documentation=man:zram-generator(8) man:zram-generator.conf(5)
-Requires=systemd-zram-setup@{zram_device}.service
-After=systemd-zram-setup@{zram_device}.service
-
+{default_dependencies}Requires={setup_service}
+After={setup_service}
+{makefs}
 [Mount]
 What=/dev/{zram_device}
 Where={mount_point}
 Options={options}
 ",
             zram_device = device.name,
+            setup_service = units::setup_service(&device.name),
             mount_point = device.mount_point.as_ref().unwrap().to_str().unwrap(),
-            options = device.options.replace('%', "%%"),
+            options = options,
+            makefs = makefs,
+            default_dependencies = match device.default_dependencies {
+                Some(false) => "DefaultDependencies=no\n",
+                _ => "",
+            },
         ),
+        dry_run,
     )?;
 
-    /* enablement symlink */
+    handle_enablement(
+        output_directory,
+        device,
+        mount_name,
+        &format!("../{}", mount_name),
+        dry_run,
+    )
+}
+
+/// fs-type=none without mount-point=: the device is set up, but nothing uses it,
+/// so the setup service is what's enabled
+fn handle_zram_raw(output_directory: &Path, device: &Device, dry_run: bool) -> Result<()> {
+    let service_name = units::setup_service(&device.name);
+
+    debug!(
+        "Enabling {} (/dev/{} with {}MB, not formatted)",
+        service_name,
+        device.name,
+        device.disksize / 1024 / 1024
+    );
+
+    handle_zram_bindings(output_directory, device, "", dry_run)?;
+    handle_zram_service(output_directory, device, dry_run)?;
+
+    /* The packaged template, which isn't in the output directory; like systemd-getty-generator, link to where it's installed. */
+    handle_enablement(
+        output_directory,
+        device,
+        &service_name,
+        &units::setup_template_path(),
+        dry_run,
+    )
+}
+
+/// mount-owner=, mount-group=, and mount-mode= as mount options, see ID_MOUNT_OPTIONS_FS
+fn id_mount_options(device: &Device) -> Vec<String> {
+    let mut options = vec![];
+    if let Some(uid) = device.mount_uid {
+        options.push(format!("uid={}", uid));
+    }
+    if let Some(gid) = device.mount_gid {
+        options.push(format!("gid={}", gid));
+    }
+    if let Some(mode) = device.mount_mode {
+        options.push(format!("umask={:04o}", !mode & 0o777));
+    }
+    options
+}
+
+/// mount-owner=, mount-group=, and mount-mode= applied to the root of the file system once it's mounted,
+/// from the line in TMPFILES_PATH, so that they're applied again if it's mounted anew
+fn handle_mount_permissions(
+    output_directory: &Path,
+    device: &Device,
+    mount_name: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let unit_name = units::instance(units::MOUNT_PERMISSIONS_BASE, &device.name, "service");
+    let mount_point = quote_exec_arg(device.mount_point.as_ref().unwrap().to_str().unwrap());
+
+    write_contents(
+        output_directory,
+        &unit_name,
+        &format!(
+            "\
+[Unit]
+Description=Set owner and mode of the file system on /dev/{zram_device}
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+DefaultDependencies=no
+BindsTo={mount_name}
+After={mount_name}
+Before=local-fs.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={bin_dir}/systemd-tmpfiles --create --prefix {mount_point} /{tmpfiles_path}
+",
+            zram_device = device.name,
+            mount_name = mount_name,
+            bin_dir = SYSTEMD_BIN_DIR,
+            mount_point = mount_point,
+            tmpfiles_path = TMPFILES_PATH,
+        ),
+        dry_run,
+    )?;
+
+    /* started together with the mount */
     let symlink_path = output_directory
-        .join("local-fs.target.wants")
-        .join(mount_name);
-    let target_path = format!("../{}", mount_name);
-    make_symlink(&target_path, &symlink_path)?;
+        .join(format!("{}.wants", mount_name))
+        .join(&unit_name);
+    make_symlink(&format!("../{}", unit_name), &symlink_path, dry_run)
+}
+
+/// The services which write the hibernation image, see systemd-hibernate.service(8)
+pub const HIBERNATE_SERVICES: &[&str] = &[
+    "systemd-hibernate.service",
+    "systemd-hybrid-sleep.service",
+    "systemd-suspend-then-hibernate.service",
+];
+
+/// zram is lost on hibernation, so anything swapped out to it needs to move to a disk first.
+/// Pulled in by the hibernation services, this stops the swap before they run,
+/// and (being no longer needed after resuming) starts it again.
+fn handle_hibernate_safe(
+    output_directory: &Path,
+    device: &Device,
+    swap_name: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let unit_name = units::instance(units::HIBERNATE_BASE, &device.name, "service");
+
+    write_contents(
+        output_directory,
+        &unit_name,
+        &format!(
+            "\
+[Unit]
+Description=Deactivate Compressed Swap on /dev/{zram_device} for Hibernation
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+DefaultDependencies=no
+Before={hibernate_services}
+StopWhenUnneeded=yes
 
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={bin_dir}/systemctl stop {swap_name}
+ExecStop={bin_dir}/systemctl start --no-block {swap_name}
+",
+            zram_device = device.name,
+            bin_dir = SYSTEMD_BIN_DIR,
+            hibernate_services = HIBERNATE_SERVICES.join(" "),
+            swap_name = swap_name,
+        ),
+        dry_run,
+    )?;
+
+    for service in HIBERNATE_SERVICES {
+        let symlink_path = output_directory
+            .join(format!("{}.wants", service))
+            .join(&unit_name);
+        make_symlink(&format!("../{}", unit_name), &symlink_path, dry_run)?;
+    }
     Ok(())
 }
 
@@ -392,6 +1258,100 @@ type         : skcipher
     }
 
     #[test]
+    fn test_quote_path_arg() {
+        assert_eq!(quote_path_arg("/var/tmp"), "\"/var/tmp\"");
+        assert_eq!(quote_path_arg("/a b"), "\"/a b\"");
+        assert_eq!(quote_path_arg("/100%"), "\"/100%%\"");
+        assert_eq!(quote_path_arg("/$HOME"), "\"/$HOME\"");
+        assert_eq!(quote_path_arg("/\"q\"\\"), "\"/\\\"q\\\"\\\\\"");
+    }
+
+    #[test]
+    fn test_quote_exec_arg() {
+        assert_eq!(quote_exec_arg("/var/tmp"), "\"/var/tmp\"");
+        assert_eq!(quote_exec_arg("/a b"), "\"/a b\"");
+        assert_eq!(quote_exec_arg("/100%"), "\"/100%%\"");
+        assert_eq!(quote_exec_arg("/$HOME"), "\"/$$HOME\"");
+        assert_eq!(quote_exec_arg("/\"q\"\\"), "\"/\\\"q\\\"\\\\\"");
+    }
+
+    #[test]
+    fn test_zram_modprobe_args() {
+        assert_eq!(zram_modprobe_args(&Globals::default()), Some(vec!["zram"]));
+
+        let mut globals = Globals {
+            module_options: vec!["num_devices=4".into(), "backend=lz4".into()],
+            ..Globals::default()
+        };
+        assert_eq!(
+            zram_modprobe_args(&globals),
+            Some(vec!["zram", "num_devices=4", "backend=lz4"])
+        );
+        globals.load_module = false;
+        assert_eq!(zram_modprobe_args(&globals), None);
+    }
+
+    #[test]
+    fn test_misplaced_options() {
+        /* The default, and things which fit */
+        assert!(misplaced_options("discard", true).is_empty());
+        assert!(misplaced_options("discard", false).is_empty());
+        assert!(misplaced_options("", true).is_empty());
+        assert!(
+            misplaced_options("pri=100,discard=pages,nofail,x-systemd.makefs", true).is_empty()
+        );
+        assert!(misplaced_options(
+            "noatime,discard=async,uid=%i,x-systemd.device-timeout=5",
+            false
+        )
+        .is_empty());
+
+        /* Swap options on a mount */
+        assert_eq!(misplaced_options("discard,pri=100", false), ["pri=100"]);
+        assert_eq!(misplaced_options("discard=once", false), ["discard=once"]);
+        assert_eq!(
+            misplaced_options("discard=pages,noatime", false),
+            ["discard=pages"]
+        );
+
+        /* Mount options on swap */
+        assert_eq!(misplaced_options("discard,noatime", true), ["noatime"]);
+        assert_eq!(misplaced_options("ro,size=50%%", true), ["ro", "size=50%%"]);
+        assert_eq!(misplaced_options("uid=0,discard", true), ["uid=0"]);
+    }
+
+    #[test]
+    fn test_expand_options() {
+        assert_eq!(expand_options("discard", "zram0"), "discard");
+        assert_eq!(expand_options("", "zram0"), "");
+        assert_eq!(
+            expand_options("x-systemd.device-timeout=5,label=%i", "zram1"),
+            "x-systemd.device-timeout=5,label=zram1"
+        );
+        assert_eq!(expand_options("%N-%i", "zram2"), "zram2-zram2");
+        assert_eq!(expand_options("size=50%%", "zram0"), "size=50%%");
+        assert_eq!(expand_options("%%i", "zram0"), "%%i");
+        assert_eq!(expand_options("a%", "zram0"), "a%%");
+        assert_eq!(expand_options("%x", "zram0"), "%%x");
+    }
+
+    #[test]
+    fn test_handle_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        handle_profile(dir.path(), "battery", true).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        handle_profile(dir.path(), "battery", false).unwrap();
+        let dropin = dir
+            .path()
+            .join(format!("{}.d/profile.conf", units::setup_template()));
+        assert!(fs::read_to_string(dropin)
+            .unwrap()
+            .ends_with("\n[Service]\nEnvironment=ZRAM_GENERATOR_PROFILE=battery\n"));
+    }
+
+    #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_unit_name_from_path() {
         assert_eq!(
             unit_name_from_path(&Path::new("/waldo"), ".mount"),
@@ -413,4 +1373,12 @@ type         : skcipher
         assert_eq!(unit_name_from_path(&Path::new("//"), ".mount"), "-.mount");
         assert_eq!(unit_name_from_path(&Path::new("///"), ".mount"), "-.mount");
     }
+
+    #[test]
+    fn test_unit_name_escape() {
+        assert_eq!(unit_name_escape("wb"), "wb");
+        assert_eq!(unit_name_escape("zram-wb"), "zram\\x2dwb");
+        assert_eq!(unit_name_escape(".wb"), "\\x2ewb");
+        assert_eq!(unit_name_escape("a/b c"), "a\\x2fb\\x20c");
+    }
 }
@@ -0,0 +1,279 @@
+/* SPDX-License-Identifier: MIT */
+
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+/// The zram_* keys of systemd-swap's swap.conf, which is sourced by a shell
+#[derive(Debug, Default)]
+struct SystemdSwapZram {
+    enabled: bool,
+    /// zram-size= expression, for all devices together
+    size: Option<String>,
+    count: u64,
+    alg: Option<String>,
+    prio: Option<i32>,
+}
+
+/// Read a systemd-swap configuration and print the equivalent zram-generator.conf
+pub fn run_import_systemd_swap(path: &Path) -> Result<()> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    print!(
+        "{}",
+        import_systemd_swap(&path.display().to_string(), &contents)
+    );
+    Ok(())
+}
+
+/// What can't be translated is noted in comments, so that it's in front of whoever reviews the result
+fn import_systemd_swap(source: &str, contents: &str) -> String {
+    let mut notes = vec![];
+    let mut conf = SystemdSwapZram {
+        count: 1,
+        ..SystemdSwapZram::default()
+    };
+
+    for line in contents.lines() {
+        let Some((key, value)) = parse_shell_assignment(line) else {
+            continue;
+        };
+        match key {
+            "zram_enabled" => match value {
+                "1" | "yes" | "true" => conf.enabled = true,
+                "0" | "no" | "false" => conf.enabled = false,
+                _ => notes.push(format!("zram_enabled={}: not a boolean, ignored", value)),
+            },
+            "zram_size" => match translate_size(value) {
+                Some(size) => conf.size = Some(size),
+                None => notes.push(format!(
+                    "zram_size={}: can't be translated, using the default zram-size",
+                    value
+                )),
+            },
+            "zram_count" => match value.parse() {
+                Ok(count) if count > 0 => conf.count = count,
+                _ => notes.push(format!(
+                    "zram_count={}: not a fixed number of devices, creating one",
+                    value
+                )),
+            },
+            "zram_alg" if !value.is_empty() => conf.alg = Some(value.to_string()),
+            "zram_prio" => match value.parse() {
+                Ok(prio) if (-1..=32767).contains(&prio) => conf.prio = Some(prio),
+                _ => notes.push(format!("zram_prio={}: not a swap priority, ignored", value)),
+            },
+            "zram_streams" => notes.push(format!(
+                "zram_streams={}: ignored, the kernel uses one compression stream per CPU",
+                value
+            )),
+            _ if key.starts_with("zram_") => {
+                notes.push(format!("{}={}: no equivalent, ignored", key, value))
+            }
+            /* swapfc_*, zswap_*, ...: not about zram */
+            _ => {}
+        }
+    }
+
+    let mut out = format!(
+        "# Converted from {} by zram-generator --import-systemd-swap\n",
+        source
+    );
+    for note in &notes {
+        writeln!(out, "# {}", note).unwrap();
+    }
+    if !conf.enabled {
+        out.push_str("# zram_enabled is not set, so systemd-swap didn't use zram\n");
+        return out;
+    }
+
+    for i in 0..conf.count {
+        writeln!(out, "\n[zram{}]", i).unwrap();
+        match (&conf.size, conf.count) {
+            (Some(size), 1) => writeln!(out, "zram-size = {}", size).unwrap(),
+            /* systemd-swap splits zram_size between the devices */
+            (Some(size), count) => writeln!(out, "zram-size = ({}) / {}", size, count).unwrap(),
+            (None, _) => {}
+        }
+        if let Some(alg) = &conf.alg {
+            writeln!(out, "compression-algorithm = {}", alg).unwrap();
+        }
+        if let Some(prio) = conf.prio {
+            writeln!(out, "swap-priority = {}", prio).unwrap();
+        }
+    }
+    out
+}
+
+/// key and value of a "key=value" line, without quotes, export, or a trailing comment
+fn parse_shell_assignment(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_once('=')?;
+    if key.is_empty() || !key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+        return None;
+    }
+    let value = match value.chars().next() {
+        Some(q @ ('"' | '\'')) => value[1..].split(q).next().unwrap_or_default(),
+        _ => value.split_once(" #").map_or(value, |(v, _)| v).trim_end(),
+    };
+    Some((key, value))
+}
+
+/// zram_size as a zram-size= expression (in MB): a size in bytes with an optional K/M/G/T suffix,
+/// or arithmetic on RAM_SIZE (bytes) that scales, i.e. only multiplies and divides it
+fn translate_size(value: &str) -> Option<String> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len()),
+    );
+    if !number.is_empty() {
+        let n: u64 = number.parse().ok()?;
+        let mb = match unit {
+            /* Round up, so that a small size doesn't turn into 0, i.e. no device */
+            "" => n.div_ceil(1024 * 1024),
+            "K" | "k" => n.div_ceil(1024),
+            "M" | "m" => n,
+            "G" | "g" => n * 1024,
+            "T" | "t" => n * 1024 * 1024,
+            _ => return None,
+        };
+        return Some(mb.to_string());
+    }
+
+    let expr = value
+        .strip_prefix("$((")
+        .and_then(|e| e.strip_suffix("))"))
+        .unwrap_or(value)
+        .replace("${RAM_SIZE}", "ram")
+        .replace("$RAM_SIZE", "ram")
+        .replace("RAM_SIZE", "ram");
+    let scales = expr
+        .replace("ram", "")
+        .chars()
+        .all(|c| c.is_ascii_digit() || " */().".contains(c));
+    (expr.contains("ram") && scales).then(|| expr.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_shell_assignment() {
+        assert_eq!(
+            parse_shell_assignment("zram_alg=lz4"),
+            Some(("zram_alg", "lz4"))
+        );
+        assert_eq!(
+            parse_shell_assignment("  export zram_alg='zstd'  # faster"),
+            Some(("zram_alg", "zstd"))
+        );
+        assert_eq!(
+            parse_shell_assignment("zram_size=\"$(( RAM_SIZE / 4 ))\""),
+            Some(("zram_size", "$(( RAM_SIZE / 4 ))"))
+        );
+        assert_eq!(
+            parse_shell_assignment("zram_prio=32767 # highest"),
+            Some(("zram_prio", "32767"))
+        );
+        assert_eq!(parse_shell_assignment("# zram_enabled=1"), None);
+        assert_eq!(parse_shell_assignment(""), None);
+        assert_eq!(parse_shell_assignment("[ -f x ] && y=1"), None);
+    }
+
+    #[test]
+    fn test_translate_size() {
+        assert_eq!(
+            translate_size("$(( RAM_SIZE / 4 ))").as_deref(),
+            Some("ram / 4")
+        );
+        assert_eq!(
+            translate_size("${RAM_SIZE}*3/4").as_deref(),
+            Some("ram*3/4")
+        );
+        assert_eq!(translate_size("512M").as_deref(), Some("512"));
+        assert_eq!(translate_size("2G").as_deref(), Some("2048"));
+        assert_eq!(translate_size("1048576K").as_deref(), Some("1024"));
+        assert_eq!(translate_size("268435456").as_deref(), Some("256"));
+        /* Rounded up, not down to no device at all */
+        assert_eq!(translate_size("512K").as_deref(), Some("1"));
+        assert_eq!(translate_size("1048577").as_deref(), Some("2"));
+        /* Adding bytes to MB doesn't translate */
+        assert_eq!(translate_size("$(( RAM_SIZE / 4 + 1024 ))"), None);
+        assert_eq!(translate_size("$(( NCPU * 1024 ))"), None);
+        assert_eq!(translate_size("2X"), None);
+        assert_eq!(translate_size(""), None);
+    }
+
+    #[test]
+    fn test_import_systemd_swap() {
+        let old = "\
+# systemd-swap configuration
+zswap_enabled=0
+zram_enabled=1
+zram_size=$(( RAM_SIZE / 4 ))
+zram_count=2
+zram_streams=${NCPU}
+zram_alg=lz4
+zram_prio=32767
+zram_wat=1
+swapfc_enabled=1
+swapfc_chunk_size=256M
+";
+        assert_eq!(
+            import_systemd_swap("/etc/systemd/swap.conf", old),
+            "\
+# Converted from /etc/systemd/swap.conf by zram-generator --import-systemd-swap
+# zram_streams=${NCPU}: ignored, the kernel uses one compression stream per CPU
+# zram_wat=1: no equivalent, ignored
+
+[zram0]
+zram-size = (ram / 4) / 2
+compression-algorithm = lz4
+swap-priority = 32767
+
+[zram1]
+zram-size = (ram / 4) / 2
+compression-algorithm = lz4
+swap-priority = 32767
+"
+        );
+
+        assert_eq!(
+            import_systemd_swap(
+                "swap.conf",
+                "zram_enabled=1\nzram_count=${NCPU}\nzram_size=1G\n"
+            ),
+            "\
+# Converted from swap.conf by zram-generator --import-systemd-swap
+# zram_count=${NCPU}: not a fixed number of devices, creating one
+
+[zram0]
+zram-size = 1024
+"
+        );
+
+        assert_eq!(
+            import_systemd_swap("swap.conf", "zram_alg=lz4\nzswap_enabled=1\n"),
+            "\
+# Converted from swap.conf by zram-generator --import-systemd-swap
+# zram_enabled is not set, so systemd-swap didn't use zram
+"
+        );
+    }
+
+    #[test]
+    fn test_run_import_systemd_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(run_import_systemd_swap(&dir.path().join("swap.conf")).is_err());
+        fs::write(dir.path().join("swap.conf"), "zram_enabled=1\n").unwrap();
+        run_import_systemd_swap(&dir.path().join("swap.conf")).unwrap();
+    }
+}
@@ -1,5 +1,9 @@
 /* SPDX-License-Identifier: MIT */
 
+pub mod benchmark;
 pub mod config;
 pub mod generator;
+pub mod import;
 pub mod setup;
+pub mod stats;
+pub mod units;
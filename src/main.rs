@@ -1,9 +1,13 @@
 /* SPDX-License-Identifier: MIT */
 
+mod benchmark;
 mod config;
 mod generator;
+mod import;
 mod kernlog;
 mod setup;
+mod stats;
+mod units;
 
 use anyhow::Result;
 use log::{info, LevelFilter};
@@ -15,10 +19,30 @@ use std::path::{Path, PathBuf};
 enum Opts {
     /// Generate units into the directory
     GenerateUnits(String),
+    /// Log the units that would be generated into the directory
+    DryRun(String),
+    /// Generate units into the directory, and set up the devices
+    All(String),
     /// Set up a single device
     SetupDevice(String),
+    /// Print the steps which would set up a single device
+    PrintPlan(String),
     /// Reset (destroy) a device
     ResetDevice(String),
+    /// Reset (destroy) all zram devices
+    ResetAll,
+    /// Describe how the size of each device was determined
+    Explain,
+    /// Compare the compression algorithms on a sample
+    Benchmark(Option<String>),
+    /// Print how well the data on a device compresses
+    Stats(String, stats::StatsFormat),
+    /// Print how this binary was built, for bug reports
+    BuildInfo,
+    /// Compare the live devices with the configuration
+    CheckDrift,
+    /// Translate a systemd-swap configuration
+    ImportSystemdSwap(String),
 }
 
 #[rustfmt::skip]
@@ -26,37 +50,153 @@ fn command() -> clap::Command {
     clap::command!()
         .override_usage("\
             \tzram-generator --setup-device <device>\n\
+            \tzram-generator --print-plan <device>\n\
             \tzram-generator --reset-device <device>\n\
-            \tzram-generator dir1 [dir2 dir3]\
+            \tzram-generator --reset-all\n\
+            \tzram-generator --explain\n\
+            \tzram-generator --benchmark [sample]\n\
+            \tzram-generator --stats <device> [--format table|json|prometheus]\n\
+            \tzram-generator --build-info\n\
+            \tzram-generator --check-drift\n\
+            \tzram-generator --import-systemd-swap <file>\n\
+            \tzram-generator [--dry-run | --all] dir1 [dir2 dir3]\
         ")
         .arg(
             clap::arg!(--"setup-device" <device> "Set up a single device")
-                .conflicts_with("reset-device")
+                .conflicts_with_all(["print-plan", "reset-device", "reset-all", "explain"])
+        )
+        .arg(
+            clap::arg!(--"print-plan" <device> "Print the steps which would set up a single device")
+                .conflicts_with_all(["reset-device", "reset-all", "explain"])
         )
         .arg(
             clap::arg!(--"reset-device" <device> "Reset (destroy) a device")
+                .conflicts_with_all(["reset-all", "explain"])
+        )
+        .arg(
+            clap::arg!(--"reset-all" "Reset (destroy) all zram devices")
+                .conflicts_with("explain")
+        )
+        .arg(
+            clap::arg!(--"explain" "Describe why each device is or isn't created, and its size")
+                .conflicts_with("benchmark")
+        )
+        .arg(
+            clap::arg!(--"benchmark" [sample] "Compare the compression algorithms on a sample file (or a built-in one)")
+                .conflicts_with_all(["setup-device", "print-plan", "reset-device", "reset-all"])
+        )
+        .arg(
+            clap::arg!(--"stats" <device> "Print the compression statistics of a device")
+                .conflicts_with_all(["setup-device", "print-plan", "reset-device", "reset-all", "explain", "benchmark"])
+        )
+        .arg(
+            clap::arg!(--"format" <format> "How to print the statistics")
+                .value_parser(stats::StatsFormat::NAMES)
+                .requires("stats")
+        )
+        .arg(
+            clap::arg!(--"build-info" "Print the build configuration and supported configuration keys")
+                .conflicts_with_all(["setup-device", "print-plan", "reset-device", "reset-all", "explain", "benchmark", "stats"])
+        )
+        .arg(
+            clap::arg!(--"check-drift" "Compare the devices in /sys/block with the configuration, failing if they differ")
+                .conflicts_with_all(["setup-device", "print-plan", "reset-device", "reset-all", "explain", "benchmark", "stats", "build-info"])
+        )
+        .arg(
+            clap::arg!(--"import-systemd-swap" <file> "Print the zram-generator.conf equivalent to a systemd-swap configuration")
+                .conflicts_with_all(["setup-device", "print-plan", "reset-device", "reset-all", "explain", "benchmark", "stats", "build-info", "check-drift"])
+        )
+        .arg(
+            clap::arg!(--"dry-run" "Log the units that would be generated instead of writing them")
+                .conflicts_with_all(["setup-device", "print-plan", "reset-device", "reset-all", "explain", "benchmark", "stats", "build-info", "check-drift", "import-systemd-swap"])
+        )
+        .arg(
+            clap::arg!(--"all" "Also set up the devices right away, reading the configuration once, for systems without systemd")
+                .conflicts_with_all(["setup-device", "print-plan", "reset-device", "reset-all", "explain", "benchmark", "stats", "build-info", "check-drift", "import-systemd-swap", "dry-run"])
+        )
+        .arg(
+            clap::arg!(--"profile" <name> "Also read the [zramN:name] sections of the configuration")
         )
         .arg(
             clap::arg!([dir] "Target directory to write output to and two optional\n\
                               unused directories to satisfy systemd.generator(5)")
                 .num_args(1..=3)
-                .conflicts_with_all(["setup-device", "reset-device"])
-                .required_unless_present_any(["setup-device", "reset-device"])
+                .conflicts_with_all(["setup-device", "print-plan", "reset-device", "reset-all", "explain", "benchmark", "stats", "build-info", "check-drift", "import-systemd-swap", "format"])
+                .required_unless_present_any(["setup-device", "print-plan", "reset-device", "reset-all", "explain", "benchmark", "stats", "build-info", "check-drift", "import-systemd-swap"])
         )
         .after_help(setup::AFTER_HELP)
 }
 
-fn get_opts() -> Opts {
+fn get_opts() -> (Opts, Option<String>) {
     let opts = command().get_matches();
+    let profile = opts.get_one::<String>("profile").cloned();
 
-    if let Some(val) = opts.get_one::<String>("setup-device") {
+    let opts = if let Some(val) = opts.get_one::<String>("setup-device") {
         Opts::SetupDevice(val.clone())
+    } else if let Some(val) = opts.get_one::<String>("print-plan") {
+        Opts::PrintPlan(val.clone())
     } else if let Some(val) = opts.get_one::<String>("reset-device") {
         Opts::ResetDevice(val.clone())
+    } else if opts.get_flag("reset-all") {
+        Opts::ResetAll
+    } else if opts.get_flag("explain") {
+        Opts::Explain
+    } else if opts.contains_id("benchmark") {
+        Opts::Benchmark(opts.get_one::<String>("benchmark").cloned())
+    } else if let Some(val) = opts.get_one::<String>("stats") {
+        let format = opts
+            .get_one::<String>("format")
+            .map_or(stats::StatsFormat::Table, |f| {
+                stats::StatsFormat::parse(f).expect("clap invariant")
+            });
+        Opts::Stats(val.clone(), format)
+    } else if opts.get_flag("build-info") {
+        Opts::BuildInfo
+    } else if opts.get_flag("check-drift") {
+        Opts::CheckDrift
+    } else if let Some(val) = opts.get_one::<String>("import-systemd-swap") {
+        Opts::ImportSystemdSwap(val.clone())
     } else {
         let val = opts.get_one::<String>("dir").expect("clap invariant");
-        Opts::GenerateUnits(val.clone())
-    }
+        if opts.get_flag("dry-run") {
+            Opts::DryRun(val.clone())
+        } else if opts.get_flag("all") {
+            Opts::All(val.clone())
+        } else {
+            Opts::GenerateUnits(val.clone())
+        }
+    };
+    (opts, profile)
+}
+
+fn build_info(exec_allowed: Result<bool>) -> String {
+    format!(
+        "zram-generator {}\n\
+         systemd util dir: {}\n\
+         makefs: {}\n\
+         mkswap: {}\n\
+         systemd bin dir: {}\n\
+         setup service: {}\n\
+         set! programs: {}\n\
+         device keys: {}\n\
+         directives: {}\n",
+        clap::crate_version!(),
+        setup::SYSTEMD_UTIL_DIR,
+        setup::SYSTEMD_MAKEFS_COMMAND,
+        setup::MKSWAP_COMMAND,
+        generator::SYSTEMD_BIN_DIR,
+        units::setup_template(),
+        match exec_allowed {
+            Ok(true) => format!("allowed (${} is set)", config::ALLOW_EXEC_ENV_VAR),
+            Ok(false) => format!(
+                "not allowed (set ${}=1 to allow)",
+                config::ALLOW_EXEC_ENV_VAR
+            ),
+            Err(e) => format!("not allowed ({})", e),
+        },
+        config::DEVICE_KEYS.join(" "),
+        config::DIRECTIVE_KEYS.join(" "),
+    )
 }
 
 fn main() -> Result<()> {
@@ -75,22 +215,97 @@ fn main() -> Result<()> {
         None => false,
         Some(true) => true,
     };
+    /* Only the generator; units generated earlier must still be able to set up and reset devices */
+    let generator_override = || {
+        if config::disabled_by_env() {
+            info!("Disabled by $ZRAM_GENERATOR_DISABLE, exiting.");
+            std::process::exit(0);
+        }
+        kernel_override()
+    };
 
-    match get_opts() {
+    let (opts, profile) = get_opts();
+    if let Some(profile) = profile {
+        /* Read by config::active_profile(), and passed on to the setup units by the generator */
+        env::set_var(config::PROFILE_ENV_VAR, profile);
+    }
+
+    match opts {
         Opts::GenerateUnits(target) => {
-            let devices = config::read_all_devices(&root, kernel_override())?;
+            let (devices, globals) =
+                config::read_all_devices_with_globals(&root, generator_override())?;
             let output_directory = PathBuf::from(target);
-            generator::run_generator(&devices, &output_directory, have_env_var)
+            generator::run_generator(
+                &devices,
+                &globals,
+                &output_directory,
+                &root,
+                have_env_var,
+                false,
+            )
+        }
+        Opts::DryRun(target) => {
+            let (devices, globals) =
+                config::read_all_devices_with_globals(&root, generator_override())?;
+            let output_directory = PathBuf::from(target);
+            generator::run_generator(
+                &devices,
+                &globals,
+                &output_directory,
+                &root,
+                have_env_var,
+                true,
+            )
+        }
+        Opts::All(target) => {
+            let (devices, globals) =
+                config::read_all_devices_with_globals(&root, generator_override())?;
+            let output_directory = PathBuf::from(target);
+            generator::run_generator_and_setup(
+                devices,
+                &globals,
+                &output_directory,
+                &root,
+                have_env_var,
+            )
         }
         Opts::SetupDevice(dev) => {
             let device = config::read_device(&root, kernel_override(), &dev)?;
             setup::run_device_setup(device, &dev)
         }
+        Opts::PrintPlan(dev) => {
+            let device = config::read_device(&root, kernel_override(), &dev)?;
+            setup::print_device_plan(device, &dev)
+        }
         Opts::ResetDevice(dev) => {
             // We don't read the config here, so that it's possible to remove a device
             // even after the config has been removed.
-            setup::run_device_reset(&dev)
+            setup::run_device_reset(&root, &dev)
+        }
+        Opts::ResetAll => {
+            let reserved = config::read_reserved_indices(&root)?;
+            setup::run_reset_all(&root, reserved)
+        }
+        Opts::Explain => {
+            for device in config::explain_devices(&root, kernel_override())? {
+                println!("{}:", device.name);
+                for line in &device.explanation {
+                    println!("  {}", line);
+                }
+            }
+            Ok(())
+        }
+        Opts::Benchmark(sample) => benchmark::run_benchmark(sample.as_deref().map(Path::new)),
+        Opts::Stats(dev, format) => stats::run_stats(&root, &dev, format),
+        Opts::BuildInfo => {
+            print!("{}", build_info(config::exec_allowed()));
+            Ok(())
         }
+        Opts::CheckDrift => {
+            let devices = config::read_all_devices(&root, kernel_override())?;
+            setup::run_check_drift(&root, &devices)
+        }
+        Opts::ImportSystemdSwap(file) => import::run_import_systemd_swap(Path::new(&file)),
     }
 }
 
@@ -115,6 +330,13 @@ mod tests {
         assert_eq!(m.get_one::<String>("reset-device").unwrap(), "/dev/zram1");
     }
 
+    #[test]
+    fn parse_reset_all() {
+        let m = command().get_matches_from(vec!["prog", "--reset-all"]);
+        assert!(m.get_flag("reset-all"));
+        assert!(m.get_one::<String>("dir").is_none());
+    }
+
     #[test]
     fn parse_with_dir() {
         let m = command().get_matches_from(vec!["prog", "/dir1"]);
@@ -123,6 +345,210 @@ mod tests {
         assert_eq!(m.get_one::<String>("dir").unwrap(), "/dir1");
     }
 
+    #[test]
+    fn parse_all() {
+        let m = command().get_matches_from(vec!["prog", "--all", "/dir1"]);
+        assert!(m.get_flag("all"));
+        assert_eq!(m.get_one::<String>("dir").unwrap(), "/dir1");
+
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--all"])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--all", "--dry-run", "/dir1"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_profile() {
+        let m = command().get_matches_from(vec!["prog", "--profile", "battery", "/dir1"]);
+        assert_eq!(m.get_one::<String>("profile").unwrap(), "battery");
+        assert_eq!(m.get_one::<String>("dir").unwrap(), "/dir1");
+
+        let m =
+            command().get_matches_from(vec!["prog", "--setup-device", "zram0", "--profile", "ac"]);
+        assert_eq!(m.get_one::<String>("profile").unwrap(), "ac");
+
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--profile"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_print_plan() {
+        let m = command().get_matches_from(vec!["prog", "--print-plan", "zram0"]);
+        assert_eq!(m.get_one::<String>("print-plan").unwrap(), "zram0");
+        assert!(m.get_one::<String>("dir").is_none());
+
+        assert!(command()
+            .try_get_matches_from(vec![
+                "prog",
+                "--print-plan",
+                "zram0",
+                "--setup-device",
+                "zram0"
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_benchmark() {
+        let m = command().get_matches_from(vec!["prog", "--benchmark"]);
+        assert!(m.contains_id("benchmark"));
+        assert!(m.get_one::<String>("benchmark").is_none());
+
+        let m = command().get_matches_from(vec!["prog", "--benchmark", "/tmp/sample"]);
+        assert_eq!(m.get_one::<String>("benchmark").unwrap(), "/tmp/sample");
+
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--benchmark", "--reset-all"])
+            .is_err());
+        let m = command().get_matches_from(vec!["prog", "/dir1"]);
+        assert!(!m.contains_id("benchmark"));
+    }
+
+    #[test]
+    fn parse_stats() {
+        let m = command().get_matches_from(vec!["prog", "--stats", "zram0"]);
+        assert_eq!(m.get_one::<String>("stats").unwrap(), "zram0");
+        assert!(m.get_one::<String>("dir").is_none());
+
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--stats"])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--stats", "zram0", "/dir1"])
+            .is_err());
+
+        assert!(m.get_one::<String>("format").is_none());
+        let m =
+            command().get_matches_from(vec!["prog", "--stats", "zram0", "--format", "prometheus"]);
+        assert_eq!(m.get_one::<String>("format").unwrap(), "prometheus");
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--stats", "zram0", "--format", "xml"])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--format", "json", "/dir1"])
+            .is_err());
+        let err = command()
+            .try_get_matches_from(vec!["prog", "--format", "json"])
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::MissingRequiredArgument);
+        assert!(err.to_string().contains("--stats <device>"), "{}", err);
+    }
+
+    #[test]
+    fn parse_import_systemd_swap() {
+        let m = command().get_matches_from(vec![
+            "prog",
+            "--import-systemd-swap",
+            "/etc/systemd/swap.conf",
+        ]);
+        assert_eq!(
+            m.get_one::<String>("import-systemd-swap").unwrap(),
+            "/etc/systemd/swap.conf"
+        );
+        assert!(m.get_one::<String>("dir").is_none());
+
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--import-systemd-swap"])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--import-systemd-swap", "swap.conf", "/dir1"])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from(vec![
+                "prog",
+                "--import-systemd-swap",
+                "swap.conf",
+                "--stats",
+                "zram0"
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_build_info() {
+        let m = command().get_matches_from(vec!["prog", "--build-info"]);
+        assert!(m.get_flag("build-info"));
+        assert!(m.get_one::<String>("dir").is_none());
+
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--build-info", "/dir1"])
+            .is_err());
+        /* clap's own --version is still there */
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--version"])
+            .is_err_and(|e| e.kind() == clap::error::ErrorKind::DisplayVersion));
+    }
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info(Ok(false));
+        let lines: Vec<_> = info.lines().collect();
+        assert_eq!(
+            lines[0],
+            concat!("zram-generator ", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(
+            lines[2],
+            format!("makefs: {}/systemd-makefs", setup::SYSTEMD_UTIL_DIR)
+        );
+        assert_eq!(lines[3], format!("mkswap: {}", setup::MKSWAP_COMMAND));
+        assert_eq!(
+            lines[4],
+            format!("systemd bin dir: {}", generator::SYSTEMD_BIN_DIR)
+        );
+        assert_eq!(
+            lines[5],
+            format!("setup service: {}@.service", units::SETUP_SERVICE_BASE)
+        );
+        assert_eq!(
+            lines[6],
+            "set! programs: not allowed (set $ZRAM_GENERATOR_ALLOW_EXEC=1 to allow)"
+        );
+        assert!(lines[7].contains(" zram-size "), "{}", lines[7]);
+        assert!(lines[8].contains("include"), "{}", lines[8]);
+
+        assert!(build_info(Ok(true)).contains("set! programs: allowed"));
+    }
+
+    #[test]
+    fn parse_check_drift() {
+        let m = command().get_matches_from(vec!["prog", "--check-drift"]);
+        assert!(m.get_flag("check-drift"));
+        assert!(m.get_one::<String>("dir").is_none());
+
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--check-drift", "/dir1"])
+            .is_err());
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--check-drift", "--setup-device", "zram0"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_explain() {
+        let m = command().get_matches_from(vec!["prog", "--explain"]);
+        assert!(m.get_flag("explain"));
+        assert!(m.get_one::<String>("dir").is_none());
+
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--explain", "/dir1"])
+            .is_err());
+    }
+
+    #[test]
+    fn parse_dry_run() {
+        let m = command().get_matches_from(vec!["prog", "--dry-run", "/dir1"]);
+        assert!(m.get_flag("dry-run"));
+        assert_eq!(m.get_one::<String>("dir").unwrap(), "/dir1");
+
+        assert!(command()
+            .try_get_matches_from(vec!["prog", "--dry-run", "--reset-all"])
+            .is_err());
+    }
+
     #[test]
     fn parse_with_dirs() {
         let m = command().get_matches_from(vec!["prog", "/dir1", "/dir2", "/dir3"]);
@@ -1,14 +1,21 @@
 /* SPDX-License-Identifier: MIT */
 
-use crate::config::Device;
+use crate::config::{self, Device, MakefsMode};
+use crate::units;
 use anyhow::{anyhow, Context, Result};
-use log::warn;
+use log::{debug, info, warn};
+use std::collections::BTreeSet;
+use std::fmt;
 use std::fs;
+use std::io;
 use std::io::ErrorKind;
+use std::ops::RangeInclusive;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::process::ExitStatusExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub const SYSTEMD_MAKEFS_COMMAND: &str = concat!(
     env!(
@@ -18,33 +25,161 @@ pub const SYSTEMD_MAKEFS_COMMAND: &str = concat!(
     ),
     "/systemd-makefs"
 );
+/// For --build-info; SYSTEMD_MAKEFS_COMMAND checks that it's defined
+pub const SYSTEMD_UTIL_DIR: &str = env!("SYSTEMD_UTIL_DIR");
+/// For swap-page-size=, which systemd-makefs can't pass on;
+/// looked up in $PATH, unless MKSWAP is set to e.g. /usr/sbin/mkswap at build time
+pub const MKSWAP_COMMAND: &str = match option_env!("MKSWAP") {
+    Some(mkswap) => mkswap,
+    None => "mkswap",
+};
+/// What run_device_setup() did to each device, for persist-across-daemon-reload=;
+/// /run survives the switch from the initrd, and run_device_reset() removes the record
+const SETUP_RECORD_DIR: &str = "run/zram-generator/setup";
+/// The devices run_device_setup() set up and run_device_reset() hasn't reset since, one file each;
+/// the generator resets only these when they're removed from the configuration
+const MANIFEST_DIR: &str = "run/zram-generator/devices";
 /// A constant string for use in clap --help output.
 #[rustfmt::skip]
 pub const AFTER_HELP: &str = concat!(
     "Uses ", env!("SYSTEMD_UTIL_DIR"), "/systemd-makefs", "."
 );
 
-pub fn run_device_setup(device: Option<Device>, device_name: &str) -> Result<()> {
-    let device = device.ok_or_else(|| anyhow!("Device {} not found", device_name))?;
+/// One step of setting up a device, see plan_device_setup()
+#[derive(Debug, PartialEq, Eq)]
+pub enum Step {
+    /// Write into comp_algorithm (prio 0) or recomp_algorithm;
+    /// an unknown algorithm or missing recompression support only cause a warning,
+    /// as does failing to write the parameters (into algorithm_params or recompress)
+    Algorithm {
+        path: PathBuf,
+        data: String,
+        algo: String,
+        prio: usize,
+        params: Option<(PathBuf, String)>,
+    },
+    /// Write into an attribute not all kernels have; if it's missing, warn with unsupported
+    Optional {
+        path: PathBuf,
+        data: Vec<u8>,
+        what: &'static str,
+        unsupported: String,
+    },
+    /// Write the global recompression parameters into recompress; failing only causes a warning
+    Recompress { path: PathBuf, data: String },
+    /// Write into an attribute to make the kernel do something;
+    /// if it's missing, or the write fails, only warn
+    Trigger {
+        path: PathBuf,
+        data: String,
+        what: &'static str,
+    },
+    /// Write into an attribute, failing if that fails
+    Write {
+        path: PathBuf,
+        data: String,
+        what: &'static str,
+    },
+    /// Format the device
+    Makefs { fs_type: String, device: PathBuf },
+    /// Format the device as swap for a different page size, with mkswap itself
+    Mkswap { page_size: u32, device: PathBuf },
+}
 
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Algorithm {
+                path, data, params, ..
+            } => {
+                write!(f, "write {} {:?}", path.display(), data)?;
+                if let Some((path, data)) = params {
+                    write!(f, "\nwrite {} {:?}", path.display(), data)?;
+                }
+                Ok(())
+            }
+            Step::Optional { path, data, .. } => write!(
+                f,
+                "write {} {:?} (if supported)",
+                path.display(),
+                String::from_utf8_lossy(data)
+            ),
+            Step::Trigger { path, data, .. } => {
+                write!(f, "write {} {:?} (if supported)", path.display(), data)
+            }
+            Step::Recompress { path, data } | Step::Write { path, data, .. } => {
+                write!(f, "write {} {:?}", path.display(), data)
+            }
+            Step::Makefs { .. } | Step::Mkswap { .. } => {
+                let command = self.command().expect("Makefs and Mkswap have commands");
+                write!(f, "exec {}", command.get_program().to_string_lossy())?;
+                for arg in command.get_args() {
+                    write!(f, " {}", arg.to_string_lossy())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Step {
+    /// The program the step runs, if any
+    pub fn command(&self) -> Option<Command> {
+        match self {
+            Step::Makefs { fs_type, device } => {
+                let mut command = Command::new(SYSTEMD_MAKEFS_COMMAND);
+                command.arg(fs_type).arg(device);
+                Some(command)
+            }
+            Step::Mkswap { page_size, device } => {
+                let mut command = Command::new(MKSWAP_COMMAND);
+                command
+                    .arg("--pagesize")
+                    .arg(page_size.to_string())
+                    .arg(device);
+                Some(command)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The writes into /sys/block/<device_name> and the makefs call which set up the device, in order.
+/// Doesn't touch the system.
+pub fn plan_device_setup(device: &Device, device_name: &str) -> Vec<Step> {
     let device_sysfs_path = Path::new("/sys/block").join(device_name);
+    let mut plan = vec![];
 
-    for (prio, (algo, params)) in device
-        .compression_algorithms
+    for (prio, algo, params) in device
         .compression_algorithms
-        .iter()
-        .enumerate()
+        .with_priorities()
+        .expect("verified in read_devices()")
     {
+        if prio == 0 && algo == "auto" {
+            debug!(
+                "{}: compression-algorithm=auto, picked when the device is set up",
+                device_name
+            );
+            continue;
+        }
+        if prio != 0 && !device.recompression {
+            debug!(
+                "{}: recompression disabled, not configuring {:?}",
+                device_name, algo
+            );
+            continue;
+        }
+
         let params = if params.is_empty() {
             None
         } else {
             Some(params)
         };
-        let (path, data, add_pathdata) = if prio == 0 {
+        let (path, data, params) = if prio == 0 {
             (
                 device_sysfs_path.join("comp_algorithm"),
-                algo,
-                params.as_ref().map(|p| {
+                algo.to_string(),
+                params.map(|p| {
                     (
                         device_sysfs_path.join("algorithm_params"),
                         format!("algo={} {}", algo, p),
@@ -54,8 +189,8 @@ pub fn run_device_setup(device: Option<Device>, device_name: &str) -> Result<()>
         } else {
             (
                 device_sysfs_path.join("recomp_algorithm"),
-                &format!("algo={} priority={}", algo, prio),
-                params.as_ref().map(|p| {
+                format!("algo={} priority={}", algo, prio),
+                params.map(|p| {
                     (
                         device_sysfs_path.join("recompress"),
                         format!("{} priority={}", p, prio),
@@ -63,96 +198,1480 @@ pub fn run_device_setup(device: Option<Device>, device_name: &str) -> Result<()>
                 }),
             )
         };
+        plan.push(Step::Algorithm {
+            path,
+            data,
+            algo: algo.to_string(),
+            prio,
+            params,
+        });
+    }
 
-        match fs::write(&path, data) {
-            Ok(_) => {
-                if let Some((add_path, add_data)) = add_pathdata {
-                    match fs::write(add_path, add_data) {
-                        Ok(_) => {}
-                        Err(err) => {
-                            warn!(
-                                "Warning: algorithm {:?} supplemental data {:?} not written: {}",
-                                algo, data, err,
-                            );
-                        }
-                    }
-                }
+    if let Some(ref wb_dev) = device.writeback_dev {
+        let writeback_path = device_sysfs_path.join("backing_dev");
+        plan.push(Step::Optional {
+            unsupported: format!(
+                "Warning: writeback-device={} set for {}, but system doesn't support write-back. Ignoring.",
+                writeback_path.display(),
+                device_name
+            ),
+            path: writeback_path,
+            data: wb_dev.as_os_str().as_bytes().to_vec(),
+            what: "write-back device",
+        });
+    }
+
+    if let Some(block_size) = device.block_size {
+        plan.push(Step::Optional {
+            path: device_sysfs_path.join("block_size"),
+            data: format!("{}", block_size).into_bytes(),
+            what: "block size",
+            unsupported: format!(
+                "Warning: block-size={} set for {}, but system doesn't support setting the block size. Ignoring.",
+                block_size, device_name
+            ),
+        });
+    }
+
+    plan.push(Step::Write {
+        path: device_sysfs_path.join("mem_limit"),
+        data: format!("{}", device.mem_limit),
+        what: "resident memory limit",
+    });
+
+    plan.push(Step::Write {
+        path: device_sysfs_path.join("disksize"),
+        data: format!("{}", device.disksize),
+        what: "disk size",
+    });
+
+    /* The kernel only accepts this once the device is initialised, i.e. has a disksize. */
+    let global = &device.compression_algorithms.recompression_global;
+    if !global.is_empty() {
+        if device.has_recompression() {
+            plan.push(Step::Recompress {
+                path: device_sysfs_path.join("recompress"),
+                data: global.clone(),
+            });
+        } else {
+            debug!(
+                "{}: no recompression algorithms, not configuring global recompression parameters {:?}",
+                device_name, global
+            );
+        }
+    }
+
+    /* With lazy-makefs= or makefs-mode=systemd, a unit of its own does it instead, see the generator,
+     * and recompresses the device afterwards. */
+    if !formats_separately(device) {
+        plan.extend(makefs_step(device, device_name));
+        plan.extend(recompress_now_steps(device, device_name));
+    }
+
+    plan
+}
+
+/// lazy-makefs= or makefs-mode=systemd: systemd-zram-makefs@.service formats the device, not the setup service
+pub fn formats_separately(device: &Device) -> bool {
+    device.lazy_makefs || device.makefs_mode == MakefsMode::Systemd
+}
+
+/// recompress-now=, after formatting, so that whatever makefs put on the device is included
+pub fn recompress_now_steps(device: &Device, device_name: &str) -> Vec<Step> {
+    let mut steps = vec![];
+    if device.recompress_now == 0 {
+        return steps;
+    }
+    if !device.has_recompression() {
+        warn!(
+            "Warning: recompress-now= set for {}, but no recompression algorithms configured. Ignoring.",
+            device_name
+        );
+        return steps;
+    }
+
+    let device_sysfs_path = Path::new("/sys/block").join(device_name);
+    let data = match &device.compression_algorithms.recompression_global[..] {
+        "" => "type=idle".to_string(),
+        global => global.to_string(),
+    };
+    for _ in 0..device.recompress_now {
+        steps.push(Step::Trigger {
+            path: device_sysfs_path.join("idle"),
+            data: "all".to_string(),
+            what: "idle marking",
+        });
+        steps.push(Step::Trigger {
+            path: device_sysfs_path.join("recompress"),
+            data: data.clone(),
+            what: "recompression",
+        });
+    }
+    steps
+}
+
+/// Formatting the device, unless it's raw
+pub fn makefs_step(device: &Device, device_name: &str) -> Option<Step> {
+    if let (Some(page_size), true) = (device.swap_page_size, device.is_swap()) {
+        Some(Step::Mkswap {
+            page_size,
+            device: Path::new("/dev").join(device_name),
+        })
+    } else if !device.is_raw() {
+        Some(Step::Makefs {
+            fs_type: device.effective_fs_type().to_string(),
+            device: Path::new("/dev").join(device_name),
+        })
+    } else {
+        None
+    }
+}
+
+pub fn run_device_setup(device: Option<Device>, device_name: &str) -> Result<()> {
+    let mut device = device.ok_or_else(|| anyhow!("Device {} not found", device_name))?;
+
+    wait_for_device(Path::new("/"), device_name, device.device_wait)?;
+    if let Some(wb_dev) = device.writeback_dev.take() {
+        device.writeback_dev = resolve_writeback_device(Path::new("/"), device_name, &wb_dev);
+    }
+    for problem in find_zram_on_zram(Path::new("/"), &device, device_name) {
+        warn!("Warning: {}", problem);
+    }
+    if is_set_up_as_recorded(Path::new("/"), &device, device_name) {
+        info!(
+            "{}: already set up as configured, not setting it up again (persist-across-daemon-reload=)",
+            device_name
+        );
+        return Ok(());
+    }
+    let device_sysfs_path = Path::new("/sys/block").join(device_name);
+    if let Some((algo, _)) = device
+        .compression_algorithms
+        .compression_algorithms
+        .first_mut()
+        .filter(|(algo, _)| algo == "auto")
+    {
+        let path = device_sysfs_path.join("comp_algorithm");
+        let available = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let candidates = config::auto_compression_algorithms(Path::new("/"))?;
+        /* Otherwise, the plan skips it and the kernel's default stays */
+        if let Some(picked) = pick_auto_algorithm(device_name, &candidates, &available, |algo| {
+            fs::write(&path, algo)
+        }) {
+            *algo = picked.to_string();
+        }
+    }
+    apply_steps(&plan_device_setup(&device, device_name), device_name)?;
+    write_setup_record(Path::new("/"), &device, device_name);
+    write_manifest_entry(Path::new("/"), &device, device_name);
+    Ok(())
+}
+
+/// Everything setting up the device writes, i.e. the plan (with compression-algorithm=auto not picked yet);
+/// None if the device isn't to be kept, or is formatted by a unit of its own, which would run again anyway
+fn setup_record(device: &Device, device_name: &str) -> Option<String> {
+    if !device.persist_across_daemon_reload || formats_separately(device) {
+        return None;
+    }
+    let mut record = String::new();
+    for step in plan_device_setup(device, device_name) {
+        record.push_str(&step.to_string());
+        record.push('\n');
+    }
+    Some(record)
+}
+
+/// Whether the last setup of the device applied the same plan, and the device hasn't been reset since
+/// (by run_device_reset(), which removes the record, or by anything else, which clears disksize)
+pub fn is_set_up_as_recorded(root: &Path, device: &Device, device_name: &str) -> bool {
+    let Some(record) = setup_record(device, device_name) else {
+        return false;
+    };
+    let path = root.join(SETUP_RECORD_DIR).join(device_name);
+    match fs::read_to_string(&path) {
+        Ok(recorded) if recorded == record => {}
+        Ok(_) => {
+            debug!("{}: configuration changed since it was set up", device_name);
+            return false;
+        }
+        Err(_) => return false,
+    }
+    read_attribute(&root.join("sys/block").join(device_name), "disksize")
+        == Some(device.disksize.to_string())
+}
+
+/// Only warns on failure: the device is set up, and will merely be set up again next time
+pub fn write_setup_record(root: &Path, device: &Device, device_name: &str) {
+    let dir = root.join(SETUP_RECORD_DIR);
+    let path = dir.join(device_name);
+    let result = match setup_record(device, device_name) {
+        Some(record) => fs::create_dir_all(&dir).and_then(|()| fs::write(&path, record)),
+        None => remove_record(&path),
+    };
+    if let Err(err) = result {
+        warn!("Warning: failed to write {}: {}", path.display(), err);
+    }
+}
+
+/// Only warns on failure: the device merely won't be reset if it's removed from the configuration
+fn write_manifest_entry(root: &Path, device: &Device, device_name: &str) {
+    let dir = root.join(MANIFEST_DIR);
+    let path = dir.join(device_name);
+    let entry = format!("{} {}\n", device.disksize, device.effective_fs_type());
+    if let Err(err) = fs::create_dir_all(&dir).and_then(|()| fs::write(&path, entry)) {
+        warn!("Warning: failed to write {}: {}", path.display(), err);
+    }
+}
+
+/// The names of the devices in the manifest, i.e. set up since boot (or in the initrd) and not reset since
+pub fn read_manifest(root: &Path) -> Result<BTreeSet<String>> {
+    let dir = root.join(MANIFEST_DIR);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", dir.display())),
+    };
+
+    let mut devices = BTreeSet::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to list {}", dir.display()))?;
+        if let Some(name) = entry.file_name().to_str() {
+            if let Some(Ok(_)) = name.strip_prefix("zram").map(str::parse::<u64>) {
+                devices.insert(name.to_string());
+            }
+        }
+    }
+    Ok(devices)
+}
+
+fn remove_record(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// compression-algorithm = auto: the first of the candidates (AUTO_COMPRESSION_ALGORITHMS permitted by policy.conf)
+/// in the comp_algorithm list which can be selected; the kernel only allocates the compression streams when it's selected,
+/// so one failing with ENOMEM (as lz4 on some kernels) is passed over for the next.
+fn pick_auto_algorithm(
+    device_name: &str,
+    candidates: &[&'static str],
+    available: &str,
+    mut select: impl FnMut(&str) -> io::Result<()>,
+) -> Option<&'static str> {
+    let available: BTreeSet<_> = available
+        .split_whitespace()
+        .map(|algo| algo.trim_matches(|c| c == '[' || c == ']'))
+        .collect();
+    for &algo in candidates {
+        if !available.contains(algo) {
+            continue;
+        }
+        match select(algo) {
+            Ok(()) => {
+                info!(
+                    "{}: compression-algorithm=auto picked {}.",
+                    device_name, algo
+                );
+                return Some(algo);
             }
-            Err(err) if err.kind() == ErrorKind::InvalidInput => {
+            Err(err) if err.kind() == ErrorKind::OutOfMemory => {
                 warn!(
-                    "Warning: algorithm {:?} not recognised; consult {} for a list of available ones",
-                    algo, path.display(),
+                    "Warning: {}: compression algorithm {} can't be allocated, trying the next one: {}",
+                    device_name, algo, err
                 );
             }
-            Err(err) if err.kind() == ErrorKind::PermissionDenied && prio != 0 => {
+            Err(err) => {
                 warn!(
-                    "Warning: recompression algorithm {:?} requested but recompression not available ({} doesn't exist)",
-                    algo, path.display(),
+                    "Warning: {}: failed to select compression algorithm {}: {}",
+                    device_name, algo, err
                 );
+                return None;
             }
-            err @ Err(_) => err.with_context(|| {
-                format!(
-                    "Failed to configure compression algorithm into {}",
-                    path.display()
-                )
-            })?,
         }
     }
+    info!(
+        "{}: compression-algorithm=auto found none of {} usable, keeping the kernel's default.",
+        device_name,
+        candidates.join(", ")
+    );
+    None
+}
 
-    if let Some(ref wb_dev) = device.writeback_dev {
-        let writeback_path = device_sysfs_path.join("backing_dev");
-        if writeback_path.exists() {
-            fs::write(&writeback_path, wb_dev.as_os_str().as_bytes()).with_context(|| {
-                format!(
-                    "Failed to configure write-back device into {}",
-                    writeback_path.display()
-                )
-            })?;
-        } else {
-            warn!("Warning: writeback-device={} set for {}, but system doesn't support write-back. Ignoring.", writeback_path.display(), device_name)
+/// The zram device a block device is, or is stacked on (through device mapper, md, and the like),
+/// following /sys/class/block/<name>/slaves
+fn zram_underneath(root: &Path, name: &str) -> Option<String> {
+    let class_block = root.join("sys/class/block");
+    let mut todo = vec![name.to_string()];
+    let mut seen = BTreeSet::new();
+    while let Some(name) = todo.pop() {
+        if name
+            .strip_prefix("zram")
+            .is_some_and(|i| i.parse::<u64>().is_ok())
+        {
+            return Some(name);
+        }
+        if !seen.insert(name.clone()) || seen.len() > 64 {
+            continue;
+        }
+        if let Ok(slaves) = fs::read_dir(class_block.join(&name).join("slaves")) {
+            todo.extend(slaves.filter_map(|e| e.ok()?.file_name().into_string().ok()));
         }
     }
+    None
+}
 
-    let resident_memory = device_sysfs_path.join("mem_limit");
-    fs::write(&resident_memory, format!("{}", device.mem_limit)).with_context(|| {
-        format!(
-            "Failed to configure resident memory limit into {}",
-            resident_memory.display()
-        )
-    })?;
+/// The kernel name of the block device at path (under root), following symlinks like /dev/mapper/*
+fn block_device_name(root: &Path, path: &Path) -> Option<String> {
+    let resolved = fs::canonicalize(root.join(path.strip_prefix("/").unwrap_or(path))).ok()?;
+    resolved.file_name()?.to_str().map(str::to_string)
+}
+
+/// The block device holding the file system path is on, from /proc/self/mountinfo
+fn mount_source_device(root: &Path, path: &Path) -> Option<String> {
+    let mountinfo = fs::read_to_string(root.join("proc/self/mountinfo")).ok()?;
+    let (_, dev) = mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let dev = fields.nth(2)?;
+            let mount_point = fields.nth(1)?;
+            Some((mount_point, dev))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| Path::new(mount_point).components().count())?;
+    let target = fs::read_link(root.join("sys/dev/block").join(dev)).ok()?;
+    target.file_name()?.to_str().map(str::to_string)
+}
 
-    let disksize_path = device_sysfs_path.join("disksize");
-    fs::write(&disksize_path, format!("{}", device.disksize)).with_context(|| {
+/// Writing back into compressed memory, or mounting onto a file system in it, is almost always a mistake.
+/// The configuration refuses the obvious cases; this finds the ones only visible on the running system.
+pub fn find_zram_on_zram(root: &Path, device: &Device, device_name: &str) -> Vec<String> {
+    let mut problems = vec![];
+    if let Some(wb_dev) = &device.writeback_dev {
+        if let Some(zram) = block_device_name(root, wb_dev).and_then(|n| zram_underneath(root, &n))
+        {
+            problems.push(format!(
+                "{}: writeback-device={} is on {}, write-back would go into compressed memory.",
+                device_name,
+                wb_dev.display(),
+                zram
+            ));
+        }
+    }
+    if let Some(parent) = device.mount_point.as_deref().and_then(Path::parent) {
+        if let Some(zram) = mount_source_device(root, parent)
+            .and_then(|n| zram_underneath(root, &n))
+            .filter(|zram| zram != device_name)
+        {
+            problems.push(format!(
+                "{}: mount-point={} is underneath a file system on {}.",
+                device_name,
+                device.mount_point.as_ref().unwrap().display(),
+                zram
+            ));
+        }
+    }
+    problems
+}
+
+/// writeback-device=PARTTYPE= is looked up among the partitions udev knows,
+/// and must match exactly one; partitions named by PARTUUID= must exist, and neither may be a zram device.
+/// Failures are only warned about: the device works without write-back.
+pub fn resolve_writeback_device(root: &Path, device_name: &str, wb_dev: &Path) -> Option<PathBuf> {
+    /* Paths were checked by config::verify_writeback_device() */
+    if !config::is_partition_path(wb_dev) {
+        return Some(wb_dev.to_path_buf());
+    }
+    let found = find_writeback_partition(root, device_name, wb_dev)?;
+    if let Some(zram) = config::zram_device_behind(root, &found) {
+        warn!(
+            "Warning: {}: writeback-device={} is a zram device ({}), ignoring.",
+            device_name,
+            found.display(),
+            zram.display()
+        );
+        return None;
+    }
+    Some(found)
+}
+
+fn find_writeback_partition(root: &Path, device_name: &str, wb_dev: &Path) -> Option<PathBuf> {
+    let dir = Path::new(config::BY_PARTTYPEUUID);
+    if config::is_partition_type_path(wb_dev) {
+        let type_guid = wb_dev.file_name().unwrap().to_string_lossy();
+        let prefix = format!("{}.", type_guid);
+        let mut found: Vec<_> = fs::read_dir(root.join(dir.strip_prefix("/").unwrap()))
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .filter(|name| name.starts_with(&prefix))
+            .collect();
+        found.sort_unstable();
+        return match &found[..] {
+            [name] => Some(dir.join(name)),
+            [] => {
+                warn!(
+                    "Warning: {}: no partition of type {} for writeback-device=, ignoring.",
+                    device_name, type_guid
+                );
+                None
+            }
+            _ => {
+                warn!(
+                    "Warning: {}: several partitions of type {} for writeback-device= ({}), ignoring.",
+                    device_name,
+                    type_guid,
+                    found.join(", ")
+                );
+                None
+            }
+        };
+    }
+
+    if !root.join(wb_dev.strip_prefix("/").unwrap()).exists() {
+        warn!(
+            "Warning: {}: writeback-device={} doesn't exist, ignoring.",
+            device_name,
+            wb_dev.display()
+        );
+        return None;
+    }
+    Some(wb_dev.to_path_buf())
+}
+
+pub fn print_device_plan(device: Option<Device>, device_name: &str) -> Result<()> {
+    let device = device.ok_or_else(|| anyhow!("Device {} not found", device_name))?;
+
+    for step in plan_device_setup(&device, device_name) {
+        println!("{}", step);
+    }
+    Ok(())
+}
+
+/// Apply the plan; the recompression algorithms either all take effect, or that is warned about once,
+/// since a part of the list leaves the device recompressing differently from what was configured
+fn apply_steps(steps: &[Step], device_name: &str) -> Result<()> {
+    let mut written = vec![];
+    let mut skipped = vec![];
+    for step in steps {
+        match step {
+            Step::Algorithm {
+                path,
+                data,
+                algo,
+                prio,
+                params,
+            } if *prio != 0 => {
+                if apply_algorithm(path, data, algo, *prio, params, device_name)? {
+                    written.push(&algo[..]);
+                } else {
+                    skipped.push(&algo[..]);
+                }
+            }
+            _ => apply_step(step, device_name)?,
+        }
+    }
+    if let Some(warning) = partial_recompression_warning(device_name, &written, &skipped) {
+        warn!("{}", warning);
+    }
+    Ok(())
+}
+
+fn partial_recompression_warning(
+    device_name: &str,
+    written: &[&str],
+    skipped: &[&str],
+) -> Option<String> {
+    (!written.is_empty() && !skipped.is_empty()).then(|| {
         format!(
-            "Failed to configure disk size into {}",
-            disksize_path.display()
+            "Warning: {}: only recompression algorithm(s) {} configured, without {}; \
+             recompression doesn't follow the configured list",
+            device_name,
+            written.join(" "),
+            skipped.join(" ")
         )
-    })?;
-
-    let fs_type = device.effective_fs_type();
-    match Command::new(SYSTEMD_MAKEFS_COMMAND).arg(fs_type).arg(Path::new("/dev").join(device_name)).status() {
-        Ok(status) =>
-            match status.code() {
-                Some(0) => Ok(()),
-                Some(code) => Err(anyhow!("{} failed with exit code {}", SYSTEMD_MAKEFS_COMMAND, code)),
-                None => Err(anyhow!("{} terminated by signal {}",
-                                    SYSTEMD_MAKEFS_COMMAND,
-                                    status.signal().expect("on unix, status status.code() is None iff status.signal() isn't; \
-                                                            this expect() will never panic, save for an stdlib bug"))),
-            },
-        Err(e) =>
-            Err(e).with_context(|| {
-                format!(
-                    "{} call failed for /dev/{}",
-                    SYSTEMD_MAKEFS_COMMAND,
-                    device_name
-                )
-            }),
+    })
+}
+
+/// Ok(false) if the algorithm wasn't configured, with a warning
+fn apply_algorithm(
+    path: &Path,
+    data: &str,
+    algo: &str,
+    prio: usize,
+    params: &Option<(PathBuf, String)>,
+    device_name: &str,
+) -> Result<bool> {
+    match write_algorithm(path, data, algo, prio, device_name) {
+        Ok(_) => {
+            if prio == 0 {
+                verify_comp_algorithm(path, algo);
+            }
+            if let Some((add_path, add_data)) = params {
+                match fs::write(add_path, add_data) {
+                    Ok(_) if prio == 0 => verify_algorithm_params(add_path, algo, add_data),
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!(
+                            "Warning: algorithm {:?} supplemental data {:?} not written: {}",
+                            algo, data, err,
+                        );
+                    }
+                }
+            }
+            Ok(true)
+        }
+        Err(err) if err.kind() == ErrorKind::InvalidInput => {
+            warn!(
+                "Warning: algorithm {:?} not recognised; consult {} for a list of available ones",
+                algo,
+                path.display(),
+            );
+            Ok(false)
+        }
+        Err(err) if err.kind() == ErrorKind::PermissionDenied && prio != 0 => {
+            warn!(
+                "Warning: recompression algorithm {:?} requested but recompression not available ({} doesn't exist)",
+                algo, path.display(),
+            );
+            Ok(false)
+        }
+        Err(err) => Err(err).with_context(|| {
+            format!(
+                "Failed to configure compression algorithm into {}",
+                path.display()
+            )
+        }),
+    }
+}
+
+fn apply_step(step: &Step, device_name: &str) -> Result<()> {
+    match step {
+        Step::Algorithm {
+            path,
+            data,
+            algo,
+            prio,
+            params,
+        } => apply_algorithm(path, data, algo, *prio, params, device_name).map(|_| ()),
+        Step::Optional {
+            path,
+            data,
+            what,
+            unsupported,
+        } => {
+            if path.exists() {
+                fs::write(path, data).with_context(|| {
+                    format!("Failed to configure {} into {}", what, path.display())
+                })
+            } else {
+                warn!("{}", unsupported);
+                Ok(())
+            }
+        }
+        Step::Recompress { path, data } => {
+            if let Err(err) = fs::write(path, data) {
+                warn!(
+                    "Warning: global recompression parameters {:?} not written into {}: {}",
+                    data,
+                    path.display(),
+                    err
+                );
+            }
+            Ok(())
+        }
+        Step::Trigger { path, data, what } => {
+            if !path.exists() {
+                warn!(
+                    "Warning: {} requested for {}, but {} doesn't exist. Ignoring.",
+                    what,
+                    device_name,
+                    path.display()
+                );
+            } else if let Err(err) = fs::write(path, data) {
+                warn!(
+                    "Warning: {} {:?} not written into {}: {}",
+                    what,
+                    data,
+                    path.display(),
+                    err
+                );
+            }
+            Ok(())
+        }
+        Step::Write { path, data, what } => fs::write(path, data)
+            .with_context(|| format!("Failed to configure {} into {}", what, path.display())),
+        Step::Makefs { .. } | Step::Mkswap { .. } => {
+            let mut command = step.command().expect("Makefs and Mkswap have commands");
+            let program = command.get_program().to_string_lossy().into_owned();
+            match command.status() {
+                Ok(status) =>
+                    match status.code() {
+                        Some(0) => Ok(()),
+                        Some(code) => Err(anyhow!("{} failed with exit code {}", program, code)),
+                        None => Err(anyhow!("{} terminated by signal {}",
+                                            program,
+                                            status.signal().expect("on unix, status status.code() is None iff status.signal() isn't; \
+                                                                    this expect() will never panic, save for an stdlib bug"))),
+                    },
+                Err(e) =>
+                    Err(e).with_context(|| {
+                        format!(
+                            "{} call failed for /dev/{}",
+                            program,
+                            device_name
+                        )
+                    }),
+            }
+        }
+    }
+}
+
+/// Write data into comp_algorithm or recomp_algorithm, unless (for comp_algorithm) algo is already selected:
+/// re-running setup on an unchanged device then doesn't write it again
+fn write_algorithm(
+    path: &Path,
+    data: &str,
+    algo: &str,
+    prio: usize,
+    device_name: &str,
+) -> io::Result<()> {
+    if prio == 0 && algorithm_already_selected(fs::read_to_string(path).ok().as_deref(), algo) {
+        debug!(
+            "{}: compression algorithm {:?} already selected, not writing {}",
+            device_name,
+            algo,
+            path.display()
+        );
+        return Ok(());
     }
+    fs::write(path, data)
 }
 
-pub fn run_device_reset(device_name: &str) -> Result<()> {
-    let reset = Path::new("/sys/block").join(device_name).join("reset");
+/// Whether the comp_algorithm readback, if any, has algo selected
+fn algorithm_already_selected(current: Option<&str>, algo: &str) -> bool {
+    current.and_then(selected_algorithm) == Some(algo)
+}
+
+/// How often wait_for_device() looks for the device
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Right after modprobe zram, /sys/block/zramN may lag behind, so poll for it for up to wait.
+/// If it's still missing, and the module is loaded, hot-add devices until it's there (or fail as check_device_exists()).
+fn wait_for_device(root: &Path, device_name: &str, wait: Duration) -> Result<()> {
+    let device_sysfs_path = root.join("sys/block").join(device_name);
+    let deadline = Instant::now() + wait;
+    while !device_sysfs_path.exists() {
+        if Instant::now() >= deadline {
+            hot_add_device(root, device_name)?;
+            break;
+        }
+        thread::sleep(DEVICE_POLL_INTERVAL);
+    }
+    check_device_exists(root, device_name)
+}
+
+/// Read hot_add until it creates the device, like the generator does for all devices at boot
+fn hot_add_device(root: &Path, device_name: &str) -> Result<()> {
+    let hot_add = root.join("sys/class/zram-control/hot_add");
+    let index: u64 = match device_name.strip_prefix("zram").map(str::parse) {
+        Some(Ok(index)) if hot_add.exists() => index,
+        _ => return Ok(()),
+    };
+    info!(
+        "{}: doesn't exist, adding it with {}",
+        device_name,
+        hot_add.display()
+    );
+    /* Each read adds the lowest free index, so at most index + 1 reads are needed */
+    for _ in 0..=index {
+        let added: u64 = fs::read_to_string(&hot_add)
+            .with_context(|| format!("Failed to read {}", hot_add.display()))?
+            .trim_end()
+            .parse()
+            .with_context(|| format!("Failed to parse {}", hot_add.display()))?;
+        if added >= index {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Fail early with a useful message, rather than on the first write into the device's directory.
+fn check_device_exists(root: &Path, device_name: &str) -> Result<()> {
+    let device_sysfs_path = root.join("sys/block").join(device_name);
+    if device_sysfs_path.exists() {
+        return Ok(());
+    }
+
+    let hot_add = root.join("sys/class/zram-control/hot_add");
+    Err(match hot_add.exists() {
+        true => anyhow!(
+            "{} doesn't exist: the device hasn't been created; reading {} creates the next one",
+            device_sysfs_path.display(),
+            hot_add.display()
+        ),
+        false => anyhow!(
+            "{} doesn't exist: the zram module isn't loaded (modprobe zram)",
+            device_sysfs_path.display()
+        ),
+    })
+}
+
+/// The algorithm marked as active in a comp_algorithm readback, i.e. "zstd" in "lzo [zstd] lz4".
+fn selected_algorithm(comp_algorithm: &str) -> Option<&str> {
+    comp_algorithm
+        .split_whitespace()
+        .find_map(|a| a.strip_prefix('[')?.strip_suffix(']'))
+}
+
+/// Requested parameters (other than algo=) that don't appear in the readback.
+fn missing_params<'a>(requested: &'a str, readback: &str) -> Vec<&'a str> {
+    let actual: BTreeSet<_> = readback.split_whitespace().collect();
+    requested
+        .split_whitespace()
+        .filter(|p| !p.starts_with("algo=") && !actual.contains(p))
+        .collect()
+}
+
+fn verify_comp_algorithm(path: &Path, algo: &str) {
+    match fs::read_to_string(path) {
+        Ok(readback) => match selected_algorithm(&readback) {
+            Some(selected) if selected != algo => warn!(
+                "Warning: algorithm {:?} requested, but {} reports {:?} in use",
+                algo,
+                path.display(),
+                selected,
+            ),
+            _ => {}
+        },
+        Err(err) => debug!(
+            "{} not readable, can't verify algorithm {:?}: {}",
+            path.display(),
+            algo,
+            err
+        ),
+    }
+}
+
+fn verify_algorithm_params(path: &Path, algo: &str, requested: &str) {
+    match fs::read_to_string(path) {
+        Ok(readback) => {
+            let missing = missing_params(requested, &readback);
+            if !missing.is_empty() {
+                warn!(
+                    "Warning: algorithm {:?} parameters {:?} not reflected in {}; the kernel may have ignored them",
+                    algo,
+                    missing.join(" "),
+                    path.display(),
+                );
+            }
+        }
+        Err(err) => debug!(
+            "{} not readable, can't verify algorithm {:?} parameters: {}",
+            path.display(),
+            algo,
+            err
+        ),
+    }
+}
+
+pub fn run_device_reset(root: &Path, device_name: &str) -> Result<()> {
+    let reset = root.join("sys/block").join(device_name).join("reset");
     fs::write(reset, b"1")?;
+    for dir in [SETUP_RECORD_DIR, MANIFEST_DIR] {
+        let record = root.join(dir).join(device_name);
+        if let Err(err) = remove_record(&record) {
+            warn!("Warning: failed to remove {}: {}", record.display(), err);
+        }
+    }
     Ok(())
 }
+
+pub fn run_reset_all(root: &Path, reserved: Option<RangeInclusive<u64>>) -> Result<()> {
+    let mut failed = 0;
+    for device_name in devices_to_reset(root, reserved.as_ref())? {
+        if let Err(e) = run_device_reset(root, &device_name) {
+            warn!("Failed to reset {}: {}", device_name, e);
+            failed += 1;
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(anyhow!("Failed to reset {} device(s)", failed)),
+    }
+}
+
+/// The zram devices --reset-all may touch: those within reserved-indices=, or all of them.
+fn devices_to_reset(root: &Path, reserved: Option<&RangeInclusive<u64>>) -> Result<Vec<String>> {
+    let mut devices = list_zram_devices(root)?;
+    if let Some(range) = reserved {
+        devices.retain(|name| {
+            let Some(index) = name
+                .strip_prefix("zram")
+                .and_then(|i| i.parse::<u64>().ok())
+            else {
+                return false;
+            };
+            if !range.contains(&index) {
+                info!(
+                    "{}: outside of reserved-indices={}-{}, not resetting.",
+                    name,
+                    range.start(),
+                    range.end()
+                );
+            }
+            range.contains(&index)
+        });
+    }
+    Ok(devices)
+}
+
+/// An attribute of a live device which doesn't match what the configuration would set.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Drift {
+    pub device: String,
+    pub attribute: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Drift {
+    /// How to apply the configuration to a device that is set up differently:
+    /// the size (and most else) of an initialised device can't be changed in place
+    pub fn remedy(&self) -> Option<String> {
+        (self.expected != "none" && self.actual != "none").then(|| {
+            format!(
+                "{}: can't be changed in place; reset and set it up again with systemctl restart {}",
+                self.device,
+                units::setup_service(&self.device)
+            )
+        })
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}: expected {}, found {}",
+            self.device, self.attribute, self.expected, self.actual
+        )
+    }
+}
+
+fn read_attribute(device_sysfs_path: &Path, attribute: &str) -> Option<String> {
+    let path = device_sysfs_path.join(attribute);
+    match fs::read_to_string(&path) {
+        Ok(value) => Some(value.trim().to_string()),
+        Err(err) => {
+            debug!("{} not readable, not comparing: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Compare the live state of the devices in /sys/block with what setting up
+/// the configured devices would produce. Doesn't touch the system.
+///
+/// Configured devices which don't exist, and initialised zram devices which aren't configured,
+/// are reported with a disksize of "none".
+pub fn find_drift(root: &Path, devices: &[Device]) -> Result<Vec<Drift>> {
+    let mut drift = vec![];
+    let present = list_zram_devices(root)?;
+
+    for device in devices {
+        let device_sysfs_path = root.join("sys/block").join(&device.name);
+        if !present.contains(&device.name) {
+            drift.push(Drift {
+                device: device.name.clone(),
+                attribute: "disksize",
+                expected: device.disksize.to_string(),
+                actual: "none".to_string(),
+            });
+            continue;
+        }
+
+        let mut compare = |attribute, expected: String, actual: Option<String>| {
+            if let Some(actual) = actual {
+                if actual != expected {
+                    drift.push(Drift {
+                        device: device.name.clone(),
+                        attribute,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        };
+
+        compare(
+            "disksize",
+            device.disksize.to_string(),
+            read_attribute(&device_sysfs_path, "disksize"),
+        );
+        /* Without compression-algorithm=, or with auto, whatever the kernel has is fine */
+        if let Some((algo, _)) = device
+            .compression_algorithms
+            .compression_algorithms
+            .first()
+            .filter(|(algo, _)| algo != "auto")
+        {
+            compare(
+                "comp_algorithm",
+                algo.clone(),
+                read_attribute(&device_sysfs_path, "comp_algorithm").map(|readback| {
+                    selected_algorithm(&readback)
+                        .unwrap_or(&readback)
+                        .to_string()
+                }),
+            );
+        }
+        /* Write-only on most kernels, in which case it's skipped */
+        compare(
+            "mem_limit",
+            device.mem_limit.to_string(),
+            read_attribute(&device_sysfs_path, "mem_limit"),
+        );
+    }
+
+    for name in present {
+        if devices.iter().any(|dev| dev.name == name) {
+            continue;
+        }
+        let device_sysfs_path = root.join("sys/block").join(&name);
+        match read_attribute(&device_sysfs_path, "disksize") {
+            Some(disksize) if disksize != "0" => drift.push(Drift {
+                device: name,
+                attribute: "disksize",
+                expected: "none".to_string(),
+                actual: disksize,
+            }),
+            _ => {}
+        }
+    }
+
+    Ok(drift)
+}
+
+pub fn run_check_drift(root: &Path, devices: &[Device]) -> Result<()> {
+    let drift = find_drift(root, devices)?;
+    for d in &drift {
+        println!("{}", d);
+    }
+    let remedies: BTreeSet<_> = drift.iter().filter_map(Drift::remedy).collect();
+    for remedy in remedies {
+        println!("{}", remedy);
+    }
+
+    match drift.len() {
+        0 => Ok(()),
+        n => Err(anyhow!("{} attribute(s) differ from the configuration", n)),
+    }
+}
+
+/// Names of the zram devices currently present in /sys/block, sorted by index.
+///
+/// This is independent of the configuration.
+pub fn list_zram_devices(root: &Path) -> Result<Vec<String>> {
+    let sys_block = root.join("sys/block");
+    let entries = match fs::read_dir(&sys_block) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("Failed to list {}", sys_block.display())),
+    };
+
+    let mut devices = vec![];
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to list {}", sys_block.display()))?;
+        if let Some(name) = entry.file_name().to_str() {
+            if let Some(Ok(index)) = name.strip_prefix("zram").map(str::parse::<u64>) {
+                devices.push((index, name.to_string()));
+            }
+        }
+    }
+
+    devices.sort_unstable();
+    Ok(devices.into_iter().map(|(_, name)| name).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_auto_algorithm() {
+        let pick = |available, enomem: &[&str]| {
+            let mut tried = vec![];
+            let picked = pick_auto_algorithm(
+                "zram0",
+                config::AUTO_COMPRESSION_ALGORITHMS,
+                available,
+                |algo| {
+                    tried.push(algo.to_string());
+                    match enomem.contains(&algo) {
+                        true => Err(io::Error::from(ErrorKind::OutOfMemory)),
+                        false => Ok(()),
+                    }
+                },
+            );
+            (picked, tried)
+        };
+
+        assert_eq!(
+            pick("lzo [lzo-rle] lz4 lz4hc 842 zstd\n", &[]),
+            (Some("zstd"), vec!["zstd".to_string()])
+        );
+        assert_eq!(pick("lzo lzo-rle [lz4]\n", &[]).0, Some("lz4"));
+        assert_eq!(pick("[lzo-rle] lzo\n", &[]).0, Some("lzo-rle"));
+        assert_eq!(pick("lzo [lz4hc] 842\n", &[]), (None, vec![]));
+        assert_eq!(pick("", &[]).0, None);
+
+        /* ENOMEM passes on to the next one */
+        assert_eq!(
+            pick("lzo-rle lz4 [zstd]\n", &["zstd"]),
+            (Some("lz4"), vec!["zstd".to_string(), "lz4".to_string()])
+        );
+        assert_eq!(
+            pick("lzo-rle lz4 [zstd]\n", &["zstd", "lz4", "lzo-rle"]).0,
+            None
+        );
+
+        /* Other errors don't */
+        let mut tried = 0;
+        let picked = pick_auto_algorithm(
+            "zram0",
+            config::AUTO_COMPRESSION_ALGORITHMS,
+            "zstd lz4\n",
+            |_| {
+                tried += 1;
+                Err(io::Error::from(ErrorKind::InvalidInput))
+            },
+        );
+        assert_eq!((picked, tried), (None, 1));
+
+        /* Only the candidates policy.conf permits are tried */
+        let mut tried = vec![];
+        let picked = pick_auto_algorithm("zram0", &["lz4", "lzo-rle"], "[zstd] lz4\n", |algo| {
+            tried.push(algo.to_string());
+            Ok(())
+        });
+        assert_eq!((picked, tried), (Some("lz4"), vec!["lz4".to_string()]));
+        let picked = pick_auto_algorithm("zram0", &["zstd", "lz4"], "[lzo-rle]\n", |_| {
+            panic!("neither is available")
+        });
+        assert_eq!(picked, None);
+    }
+
+    #[test]
+    fn test_selected_algorithm() {
+        assert_eq!(
+            selected_algorithm("lzo [lzo-rle] lz4 zstd\n"),
+            Some("lzo-rle")
+        );
+        assert_eq!(selected_algorithm("[zstd]\n"), Some("zstd"));
+        assert_eq!(selected_algorithm("lzo lz4\n"), None);
+        assert_eq!(selected_algorithm(""), None);
+    }
+
+    #[test]
+    fn test_algorithm_already_selected() {
+        assert!(algorithm_already_selected(
+            Some("lzo [lzo-rle] lz4 zstd\n"),
+            "lzo-rle"
+        ));
+        assert!(!algorithm_already_selected(
+            Some("lzo [lzo-rle] lz4 zstd\n"),
+            "zstd"
+        ));
+        /* Listed, but not selected */
+        assert!(!algorithm_already_selected(Some("lzo lz4 zstd\n"), "zstd"));
+        assert!(!algorithm_already_selected(None, "zstd"));
+    }
+
+    #[test]
+    fn test_apply_algorithm_skip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("comp_algorithm");
+        let step = |algo: &str, prio| Step::Algorithm {
+            path: path.clone(),
+            data: algo.to_string(),
+            algo: algo.to_string(),
+            prio,
+            params: None,
+        };
+
+        /* Already selected: not written (the readback stays as it was) */
+        fs::write(&path, "lzo [zstd] lz4\n").unwrap();
+        apply_step(&step("zstd", 0), "zram0").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "lzo [zstd] lz4\n");
+
+        apply_step(&step("lz4", 0), "zram0").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "lz4");
+
+        /* Recompression algorithms are always written */
+        fs::write(&path, "lzo [zstd] lz4\n").unwrap();
+        apply_step(&step("zstd", 1), "zram0").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "zstd");
+    }
+
+    #[test]
+    fn test_partial_recompression_warning() {
+        assert_eq!(partial_recompression_warning("zram0", &["zstd"], &[]), None);
+        assert_eq!(partial_recompression_warning("zram0", &[], &["zstd"]), None);
+        assert_eq!(
+            partial_recompression_warning("zram0", &["zstd"], &["lz4hc", "deflate"]).unwrap(),
+            "Warning: zram0: only recompression algorithm(s) zstd configured, without lz4hc deflate; \
+             recompression doesn't follow the configured list"
+        );
+    }
+
+    #[test]
+    fn test_missing_params() {
+        assert!(missing_params("algo=zstd level=19", "level=19\n").is_empty());
+        assert!(missing_params("algo=zstd level=19", "algo=zstd level=19\n").is_empty());
+        assert_eq!(
+            missing_params("algo=zstd level=19 dict=/etc/d", "level=3\n"),
+            ["level=19", "dict=/etc/d"]
+        );
+        assert_eq!(missing_params("algo=lzo level=9", ""), ["level=9"]);
+    }
+
+    #[test]
+    fn test_apply_recompress() {
+        let dir = tempfile::tempdir().unwrap();
+        let step = Step::Recompress {
+            path: dir.path().join("recompress"),
+            data: "type=idle threshold=1024".to_string(),
+        };
+        assert_eq!(
+            step.to_string(),
+            format!(
+                "write {}/recompress \"type=idle threshold=1024\"",
+                dir.path().display()
+            )
+        );
+        apply_step(&step, "zram0").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("recompress")).unwrap(),
+            "type=idle threshold=1024"
+        );
+
+        /* Kernels without recompression: only a warning */
+        let step = Step::Recompress {
+            path: dir.path().join("nonexistent/recompress"),
+            data: "type=idle".to_string(),
+        };
+        apply_step(&step, "zram0").unwrap();
+    }
+
+    #[test]
+    fn test_apply_trigger() {
+        let dir = tempfile::tempdir().unwrap();
+        let step = Step::Trigger {
+            path: dir.path().join("recompress"),
+            data: "type=idle".to_string(),
+            what: "recompression",
+        };
+        assert_eq!(
+            step.to_string(),
+            format!(
+                "write {}/recompress \"type=idle\" (if supported)",
+                dir.path().display()
+            )
+        );
+
+        /* Missing attribute: not created, only a warning */
+        apply_step(&step, "zram0").unwrap();
+        assert!(!dir.path().join("recompress").exists());
+
+        fs::write(dir.path().join("recompress"), "").unwrap();
+        apply_step(&step, "zram0").unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("recompress")).unwrap(),
+            "type=idle"
+        );
+    }
+
+    #[test]
+    fn test_resolve_writeback_device() {
+        let root = tempfile::tempdir().unwrap();
+        let by_partuuid = root.path().join("dev/disk/by-partuuid");
+        let by_parttypeuuid = root.path().join("dev/disk/by-parttypeuuid");
+        fs::create_dir_all(&by_partuuid).unwrap();
+        fs::create_dir_all(&by_parttypeuuid).unwrap();
+        let swap_type = "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f";
+        let linux_type = "0fc63daf-8483-4772-8e79-3d69d8477de4";
+        for (type_guid, uuid) in [
+            (swap_type, "9f3e6a2b-1c4d-4e5f-8a9b-0c1d2e3f4a5b"),
+            (linux_type, "11111111-2222-3333-4444-555555555555"),
+            (linux_type, "66666666-7777-8888-9999-000000000000"),
+        ] {
+            fs::write(by_partuuid.join(uuid), "").unwrap();
+            fs::write(by_parttypeuuid.join(format!("{}.{}", type_guid, uuid)), "").unwrap();
+        }
+
+        let partuuid = Path::new("/dev/disk/by-partuuid/9f3e6a2b-1c4d-4e5f-8a9b-0c1d2e3f4a5b");
+        assert_eq!(
+            resolve_writeback_device(root.path(), "zram0", partuuid).as_deref(),
+            Some(partuuid)
+        );
+        let missing = Path::new("/dev/disk/by-partuuid/ffffffff-1c4d-4e5f-8a9b-0c1d2e3f4a5b");
+        assert_eq!(
+            resolve_writeback_device(root.path(), "zram0", missing),
+            None
+        );
+
+        let by_type = |type_guid| {
+            resolve_writeback_device(
+                root.path(),
+                "zram0",
+                &Path::new(config::BY_PARTTYPEUUID).join(type_guid),
+            )
+        };
+        assert_eq!(
+            by_type(swap_type).as_deref(),
+            Some(Path::new(
+                "/dev/disk/by-parttypeuuid/0657fd6d-a4ab-43c4-84e5-0933c84b4f4f.9f3e6a2b-1c4d-4e5f-8a9b-0c1d2e3f4a5b"
+            ))
+        );
+        /* Ambiguous, and none */
+        assert_eq!(by_type(linux_type), None);
+        assert_eq!(by_type("c12a7328-f81f-11d2-ba4b-00a0c93ec93b"), None);
+
+        /* Anything else is left alone, even if it doesn't exist (yet) */
+        assert_eq!(
+            resolve_writeback_device(root.path(), "zram0", Path::new("/dev/sda2")).as_deref(),
+            Some(Path::new("/dev/sda2"))
+        );
+
+        /* A zram device, by either name */
+        fs::create_dir_all(root.path().join("dev")).unwrap();
+        fs::write(root.path().join("dev/zram1"), "").unwrap();
+        let zram_type = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+        let zram_uuid = "aaaaaaaa-bbbb-cccc-dddd-eeeeeeeeeeee";
+        std::os::unix::fs::symlink("../../zram1", by_partuuid.join(zram_uuid)).unwrap();
+        std::os::unix::fs::symlink(
+            "/dev/zram1",
+            by_parttypeuuid.join(format!("{}.{}", zram_type, zram_uuid)),
+        )
+        .unwrap();
+        assert_eq!(
+            resolve_writeback_device(
+                root.path(),
+                "zram0",
+                &Path::new(config::BY_PARTUUID).join(zram_uuid)
+            ),
+            None
+        );
+        assert_eq!(by_type(zram_type), None);
+        assert_eq!(
+            config::zram_device_behind(
+                root.path(),
+                &Path::new(config::BY_PARTUUID).join(zram_uuid)
+            ),
+            Some(PathBuf::from("/dev/zram1"))
+        );
+        assert_eq!(config::zram_device_behind(root.path(), partuuid), None);
+    }
+
+    #[test]
+    fn test_step_command() {
+        let step = Step::Mkswap {
+            page_size: 16384,
+            device: PathBuf::from("/dev/zram0"),
+        };
+        let command = step.command().unwrap();
+        assert_eq!(command.get_program(), "mkswap");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["--pagesize", "16384", "/dev/zram0"]
+        );
+        assert_eq!(step.to_string(), "exec mkswap --pagesize 16384 /dev/zram0");
+
+        let step = Step::Makefs {
+            fs_type: "swap".to_string(),
+            device: PathBuf::from("/dev/zram0"),
+        };
+        let command = step.command().unwrap();
+        assert_eq!(command.get_program(), SYSTEMD_MAKEFS_COMMAND);
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["swap", "/dev/zram0"]
+        );
+        assert_eq!(
+            step.to_string(),
+            format!("exec {} swap /dev/zram0", SYSTEMD_MAKEFS_COMMAND)
+        );
+
+        let step = Step::Recompress {
+            path: PathBuf::from("/sys/block/zram0/recompress"),
+            data: "type=idle".to_string(),
+        };
+        assert!(step.command().is_none());
+    }
+
+    #[test]
+    fn test_check_device_exists() {
+        let root = tempfile::tempdir().unwrap();
+        let err = check_device_exists(root.path(), "zram1")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("zram module isn't loaded"), "{}", err);
+
+        fs::create_dir_all(root.path().join("sys/class/zram-control")).unwrap();
+        fs::write(root.path().join("sys/class/zram-control/hot_add"), "1\n").unwrap();
+        let err = check_device_exists(root.path(), "zram1")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("hasn't been created"), "{}", err);
+
+        fs::create_dir_all(root.path().join("sys/block/zram1")).unwrap();
+        check_device_exists(root.path(), "zram1").unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_device() {
+        let root = tempfile::tempdir().unwrap();
+        let err = wait_for_device(root.path(), "zram1", Duration::ZERO)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("zram module isn't loaded"), "{}", err);
+
+        /* Appears after a tick */
+        let sys = root.path().join("sys/block/zram1");
+        let creator = {
+            let sys = sys.clone();
+            thread::spawn(move || {
+                thread::sleep(DEVICE_POLL_INTERVAL * 2);
+                fs::create_dir_all(sys).unwrap();
+            })
+        };
+        wait_for_device(root.path(), "zram1", Duration::from_secs(10)).unwrap();
+        creator.join().unwrap();
+
+        /* Doesn't appear: hot_add is read, but a regular file can't add it */
+        fs::remove_dir(&sys).unwrap();
+        fs::create_dir_all(root.path().join("sys/class/zram-control")).unwrap();
+        fs::write(root.path().join("sys/class/zram-control/hot_add"), "1\n").unwrap();
+        let start = Instant::now();
+        let err = wait_for_device(root.path(), "zram1", Duration::from_millis(120))
+            .unwrap_err()
+            .to_string();
+        assert!(start.elapsed() >= Duration::from_millis(120));
+        assert!(err.contains("hasn't been created"), "{}", err);
+
+        fs::write(root.path().join("sys/class/zram-control/hot_add"), "x\n").unwrap();
+        let err = format!(
+            "{:#}",
+            wait_for_device(root.path(), "zram1", Duration::ZERO).unwrap_err()
+        );
+        assert!(err.contains("Failed to parse"), "{}", err);
+    }
+
+    #[test]
+    fn test_list_zram_devices() {
+        let root = tempfile::tempdir().unwrap();
+        let sys_block = root.path().join("sys/block");
+        for name in [
+            "zram10", "sda", "zram2", "zram0", "zramfoo", "loop0", "zram1",
+        ] {
+            fs::create_dir_all(sys_block.join(name)).unwrap();
+        }
+
+        assert_eq!(
+            list_zram_devices(root.path()).unwrap(),
+            ["zram0", "zram1", "zram2", "zram10"]
+        );
+    }
+
+    #[test]
+    fn test_devices_to_reset() {
+        let root = tempfile::tempdir().unwrap();
+        let sys_block = root.path().join("sys/block");
+        for name in ["zram0", "zram1", "zram2", "zram10", "sda"] {
+            fs::create_dir_all(sys_block.join(name)).unwrap();
+        }
+
+        assert_eq!(
+            devices_to_reset(root.path(), None).unwrap(),
+            ["zram0", "zram1", "zram2", "zram10"]
+        );
+        assert_eq!(
+            devices_to_reset(root.path(), Some(&(0..=1))).unwrap(),
+            ["zram0", "zram1"]
+        );
+        assert_eq!(
+            devices_to_reset(root.path(), Some(&(2..=2))).unwrap(),
+            ["zram2"]
+        );
+        assert!(devices_to_reset(root.path(), Some(&(3..=9)))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_run_reset_all() {
+        let root = tempfile::tempdir().unwrap();
+        let sys_block = root.path().join("sys/block");
+        for name in ["zram0", "zram1", "sda"] {
+            fs::create_dir_all(sys_block.join(name)).unwrap();
+        }
+
+        run_reset_all(root.path(), Some(1..=1)).unwrap();
+        assert!(!sys_block.join("zram0/reset").exists());
+        assert_eq!(
+            fs::read_to_string(sys_block.join("zram1/reset")).unwrap(),
+            "1"
+        );
+
+        run_reset_all(root.path(), None).unwrap();
+        assert_eq!(
+            fs::read_to_string(sys_block.join("zram0/reset")).unwrap(),
+            "1"
+        );
+        assert!(!sys_block.join("sda/reset").exists());
+    }
+
+    #[test]
+    fn test_manifest() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(read_manifest(root.path()).unwrap().is_empty());
+
+        fs::create_dir_all(root.path().join("etc/systemd")).unwrap();
+        fs::create_dir_all(root.path().join("proc")).unwrap();
+        fs::create_dir_all(root.path().join("sys/block/zram1")).unwrap();
+        fs::write(root.path().join("proc/meminfo"), "MemTotal: 1048576 kB\n").unwrap();
+        fs::write(
+            root.path().join("etc/systemd/zram-generator.conf"),
+            "[zram1]\nzram-size = 512\nmount-point = /var/tmp\n",
+        )
+        .unwrap();
+        let device = config::read_device(root.path(), false, "zram1")
+            .unwrap()
+            .unwrap();
+        write_manifest_entry(root.path(), &device, "zram1");
+        fs::write(root.path().join(MANIFEST_DIR).join("notes"), "").unwrap();
+        assert_eq!(
+            fs::read_to_string(root.path().join(MANIFEST_DIR).join("zram1")).unwrap(),
+            "536870912 ext2\n"
+        );
+        assert_eq!(
+            read_manifest(root.path()).unwrap(),
+            BTreeSet::from(["zram1".to_string()])
+        );
+
+        run_device_reset(root.path(), "zram1").unwrap();
+        assert!(read_manifest(root.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list_zram_devices_no_sysfs() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(list_zram_devices(root.path()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,409 @@
+/* SPDX-License-Identifier: MIT */
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::json;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// bd_stat counts in units of 4k, regardless of the page size
+const BD_STAT_UNIT: u64 = 4096;
+
+/// The fields of /sys/block/zramN/mm_stat, in bytes unless noted;
+/// older kernels have fewer, so all but the first three are optional
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MmStat {
+    pub orig_data_size: u64,
+    pub compr_data_size: u64,
+    pub mem_used_total: u64,
+    pub mem_limit: Option<u64>,
+    pub mem_used_max: Option<u64>,
+    /// pages
+    pub same_pages: Option<u64>,
+    /// pages
+    pub pages_compacted: Option<u64>,
+    /// pages
+    pub huge_pages: Option<u64>,
+}
+
+impl MmStat {
+    pub fn parse(mm_stat: &str) -> Option<MmStat> {
+        let mut fields = mm_stat.split_whitespace().map(|f| f.parse().ok());
+        let mut next = || fields.next().map(|f| f.ok_or(())).transpose();
+        Some(MmStat {
+            orig_data_size: next().ok()??,
+            compr_data_size: next().ok()??,
+            mem_used_total: next().ok()??,
+            mem_limit: next().ok()?,
+            mem_used_max: next().ok()?,
+            same_pages: next().ok()?,
+            pages_compacted: next().ok()?,
+            huge_pages: next().ok()?,
+        })
+    }
+
+    fn ratio(&self) -> Option<f64> {
+        match self.compr_data_size {
+            0 => None,
+            c => Some(self.orig_data_size as f64 / c as f64),
+        }
+    }
+}
+
+/// How --stats prints the statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// For people
+    Table,
+    Json,
+    /// The Prometheus text format, e.g. for node_exporter's textfile collector
+    Prometheus,
+}
+
+impl StatsFormat {
+    pub const NAMES: [&'static str; 3] = ["table", "json", "prometheus"];
+
+    pub fn parse(format: &str) -> Option<StatsFormat> {
+        match format {
+            "table" => Some(StatsFormat::Table),
+            "json" => Some(StatsFormat::Json),
+            "prometheus" => Some(StatsFormat::Prometheus),
+            _ => None,
+        }
+    }
+}
+
+/// The fields of /sys/block/zramN/bd_stat, in units of 4k
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BdStat {
+    /// pages currently written back
+    pub bd_count: u64,
+    pub bd_reads: u64,
+    pub bd_writes: u64,
+}
+
+impl BdStat {
+    pub fn parse(bd_stat: &str) -> Option<BdStat> {
+        let mut fields = bd_stat.split_whitespace().map(|f| f.parse().ok());
+        Some(BdStat {
+            bd_count: fields.next()??,
+            bd_reads: fields.next()??,
+            bd_writes: fields.next()??,
+        })
+    }
+}
+
+/// Print how well the data on the device compresses, and what's been written back.
+/// Doesn't touch the device.
+pub fn run_stats(root: &Path, device_name: &str, format: StatsFormat) -> Result<()> {
+    let device_sysfs_path = root.join("sys/block").join(device_name);
+
+    let mm_stat_path = device_sysfs_path.join("mm_stat");
+    let mm_stat = match fs::read_to_string(&mm_stat_path) {
+        Ok(mm_stat) => mm_stat,
+        Err(e) if e.kind() == ErrorKind::NotFound && !device_sysfs_path.exists() => {
+            return Err(anyhow!("{} doesn't exist", device_sysfs_path.display()));
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", mm_stat_path.display()))
+        }
+    };
+    let mm_stat = MmStat::parse(&mm_stat)
+        .ok_or_else(|| anyhow!("Failed to parse {}: {:?}", mm_stat_path.display(), mm_stat))?;
+
+    /* Only meaningful with a backing device; kernels without writeback don't have either file. */
+    let bd_stat = match fs::read_to_string(device_sysfs_path.join("backing_dev")) {
+        Ok(backing_dev) if backing_dev.trim() != "none" => {
+            fs::read_to_string(device_sysfs_path.join("bd_stat"))
+                .ok()
+                .and_then(|s| BdStat::parse(&s))
+        }
+        _ => None,
+    };
+
+    let bd_stat = bd_stat.as_ref();
+    print!(
+        "{}",
+        match format {
+            StatsFormat::Table => format_stats(device_name, &mm_stat, bd_stat),
+            StatsFormat::Json => format_stats_json(device_name, &mm_stat, bd_stat),
+            StatsFormat::Prometheus => format_stats_prometheus(device_name, &mm_stat),
+        }
+    );
+    Ok(())
+}
+
+fn format_stats(device_name: &str, mm_stat: &MmStat, bd_stat: Option<&BdStat>) -> String {
+    let mut out = format!("{}:\n", device_name);
+    writeln!(out, "  original:    {}", human_size(mm_stat.orig_data_size)).unwrap();
+    writeln!(
+        out,
+        "  compressed:  {}",
+        human_size(mm_stat.compr_data_size)
+    )
+    .unwrap();
+    writeln!(out, "  memory used: {}", human_size(mm_stat.mem_used_total)).unwrap();
+    if let Some(max) = mm_stat.mem_used_max {
+        writeln!(out, "  memory peak: {}", human_size(max)).unwrap();
+    }
+    match mm_stat.ratio() {
+        Some(ratio) => writeln!(out, "  ratio:       {:.2}", ratio).unwrap(),
+        None => writeln!(out, "  ratio:       -").unwrap(),
+    }
+    if let Some(same_pages) = mm_stat.same_pages {
+        writeln!(out, "  same pages:  {}", same_pages).unwrap();
+    }
+    if let Some(huge_pages) = mm_stat.huge_pages {
+        writeln!(out, "  huge pages:  {}", huge_pages).unwrap();
+    }
+    if let Some(bd_stat) = bd_stat {
+        writeln!(
+            out,
+            "  written back: {} pages ({}), {} reads, {} writes",
+            bd_stat.bd_count,
+            human_size(bd_stat.bd_count * BD_STAT_UNIT),
+            bd_stat.bd_reads,
+            bd_stat.bd_writes
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// All in bytes or pages, for scripts; fields the kernel doesn't provide are null
+fn format_stats_json(device_name: &str, mm_stat: &MmStat, bd_stat: Option<&BdStat>) -> String {
+    let mut stats = json!({
+        "device": device_name,
+        "orig_data_bytes": mm_stat.orig_data_size,
+        "compr_data_bytes": mm_stat.compr_data_size,
+        "mem_used_bytes": mm_stat.mem_used_total,
+        "mem_used_max_bytes": mm_stat.mem_used_max,
+        "compression_ratio": mm_stat.ratio(),
+        "same_pages": mm_stat.same_pages,
+        "huge_pages": mm_stat.huge_pages,
+    });
+    if let Some(bd_stat) = bd_stat {
+        stats["written_back"] = json!({
+            "pages": bd_stat.bd_count,
+            "bytes": bd_stat.bd_count * BD_STAT_UNIT,
+            "reads": bd_stat.bd_reads,
+            "writes": bd_stat.bd_writes,
+        });
+    }
+    format!("{}\n", stats)
+}
+
+/// Gauges labelled with the device; write one file per device for the textfile collector
+fn format_stats_prometheus(device_name: &str, mm_stat: &MmStat) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, help: &str, value: &dyn std::fmt::Display| {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        writeln!(out, "{}{{device=\"{}\"}} {}", name, device_name, value).unwrap();
+    };
+    metric(
+        "zram_orig_data_bytes",
+        "Uncompressed size of the data stored in the device.",
+        &mm_stat.orig_data_size,
+    );
+    metric(
+        "zram_compr_data_bytes",
+        "Compressed size of the data stored in the device.",
+        &mm_stat.compr_data_size,
+    );
+    metric(
+        "zram_mem_used_bytes",
+        "Memory used by the device, including fragmentation and metadata.",
+        &mm_stat.mem_used_total,
+    );
+    /* No sample for an empty device, rather than a meaningless one */
+    if let Some(ratio) = mm_stat.ratio() {
+        metric(
+            "zram_compression_ratio",
+            "Uncompressed size divided by compressed size.",
+            &ratio,
+        );
+    }
+    out
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{}B", bytes),
+        _ => format!("{:.1}{}", size, UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mm_stat() {
+        assert_eq!(
+            MmStat::parse(
+                "16777216  4353124  4513792        0  4513792       12     1024        3        5\n"
+            ),
+            Some(MmStat {
+                orig_data_size: 16777216,
+                compr_data_size: 4353124,
+                mem_used_total: 4513792,
+                mem_limit: Some(0),
+                mem_used_max: Some(4513792),
+                same_pages: Some(12),
+                pages_compacted: Some(1024),
+                huge_pages: Some(3),
+            })
+        );
+
+        /* Older kernels: no huge_pages, or fewer */
+        let mm_stat = MmStat::parse("16777216 4353124 4513792 0 4513792 12 1024\n").unwrap();
+        assert_eq!(mm_stat.pages_compacted, Some(1024));
+        assert_eq!(mm_stat.huge_pages, None);
+        let mm_stat = MmStat::parse("16777216 4353124 4513792\n").unwrap();
+        assert_eq!(mm_stat.mem_limit, None);
+        assert_eq!(mm_stat.same_pages, None);
+
+        assert_eq!(MmStat::parse("16777216 4353124\n"), None);
+        assert_eq!(MmStat::parse(""), None);
+        assert_eq!(MmStat::parse("a b c\n"), None);
+        assert_eq!(MmStat::parse("1 2 3 x\n"), None);
+    }
+
+    #[test]
+    fn test_parse_bd_stat() {
+        assert_eq!(
+            BdStat::parse("     256      100      300\n"),
+            Some(BdStat {
+                bd_count: 256,
+                bd_reads: 100,
+                bd_writes: 300,
+            })
+        );
+        assert_eq!(BdStat::parse("256 100\n"), None);
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0B");
+        assert_eq!(human_size(1023), "1023B");
+        assert_eq!(human_size(1536), "1.5KiB");
+        assert_eq!(human_size(16777216), "16.0MiB");
+        assert_eq!(human_size(3 << 30), "3.0GiB");
+    }
+
+    #[test]
+    fn test_format_stats() {
+        let mm_stat = MmStat::parse("16777216 4194304 4513792 0 4513792 12 1024 3\n").unwrap();
+        assert_eq!(
+            format_stats("zram0", &mm_stat, None),
+            "zram0:\n  \
+             original:    16.0MiB\n  \
+             compressed:  4.0MiB\n  \
+             memory used: 4.3MiB\n  \
+             memory peak: 4.3MiB\n  \
+             ratio:       4.00\n  \
+             same pages:  12\n  \
+             huge pages:  3\n"
+        );
+
+        let mm_stat = MmStat::parse("0 0 0\n").unwrap();
+        let bd_stat = BdStat::parse("256 100 300\n").unwrap();
+        assert_eq!(
+            format_stats("zram1", &mm_stat, Some(&bd_stat)),
+            "zram1:\n  \
+             original:    0B\n  \
+             compressed:  0B\n  \
+             memory used: 0B\n  \
+             ratio:       -\n  \
+             written back: 256 pages (1.0MiB), 100 reads, 300 writes\n"
+        );
+    }
+
+    #[test]
+    fn test_stats_format() {
+        for name in StatsFormat::NAMES {
+            assert!(StatsFormat::parse(name).is_some(), "{}", name);
+        }
+        assert_eq!(StatsFormat::parse("json"), Some(StatsFormat::Json));
+        assert_eq!(StatsFormat::parse("JSON"), None);
+        assert_eq!(StatsFormat::parse(""), None);
+    }
+
+    #[test]
+    fn test_format_stats_json() {
+        let mm_stat = MmStat::parse("16777216 4194304 4513792 0 4513792 12 1024 3\n").unwrap();
+        assert_eq!(
+            format_stats_json("zram0", &mm_stat, None),
+            "{\"device\":\"zram0\",\"orig_data_bytes\":16777216,\"compr_data_bytes\":4194304,\
+             \"mem_used_bytes\":4513792,\"mem_used_max_bytes\":4513792,\"compression_ratio\":4.0,\
+             \"same_pages\":12,\"huge_pages\":3}\n"
+        );
+
+        let mm_stat = MmStat::parse("0 0 0\n").unwrap();
+        let bd_stat = BdStat::parse("256 100 300\n").unwrap();
+        assert_eq!(
+            format_stats_json("zram1", &mm_stat, Some(&bd_stat)),
+            "{\"device\":\"zram1\",\"orig_data_bytes\":0,\"compr_data_bytes\":0,\
+             \"mem_used_bytes\":0,\"mem_used_max_bytes\":null,\"compression_ratio\":null,\
+             \"same_pages\":null,\"huge_pages\":null,\
+             \"written_back\":{\"pages\":256,\"bytes\":1048576,\"reads\":100,\"writes\":300}}\n"
+        );
+    }
+
+    #[test]
+    fn test_format_stats_prometheus() {
+        let mm_stat = MmStat::parse("16777216 6291456 6553600 0 6553600 12 1024 3\n").unwrap();
+        assert_eq!(
+            format_stats_prometheus("zram0", &mm_stat),
+            "\
+# HELP zram_orig_data_bytes Uncompressed size of the data stored in the device.
+# TYPE zram_orig_data_bytes gauge
+zram_orig_data_bytes{device=\"zram0\"} 16777216
+# HELP zram_compr_data_bytes Compressed size of the data stored in the device.
+# TYPE zram_compr_data_bytes gauge
+zram_compr_data_bytes{device=\"zram0\"} 6291456
+# HELP zram_mem_used_bytes Memory used by the device, including fragmentation and metadata.
+# TYPE zram_mem_used_bytes gauge
+zram_mem_used_bytes{device=\"zram0\"} 6553600
+# HELP zram_compression_ratio Uncompressed size divided by compressed size.
+# TYPE zram_compression_ratio gauge
+zram_compression_ratio{device=\"zram0\"} 2.6666666666666665
+"
+        );
+
+        let mm_stat = MmStat::parse("0 0 0\n").unwrap();
+        let out = format_stats_prometheus("zram1", &mm_stat);
+        assert!(out.contains("zram_mem_used_bytes{device=\"zram1\"} 0\n"));
+        assert!(!out.contains("zram_compression_ratio"));
+    }
+
+    #[test]
+    fn test_run_stats() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(run_stats(root.path(), "zram0", StatsFormat::Table).is_err());
+
+        let sys = root.path().join("sys/block/zram0");
+        fs::create_dir_all(&sys).unwrap();
+        assert!(run_stats(root.path(), "zram0", StatsFormat::Table).is_err());
+        fs::write(sys.join("mm_stat"), "12\n").unwrap();
+        assert!(run_stats(root.path(), "zram0", StatsFormat::Table).is_err());
+
+        fs::write(sys.join("mm_stat"), "16777216 4194304 4513792\n").unwrap();
+        run_stats(root.path(), "zram0", StatsFormat::Table).unwrap();
+        /* A short bd_stat is skipped */
+        fs::write(sys.join("backing_dev"), "/dev/sda2\n").unwrap();
+        fs::write(sys.join("bd_stat"), "1\n").unwrap();
+        run_stats(root.path(), "zram0", StatsFormat::Table).unwrap();
+        run_stats(root.path(), "zram0", StatsFormat::Json).unwrap();
+        run_stats(root.path(), "zram0", StatsFormat::Prometheus).unwrap();
+    }
+}
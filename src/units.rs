@@ -0,0 +1,80 @@
+/* SPDX-License-Identifier: MIT */
+
+//! Names of the units the generator writes drop-ins for, creates, or refers to
+
+/// The packaged template setting up each device; a distribution shipping it under another name
+/// (e.g. zram-setup@.service) sets ZRAM_SETUP_SERVICE to that at build time
+pub const SETUP_SERVICE_BASE: &str = match option_env!("ZRAM_SETUP_SERVICE") {
+    Some(base) => base,
+    None => "systemd-zram-setup",
+};
+const _: () = assert!(
+    !SETUP_SERVICE_BASE.is_empty(),
+    "ZRAM_SETUP_SERVICE is empty"
+);
+
+/// Where the packaged template is installed, for enablement links to it
+pub const SYSTEM_UNIT_DIR: &str = match option_env!("SYSTEMD_SYSTEM_UNIT_DIR") {
+    Some(dir) => dir,
+    None => "/usr/lib/systemd/system",
+};
+
+/// Formats the device for lazy-makefs= and makefs-mode=systemd
+pub const ZRAM_MAKEFS_BASE: &str = "systemd-zram-makefs";
+/// Service and timer for recompress-interval=
+pub const RECOMPRESS_BASE: &str = "zram-recompress";
+/// For mount-owner=, mount-group=, and mount-mode=
+pub const MOUNT_PERMISSIONS_BASE: &str = "zram-mount-permissions";
+/// For hibernate-safe=
+pub const HIBERNATE_BASE: &str = "zram-hibernate";
+/// Resets the devices removed from the configuration
+pub const RESET_STALE_SERVICE: &str = "zram-reset-stale.service";
+
+/// "base@instance.suffix"
+pub fn instance(base: &str, instance: &str, suffix: &str) -> String {
+    format!("{}@{}.{}", base, instance, suffix)
+}
+
+/// "base@.suffix"
+pub fn template(base: &str, suffix: &str) -> String {
+    format!("{}@.{}", base, suffix)
+}
+
+/// The setup service of a device, i.e. systemd-zram-setup@zramN.service
+pub fn setup_service(device_name: &str) -> String {
+    instance(SETUP_SERVICE_BASE, device_name, "service")
+}
+
+/// The packaged template itself, i.e. systemd-zram-setup@.service
+pub fn setup_template() -> String {
+    template(SETUP_SERVICE_BASE, "service")
+}
+
+/// The installed packaged template, i.e. /usr/lib/systemd/system/systemd-zram-setup@.service
+pub fn setup_template_path() -> String {
+    format!("{}/{}", SYSTEM_UNIT_DIR, setup_template())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unit_names() {
+        assert_eq!(
+            instance(RECOMPRESS_BASE, "zram1", "timer"),
+            "zram-recompress@zram1.timer"
+        );
+        assert_eq!(
+            instance(ZRAM_MAKEFS_BASE, "zram0", "service"),
+            "systemd-zram-makefs@zram0.service"
+        );
+        assert_eq!(
+            setup_service("zram0"),
+            format!("{}@zram0.service", SETUP_SERVICE_BASE)
+        );
+        assert_eq!(setup_template(), format!("{}@.service", SETUP_SERVICE_BASE));
+        assert!(setup_template_path().starts_with('/'));
+        assert!(setup_template_path().ends_with(&format!("/{}", setup_template())));
+    }
+}
@@ -10,6 +10,7 @@ set -x
 
 $program --help
 $program --version
+$program --build-info | grep -q "^makefs: "
 
 # This should pass
 mkdir $dir/{a,b,c}
@@ -40,3 +41,9 @@ $program --reset-device /no/such/file/or/directory
 ret=$?
 set -e
 test $ret -eq 1
+
+# The environment variable turns everything off, before looking at the device
+ZRAM_GENERATOR_DISABLE=1 $program --setup-device /no/such/file/or/directory
+mkdir $dir/d
+ZRAM_GENERATOR_DISABLE=1 $program $dir/d
+test -z "$(ls -A $dir/d)"
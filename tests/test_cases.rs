@@ -1,6 +1,6 @@
 /* SPDX-License-Identifier: MIT */
 
-use zram_generator::{config, generator};
+use zram_generator::{config, generator, setup, units};
 
 use anyhow::Result;
 use fs_extra::dir::{copy, CopyOptions};
@@ -14,6 +14,7 @@ use std::process::{exit, Command};
 use tempfile::TempDir;
 
 #[ctor::ctor]
+#[allow(clippy::into_iter_on_ref)]
 fn unshorn() {
     use nix::{errno, mount, sched, unistd};
     use std::os::unix::fs::symlink;
@@ -55,6 +56,9 @@ fn unshorn() {
         path.insert(0, b);
     }
     env::set_var("PATH", OsString::from_vec(path));
+
+    // tests/10-example uses set!
+    env::set_var("ZRAM_GENERATOR_ALLOW_EXEC", "1");
 }
 
 fn prepare_directory(srcroot: &Path) -> Result<TempDir> {
@@ -62,7 +66,7 @@ fn prepare_directory(srcroot: &Path) -> Result<TempDir> {
     let root = rootdir.path();
 
     let opts = CopyOptions::new();
-    for p in ["etc", "usr", "proc"]
+    for p in ["etc", "usr", "proc", "run", "sys"]
         .iter()
         .map(|p| srcroot.join(p))
         .filter(|p| p.exists())
@@ -76,6 +80,22 @@ fn prepare_directory(srcroot: &Path) -> Result<TempDir> {
     Ok(rootdir)
 }
 
+/* A root with just a configuration and tests/07-mount-point's meminfo */
+fn prepare_config(conf: &str) -> TempDir {
+    let rootdir = TempDir::new().unwrap();
+    let root = rootdir.path();
+    fs::create_dir_all(root.join("etc/systemd")).unwrap();
+    fs::create_dir_all(root.join("proc")).unwrap();
+    fs::copy(
+        "tests/07-mount-point/proc/meminfo",
+        root.join("proc/meminfo"),
+    )
+    .unwrap();
+    fs::write(root.join("etc/systemd/zram-generator.conf"), conf).unwrap();
+    rootdir
+}
+
+#[allow(clippy::needless_borrow)]
 fn test_generation(path: &str) -> Result<Vec<config::Device>> {
     let srcroot = Path::new(path);
     let rootdir = prepare_directory(&srcroot)?;
@@ -88,18 +108,29 @@ fn test_generation(path: &str) -> Result<Vec<config::Device>> {
         }
         _ => false,
     };
-    let devices = config::read_all_devices(root, kernel_override)?;
+    let (devices, globals) = config::read_all_devices_with_globals(root, kernel_override)?;
 
     let output_directory = root.join("run/units");
-    generator::run_generator(&devices, &output_directory, true)?;
+    generator::run_generator(&devices, &globals, &output_directory, root, true, false)?;
+
+    compare_output(srcroot, root)?;
+    Ok(devices)
+}
 
+fn compare_output(srcroot: &Path, root: &Path) -> Result<()> {
     // Compare output directory to expected value.
     // ExecStart lines include the full path to the generating binary,
     // so exclude them from comparison.
+    let mut excluded = vec![".empty".to_string()];
+    compare_absolute_symlinks(
+        &srcroot.join("run.expected"),
+        &root.join("run"),
+        &mut excluded,
+    )?;
     let diff = Command::new("diff")
         .arg("-u")
         .arg("--recursive")
-        .arg("--exclude=.empty")
+        .args(excluded.iter().map(|name| format!("--exclude={}", name)))
         .arg(srcroot.join("run.expected"))
         .arg(root.join("run"))
         .output()?;
@@ -109,8 +140,36 @@ fn test_generation(path: &str) -> Result<Vec<config::Device>> {
         }
     }
     assert!(diff.status.success());
+    Ok(())
+}
 
-    Ok(devices)
+/// Enablement links to installed units point outside of the output directory, at files which needn't exist here,
+/// so their targets are compared, and diff, which would follow them, is told to skip them
+fn compare_absolute_symlinks(
+    expected: &Path,
+    generated: &Path,
+    excluded: &mut Vec<String>,
+) -> Result<()> {
+    for entry in fs::read_dir(expected)? {
+        let entry = entry?;
+        let generated = generated.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            compare_absolute_symlinks(&entry.path(), &generated, excluded)?;
+            continue;
+        }
+        let target = match fs::read_link(entry.path()) {
+            Ok(target) if target.is_absolute() => target,
+            _ => continue,
+        };
+        assert_eq!(
+            fs::read_link(&generated).ok(),
+            Some(target),
+            "{}",
+            generated.display()
+        );
+        excluded.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    Ok(())
 }
 
 fn z_s_name(zram_size: &(String, fasteval::ExpressionI, fasteval::Slab)) -> &str {
@@ -379,3 +438,1486 @@ fn test_11_obsolete() {
         }
     }
 }
+
+#[test]
+fn test_12_setup_timeout() {
+    let devices = test_generation("tests/12-setup-timeout").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                assert!(d.is_swap());
+                assert_eq!(d.setup_timeout.as_deref(), Some("5min 30s"));
+            }
+            "zram1" => {
+                assert!(!d.is_swap());
+                assert_eq!(d.setup_timeout.as_deref(), Some("infinity"));
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_13_recompression_off() {
+    let devices = test_generation("tests/13-recompression-off").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        assert!(d.is_swap());
+        assert_eq!(
+            d.compression_algorithms.compression_algorithms,
+            [
+                ("lzo-rle".into(), "".into()),
+                ("zstd".into(), "level=3".into())
+            ]
+        );
+        match d.name.as_str() {
+            "zram0" => {
+                assert!(d.recompression);
+                assert!(!d.to_string().contains("recompression=off"));
+            }
+            "zram1" => {
+                assert!(!d.recompression);
+                assert!(d.to_string().contains("recompression=off"));
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_14_swap_headroom() {
+    let devices = test_generation("tests/14-swap-headroom").unwrap();
+    // swap is /dev/sda2 from /proc/swaps, without the zram swap in SwapTotal
+    // zram0 has max(0, ram - swap) = 0 and is skipped
+    assert_eq!(devices.len(), 1);
+    let d = &devices[0];
+    assert_eq!(d.name, "zram1");
+    assert!(d.is_swap());
+    assert_eq!(d.zram_size.as_ref().map(z_s_name), Some("swap / 4"));
+    assert_eq!(d.disksize, 2047 * 1024 * 1024 / 4);
+}
+
+#[test]
+fn test_15_exec_start_post() {
+    let devices = test_generation("tests/15-exec-start-post").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert_eq!(
+                d.exec_start_post,
+                [
+                    "-sysctl vm.page-cluster=0",
+                    "/bin/sh -c 'echo %i >/run/zram-set-up'"
+                ]
+            ),
+            "zram1" => assert_eq!(d.exec_start_post, ["/usr/bin/true"]),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_16_swappiness() {
+    let devices = test_generation("tests/16-swappiness").unwrap();
+    assert_eq!(devices.len(), 3);
+
+    for d in &devices {
+        match d.name.as_str() {
+            // vm.swappiness is global, the highest-numbered swap device wins
+            "zram0" | "zram1" => {
+                assert!(d.is_swap());
+                assert_eq!(d.swappiness, Some(180));
+            }
+            "zram2" => {
+                assert!(!d.is_swap());
+                assert_eq!(d.swappiness, Some(10));
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+
+    /* Set once for the system, not by each setup service */
+    let sysctl =
+        fs::read_to_string("tests/16-swappiness/run.expected/sysctl.d/zram-generator.conf")
+            .unwrap();
+    assert_eq!(
+        sysctl
+            .lines()
+            .filter(|l| l.contains("swappiness"))
+            .collect::<Vec<_>>(),
+        ["vm.swappiness = 180"]
+    );
+}
+
+#[test]
+fn test_17_create_mount_point() {
+    let devices = test_generation("tests/17-create-mount-point").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        assert!(!d.is_swap());
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(
+                    d.mount_point.as_ref().unwrap(),
+                    Path::new("/var/cache/compressed")
+                );
+                assert!(d.create_mount_point);
+            }
+            "zram1" => {
+                assert_eq!(
+                    d.mount_point.as_ref().unwrap(),
+                    Path::new("/var/compressed")
+                );
+                assert!(!d.create_mount_point);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_18_kernel_enabled_zram1() {
+    let devices = test_generation("tests/18-kernel-enabled-zram1").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        assert!(d.is_swap());
+        match d.name.as_str() {
+            // Not in the config, created because of systemd.zram
+            "zram0" => {
+                assert_eq!(d.zram_size.as_ref().map(z_s_name), None);
+                assert_eq!(d.disksize, 7825 * 1024 * 1024 / 2);
+            }
+            "zram1" => {
+                assert_eq!(d.zram_size.as_ref().map(z_s_name), Some("ram / 4"));
+                assert_eq!(d.disksize, 7825 * 1024 * 1024 / 4);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_19_stale_devices() {
+    // zram1 was created by us and is still there: reset it;
+    // zram2 wasn't created by us, zram3 is already gone: leave them alone
+    let devices = test_generation("tests/19-stale-devices").unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].name, "zram0");
+}
+
+#[test]
+fn test_20_swap_tier() {
+    let devices = test_generation("tests/20-swap-tier").unwrap();
+    assert_eq!(devices.len(), 3);
+
+    for d in &devices {
+        assert!(d.is_swap());
+        match d.name.as_str() {
+            "zram0" => assert_eq!(d.effective_swap_priority(), 150),
+            "zram1" => assert_eq!(d.effective_swap_priority(), -1),
+            "zram2" => assert_eq!(d.effective_swap_priority(), 32767),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_21_cpu_affinity() {
+    let devices = test_generation("tests/21-cpu-affinity").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert_eq!(d.cpu_affinity.as_deref(), Some("0-1,4")),
+            "zram1" => assert_eq!(d.cpu_affinity.as_deref(), Some("numa")),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_dry_run() {
+    let rootdir = prepare_directory(Path::new("tests/10-example")).unwrap();
+    let root = rootdir.path();
+
+    let (devices, globals) = config::read_all_devices_with_globals(root, false).unwrap();
+    assert_eq!(devices.len(), 2);
+
+    let output_directory = root.join("run/units");
+    generator::run_generator(&devices, &globals, &output_directory, root, true, true).unwrap();
+
+    assert_eq!(fs::read_dir(&output_directory).unwrap().count(), 0);
+    assert!(!root.join("run/zram-generator").exists());
+}
+
+#[test]
+fn test_22_exclusive() {
+    let devices = test_generation("tests/22-exclusive").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert!(d.exclusive),
+            "zram1" => assert!(!d.exclusive),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_23_options_specifiers() {
+    let devices = test_generation("tests/23-options-specifiers").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert_eq!(d.options, "discard,x-zram=%i"),
+            "zram1" => assert_eq!(d.options, "context=system_u:object_r:%N_t:s0,size=100%%"),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_24_recompress_interval() {
+    let devices = test_generation("tests/24-recompress-interval").unwrap();
+    assert_eq!(devices.len(), 3);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert!(d.has_recompression()),
+            "zram1" | "zram2" => assert!(!d.has_recompression()),
+            _ => panic!("Unexpected device {}", d),
+        }
+        assert!(d.recompress_interval.is_some());
+    }
+}
+
+#[test]
+fn test_25_on_battery() {
+    let devices = test_generation("tests/25-on-battery").unwrap();
+    assert_eq!(devices.len(), 1);
+    let d = &devices[0];
+    assert!(d.is_swap());
+    assert_eq!(d.disksize, 782 * 1024 * 1024 / 4);
+}
+
+#[test]
+fn test_26_ram_usable() {
+    let devices = test_generation("tests/26-ram-usable").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            /* 7825MB of RAM, 512MB of CMA, 1024MB reserved */
+            "zram0" => assert_eq!(d.disksize, (7825 - 512 - 1024) * 1024 * 1024 / 2),
+            "zram1" => assert_eq!(d.disksize, 7825 * 1024 * 1024 / 2),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_27_wanted_by() {
+    let devices = test_generation("tests/27-wanted-by").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(d.effective_wanted_by(), ["swap.target", "hibernate.target"]);
+                assert!(d.required_by.is_empty());
+            }
+            "zram1" => {
+                assert_eq!(d.effective_wanted_by(), ["builder.target"]);
+                assert_eq!(d.required_by, ["builder.service"]);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_28_ndevices() {
+    let devices = test_generation("tests/28-ndevices").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        assert!(d.is_swap());
+        assert_eq!(d.disksize, 782 * 1024 * 1024 / 4);
+    }
+}
+
+#[test]
+fn test_29_compression_default() {
+    let devices = test_generation("tests/29-compression-default").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert_eq!(
+                d.compression_algorithms,
+                config::Algorithms {
+                    compression_algorithms: vec![
+                        ("zstd".into(), "level=3".into()),
+                        ("lz4hc".into(), "".into())
+                    ],
+                    ..Default::default()
+                }
+            ),
+            "zram1" => assert_eq!(
+                d.compression_algorithms,
+                config::Algorithms {
+                    compression_algorithms: vec![("lzo-rle".into(), "".into())],
+                    ..Default::default()
+                }
+            ),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_30_setup_limits() {
+    let devices = test_generation("tests/30-setup-limits").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(d.setup_memory_max.as_deref(), Some("64M"));
+                assert_eq!(d.setup_tasks_max.as_deref(), Some("16"));
+            }
+            "zram1" => {
+                assert_eq!(d.setup_memory_max, None);
+                assert_eq!(d.setup_tasks_max.as_deref(), Some("10%"));
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_31_json_config() {
+    let devices = test_generation("tests/31-json-config").unwrap();
+    let ini_devices = test_generation("tests/31a-ini-config").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    let describe = |devices: &[config::Device]| {
+        let mut d: Vec<_> = devices
+            .iter()
+            .map(|d| {
+                (
+                    d.to_string(),
+                    d.wanted_by.clone(),
+                    d.exec_start_post.clone(),
+                )
+            })
+            .collect();
+        d.sort();
+        d
+    };
+    assert_eq!(describe(&devices), describe(&ini_devices));
+}
+
+#[test]
+fn test_32_cgroup_high() {
+    let devices = test_generation("tests/32-cgroup-high").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            /* 782MB of RAM, memory.high of 256MB */
+            "zram0" => assert_eq!(d.disksize, 256 * 1024 * 1024),
+            "zram1" => assert_eq!(d.disksize, 128 * 1024 * 1024),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_53_cgroup_parent() {
+    let devices = test_generation("tests/53-cgroup-parent").unwrap();
+    assert_eq!(devices.len(), 1);
+    /* 782MB of RAM, memory.max of 512MB at the root; the generator's own init.scope limit doesn't count */
+    assert_eq!(devices[0].disksize, 512 * 1024 * 1024);
+}
+
+#[test]
+fn test_54_mount_point_space() {
+    let devices = test_generation("tests/54-mount-point-space").unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(
+        devices[0].mount_point.as_ref().unwrap(),
+        Path::new("/srv/scratch space/compressed")
+    );
+
+    /* A single path, not "/srv/scratch" and "space" */
+    let service = fs::read_to_string(
+        "tests/54-mount-point-space/run.expected/units/systemd-zram-setup@zram0.service.d/service.conf",
+    )
+    .unwrap();
+    assert!(service.contains("RequiresMountsFor=\"/srv/scratch space\"\n"));
+}
+
+#[test]
+fn test_33_after_before() {
+    let devices = test_generation("tests/33-after-before").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(
+                    d.after,
+                    [
+                        "systemd-cryptsetup@wb.service",
+                        "dev-mapper-wb.device",
+                        "network.target"
+                    ]
+                );
+                assert_eq!(d.before, ["foo.target", "bar.service"]);
+            }
+            "zram1" => {
+                assert_eq!(d.after, ["unlock.service"]);
+                assert!(d.before.is_empty());
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_34_include() {
+    let devices = test_generation("tests/34-include").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            /* The included file overrides the one including it, drop-ins override both */
+            "zram0" => {
+                assert_eq!(d.disksize, 782 * 1024 * 1024 / 2);
+                assert_eq!(d.swap_priority, Some(20));
+            }
+            /* Included from the included file */
+            "zram1" => assert_eq!(d.disksize, 128 * 1024 * 1024),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_35_mount_permissions() {
+    let devices = test_generation("tests/35-mount-permissions").unwrap();
+    assert_eq!(devices.len(), 3);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(d.mount_mode, Some(0o1777));
+                assert_eq!((d.mount_uid, d.mount_gid), (None, Some(990)));
+            }
+            "zram1" => {
+                assert_eq!(d.mount_mode, Some(0o750));
+                assert_eq!((d.mount_uid, d.mount_gid), (Some(1001), Some(100)));
+            }
+            "zram2" => {
+                assert_eq!(d.mount_mode, None);
+                assert_eq!((d.mount_uid, d.mount_gid), (Some(1000), None));
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+
+    /* vfat takes them as mount options instead */
+    let tmpfiles = fs::read_to_string(
+        "tests/35-mount-permissions/run.expected/tmpfiles.d/zram-generator.conf",
+    )
+    .unwrap();
+    assert_eq!(
+        tmpfiles.lines().skip(2).collect::<Vec<_>>(),
+        [
+            "z \"/var/cache/build\" - 1000 - -",
+            "z \"/var/scratch\" 1777 - 990 -"
+        ]
+    );
+}
+
+#[test]
+fn test_36_raw_device() {
+    let devices = test_generation("tests/36-raw-device").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        assert!(d.is_raw());
+        assert!(!d.is_swap());
+        assert_eq!(d.effective_fs_type(), "none");
+    }
+}
+
+#[test]
+fn test_36_raw_device_plan() {
+    /* configured, but not formatted */
+    assert_eq!(
+        plan("tests/36-raw-device", "zram0"),
+        [
+            "write /sys/block/zram0/comp_algorithm \"zstd\"",
+            "write /sys/block/zram0/mem_limit \"0\"",
+            "write /sys/block/zram0/disksize \"409993216\"",
+        ]
+    );
+}
+
+#[test]
+fn test_37_recompress_now() {
+    let devices = test_generation("tests/37-recompress-now").unwrap();
+    assert_eq!(devices.len(), 4);
+
+    for d in &devices {
+        let passes = match &d.name[..] {
+            "zram0" => 2,
+            "zram1" => 0,
+            _ => 1,
+        };
+        assert_eq!(d.recompress_now, passes, "{}", d.name);
+    }
+}
+
+#[test]
+fn test_37_recompress_now_plan() {
+    let setup = [
+        "write /sys/block/zram0/comp_algorithm \"lzo-rle\"",
+        "write /sys/block/zram0/recomp_algorithm \"algo=zstd priority=1\"",
+        "write /sys/block/zram0/mem_limit \"0\"",
+        "write /sys/block/zram0/disksize \"409993216\"",
+    ];
+    let pass = [
+        "write /sys/block/zram0/idle \"all\" (if supported)",
+        "write /sys/block/zram0/recompress \"type=idle\" (if supported)",
+    ];
+    assert_eq!(
+        plan("tests/37-recompress-now", "zram0"),
+        [&setup[..], &pass[..], &pass[..]].concat()
+    );
+
+    /* recompression is only triggered when asked for */
+    let zram1 = plan("tests/37-recompress-now", "zram1");
+    assert_eq!(zram1.len(), 4);
+    assert!(zram1.iter().all(|step| !step.contains("idle")));
+
+    /* with the global recompression parameters */
+    let zram2 = plan("tests/37-recompress-now", "zram2");
+    assert_eq!(
+        zram2[4..],
+        [
+            "write /sys/block/zram2/recompress \"type=huge threshold=1024\"",
+            "write /sys/block/zram2/idle \"all\" (if supported)",
+            "write /sys/block/zram2/recompress \"type=huge threshold=1024\" (if supported)",
+        ]
+    );
+
+    /* nothing to recompress with */
+    assert_eq!(plan("tests/37-recompress-now", "zram3").len(), 3);
+}
+
+#[test]
+fn test_38_skip_if_swap_above() {
+    let devices = test_generation("tests/38-skip-if-swap-above").unwrap();
+    /* zram0 isn't created: /dev/sda2 (2GB) is over its limit */
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match &d.name[..] {
+            /* under the limit, since /dev/zram0 in /proc/swaps doesn't count */
+            "zram1" => {
+                assert_eq!(d.skip_if_swap_above_mb, Some(4096));
+                assert_eq!(d.disksize, 782 * 1024 * 1024 / 2);
+            }
+            "zram2" => {
+                assert_eq!(d.skip_if_swap_above_mb, None);
+                assert_eq!(d.disksize, 782 * 1024 * 1024 / 2);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_39_writeback_crypttab() {
+    let devices = test_generation("tests/39-writeback-crypttab").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match &d.name[..] {
+            "zram0" => assert_eq!(d.writeback_crypttab_name.as_deref(), Some("zram-wb")),
+            "zram1" => assert_eq!(d.writeback_crypttab_name, None),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_40_default_dependencies() {
+    let devices = test_generation("tests/40-default-dependencies").unwrap();
+    assert_eq!(devices.len(), 4);
+
+    let unit = |name: &str| {
+        fs::read_to_string(Path::new("tests/40-default-dependencies/run.expected/units").join(name))
+            .unwrap()
+    };
+    assert!(unit("dev-zram0.swap").contains("\nDefaultDependencies=yes\n"));
+    assert!(unit("dev-zram2.swap").contains("\nDefaultDependencies=no\n"));
+    assert!(unit("var-scratch.mount").contains("\nDefaultDependencies=no\n"));
+    assert!(!unit("var-tmp.mount").contains("DefaultDependencies="));
+}
+
+#[test]
+fn test_41_conditions() {
+    let devices = test_generation("tests/41-conditions").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert_eq!(
+                d.conditions,
+                [
+                    "ConditionPathExists=/dev/dri/renderD128",
+                    "AssertKernelCommandLine=!nozram",
+                    "ConditionACPower=true",
+                    "ConditionVirtualization=!container",
+                ]
+            ),
+            "zram1" => assert_eq!(d.conditions, ["ConditionMemory=>=4G"]),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_42_exec_stop_post() {
+    let devices = test_generation("tests/42-exec-stop-post").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert_eq!(
+                d.exec_stop_post,
+                [
+                    "-/bin/rm -f /run/zram-set-up",
+                    "/usr/bin/logger -t zram-generator \"%i torn down\""
+                ]
+            ),
+            "zram1" => assert_eq!(d.exec_stop_post, ["-/bin/rmdir /var/scratch"]),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+
+    /* In the setup service, after the ExecStartPost= lines, since the swap and mount units have no [Service] */
+    let service = fs::read_to_string(
+        "tests/42-exec-stop-post/run.expected/units/systemd-zram-setup@zram0.service.d/service.conf",
+    )
+    .unwrap();
+    assert!(service.ends_with(
+        "[Service]\n\
+         ExecStartPost=/bin/sh -c 'echo %i >/run/zram-set-up'\n\
+         ExecStopPost=-/bin/rm -f /run/zram-set-up\n\
+         ExecStopPost=/usr/bin/logger -t zram-generator \"%i torn down\"\n"
+    ));
+    let mount =
+        fs::read_to_string("tests/42-exec-stop-post/run.expected/units/var-scratch.mount").unwrap();
+    assert!(!mount.contains("ExecStopPost="));
+}
+
+#[test]
+fn test_52_profiles() {
+    /* Without a profile, only the plain [zramN] sections apply */
+    let devices = test_generation("tests/52-profiles").unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].disksize, 1024 * 1024 * 1024);
+    assert!(
+        !Path::new("tests/52-profiles/run.expected/units/systemd-zram-setup@.service.d").exists()
+    );
+}
+
+#[test]
+fn test_51_hibernate_safe() {
+    let srcroot = Path::new("tests/51-hibernate-safe");
+    let rootdir = prepare_directory(srcroot).unwrap();
+    let root = rootdir.path();
+    let (devices, globals) = config::read_all_devices_with_globals(root, false).unwrap();
+    let units = root.join("run/units");
+    generator::run_generator(&devices, &globals, &units, root, true, false).unwrap();
+    compare_output(srcroot, root).unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert!(d.hibernate_safe),
+            /* Ignored with a warning for a mount */
+            "zram1" => assert!(!d.hibernate_safe),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+
+    let service = fs::read_to_string(units.join("zram-hibernate@zram0.service")).unwrap();
+    assert!(service.contains(&format!(
+        "ExecStart={0}/systemctl stop dev-zram0.swap\n\
+         ExecStop={0}/systemctl start --no-block dev-zram0.swap\n",
+        generator::SYSTEMD_BIN_DIR
+    )));
+    /* Ordered before every service it's pulled in by, so the swap is off before the image is written */
+    let before: Vec<_> = service
+        .lines()
+        .filter_map(|line| line.strip_prefix("Before="))
+        .flat_map(str::split_whitespace)
+        .collect();
+    assert_eq!(before, generator::HIBERNATE_SERVICES);
+    for service in generator::HIBERNATE_SERVICES {
+        assert_eq!(
+            fs::read_link(
+                units
+                    .join(format!("{}.wants", service))
+                    .join("zram-hibernate@zram0.service")
+            )
+            .unwrap(),
+            Path::new("../zram-hibernate@zram0.service")
+        );
+    }
+    assert!(!units.join("zram-hibernate@zram1.service").exists());
+}
+
+#[test]
+fn test_setup_service_base() {
+    let rootdir = prepare_directory(Path::new("tests/10-example")).unwrap();
+    let root = rootdir.path();
+    let (devices, globals) = config::read_all_devices_with_globals(root, false).unwrap();
+    let output_directory = root.join("run/units");
+    generator::run_generator(&devices, &globals, &output_directory, root, true, false).unwrap();
+
+    /* Whatever name the packaged service was built with */
+    let setup_service = units::setup_service("zram0");
+    assert!(setup_service.starts_with(units::SETUP_SERVICE_BASE));
+    let swap = fs::read_to_string(output_directory.join("dev-zram0.swap")).unwrap();
+    assert!(swap.contains(&format!(
+        "Requires={}\nAfter={}\n",
+        setup_service, setup_service
+    )));
+    assert!(output_directory
+        .join(format!("{}.d/bindings.conf", setup_service))
+        .exists());
+}
+
+#[test]
+fn test_persist_across_daemon_reload() {
+    let rootdir = prepare_directory(Path::new("tests/02-zstd")).unwrap();
+    let root = rootdir.path();
+    let mut devices = config::read_all_devices(root, false).unwrap();
+    let device = &mut devices[0];
+    let sysfs = root.join("sys/block/zram0");
+    fs::create_dir_all(&sysfs).unwrap();
+
+    /* Never set up */
+    assert!(!setup::is_set_up_as_recorded(root, device, "zram0"));
+
+    /* Set up, e.g. in the initrd, and nothing changed since: no reset, no setup */
+    setup::write_setup_record(root, device, "zram0");
+    fs::write(sysfs.join("disksize"), format!("{}\n", device.disksize)).unwrap();
+    assert!(setup::is_set_up_as_recorded(root, device, "zram0"));
+
+    /* Anything the setup writes changed */
+    device.fs_type = Some("ext4".to_string());
+    assert!(!setup::is_set_up_as_recorded(root, device, "zram0"));
+    device.fs_type = None;
+    device.mem_limit += 1;
+    assert!(!setup::is_set_up_as_recorded(root, device, "zram0"));
+    device.mem_limit -= 1;
+    assert!(setup::is_set_up_as_recorded(root, device, "zram0"));
+
+    /* Reset by something else */
+    fs::write(sysfs.join("disksize"), "0\n").unwrap();
+    assert!(!setup::is_set_up_as_recorded(root, device, "zram0"));
+    fs::write(sysfs.join("disksize"), format!("{}\n", device.disksize)).unwrap();
+
+    /* Reset by the service being stopped, or restarted */
+    setup::run_device_reset(root, "zram0").unwrap();
+    assert!(!setup::is_set_up_as_recorded(root, device, "zram0"));
+
+    /* Not to be kept */
+    setup::write_setup_record(root, device, "zram0");
+    device.persist_across_daemon_reload = false;
+    assert!(!setup::is_set_up_as_recorded(root, device, "zram0"));
+    setup::write_setup_record(root, device, "zram0");
+    device.persist_across_daemon_reload = true;
+    assert!(!setup::is_set_up_as_recorded(root, device, "zram0"));
+}
+
+fn plan(path: &str, device_name: &str) -> Vec<String> {
+    let rootdir = prepare_directory(Path::new(path)).unwrap();
+    let device = config::read_device(rootdir.path(), false, device_name)
+        .unwrap()
+        .unwrap();
+    setup::plan_device_setup(&device, device_name)
+        .iter()
+        .map(|step| step.to_string())
+        .collect()
+}
+
+#[test]
+fn test_13_recompression_off_plan() {
+    let zram0 = plan("tests/13-recompression-off", "zram0");
+    assert_eq!(
+        zram0[..5],
+        [
+            "write /sys/block/zram0/comp_algorithm \"lzo-rle\"",
+            "write /sys/block/zram0/recomp_algorithm \"algo=zstd priority=1\"\n\
+             write /sys/block/zram0/recompress \"level=3 priority=1\"",
+            "write /sys/block/zram0/mem_limit \"0\"",
+            "write /sys/block/zram0/disksize \"409993216\"",
+            /* the global recompression parameters, once the device is initialised */
+            "write /sys/block/zram0/recompress \"type=idle\"",
+        ]
+    );
+    assert!(zram0[5].ends_with("systemd-makefs swap /dev/zram0"));
+    assert_eq!(zram0.len(), 6);
+
+    /* recompression=off: the recompression algorithm is not configured */
+    let zram1 = plan("tests/13-recompression-off", "zram1");
+    assert_eq!(
+        zram1[..3],
+        [
+            "write /sys/block/zram1/comp_algorithm \"lzo-rle\"",
+            "write /sys/block/zram1/mem_limit \"0\"",
+            "write /sys/block/zram1/disksize \"409993216\"",
+        ]
+    );
+    assert!(zram1[3].ends_with("systemd-makefs swap /dev/zram1"));
+    assert_eq!(zram1.len(), 4);
+}
+
+#[test]
+fn test_07_mount_point_plan() {
+    let zram11 = plan("tests/07-mount-point", "zram11");
+    assert!(zram11
+        .last()
+        .unwrap()
+        .ends_with("systemd-makefs ext4 /dev/zram11"));
+}
+
+/// Units from the generated files, i.e. "systemd-zram-setup@zram0.service" in "After=... systemd-zram-setup@zram0.service"
+fn dependencies(unit: &str) -> Vec<&str> {
+    const DEPENDENCIES: &[&str] = &[
+        "Requires=",
+        "Requisite=",
+        "BindsTo=",
+        "PartOf=",
+        "Wants=",
+        "After=",
+        "Before=",
+        "Conflicts=",
+    ];
+    unit.lines()
+        .filter_map(|line| DEPENDENCIES.iter().find_map(|d| line.strip_prefix(d)))
+        .flat_map(str::split_whitespace)
+        .collect()
+}
+
+fn generated_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_symlink() {
+            continue;
+        }
+        if path.is_dir() {
+            generated_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+#[test]
+fn test_setup_service_references() {
+    /* The generated units order themselves against instances of the shipped template,
+     * so those must be what it's called. */
+    assert!(Path::new("units/systemd-zram-setup@.service.in").exists());
+
+    let mut files = vec![];
+    for fixture in fs::read_dir("tests").unwrap() {
+        let expected = fixture.unwrap().path().join("run.expected/units");
+        if expected.is_dir() {
+            generated_files(&expected, &mut files);
+        }
+    }
+    assert!(!files.is_empty());
+
+    for file in &files {
+        let name = file.file_name().unwrap().to_str().unwrap();
+        if name == "service.conf" {
+            /* after= and before= from the configuration */
+            continue;
+        }
+        let contents = fs::read_to_string(file).unwrap();
+        for unit in dependencies(&contents) {
+            let Some((template, instance)) = unit.split_once('@') else {
+                continue;
+            };
+            assert!(
+                [
+                    "systemd-zram-setup",
+                    "systemd-zram-makefs",
+                    "zram-recompress",
+                    "zram-mount-permissions"
+                ]
+                .contains(&template),
+                "{}: {}",
+                file.display(),
+                unit
+            );
+            let device = instance
+                .strip_suffix(".service")
+                .unwrap_or_else(|| panic!("{}: {} is not a service", file.display(), unit));
+
+            /* Units for one device only refer to that device's instances */
+            if name.starts_with("dev-zram") || name.contains('@') {
+                assert!(
+                    name.contains(&format!("{}.", device))
+                        || name.contains(&format!("{}-", device)),
+                    "{}: {}",
+                    file.display(),
+                    unit
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_02_zstd_drift() {
+    let rootdir = prepare_directory(Path::new("tests/02-zstd")).unwrap();
+    let root = rootdir.path();
+    let devices = config::read_all_devices(root, false).unwrap();
+
+    /* The configured device doesn't exist */
+    let drift = setup::find_drift(root, &devices).unwrap();
+    assert_eq!(
+        drift.iter().map(|d| d.to_string()).collect::<Vec<_>>(),
+        ["zram0: disksize: expected 614989824, found none"]
+    );
+
+    let sys = root.join("sys/block/zram0");
+    fs::create_dir_all(&sys).unwrap();
+    fs::write(sys.join("disksize"), "614989824\n").unwrap();
+    fs::write(sys.join("comp_algorithm"), "lzo lzo-rle lz4 [zstd]\n").unwrap();
+    /* mem_limit is usually write-only, and not readable */
+    assert!(setup::find_drift(root, &devices).unwrap().is_empty());
+    setup::run_check_drift(root, &devices).unwrap();
+
+    fs::write(sys.join("disksize"), "536870912\n").unwrap();
+    fs::write(sys.join("comp_algorithm"), "lzo [lzo-rle] lz4 zstd\n").unwrap();
+    fs::write(sys.join("mem_limit"), "0\n").unwrap();
+    /* An uninitialised device is fine, an initialised one isn't */
+    fs::create_dir_all(root.join("sys/block/zram1")).unwrap();
+    fs::write(root.join("sys/block/zram1/disksize"), "0\n").unwrap();
+    fs::create_dir_all(root.join("sys/block/zram2")).unwrap();
+    fs::write(root.join("sys/block/zram2/disksize"), "4096\n").unwrap();
+
+    let drift = setup::find_drift(root, &devices).unwrap();
+    assert_eq!(
+        drift.iter().map(|d| d.to_string()).collect::<Vec<_>>(),
+        [
+            "zram0: disksize: expected 614989824, found 536870912",
+            "zram0: comp_algorithm: expected zstd, found lzo-rle",
+            "zram0: mem_limit: expected 10484711424, found 0",
+            "zram2: disksize: expected none, found 4096",
+        ]
+    );
+    let remedy = format!(
+        "zram0: can't be changed in place; reset and set it up again with systemctl restart {}",
+        units::setup_service("zram0")
+    );
+    assert_eq!(
+        drift.iter().filter_map(|d| d.remedy()).collect::<Vec<_>>(),
+        [remedy.clone(), remedy.clone(), remedy]
+    );
+    let err = setup::run_check_drift(root, &devices).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "4 attribute(s) differ from the configuration"
+    );
+}
+
+#[test]
+fn test_swap_page_size_plan() {
+    let rootdir = prepare_config(
+        "[zram0]\nswap-page-size = 65536\n\
+         [zram1]\nswap-page-size = 16384\nmount-point = /var/tmp\n",
+    );
+    let root = rootdir.path();
+    let plan = |name| -> Vec<String> {
+        let device = config::read_device(root, false, name).unwrap().unwrap();
+        setup::plan_device_setup(&device, name)
+            .iter()
+            .map(|step| step.to_string())
+            .collect()
+    };
+
+    /* mkswap instead of systemd-makefs */
+    assert_eq!(
+        plan("zram0").last().unwrap(),
+        "exec mkswap --pagesize 65536 /dev/zram0"
+    );
+    /* Ignored for file systems */
+    assert!(plan("zram1")
+        .last()
+        .unwrap()
+        .ends_with("systemd-makefs ext2 /dev/zram1"));
+}
+
+#[test]
+fn test_recomp_algorithm_priorities() {
+    let rootdir =
+        prepare_config("[zram0]\ncompression-algorithm = lzo-rle zstd(level=3,priority=3) lz4hc\n");
+    let root = rootdir.path();
+    let conf = root.join("etc/systemd/zram-generator.conf");
+
+    let device = config::read_device(root, false, "zram0").unwrap().unwrap();
+    let plan: Vec<_> = setup::plan_device_setup(&device, "zram0")
+        .iter()
+        .map(|step| step.to_string())
+        .collect();
+    assert_eq!(
+        plan[..3],
+        [
+            "write /sys/block/zram0/comp_algorithm \"lzo-rle\"",
+            "write /sys/block/zram0/recomp_algorithm \"algo=zstd priority=3\"\n\
+             write /sys/block/zram0/recompress \"level=3 priority=3\"",
+            "write /sys/block/zram0/recomp_algorithm \"algo=lz4hc priority=2\"",
+        ]
+    );
+
+    fs::write(
+        &conf,
+        "[zram0]\ncompression-algorithm = lzo-rle zstd(priority=2) lz4hc\n",
+    )
+    .unwrap();
+    let err = config::read_device(root, false, "zram0").err().unwrap();
+    assert_eq!(
+        format!("{:#}", err),
+        "zram0: compression-algorithm: compression algorithm lz4hc: priority 2 is already taken"
+    );
+
+    /* auto is picked at setup, before the plan; recompression stays as configured */
+    fs::write(&conf, "[zram0]\ncompression-algorithm = auto zstd\n").unwrap();
+    let device = config::read_device(root, false, "zram0").unwrap().unwrap();
+    let plan: Vec<_> = setup::plan_device_setup(&device, "zram0")
+        .iter()
+        .map(|step| step.to_string())
+        .collect();
+    assert_eq!(
+        plan[0],
+        "write /sys/block/zram0/recomp_algorithm \"algo=zstd priority=1\""
+    );
+    assert!(!plan.iter().any(|step| step.contains("/comp_algorithm")));
+}
+
+#[test]
+fn test_43_writeback_partition() {
+    let devices = test_generation("tests/43-writeback-partition").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert_eq!(
+                d.writeback_dev.as_deref(),
+                Some(Path::new(
+                    "/dev/disk/by-partuuid/9f3e6a2b-1c4d-4e5f-8a9b-0c1d2e3f4a5b"
+                ))
+            ),
+            "zram1" => assert_eq!(
+                d.writeback_dev.as_deref(),
+                Some(Path::new(
+                    "/dev/disk/by-parttypeuuid/0657fd6d-a4ab-43c4-84e5-0933c84b4f4f"
+                ))
+            ),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_44_lazy_makefs() {
+    let devices = test_generation("tests/44-lazy-makefs").unwrap();
+    assert_eq!(devices.len(), 3);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert!(d.lazy_makefs),
+            "zram1" | "zram2" => assert!(!d.lazy_makefs),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+
+    /* Formatting is left to systemd-zram-makefs@zram0.service */
+    let plan0 = plan("tests/44-lazy-makefs", "zram0");
+    assert!(!plan0.iter().any(|s| s.starts_with("exec ")), "{:?}", plan0);
+    let plan1 = plan("tests/44-lazy-makefs", "zram1");
+    assert_eq!(
+        plan1.last().unwrap(),
+        &format!("exec {} swap /dev/zram1", setup::SYSTEMD_MAKEFS_COMMAND)
+    );
+
+    /* Without swap-page-size=, with systemd-makefs, whose path depends on the build */
+    let rootdir = prepare_directory(Path::new("tests/44-lazy-makefs")).unwrap();
+    let root = rootdir.path();
+    let mut devices = config::read_all_devices(root, false).unwrap();
+    for d in &mut devices {
+        d.lazy_makefs = d.name == "zram1";
+    }
+    let output_directory = root.join("run/units");
+    generator::run_generator(
+        &devices,
+        &config::Globals::default(),
+        &output_directory,
+        root,
+        true,
+        false,
+    )
+    .unwrap();
+    let unit =
+        fs::read_to_string(output_directory.join("systemd-zram-makefs@zram1.service")).unwrap();
+    assert!(unit.ends_with(&format!(
+        "ExecStart=\"{}\" \"swap\" \"/dev/zram1\"\n",
+        setup::SYSTEMD_MAKEFS_COMMAND
+    )));
+    assert!(!output_directory
+        .join("systemd-zram-makefs@zram0.service")
+        .exists());
+    let swap = fs::read_to_string(output_directory.join("dev-zram1.swap")).unwrap();
+    assert!(swap.contains(
+        "\nRequires=systemd-zram-makefs@zram1.service\nAfter=systemd-zram-makefs@zram1.service\n"
+    ));
+}
+
+#[test]
+fn test_generator_and_setup() {
+    /* --all: the units are the same as from the generator, followed by the setup of each device */
+    let srcroot = Path::new("tests/10-example");
+    let rootdir = prepare_directory(srcroot).unwrap();
+    let root = rootdir.path();
+
+    let (devices, globals) = config::read_all_devices_with_globals(root, false).unwrap();
+    assert_eq!(devices.len(), 2);
+    let output_directory = root.join("run/units");
+    generator::run_generator_and_setup(devices, &globals, &output_directory, root, true).unwrap();
+
+    compare_output(srcroot, root).unwrap();
+}
+
+#[test]
+fn test_makefs_mode_systemd() {
+    let rootdir = prepare_config(
+        "[zram0]\nmakefs-mode = systemd\nlazy-makefs = yes\n\
+         [zram1]\nmakefs-mode = systemd\nmount-point = /var/compressed\nfs-type = ext4\n\
+         compression-algorithm = lzo-rle zstd\nrecompress-now = yes\n\
+         [zram2]\nmakefs-mode = systemd\nswap-page-size = 65536\n",
+    );
+    let root = rootdir.path();
+
+    let (devices, globals) = config::read_all_devices_with_globals(root, false).unwrap();
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(d.makefs_mode, config::MakefsMode::Systemd);
+                assert!(!d.lazy_makefs);
+            }
+            "zram1" => assert_eq!(d.makefs_mode, config::MakefsMode::Systemd),
+            /* systemd-makefs can't do swap-page-size= */
+            "zram2" => assert_eq!(d.makefs_mode, config::MakefsMode::Setup),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+
+    let output_directory = root.join("run/units");
+    generator::run_generator(&devices, &globals, &output_directory, root, true, false).unwrap();
+
+    /* No self-makefs, and recompress-now= is left for after the formatting */
+    for name in ["zram0", "zram1"] {
+        let device = config::read_device(root, false, name).unwrap().unwrap();
+        let plan = setup::plan_device_setup(&device, name);
+        assert!(plan.iter().all(|step| step.command().is_none()));
+        assert!(plan
+            .iter()
+            .all(|step| !matches!(step, setup::Step::Trigger { .. })));
+    }
+
+    let unit =
+        fs::read_to_string(output_directory.join("systemd-zram-makefs@zram0.service")).unwrap();
+    assert_eq!(
+        unit,
+        format!(
+            "\
+# Automatically generated by {}
+
+[Unit]
+Description=Create swap on /dev/zram0
+Documentation=man:zram-generator(8) man:zram-generator.conf(5)
+DefaultDependencies=no
+BindsTo=systemd-zram-setup@zram0.service
+After=systemd-zram-setup@zram0.service
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart=\"{}\" \"swap\" \"/dev/zram0\"
+",
+            std::env::current_exe().unwrap().display(),
+            setup::SYSTEMD_MAKEFS_COMMAND
+        )
+    );
+    let swap = fs::read_to_string(output_directory.join("dev-zram0.swap")).unwrap();
+    assert!(swap.contains(
+        "\nRequires=systemd-zram-makefs@zram0.service\nAfter=systemd-zram-makefs@zram0.service\n"
+    ));
+
+    let unit =
+        fs::read_to_string(output_directory.join("systemd-zram-makefs@zram1.service")).unwrap();
+    assert!(unit.contains(
+        "\" \"ext4\" \"/dev/zram1\"\n\
+         ExecStart=-/bin/sh -c 'echo all >/sys/block/zram1/idle'\n\
+         ExecStart=-/bin/sh -c 'echo type=idle >/sys/block/zram1/recompress'\n"
+    ));
+    let mount = fs::read_to_string(output_directory.join("var-compressed.mount")).unwrap();
+    assert!(mount.contains(
+        "\nRequires=systemd-zram-makefs@zram1.service\nAfter=systemd-zram-makefs@zram1.service\n"
+    ));
+
+    assert!(!output_directory
+        .join("systemd-zram-makefs@zram2.service")
+        .exists());
+}
+
+#[test]
+fn test_45_kernel_overrides() {
+    let devices = test_generation("tests/45-kernel-overrides").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(d.zram_size.as_ref().map(z_s_name), Some("min(ram,2048)"));
+                assert_eq!(d.disksize, 782 << 20);
+            }
+            /* The invalid swap-priority=high is ignored, the earlier one stays */
+            "zram1" => assert_eq!(d.swap_priority, Some(10)),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+
+    let plan0 = plan("tests/45-kernel-overrides", "zram0");
+    assert_eq!(plan0[0], "write /sys/block/zram0/comp_algorithm \"zstd\"");
+}
+
+#[test]
+fn test_46_writeback_required() {
+    let devices = test_generation("tests/46-writeback-required").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    let bindings = |name: &str| {
+        fs::read_to_string(
+            Path::new("tests/46-writeback-required/run.expected/units").join(format!(
+                "systemd-zram-setup@{}.service.d/bindings.conf",
+                name
+            )),
+        )
+        .unwrap()
+    };
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                assert!(d.writeback_required);
+                assert!(bindings("zram0").contains(
+                    "\nBindsTo=dev-%i.swap dev-disk-by\\x2dpartlabel-zram\\x2dwb.device\n"
+                ));
+            }
+            "zram1" => {
+                assert!(!d.writeback_required);
+                let bindings = bindings("zram1");
+                assert!(bindings.contains("\nBindsTo=dev-%i.swap\nWants=dev-sdb1.device\n"));
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_47_slice() {
+    let devices = test_generation("tests/47-slice").unwrap();
+    assert_eq!(devices.len(), 2);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert_eq!(d.slice.as_deref(), Some("system-zram.slice")),
+            "zram1" => assert_eq!(d.slice, None),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+
+    let service = fs::read_to_string(
+        "tests/47-slice/run.expected/units/systemd-zram-setup@zram0.service.d/service.conf",
+    )
+    .unwrap();
+    assert!(service.ends_with("[Service]\nSlice=system-zram.slice\n"));
+}
+
+#[test]
+fn test_48_uptime() {
+    let devices = test_generation("tests/48-uptime").unwrap();
+    assert_eq!(devices.len(), 1);
+    /* 7384s since boot, so ram / 2 rather than ram / 4 */
+    assert_eq!(devices[0].disksize, 782 * 1024 * 1024 / 2);
+
+    /* Right after boot */
+    let rootdir = prepare_directory(Path::new("tests/48-uptime")).unwrap();
+    let root = rootdir.path();
+    fs::write(root.join("proc/uptime"), "3.05 9.87\n").unwrap();
+    let devices = config::read_all_devices(root, false).unwrap();
+    assert_eq!(devices[0].disksize, 782 * 1024 * 1024 / 4);
+}
+
+#[test]
+fn test_49_swap_nofail() {
+    let devices = test_generation("tests/49-swap-nofail").unwrap();
+    assert_eq!(devices.len(), 3);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => assert!(d.swap_nofail),
+            "zram1" => assert!(!d.swap_nofail),
+            /* Ignored with a warning for a mount */
+            "zram2" => assert!(!d.swap_nofail),
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+
+    let units = Path::new("tests/49-swap-nofail/run.expected/units");
+    let swap = fs::read_to_string(units.join("dev-zram0.swap")).unwrap();
+    assert!(!swap.contains("Before=swap.target"));
+    let swap = fs::read_to_string(units.join("dev-zram1.swap")).unwrap();
+    assert!(swap.contains("Before=swap.target\n"));
+
+    /* Not a hard requirement of any target, required-by= notwithstanding */
+    for entry in fs::read_dir(units).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|e| e == "requires") {
+            assert!(!path.join("dev-zram0.swap").exists(), "{}", path.display());
+        } else if path.is_file() {
+            let unit = fs::read_to_string(&path).unwrap();
+            assert!(!unit.contains("dev-zram0.swap"), "{}", path.display());
+        }
+    }
+    for target in ["swap.target", "multi-user.target"] {
+        assert_eq!(
+            fs::read_link(units.join(format!("{}.wants/dev-zram0.swap", target))).unwrap(),
+            Path::new("../dev-zram0.swap")
+        );
+    }
+    assert!(units
+        .join("multi-user.target.requires/dev-zram1.swap")
+        .exists());
+}
+
+#[test]
+fn test_50_fs_reserve() {
+    let devices = test_generation("tests/50-fs-reserve").unwrap();
+    assert_eq!(devices.len(), 3);
+
+    for d in &devices {
+        match d.name.as_str() {
+            "zram0" => {
+                assert_eq!(d.fs_reserve, Some(5.));
+                /* 512MB / 95%, rounded up to a page */
+                assert_eq!(d.disksize, 565129216);
+            }
+            "zram1" => {
+                assert_eq!(d.fs_reserve, None);
+                assert_eq!(d.disksize, 782 << 20);
+            }
+            "zram2" => {
+                assert!(d.is_swap());
+                assert_eq!(d.disksize, 782 * 1024 * 1024 / 2);
+            }
+            _ => panic!("Unexpected device {}", d),
+        }
+    }
+}
+
+#[test]
+fn test_zram_on_zram() {
+    let rootdir = prepare_config(
+        "[zram0]\nwriteback-device = /dev/mapper/wb\n\
+         [zram2]\nwriteback-device = /dev/sda1\nmount-point = /srv/scratch\n\
+         [zram3]\nmount-point = /var/scratch\n",
+    );
+    let root = rootdir.path();
+    fs::create_dir_all(root.join("proc/self")).unwrap();
+
+    /* dm-1 (/dev/mapper/wb, mounted on /srv) is on dm-0, which is on zram1 */
+    for (name, slaves) in [
+        ("dm-0", &["zram1"][..]),
+        ("dm-1", &["dm-0"]),
+        ("sda1", &[]),
+        ("zram1", &[]),
+    ] {
+        fs::create_dir_all(root.join("sys/class/block").join(name).join("slaves")).unwrap();
+        for slave in slaves {
+            fs::create_dir_all(
+                root.join("sys/class/block")
+                    .join(name)
+                    .join("slaves")
+                    .join(slave),
+            )
+            .unwrap();
+        }
+        fs::create_dir_all(root.join("dev")).unwrap();
+        fs::write(root.join("dev").join(name), "").unwrap();
+    }
+    fs::create_dir_all(root.join("dev/mapper")).unwrap();
+    std::os::unix::fs::symlink("../dm-1", root.join("dev/mapper/wb")).unwrap();
+    fs::create_dir_all(root.join("sys/dev/block")).unwrap();
+    std::os::unix::fs::symlink(
+        "../../devices/pci0000:00/0000:00:1f.2/ata1/host0/target0:0:0/0:0:0:0/block/sda/sda1",
+        root.join("sys/dev/block/8:1"),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink(
+        "../../devices/virtual/block/dm-1",
+        root.join("sys/dev/block/253:1"),
+    )
+    .unwrap();
+    fs::write(
+        root.join("proc/self/mountinfo"),
+        "1 0 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+         2 1 253:1 / /srv rw,relatime shared:2 - xfs /dev/mapper/wb rw\n",
+    )
+    .unwrap();
+
+    let problems = |name| {
+        let device = config::read_device(root, false, name).unwrap().unwrap();
+        setup::find_zram_on_zram(root, &device, name)
+    };
+    assert_eq!(
+        problems("zram0"),
+        ["zram0: writeback-device=/dev/mapper/wb is on zram1, write-back would go into compressed memory."]
+    );
+    assert_eq!(
+        problems("zram2"),
+        ["zram2: mount-point=/srv/scratch is underneath a file system on zram1."]
+    );
+    assert!(problems("zram3").is_empty());
+}